@@ -5,6 +5,7 @@ use crate::arch::address::VirtAddr;
 use crate::arch::paging::ActiveMapping;
 use crate::arch::paging::EntryFlags;
 use crate::mm::mapper::MemoryMapper;
+use core::cmp::min;
 use core::convert::TryInto;
 
 #[derive(Debug)]
@@ -119,4 +120,12 @@ impl Hpet {
     pub fn counter_to_ns(&self, val: u64) -> u64 {
         (val / 1000) * (self.clock_period / 1000)
     }
+
+    /// Convert nanoseconds to a counter difference, saturates at the maximum counter value.
+    pub fn ns_to_counter(&self, ns: u64) -> u64 {
+        min(
+            ns as u128 * 1_000_000 / self.clock_period as u128,
+            core::u64::MAX as u128,
+        ) as u64
+    }
 }
@@ -37,9 +37,13 @@ _switch_to_next:
 
     ret
 
+.extern EPOCH
+
 .global irq0
 .type irq0, @function
 irq0:
+    // Bump the epoch, even if we can't preempt, see `wasm::epoch`.
+    lock incq EPOCH(%rip)
     cmpl $0, %gs:8 // Check if preempt_count != 0
     jnz .flag
     pushq %rax
@@ -0,0 +1,77 @@
+.section .text
+
+// Entry points of the exceptions that wasm traps cause.
+// They pass the frame pointer of the interrupted code to the handler, for backtraces. A handler
+// can't read it itself, because its prologue may already have changed %rbp.
+
+.macro push_scratch
+    pushq %rax
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %rcx
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+.endm
+
+.macro pop_scratch
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rcx
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rax
+.endm
+
+// Exception without error code, calls handler(frame: &mut ISRStackFrame, fp: VirtAddr).
+.macro exception_entry name, handler
+.extern \handler
+.global \name
+.type \name, @function
+\name:
+    pushq %rbp
+    movq %rsp, %rbp
+    push_scratch
+
+    // The stack is 16 byte aligned after the frame of 5 words, so realign after 10 pushes.
+    subq $8, %rsp
+    leaq 8(%rbp), %rdi
+    movq (%rbp), %rsi
+    cld
+    call \handler
+    addq $8, %rsp
+
+    pop_scratch
+    popq %rbp
+    iretq
+.endm
+
+exception_entry exc_divide_by_zero_entry, exc_divide_by_zero
+exception_entry exc_invalid_opcode_entry, exc_invalid_opcode
+
+// Page fault, calls exc_pf(frame: &mut ISRStackFrame, err: u64, fp: VirtAddr).
+.extern exc_pf
+.global exc_pf_entry
+.type exc_pf_entry, @function
+exc_pf_entry:
+    pushq %rbp
+    movq %rsp, %rbp
+    push_scratch
+
+    // The frame of 5 words, the error code and 10 pushes keep the stack 16 byte aligned.
+    leaq 16(%rbp), %rdi
+    movq 8(%rbp), %rsi
+    movq (%rbp), %rdx
+    cld
+    call exc_pf
+
+    pop_scratch
+    popq %rbp
+    // Pop the error code.
+    addq $8, %rsp
+    iretq
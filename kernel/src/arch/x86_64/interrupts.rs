@@ -6,7 +6,8 @@ use lazy_static::lazy_static;
 use crate::arch::x86_64::address::VirtAddr;
 use crate::arch::x86_64::paging::PageFaultError;
 use crate::arch::x86_64::port::write_port8;
-use crate::tasking::scheduler;
+use crate::wasm::trap;
+use cranelift_codegen::ir::TrapCode;
 
 /// The stack frame pushed by the CPU for an ISR.
 #[derive(Debug)]
@@ -108,13 +109,13 @@ lazy_static! {
         let exc_flags = EntryFlags::PRESENT | EntryFlags::INT_GATE;
 
         let mut idt = IDT::new();
-        idt.set_handler(0, exc_divide_by_zero as usize, exc_flags, 0);
+        idt.set_handler(0, exc_divide_by_zero_entry as usize, exc_flags, 0);
         idt.set_handler(1, exc_debug as usize, exc_flags, 0);
         idt.set_handler(2, exc_nmi as usize, exc_flags, 1);
         idt.set_handler(3, exc_breakpoint as usize, exc_flags, 0);
         idt.set_handler(4, exc_overflow as usize, exc_flags, 0);
         idt.set_handler(5, exc_bound_range_exceeded as usize, exc_flags, 0);
-        idt.set_handler(6, exc_invalid_opcode as usize, exc_flags, 0);
+        idt.set_handler(6, exc_invalid_opcode_entry as usize, exc_flags, 0);
         idt.set_handler(7, exc_device_not_available as usize, exc_flags, 0);
         idt.set_handler(8, exc_double_fault as usize, exc_flags, 1);
         idt.set_handler(9, exc_unknown as usize, exc_flags, 0);
@@ -122,7 +123,7 @@ lazy_static! {
         idt.set_handler(11, exc_segment_not_present as usize, exc_flags, 0);
         idt.set_handler(12, exc_stack_segment as usize, exc_flags, 0);
         idt.set_handler(13, exc_gpf as usize, exc_flags, 1);
-        idt.set_handler(14, exc_pf as usize, exc_flags, 1);
+        idt.set_handler(14, exc_pf_entry as usize, exc_flags, 1);
         idt.set_handler(15, exc_unknown as usize, exc_flags, 0);
         idt.set_handler(16, exc_fp as usize, exc_flags, 0);
         idt.set_handler(17, exc_alignment_check as usize, exc_flags, 0);
@@ -142,6 +143,9 @@ lazy_static! {
         idt.set_handler(31, exc_unknown as usize, exc_flags, 0);
 
         extern "C" {
+            fn exc_divide_by_zero_entry();
+            fn exc_invalid_opcode_entry();
+            fn exc_pf_entry();
             fn irq0();
         }
 
@@ -218,7 +222,10 @@ extern "x86-interrupt" fn exc_unknown(frame: &mut ISRStackFrame) {
     panic!("Unknown exception: {:#?}", frame);
 }
 
-extern "x86-interrupt" fn exc_divide_by_zero(frame: &mut ISRStackFrame) {
+/// Called from `exc_divide_by_zero_entry`, `fp` is the frame pointer of the interrupted code.
+#[no_mangle]
+extern "C" fn exc_divide_by_zero(frame: &mut ISRStackFrame, fp: VirtAddr) {
+    trap::handle_fault(frame.rip, fp, TrapCode::IntegerDivisionByZero);
     panic!("Divide by zero exception: {:#?}", frame);
 }
 
@@ -242,10 +249,15 @@ extern "x86-interrupt" fn exc_bound_range_exceeded(frame: &mut ISRStackFrame) {
     panic!("Bound range exceeded exception: {:#?}", frame);
 }
 
-extern "x86-interrupt" fn exc_invalid_opcode(_frame: &mut ISRStackFrame) {
-    // Can't check RIP because we do ud2 too in kernel code for userspace.
-    println!("wasm trap, thread killed");
-    scheduler::thread_exit(u32::MAX); // TODO: exit code
+/// Called from `exc_invalid_opcode_entry`, `fp` is the frame pointer of the interrupted code.
+#[no_mangle]
+extern "C" fn exc_invalid_opcode(frame: &mut ISRStackFrame, fp: VirtAddr) {
+    // Cranelift emits ud2 for traps.
+    if let Some(ip) = trap::handle_trap_instruction(frame.rip, fp) {
+        frame.rip = ip;
+        return;
+    }
+    panic!("Invalid opcode: {:#?}", frame);
 }
 
 extern "x86-interrupt" fn exc_device_not_available(frame: &mut ISRStackFrame) {
@@ -272,7 +284,10 @@ extern "x86-interrupt" fn exc_gpf(frame: &mut ISRStackFrame, err: u64) {
     panic!("GPF: {:#?}, errcode {:x}", frame, err);
 }
 
-extern "x86-interrupt" fn exc_pf(frame: &mut ISRStackFrame, err: PageFaultError) {
+/// Called from `exc_pf_entry`, `fp` is the frame pointer of the interrupted code.
+#[no_mangle]
+extern "C" fn exc_pf(frame: &mut ISRStackFrame, err: u64, fp: VirtAddr) {
+    let err = PageFaultError::from_bits_truncate(err);
     let addr: VirtAddr;
     unsafe {
         llvm_asm!("movq %cr2, $0" : "=r" (addr));
@@ -282,6 +297,7 @@ extern "x86-interrupt" fn exc_pf(frame: &mut ISRStackFrame, err: PageFaultError)
     crate::mm::page_fault(
         addr,
         frame.rip,
+        fp,
         err.contains(PageFaultError::CAUSED_BY_WRITE),
     );
 }
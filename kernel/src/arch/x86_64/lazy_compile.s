@@ -0,0 +1,35 @@
+.section .text
+
+.global lazy_compile_trampoline
+.type lazy_compile_trampoline, @function
+
+// Jumped to from the stub of a lazily compiled function, the function index is in %eax.
+// The arguments of the function are still in place, with the vmctx in %rdi.
+// Compiles the function and continues in the compiled code.
+lazy_compile_trampoline:
+    pushq %rbp
+    movq %rsp, %rbp
+
+    // Save the integer argument registers. The vector registers don't need to be saved,
+    // because the kernel doesn't use them.
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %rcx
+    pushq %r8
+    pushq %r9
+
+    // The stack is 16 byte aligned again: the return address and 7 pushes.
+    movl %eax, %esi
+    callq runtime_lazy_compile
+
+    popq %r9
+    popq %r8
+    popq %rcx
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rbp
+
+    // The return address of the caller is still on the stack.
+    jmpq *%rax
@@ -69,6 +69,17 @@ impl Drop for SimdState {
 pub fn setup_simd() {
     let cpuid = CpuId::new();
 
+    // Wasm code is compiled for a fixed set of features, see `wasm_compiler::TARGET_FEATURES`.
+    let features = cpuid.get_feature_info().unwrap();
+    assert!(
+        features.has_sse3()
+            && features.has_ssse3()
+            && features.has_sse41()
+            && features.has_sse42()
+            && features.has_popcnt(),
+        "the CPU lacks features that wasm code uses"
+    );
+
     // Set OSFXSR and OSXMMEXCPT bits, at least SSE2 is available.
     let mut cr4 = cr4_read();
     cr4 |= (1 << 9) | (1 << 10);
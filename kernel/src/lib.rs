@@ -38,12 +38,17 @@ use crate::arch::hpet;
 use crate::arch::paging::{ActiveMapping, EntryFlags};
 use crate::mm::mapper::MemoryMapper;
 use crate::mm::tcb_alloc::with_thread;
+use crate::tasking::protection_domain::ProtectionDomain;
 use crate::tasking::scheduler::{self, thread_exit, with_core_scheduler, with_current_thread};
 use crate::tasking::scheme_container::schemes;
 use crate::tasking::thread::Thread;
 use crate::util::boot_module::{BootModule, BootModuleProvider};
+use crate::util::manifest::{Manifest, StartMode, KERNEL_DOMAIN_NAME, MANIFEST_FILE_NAME};
 use crate::util::tar::Tar;
 use alloc::boxed::Box;
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use wasm_compiler::artifact::ARTIFACT_SUFFIX;
 
 #[macro_use]
 mod util;
@@ -92,7 +97,7 @@ pub fn kernel_run(reserved_end: VirtAddr, _boot_modules: impl BootModuleProvider
 fn handle_module(module: BootModule) -> Option<()> {
     println!("Handle module {:?}", module);
 
-    // Safety: module data is correct.
+    // Safety: module data is correct, boot modules are never unmapped.
     let tar = unsafe {
         Tar::from_slice(slice::from_raw_parts(
             module.range.start.as_const(),
@@ -100,16 +105,64 @@ fn handle_module(module: BootModule) -> Option<()> {
         ))
     }?;
 
-    // For now, just try to run all files in the tar.
-    // Might need a manifest or something alike in the future.
-    for file in tar {
-        // TODO: we should probably have a manifest file which describes what services should be
-        //       in the same domain.
-        //let domain = ProtectionDomain::new().expect("domain");
-        let domain = with_current_thread(|t| t.domain().clone());
-        wasm::main::run(file.as_slice(), domain).unwrap_or_else(|e| {
-            println!("Could not start: {:?}", e);
-        });
+    // The manifest describes which files are services, all other files are data.
+    let manifest = match tar.find(MANIFEST_FILE_NAME) {
+        Some(file) => Manifest::parse(file.as_slice())
+            .map_err(|e| println!("Could not parse the boot manifest: {:?}", e))
+            .ok()?,
+        None => {
+            println!("No boot manifest found in module");
+            return Some(());
+        }
+    };
+
+    let mut domains: BTreeMap<&str, ProtectionDomain> = BTreeMap::new();
+
+    for entry in manifest.entries() {
+        let file = match tar.find(entry.file.as_bytes()) {
+            Some(file) => file,
+            None => {
+                println!("Could not find {} in module", entry.file);
+                continue;
+            }
+        };
+
+        let domain = match domains.entry(entry.domain) {
+            Entry::Occupied(o) => o.get().clone(),
+            Entry::Vacant(v) => {
+                let domain = if entry.domain == KERNEL_DOMAIN_NAME {
+                    with_current_thread(|t| t.domain().clone())
+                } else {
+                    match ProtectionDomain::new() {
+                        Ok(domain) => domain,
+                        Err(e) => {
+                            println!("Could not create domain {}: {:?}", entry.domain, e);
+                            continue;
+                        }
+                    }
+                };
+                v.insert(domain).clone()
+            }
+        };
+
+        // A precompiled artifact is stored next to the module.
+        let artifact_name = format!("{}{}", entry.file, ARTIFACT_SUFFIX);
+        let artifact = tar
+            .find(artifact_name.as_bytes())
+            .map(|file| file.as_slice());
+
+        match entry.start_mode {
+            StartMode::Boot => {
+                if let Err(e) = wasm::main::run(file.as_slice(), artifact, domain, entry) {
+                    println!("Could not start {}: {:?}", entry.file, e);
+                }
+            }
+            StartMode::Manual => {
+                if !wasm::programs::register(file.as_slice(), artifact, domain, entry.clone()) {
+                    println!("Program {} is already registered", entry.file);
+                }
+            }
+        }
     }
 
     Some(())
@@ -3,8 +3,10 @@ use crate::arch::address::VirtAddr;
 use crate::arch::paging::{get_cpu_page_mapping, ActiveMapping};
 use crate::mm::mapper::MemoryMapper;
 use crate::mm::tcb_alloc::pagefault_tcb_alloc;
-use crate::tasking::scheduler::{self, with_current_thread};
+use crate::tasking::scheduler::with_current_thread;
+use crate::wasm::trap;
 use core::intrinsics::unlikely;
+use cranelift_codegen::ir::TrapCode;
 
 mod alloc;
 pub mod avl_interval_tree;
@@ -20,7 +22,7 @@ pub unsafe fn init(reserved_end: VirtAddr) {
 }
 
 /// Page fault handler.
-pub fn page_fault(fault_addr: VirtAddr, ip: VirtAddr, write: bool) {
+pub fn page_fault(fault_addr: VirtAddr, ip: VirtAddr, fp: VirtAddr, write: bool) {
     if fault_addr.as_usize() >= arch::TCB_START
         && fault_addr.as_usize() < arch::TCB_START + arch::TCB_LEN
     {
@@ -32,24 +34,30 @@ pub fn page_fault(fault_addr: VirtAddr, ip: VirtAddr, write: bool) {
     let failed = !with_current_thread(|thread| thread.page_fault(fault_addr));
 
     if unlikely(failed) {
-        if fault_addr.as_usize() < arch::USER_START || ip.as_usize() < arch::USER_START {
-            // Kernel fault.
-            // TODO: show cause (recognize stack overflow for example)
-            panic!(
-                "Pagefault in kernel, faulting address: {:?} -> {:?}, IP: {:?}, PAGEMAP: {:?}",
-                fault_addr,
-                // We're crashing anyway, so no concurrent things will be happening.
-                unsafe { ActiveMapping::get_unlocked().translate(fault_addr) },
-                ip,
-                get_cpu_page_mapping()
-            );
-        } else {
-            // Kill the thread.
-            println!("Pagefault in thread, faulting address: {:?}", fault_addr);
-            //println!("{:?}", unsafe {
-            //    ActiveMapping::get_unlocked().translate(fault_addr)
-            //});
-            scheduler::thread_exit(u32::MAX); // TODO: exit code
+        // Stack overflow in wasm code.
+        // Host functions run on the same stack, an overflow in there is a kernel fault.
+        if with_current_thread(|thread| {
+            thread.stack.is_in_guard(fault_addr)
+                && thread
+                    .try_with_wasm_data(|data| data.instance().find_code(ip).is_some())
+                    .unwrap_or(false)
+        }) {
+            trap::terminate(TrapCode::StackOverflow, ip, fp);
         }
+
+        if fault_addr.as_usize() >= arch::USER_START && ip.as_usize() >= arch::USER_START {
+            // Out of bounds memory access in wasm code.
+            trap::handle_fault(ip, fp, TrapCode::HeapOutOfBounds);
+        }
+
+        // Kernel fault, or a fault that can't be explained by wasm code.
+        panic!(
+            "Unhandled pagefault, faulting address: {:?} -> {:?}, IP: {:?}, PAGEMAP: {:?}",
+            fault_addr,
+            // We're crashing anyway, so no concurrent things will be happening.
+            unsafe { ActiveMapping::get_unlocked().translate(fault_addr) },
+            ip,
+            get_cpu_page_mapping()
+        );
     }
 }
@@ -169,3 +169,41 @@ where
         f(block.0.assume_init_ref())
     }
 }
+
+/// Executes something in context of a thread, if the thread still exists.
+/// Unlike `with_thread`, this can be used with thread ids that come from untrusted code.
+/// The thread can't be deallocated while `f` runs.
+pub fn try_with_thread<F, T>(tid: ThreadId, f: F) -> Option<T>
+where
+    F: FnOnce(&Thread) -> T,
+{
+    // The lock keeps the thread and its page from being deallocated.
+    let _guard = TCB_PAGE_LOCK.lock();
+
+    let (page_addr, offset) = tid_to_addr(tid);
+    if page_addr >= TCB_START + TCB_LEN {
+        return None;
+    }
+
+    // Safety:
+    // No concurrent access on the shared page tables because these are unique for the TCB,
+    // and we're locking.
+    let mapping = unsafe { ActiveMapping::get_unlocked() };
+    // Reading a page that isn't mapped would fault.
+    mapping.translate(VirtAddr::new(page_addr))?;
+
+    // Safety:
+    // Only non-mutable references are ever made to `TcbPage`.
+    let page = unsafe { &*(page_addr as *const TcbPage) };
+    if page.meta_data().free.load(Ordering::Acquire) & (1 << offset) != 0 {
+        return None;
+    }
+
+    // Safety: the slot is in use, so the thread is initialised.
+    let thread = unsafe { page.threads[offset].0.assume_init_ref() };
+    if thread.id == tid {
+        Some(f(thread))
+    } else {
+        None
+    }
+}
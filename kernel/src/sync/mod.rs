@@ -2,3 +2,4 @@ pub mod cond_var_single;
 pub mod spinlock;
 pub mod thread_block_guard;
 pub mod wait_queue;
+pub mod wait_table;
@@ -2,12 +2,15 @@ use crate::tasking::scheduler;
 use crate::tasking::scheduler::with_current_thread;
 use crate::tasking::thread::ThreadStatus;
 use core::intrinsics::likely;
+use core::sync::atomic::{fence, Ordering};
 
 /// Guard that marks the thread as blocked.
 /// The thread will be yielded and woken up later on drop if the resource hasn't become available
 /// already.
 /// If it's become available already, no yield will happen and the thread can continue immediately.
-pub struct ThreadBlockGuard {}
+pub struct ThreadBlockGuard {
+    interruptible: bool,
+}
 
 impl ThreadBlockGuard {
     /// Activates the block guard.
@@ -15,7 +18,27 @@ impl ThreadBlockGuard {
         // Mark the thread as blocked.
         // Next context switch the thread will block.
         with_current_thread(|thread| thread.set_status(ThreadStatus::Blocked));
-        Self {}
+        Self {
+            interruptible: false,
+        }
+    }
+
+    /// Activates the block guard for a wait that a kill request can interrupt,
+    /// see `wasm::epoch::kill`. The waiter must handle being woken up without a reason.
+    pub fn activate_interruptible() -> Self {
+        with_current_thread(|thread| {
+            thread.set_interruptible_block(true);
+            thread.set_status(ThreadStatus::Blocked);
+            // Pairs with the fence in `wasm::epoch::kill`: either the killer sees that we block,
+            // or we see the request here and don't block at all.
+            fence(Ordering::SeqCst);
+            if thread.kill_requested() {
+                thread.unblock();
+            }
+        });
+        Self {
+            interruptible: true,
+        }
     }
 }
 
@@ -29,5 +52,9 @@ impl Drop for ThreadBlockGuard {
         if likely(with_current_thread(|thread| thread.status()) == ThreadStatus::Blocked) {
             scheduler::thread_yield();
         }
+
+        if self.interruptible {
+            with_current_thread(|thread| thread.set_interruptible_block(false));
+        }
     }
 }
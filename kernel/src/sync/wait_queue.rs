@@ -1,6 +1,8 @@
 use crate::sync::cond_var_single::CondVarSingle;
 use crate::sync::spinlock::Spinlock;
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp::min;
 use core::intrinsics::unlikely;
 
 /// A queue with one waiter and multiple producers.
@@ -39,28 +41,17 @@ impl<T> WaitQueue<T> {
     }
 
     /// If there are no elements available: block.
-    /// Otherwise: pops as many elements as possible without going to block.
-    pub fn pop_front_many(&self, buffer: &mut [T]) -> usize {
-        if unlikely(buffer.is_empty()) {
-            return 0;
+    /// Otherwise: pops as many elements as possible without going to block, at most `max`.
+    pub fn pop_front_many(&self, max: usize) -> Vec<T> {
+        if unlikely(max == 0) {
+            return Vec::new();
         }
 
         loop {
             let mut guard = self.queue.lock();
-            if let Some(t) = guard.pop_front() {
-                buffer[0] = t;
-
-                let mut count = 1usize;
-                while count < buffer.len() {
-                    if let Some(t) = guard.pop_front() {
-                        buffer[count] = t;
-                        count += 1;
-                    } else {
-                        break;
-                    }
-                }
-
-                return count;
+            if !guard.is_empty() {
+                let count = min(max, guard.len());
+                return guard.drain(..count).collect();
             } else {
                 self.cond_var.wait(guard);
             }
@@ -0,0 +1,141 @@
+use crate::mm::tcb_alloc::with_thread;
+use crate::sync::spinlock::Spinlock;
+use crate::sync::thread_block_guard::ThreadBlockGuard;
+use crate::tasking::scheduler::{with_core_scheduler, with_current_thread};
+use crate::tasking::thread::ThreadId;
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Result of a wait.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WaitResult {
+    /// Woken up by a notify.
+    Woken,
+    /// The condition didn't hold, so the thread didn't wait.
+    NotEqual,
+    /// The deadline was reached before the thread was woken up.
+    TimedOut,
+    /// A kill request woke the thread up, see `wasm::epoch::kill`.
+    Interrupted,
+}
+
+/// Queues of waiting threads, keyed by address: multiple waiters, multiple notifiers.
+/// Waiters are woken up in the order they started waiting.
+pub struct WaitTable {
+    queues: Spinlock<BTreeMap<usize, VecDeque<ThreadId>>>,
+}
+
+impl WaitTable {
+    /// Creates a new `WaitTable`.
+    pub fn new() -> Self {
+        Self {
+            queues: Spinlock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Waits on an address until notified, if the condition holds.
+    /// The condition is checked while notifiers are held off, so no notify can get lost.
+    /// The deadline is an Hpet counter value.
+    pub fn wait<F>(&self, address: usize, condition: F, deadline: Option<u64>) -> WaitResult
+    where
+        F: FnOnce() -> bool,
+    {
+        self.wait_inner(address, condition, deadline, false)
+    }
+
+    /// Like `wait`, but a kill request of the thread can also end the wait.
+    pub fn wait_interruptible<F>(
+        &self,
+        address: usize,
+        condition: F,
+        deadline: Option<u64>,
+    ) -> WaitResult
+    where
+        F: FnOnce() -> bool,
+    {
+        self.wait_inner(address, condition, deadline, true)
+    }
+
+    fn wait_inner<F>(
+        &self,
+        address: usize,
+        condition: F,
+        deadline: Option<u64>,
+        interruptible: bool,
+    ) -> WaitResult
+    where
+        F: FnOnce() -> bool,
+    {
+        let tid = with_current_thread(|thread| thread.id);
+
+        {
+            let mut queues = self.queues.lock();
+            if !condition() {
+                return WaitResult::NotEqual;
+            }
+
+            queues.entry(address).or_default().push_back(tid);
+
+            let _block_guard = if interruptible {
+                ThreadBlockGuard::activate_interruptible()
+            } else {
+                ThreadBlockGuard::activate()
+            };
+            if let Some(deadline) = deadline {
+                with_core_scheduler(|s| s.add_timeout(tid, deadline));
+            }
+            drop(queues);
+        }
+
+        if let Some(deadline) = deadline {
+            with_core_scheduler(|s| s.cancel_timeout(tid, deadline));
+        }
+
+        // A notify removes the waiter from the queue, so if we're still in there we timed out or
+        // were interrupted.
+        let mut queues = self.queues.lock();
+        match queues.get_mut(&address) {
+            Some(queue) => match queue.iter().position(|waiter| *waiter == tid) {
+                Some(position) => {
+                    queue.remove(position);
+                    if queue.is_empty() {
+                        queues.remove(&address);
+                    }
+                    if interruptible && with_current_thread(|thread| thread.kill_requested()) {
+                        WaitResult::Interrupted
+                    } else {
+                        WaitResult::TimedOut
+                    }
+                }
+                None => WaitResult::Woken,
+            },
+            None => WaitResult::Woken,
+        }
+    }
+
+    /// Wakes up at most `count` waiters of an address.
+    /// Returns the amount of woken up waiters.
+    pub fn notify(&self, address: usize, count: u32) -> u32 {
+        let mut queues = self.queues.lock();
+        let queue = match queues.get_mut(&address) {
+            Some(queue) => queue,
+            None => return 0,
+        };
+
+        let mut woken = 0;
+        while woken < count {
+            match queue.pop_front() {
+                Some(tid) => {
+                    with_thread(tid, |t| t.wakeup());
+                    woken += 1;
+                }
+                None => break,
+            }
+        }
+
+        if queue.is_empty() {
+            queues.remove(&address);
+        }
+
+        woken
+    }
+}
@@ -1,8 +1,9 @@
-use crate::tasking::scheme::{Scheme, SchemePtr};
-use crate::wasm::wasi::Errno;
+use crate::tasking::scheme::{OpenMode, Scheme, SchemePtr};
+use crate::wasm::wasi::{Errno, FdFlags, FdStat, FileType, OFlags, Rights};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::mem;
 
 /// Maximum amount of files a single table can have opened.
 const MAX_FILES: usize = 32;
@@ -27,6 +28,14 @@ pub enum FileHandle {
 pub struct FileDescriptor {
     scheme: SchemePtr,
     handle: FileHandle,
+    /// Path of the file inside its scheme, paths are resolved relative to it.
+    path: Box<[u8]>,
+    file_type: FileType,
+    fd_flags: FdFlags,
+    /// Rights of this descriptor.
+    rights_base: Rights,
+    /// Rights of descriptors opened through this descriptor.
+    rights_inheriting: Rights,
     /// Files can be pre-opened and even mapped to a different name.
     /// Keep track of this because WASI needs it.
     pre_open_path: Option<Box<[u8]>>,
@@ -40,14 +49,66 @@ pub struct FileDescriptorTable {
 
 impl FileDescriptor {
     /// Creates a file descriptor from scheme data.
-    pub fn from(scheme: SchemePtr, handle: FileHandle) -> Self {
+    pub fn from(scheme: SchemePtr, handle: FileHandle, path: Box<[u8]>, mode: OpenMode) -> Self {
+        // The scheme itself is a directory, other files are only known to be one if the open
+        // required it.
+        let file_type = match handle {
+            FileHandle::Own => FileType::Directory,
+            FileHandle::Inner(_) if mode.o_flags.contains(OFlags::DIRECTORY) => FileType::Directory,
+            FileHandle::Inner(_) => FileType::Unknown,
+        };
+
         Self {
             scheme,
             handle,
+            path,
+            file_type,
+            fd_flags: mode.fd_flags,
+            rights_base: mode.rights_base,
+            rights_inheriting: mode.rights_inheriting,
             pre_open_path: None,
         }
     }
 
+    /// Path inside the scheme.
+    pub fn path(&self) -> &[u8] {
+        &self.path
+    }
+
+    /// Scheme of the file.
+    pub fn scheme(&self) -> &SchemePtr {
+        &self.scheme
+    }
+
+    /// Checks if this descriptor has all of the required rights.
+    pub fn check_rights(&self, required: Rights) -> Result<(), Errno> {
+        if self.rights_base.contains(required) {
+            Ok(())
+        } else {
+            Err(Errno::NotCapable)
+        }
+    }
+
+    /// Gets the rights of descriptors opened through this descriptor.
+    pub fn rights_inheriting(&self) -> Rights {
+        self.rights_inheriting
+    }
+
+    /// Gets the WASI status of this descriptor.
+    pub fn fd_stat(&self) -> FdStat {
+        FdStat {
+            fs_filetype: self.file_type,
+            fs_flags: self.fd_flags,
+            fs_rights_base: self.rights_base,
+            fs_rights_inheriting: self.rights_inheriting,
+        }
+    }
+
+    /// Sets the fd flags.
+    pub fn set_fd_flags(&mut self, fd_flags: FdFlags) {
+        self.fd_flags = fd_flags;
+    }
+
     /// Pre open path.
     pub fn pre_open_path(&self) -> Option<&[u8]> {
         self.pre_open_path.as_ref().map(|path| &path[..])
@@ -101,4 +162,33 @@ impl FileDescriptorTable {
     pub fn get(&self, idx: FileIdx) -> Option<&FileDescriptor> {
         self.files.get(idx).unwrap_or(&None).as_ref()
     }
+
+    /// Gets a mutable file descriptor.
+    pub fn get_mut(&mut self, idx: FileIdx) -> Option<&mut FileDescriptor> {
+        self.files.get_mut(idx).and_then(|file| file.as_mut())
+    }
+
+    /// Removes a file descriptor.
+    pub fn remove(&mut self, idx: FileIdx) -> Option<FileDescriptor> {
+        self.files.get_mut(idx).and_then(|file| file.take())
+    }
+
+    /// Moves a file descriptor to another index, which must be in use.
+    /// Returns the file descriptor that was replaced, it should be closed.
+    pub fn renumber(
+        &mut self,
+        from: FileIdx,
+        to: FileIdx,
+    ) -> Result<Option<FileDescriptor>, Errno> {
+        if self.get(from).is_none() || self.get(to).is_none() {
+            return Err(Errno::BadF);
+        }
+
+        if from == to {
+            Ok(None)
+        } else {
+            let file = self.files[from].take();
+            Ok(mem::replace(&mut self.files[to], file))
+        }
+    }
 }
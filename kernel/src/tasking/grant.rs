@@ -0,0 +1,238 @@
+//! Grants: temporary access of a scheme to a buffer of a client.
+//! A client that reads or writes a file of a scheme grants the scheme access to its buffer for
+//! the duration of the command. The serving process copies from or to that buffer using the grant,
+//! so the data is copied only once, without a bounce buffer in between.
+//! If the server runs in the domain of the client, the buffer is accessed directly. Otherwise the
+//! pages of the buffer are mapped into the domain of the server while copying.
+
+use crate::arch::address::{align_up, VirtAddr};
+use crate::arch::paging::{ActiveMapping, EntryFlags, PAGE_SIZE};
+use crate::mm::mapper::MemoryMapper;
+use crate::tasking::protection_domain::ProtectionDomain;
+use crate::tasking::scheduler::with_current_thread;
+use crate::wasm::wasi::Errno;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::ptr::{copy_nonoverlapping, read_volatile};
+
+/// Identifies a grant inside a scheme.
+pub type GrantId = u64;
+
+/// A buffer the kernel copies from or to on behalf of a thread, usually in wasm memory.
+/// Wasm code of other threads can access a shared memory while the kernel does, so the buffer is
+/// only accessed through raw pointers and never through Rust references.
+#[derive(Copy, Clone)]
+pub struct UserBuffer<'b> {
+    ptr: *mut u8,
+    len: usize,
+    writable: bool,
+    _phantom: PhantomData<&'b mut [u8]>,
+}
+
+/// Access to a buffer in the domain of a client.
+pub struct Grant {
+    domain: ProtectionDomain,
+    address: VirtAddr,
+    len: usize,
+    /// Whether the scheme may write to the buffer.
+    writable: bool,
+}
+
+impl<'b> UserBuffer<'b> {
+    /// Creates a buffer that is only read from.
+    /// Unsafe because the pointer must be valid for reads of `len` bytes during `'b`.
+    pub unsafe fn shared(ptr: *const u8, len: usize) -> Self {
+        Self {
+            ptr: ptr as *mut u8,
+            len,
+            writable: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Creates a buffer that can also be written to.
+    /// Unsafe because the pointer must be valid for reads and writes of `len` bytes during `'b`.
+    pub unsafe fn mutable(ptr: *mut u8, len: usize) -> Self {
+        Self {
+            ptr,
+            len,
+            writable: true,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Length in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if the buffer is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks if the buffer can be written to.
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Gets the pointer to read from.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Gets the pointer to write to, fails if the buffer can only be read from.
+    #[inline]
+    pub fn as_mut_ptr(&self) -> Result<*mut u8, Errno> {
+        if self.writable {
+            Ok(self.ptr)
+        } else {
+            Err(Errno::Access)
+        }
+    }
+}
+
+impl<'b> From<&'b [u8]> for UserBuffer<'b> {
+    fn from(buffer: &'b [u8]) -> Self {
+        // Safety: the slice is valid for reads during its lifetime.
+        unsafe { Self::shared(buffer.as_ptr(), buffer.len()) }
+    }
+}
+
+impl<'b> From<&'b mut [u8]> for UserBuffer<'b> {
+    fn from(buffer: &'b mut [u8]) -> Self {
+        // Safety: the slice is valid for reads and writes during its lifetime.
+        unsafe { Self::mutable(buffer.as_mut_ptr(), buffer.len()) }
+    }
+}
+
+impl Grant {
+    /// Grants access to a buffer of the current thread.
+    /// Unsafe because the buffer must stay valid as long as the grant exists.
+    pub unsafe fn new(buffer: UserBuffer) -> Self {
+        // Buffers in wasm memory are mapped lazily. Touch every page of the buffer,
+        // so the pages are mapped by the time the server looks them up.
+        let ptr = buffer.as_ptr();
+        let len = buffer.len();
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            read_volatile(ptr.add(offset));
+        }
+        if len > 0 {
+            read_volatile(ptr.add(len - 1));
+        }
+
+        Self {
+            domain: with_current_thread(|thread| thread.domain().clone()),
+            address: VirtAddr::new(ptr as usize),
+            len,
+            writable: buffer.is_writable(),
+        }
+    }
+
+    /// Copies from the buffer, starting at an offset in the buffer.
+    /// Returns the amount of bytes copied, which is less than requested at the end of the buffer.
+    pub fn copy_from(&self, offset: usize, dst: UserBuffer) -> Result<usize, Errno> {
+        let dst_ptr = dst.as_mut_ptr()?;
+        let len = self.clamp(offset, dst.len());
+        // Safety: both pointers are valid for `len` bytes.
+        self.with_buffer(offset, len, |src| unsafe {
+            copy_nonoverlapping(src, dst_ptr, len)
+        })?;
+        Ok(len)
+    }
+
+    /// Copies to the buffer, starting at an offset in the buffer.
+    /// Returns the amount of bytes copied, which is less than requested at the end of the buffer.
+    pub fn copy_to(&self, offset: usize, src: UserBuffer) -> Result<usize, Errno> {
+        if !self.writable {
+            return Err(Errno::Access);
+        }
+
+        let len = self.clamp(offset, src.len());
+        // Safety: both pointers are valid for `len` bytes.
+        self.with_buffer(offset, len, |dst| unsafe {
+            copy_nonoverlapping(src.as_ptr(), dst, len)
+        })?;
+        Ok(len)
+    }
+
+    /// Limits a length to what's left of the buffer after the offset.
+    fn clamp(&self, offset: usize, len: usize) -> usize {
+        min(len, self.len.saturating_sub(offset))
+    }
+
+    /// Executes with a pointer to a part of the buffer, which is valid in the current domain.
+    fn with_buffer<F>(&self, offset: usize, len: usize, f: F) -> Result<(), Errno>
+    where
+        F: FnOnce(*mut u8),
+    {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let start = self.address + offset;
+        let domain = with_current_thread(|thread| thread.domain().clone());
+        if domain.is_same(&self.domain) {
+            f(start.as_usize() as *mut u8);
+            return Ok(());
+        }
+
+        // Look up the frames of the buffer in the domain of the client.
+        // Memories are mapped using 4 KiB pages, so every page has its own frame.
+        let first_page = start.align_down();
+        let size = align_up(start.as_usize() + len) - first_page.as_usize();
+        let frames = unsafe {
+            let _guard = self.domain.temporarily_switch();
+            // Safety: the mapping is only read.
+            let mapping = ActiveMapping::get_unlocked();
+            (0..size)
+                .step_by(PAGE_SIZE)
+                .map(|offset| mapping.translate(first_page + offset))
+                .collect::<Option<Vec<_>>>()
+        }
+        .ok_or(Errno::Fault)?;
+
+        // Map the frames into our domain, without taking ownership of them.
+        // The copy itself happens outside of the domain lock, because it can cause page faults
+        // in our own memory.
+        let mut flags = EntryFlags::PRESENT | EntryFlags::NX;
+        if self.writable {
+            flags |= EntryFlags::WRITABLE;
+        }
+        let (region, mapped) = domain.with(|vma_allocator, mapping| -> Result<_, Errno> {
+            let region = vma_allocator.alloc_region(size).ok_or(Errno::NoMem)?;
+            let mut mapped = 0;
+            for &frame in &frames {
+                if mapping
+                    .map_single(region + mapped * PAGE_SIZE, frame, flags)
+                    .is_err()
+                {
+                    break;
+                }
+                mapped += 1;
+            }
+            Ok((region, mapped))
+        })?;
+
+        let result = if mapped == frames.len() {
+            f((region + (start.as_usize() - first_page.as_usize())).as_usize() as *mut u8);
+            Ok(())
+        } else {
+            Err(Errno::NoMem)
+        };
+
+        domain.with(|vma_allocator, mapping| {
+            for offset in (0..mapped * PAGE_SIZE).step_by(PAGE_SIZE) {
+                mapping.unmap_single(region + offset);
+            }
+            vma_allocator.insert_region(region, size);
+        });
+
+        result
+    }
+}
@@ -1,4 +1,6 @@
 pub mod file;
+pub mod grant;
+pub mod process;
 pub mod protection_domain;
 pub mod scheduler;
 pub mod scheme;
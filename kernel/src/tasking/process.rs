@@ -0,0 +1,235 @@
+//! Processes: a running wasm program and everything its threads share.
+//! A process owns the instance of the program, so the code, memories and tables live as long as
+//! the process does. It also owns the file descriptor table and the program arguments and
+//! environment. Every thread running the program belongs to the process. The process exits when
+//! its last thread exits. A thread can also exit the whole process, the other threads are then
+//! killed, see `Process::exit`.
+//!
+//! The exit status is kept apart from the process, so a supervisor can wait for the exit without
+//! keeping the process alive. Supervisors refer to the processes they spawned using handles.
+
+use crate::mm::tcb_alloc::try_with_thread;
+use crate::sync::spinlock::{PreemptCounterInfluence, Spinlock};
+use crate::sync::wait_table::{WaitResult, WaitTable};
+use crate::tasking::file::FileDescriptorTable;
+use crate::tasking::scheduler::with_current_thread;
+use crate::tasking::thread::ThreadId;
+use crate::util::string_list::StringList;
+use crate::wasm::epoch;
+use crate::wasm::instance::Instance;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::MutexGuard;
+
+/// Handle of a spawned process, only valid in the process that spawned it.
+pub type ProcessHandle = u32;
+
+/// Exit status of a process, the exit code is known once all threads have exited.
+/// Exit codes of traps are the trap exit codes, see `wasm::trap`.
+pub struct ExitStatus {
+    exited: AtomicBool,
+    /// Only valid once exited.
+    exit_code: AtomicU32,
+    waiters: WaitTable,
+}
+
+/// Set in the exit code of a process when a thread exited the whole process.
+const EXIT_REQUESTED: u64 = 1 << 32;
+
+/// Processes spawned by a process, which it did not wait for yet.
+struct Children {
+    statuses: BTreeMap<ProcessHandle, Arc<ExitStatus>>,
+    next_handle: ProcessHandle,
+}
+
+/// A running wasm program.
+pub struct Process {
+    instance: Arc<Instance>,
+    file_descriptor_table: Spinlock<FileDescriptorTable>,
+    args: StringList,
+    env: StringList,
+    /// Threads that belong to this process and didn't exit yet.
+    threads: Spinlock<BTreeSet<ThreadId>>,
+    /// Exit code of the thread that exited the process if `EXIT_REQUESTED` is set.
+    /// Otherwise the exit code of the first thread that exited with a non-zero code, zero if
+    /// there is none.
+    exit_code: AtomicU64,
+    exit_status: Arc<ExitStatus>,
+    children: Spinlock<Children>,
+}
+
+impl ExitStatus {
+    /// Creates the status of a process that did not exit yet.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            exited: AtomicBool::new(false),
+            exit_code: AtomicU32::new(0),
+            waiters: WaitTable::new(),
+        })
+    }
+
+    /// Sets the exit code and wakes up all waiters.
+    pub fn set(&self, exit_code: u32) {
+        self.exit_code.store(exit_code, Ordering::Relaxed);
+        self.exited.store(true, Ordering::Release);
+        self.waiters.notify(0, u32::MAX);
+    }
+
+    /// Gets the exit code, if the process has exited.
+    pub fn get(&self) -> Option<u32> {
+        if self.exited.load(Ordering::Acquire) {
+            Some(self.exit_code.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    /// Blocks until the process has exited, returns the exit code.
+    /// Returns `None` if a kill request of the waiting thread ends the wait.
+    pub fn wait(&self) -> Option<u32> {
+        loop {
+            if let Some(exit_code) = self.get() {
+                return Some(exit_code);
+            }
+
+            if self
+                .waiters
+                .wait_interruptible(0, || self.get().is_none(), None)
+                == WaitResult::Interrupted
+            {
+                return None;
+            }
+        }
+    }
+}
+
+impl Process {
+    /// Creates a process without threads.
+    pub fn new(
+        instance: Arc<Instance>,
+        file_descriptor_table: FileDescriptorTable,
+        args: StringList,
+        env: StringList,
+        exit_status: Arc<ExitStatus>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            instance,
+            file_descriptor_table: Spinlock::new(file_descriptor_table),
+            args,
+            env,
+            threads: Spinlock::new(BTreeSet::new()),
+            exit_code: AtomicU64::new(0),
+            exit_status,
+            children: Spinlock::new(Children {
+                statuses: BTreeMap::new(),
+                next_handle: 0,
+            }),
+        })
+    }
+
+    /// Gets the instance of the program.
+    #[inline]
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance
+    }
+
+    /// Gets the file descriptor table.
+    #[inline]
+    pub fn file_descriptor_table(
+        &self,
+    ) -> MutexGuard<FileDescriptorTable, PreemptCounterInfluence> {
+        self.file_descriptor_table.lock()
+    }
+
+    /// Gets the program arguments.
+    #[inline]
+    pub fn args(&self) -> &StringList {
+        &self.args
+    }
+
+    /// Gets the environment variables.
+    #[inline]
+    pub fn env(&self) -> &StringList {
+        &self.env
+    }
+
+    /// Adds a thread to the process.
+    /// This must happen before the thread is scheduled, so the process can't exit in between.
+    pub fn add_thread(&self, id: ThreadId) {
+        self.threads.lock().insert(id);
+    }
+
+    /// Removes an exited thread from the process.
+    /// If it was the last thread, the process has exited and its waiters are woken up.
+    pub fn remove_thread(&self, id: ThreadId, exit_code: u32) {
+        if exit_code != 0 {
+            // Only the first failure is kept, and only if no thread exited the process.
+            self.exit_code
+                .compare_exchange(0, exit_code as u64, Ordering::AcqRel, Ordering::Acquire)
+                .ok();
+        }
+
+        let last = {
+            let mut threads = self.threads.lock();
+            threads.remove(&id);
+            threads.is_empty()
+        };
+        if last {
+            self.exit_status
+                .set(self.exit_code.load(Ordering::Acquire) as u32);
+        }
+    }
+
+    /// Exits the process with an exit code, called by one of its threads.
+    /// The exit code becomes the exit status of the process, unless another thread exited the
+    /// process before. The other threads are killed at their next safe point, see `epoch::kill`.
+    /// Threads that don't run interruptible code can't be killed, they have to exit themselves.
+    pub fn exit(&self, exit_code: u32) {
+        let requested = EXIT_REQUESTED | exit_code as u64;
+        self.exit_code
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                (current & EXIT_REQUESTED == 0).then_some(requested)
+            })
+            .ok();
+
+        // Kill outside of the lock, killing wakes up threads which then leave the process.
+        // A thread that is added after this starts sees that the process is exiting.
+        let current = with_current_thread(|thread| thread.id);
+        let threads: Vec<ThreadId> = self.threads.lock().iter().copied().collect();
+        for id in threads.into_iter().filter(|id| *id != current) {
+            try_with_thread(id, |thread| epoch::kill(thread));
+        }
+    }
+
+    /// Checks if a thread exited the process, new threads must exit right away then.
+    pub fn is_exiting(&self) -> bool {
+        self.exit_code.load(Ordering::SeqCst) & EXIT_REQUESTED != 0
+    }
+
+    /// Adds a spawned process, returns its handle.
+    /// Returns `None` if there are no handles left.
+    pub fn add_child(&self, exit_status: Arc<ExitStatus>) -> Option<ProcessHandle> {
+        let mut children = self.children.lock();
+        let handle = children.next_handle;
+        children.next_handle = handle.checked_add(1)?;
+        children.statuses.insert(handle, exit_status);
+        Some(handle)
+    }
+
+    /// Gets the exit status of a spawned process.
+    pub fn child(&self, handle: ProcessHandle) -> Option<Arc<ExitStatus>> {
+        self.children.lock().statuses.get(&handle).cloned()
+    }
+
+    /// Removes a spawned process, the handle is invalid afterwards.
+    pub fn take_child(&self, handle: ProcessHandle) -> Option<Arc<ExitStatus>> {
+        self.children.lock().statuses.remove(&handle)
+    }
+}
+
+/// Gets the process of the current thread, if the thread runs wasm code.
+pub fn current() -> Option<Arc<Process>> {
+    with_current_thread(|thread| thread.process())
+}
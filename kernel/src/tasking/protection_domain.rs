@@ -116,6 +116,11 @@ impl ProtectionDomain {
         Self(self.0.clone())
     }
 
+    /// Checks if both references refer to the same domain.
+    pub fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
     /// Execute action with both the Vma allocator and active mapping.
     #[inline]
     pub fn with<F, T>(&self, f: F) -> T
@@ -1,12 +1,12 @@
 use crate::arch::address::VirtAddr;
-use crate::arch::get_per_cpu_data;
 use crate::arch::paging::{get_cpu_page_mapping, CpuPageMapping};
+use crate::arch::{get_per_cpu_data, hpet};
 use crate::mm::tcb_alloc::{tcb_alloc, tcb_dealloc, with_thread};
 use crate::mm::vma_allocator::MappedVma;
 use crate::sync::spinlock::Spinlock;
 use crate::tasking::protection_domain::ProtectionDomain;
 use crate::tasking::thread::{Stack, Thread, ThreadId, ThreadStatus};
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeSet, VecDeque};
 use atomic::Atomic;
 use core::intrinsics::{likely, unlikely};
 use core::sync::atomic::Ordering;
@@ -15,6 +15,8 @@ use spin::Once;
 /// Per-core queues.
 struct Queues {
     run_queue: VecDeque<ThreadId>,
+    /// Blocked threads that are woken up at a deadline, in Hpet counter values.
+    timeouts: BTreeSet<(u64, ThreadId)>,
 }
 
 /// Per-core scheduler.
@@ -36,6 +38,7 @@ impl Scheduler {
         Self {
             queues: Spinlock::new(Queues {
                 run_queue: VecDeque::new(),
+                timeouts: BTreeSet::new(),
             }),
             garbage: Atomic::new(ThreadId::zero()),
             current_thread_id: Atomic::new(idle_thread_id),
@@ -79,6 +82,40 @@ impl Scheduler {
         queues.run_queue.push_front(thread_id);
     }
 
+    /// Wakes up a blocked thread once the Hpet counter reaches the deadline, unless it is woken up
+    /// before that. The thread must cancel the timeout when it continues, see `cancel_timeout`.
+    pub fn add_timeout(&self, thread_id: ThreadId, deadline: u64) {
+        self.queues.lock().timeouts.insert((deadline, thread_id));
+    }
+
+    /// Cancels a timeout of a thread, if it didn't expire yet.
+    pub fn cancel_timeout(&self, thread_id: ThreadId, deadline: u64) {
+        self.queues.lock().timeouts.remove(&(deadline, thread_id));
+    }
+
+    /// Wakes up the threads of which the timeout expired.
+    fn expire_timeouts(queues: &mut Queues) {
+        if likely(queues.timeouts.is_empty()) {
+            return;
+        }
+
+        let now = match hpet() {
+            Some(hpet) => hpet.counter(),
+            None => return,
+        };
+
+        while let Some(&(deadline, thread_id)) = queues.timeouts.iter().next() {
+            if deadline > now {
+                break;
+            }
+
+            queues.timeouts.remove(&(deadline, thread_id));
+            if with_thread(thread_id, |thread| thread.unblock()) {
+                queues.run_queue.push_back(thread_id);
+            }
+        }
+    }
+
     /// Sets the scheduler up for switching to the next thread and gets the next thread stack address.
     fn next_thread_state(&self, old_stack: VirtAddr) -> NextThreadState {
         // Cleanup old thread.
@@ -136,6 +173,9 @@ impl Scheduler {
         }
         println!();*/
 
+        // After the old thread is handled, so a thread that times out can't be queued twice.
+        Self::expire_timeouts(&mut queues);
+
         let next_thread_id = self.next_thread(&mut queues);
         debug_assert_eq!(
             { with_thread(next_thread_id, |next_thread| next_thread.status(),) },
@@ -186,12 +226,6 @@ pub fn thread_yield() {
     switch_to_next();
 }
 
-/// Wakeup and yield.
-pub fn wakeup_and_yield(tid: ThreadId) {
-    with_thread(tid, |t| t.wakeup());
-    thread_yield();
-}
-
 /// Exit the thread.
 pub fn thread_exit(exit_code: u32) -> ! {
     extern "C" {
@@ -204,6 +238,7 @@ pub fn thread_exit(exit_code: u32) -> ! {
                 thread.id, s.idle_thread_id,
                 "Attempting to kill the idle thread"
             );
+            thread.leave_process(exit_code);
             thread.set_status(ThreadStatus::Exit(exit_code))
         })
     });
@@ -1,18 +1,25 @@
 use crate::arch::{preempt_disable, preempt_enable};
-use crate::mm::tcb_alloc::with_thread;
+use crate::mm::tcb_alloc::try_with_thread;
+use crate::sync::spinlock::Spinlock;
 use crate::sync::thread_block_guard::ThreadBlockGuard;
 use crate::sync::wait_queue::WaitQueue;
 use crate::tasking::file::{FileHandle, InnerFileHandle};
+use crate::tasking::grant::{Grant, GrantId, UserBuffer};
 use crate::tasking::scheduler::{self, with_current_thread};
 use crate::tasking::scheme_container::SchemeId;
 use crate::tasking::thread::ThreadId;
-use crate::wasm::wasi::Errno;
+use crate::wasm::wasi::{Advice, Errno, FdFlags, FstFlags, LookupFlags, OFlags, Rights, Whence};
+use alloc::collections::BTreeMap;
 use alloc::sync::Weak;
 use atomic::Atomic;
+use core::cmp::min;
 use core::mem::size_of;
-use core::slice;
+use core::ptr;
 use core::sync::atomic::{AtomicU64, Ordering};
 
+/// Maximum length of a path in an open command.
+pub const MAX_PATH_LEN: usize = 256;
+
 /// Reply payload.
 /// We only wait at most for one reply. The reply data is very simple, it's just a status + data pair.
 /// In the case we have a non-blocking send, we don't have reply data.
@@ -37,10 +44,168 @@ pub struct Reply {
     payload: ReplyPayload,
 }
 
+/// How a file is opened, the flags and rights are those of WASI `path_open`.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct OpenMode {
+    /// Rights of the opened file.
+    pub rights_base: Rights,
+    /// Rights of files opened through the opened file.
+    pub rights_inheriting: Rights,
+    pub o_flags: OFlags,
+    pub fd_flags: FdFlags,
+}
+
+/// Path inside a scheme, relative to the root of the scheme.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SchemePath {
+    /// Length of the path, only that part of `bytes` is valid.
+    len: u16,
+    bytes: [u8; MAX_PATH_LEN],
+}
+
+/// Command to open a file.
+/// The scheme replies with the file handle as value, or with an error status.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct OpenCommand {
+    mode: OpenMode,
+    path: SchemePath,
+}
+
+/// Command that transfers data between a file and the buffer of the client.
+/// The data is transferred through the grant of the buffer of the client, using the kwast host
+/// module. The scheme replies with the amount of bytes transferred as value.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TransferCommand {
+    handle: InnerFileHandle,
+    grant: GrantId,
+    /// Length of the buffer of the client.
+    len: u64,
+    /// Offset in the file for positioned reads and writes, the cookie for directory reads.
+    offset: u64,
+}
+
+/// Command to move the offset of a file.
+/// The scheme replies with the new offset as value.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SeekCommand {
+    handle: InnerFileHandle,
+    offset: i64,
+    whence: Whence,
+}
+
+/// Command to change the fd flags of a file.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SetFlagsCommand {
+    handle: InnerFileHandle,
+    fd_flags: FdFlags,
+}
+
+/// Command to advise the scheme about how a range of a file will be used.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct AdviseCommand {
+    handle: InnerFileHandle,
+    offset: u64,
+    len: u64,
+    advice: Advice,
+}
+
+/// Command with two paths, both inside the scheme.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PathPairCommand {
+    old: SchemePath,
+    new: SchemePath,
+}
+
+/// Command to create a hard link.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct LinkCommand {
+    /// How the old path is looked up.
+    lookup_flags: LookupFlags,
+    old: SchemePath,
+    new: SchemePath,
+}
+
+/// Command that transfers data about a path to the buffer of the client, see `TransferCommand`.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PathTransferCommand {
+    lookup_flags: LookupFlags,
+    grant: GrantId,
+    /// Length of the buffer of the client.
+    len: u64,
+    path: SchemePath,
+}
+
+/// Command that transfers the contents of a symbolic link to the buffer of the client, see
+/// `TransferCommand`. The link itself is read, so there are no lookup flags.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ReadLinkCommand {
+    grant: GrantId,
+    /// Length of the buffer of the client.
+    len: u64,
+    path: SchemePath,
+}
+
+/// Command to set the timestamps of a file, as WASI `path_filestat_set_times`.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SetTimesCommand {
+    lookup_flags: LookupFlags,
+    atim: u64,
+    mtim: u64,
+    fst_flags: FstFlags,
+    path: SchemePath,
+}
+
+/// Commands a scheme handles, unless noted otherwise the scheme replies with a zero value.
 #[repr(C)]
 pub enum CommandData {
-    Open(i32), // TODO: test
-    Read(InnerFileHandle),
+    Open(OpenCommand),
+    Read(TransferCommand),
+    Write(TransferCommand),
+    /// Closes a file, the handle is invalid afterwards.
+    Close(InnerFileHandle),
+    Seek(SeekCommand),
+    SetFlags(SetFlagsCommand),
+    /// Writes the `FileStat` of a file into the grant.
+    FileStat(TransferCommand),
+    /// Syncs the data and metadata of a file.
+    Sync(InnerFileHandle),
+    /// Syncs the data of a file.
+    DataSync(InnerFileHandle),
+    /// Reads from a file at an offset, without moving the offset of the file.
+    ReadAt(TransferCommand),
+    /// Writes to a file at an offset, without moving the offset of the file.
+    WriteAt(TransferCommand),
+    /// Reads WASI directory entries, starting at the entry after the cookie.
+    ReadDir(TransferCommand),
+    Advise(AdviseCommand),
+    CreateDirectory(SchemePath),
+    /// Removes an empty directory.
+    RemoveDirectory(SchemePath),
+    UnlinkFile(SchemePath),
+    /// Renames the old path to the new path.
+    Rename(PathPairCommand),
+    /// Creates a hard link at the new path to the old path.
+    Link(LinkCommand),
+    /// Creates a symbolic link at the new path, the old path is its contents. The contents are
+    /// passed as given by the client, they are not resolved.
+    Symlink(PathPairCommand),
+    /// Writes the contents of a symbolic link into the grant.
+    ReadLink(ReadLinkCommand),
+    /// Writes the `FileStat` of a file into the grant.
+    PathFileStat(PathTransferCommand),
+    SetTimes(SetTimesCommand),
 }
 
 #[repr(C)]
@@ -57,6 +222,37 @@ pub struct Scheme {
     id: SchemeId,
     /// Command queue.
     command_queue: WaitQueue<Command>,
+    /// Grants of the clients for the commands in flight.
+    grants: Spinlock<BTreeMap<GrantId, Grant>>,
+    next_grant_id: AtomicU64,
+}
+
+impl OpenMode {
+    /// Mode of a directory with all rights.
+    pub fn directory() -> Self {
+        Self {
+            rights_base: Rights::all(),
+            rights_inheriting: Rights::all(),
+            o_flags: OFlags::DIRECTORY,
+            fd_flags: FdFlags::empty(),
+        }
+    }
+}
+
+impl SchemePath {
+    /// Creates a path, fails if the path is too long.
+    fn new(path: &[u8]) -> Result<Self, Errno> {
+        if path.len() > MAX_PATH_LEN {
+            return Err(Errno::NameTooLong);
+        }
+
+        let mut scheme_path = Self {
+            len: path.len() as u16,
+            bytes: [0; MAX_PATH_LEN],
+        };
+        scheme_path.bytes[..path.len()].copy_from_slice(path);
+        Ok(scheme_path)
+    }
 }
 
 impl ReplyPayload {
@@ -90,6 +286,8 @@ impl Scheme {
         Self {
             id,
             command_queue: WaitQueue::new(),
+            grants: Spinlock::new(BTreeMap::new()),
+            next_grant_id: AtomicU64::new(0),
         }
     }
 
@@ -99,7 +297,7 @@ impl Scheme {
             // Blocks the thread, sends the command and notifies the receiving thread.
             {
                 preempt_disable();
-                let _block_guard = ThreadBlockGuard::activate();
+                let _block_guard = ThreadBlockGuard::activate_interruptible();
                 //self.a.lock().insert(t.id, t.clone());
                 t.set_ipc_blocked_on(self.id);
                 self.command_queue.push_back(Command {
@@ -109,47 +307,55 @@ impl Scheme {
                 preempt_enable();
             }
 
+            // Late replies are dropped from now on.
             t.set_ipc_blocked_on(SchemeId::sentinel());
 
+            // A kill request can wake us up before the reply came in, see `wasm::epoch::kill`.
+            if t.kill_requested() {
+                return ReplyPayload {
+                    status: Errno::Intr,
+                    value: 0,
+                };
+            }
+
             // Response to sender comes here.
             ReplyPayload::from(&t.reply)
         })
     }
 
-    pub fn send_replies(&self, buffer: &[u8]) -> Result<usize, Errno> {
-        // TODO: document
-        let buffer = unsafe {
-            slice::from_raw_parts(
-                buffer as *const _ as *const Reply,
-                buffer.len() / size_of::<Reply>(),
-            )
-        };
+    /// Sends the replies in a buffer, returns the amount of bytes used.
+    pub fn send_replies(&self, buffer: UserBuffer) -> Result<usize, Errno> {
+        let count = buffer.len() / size_of::<Reply>();
+        let replies = buffer.as_ptr() as *const Reply;
 
-        for reply in buffer {
-            self.send_reply(*reply);
+        for i in 0..count {
+            // Safety: the buffer holds `count` replies, which don't have to be aligned.
+            let reply = unsafe { ptr::read_unaligned(replies.add(i)) };
+            self.send_reply(reply);
         }
 
-        Ok(buffer.len() * size_of::<Reply>())
+        Ok(count * size_of::<Reply>())
     }
 
     /// Opens a file inside the scheme.
-    pub(crate) fn open(&self, lol: i32) -> Result<FileHandle, Errno> {
-        let response = self.send_command_blocking(CommandData::Open(lol));
-        match response.status {
-            Errno::Success => Ok(FileHandle::Inner(InnerFileHandle(response.value))),
-            e => Err(e),
-        }
+    /// The path is relative to the root of the scheme.
+    pub(crate) fn open(&self, path: &[u8], mode: OpenMode) -> Result<FileHandle, Errno> {
+        let command = CommandData::Open(OpenCommand {
+            mode,
+            path: SchemePath::new(path)?,
+        });
+        self.request(command)
+            .map(|value| FileHandle::Inner(InnerFileHandle(value)))
     }
 
-    pub fn write(&self, handle: FileHandle, buffer: &[u8]) -> Result<usize, Errno> {
-        // TODO: needs grants
+    pub fn write(&self, handle: FileHandle, buffer: UserBuffer) -> Result<usize, Errno> {
         match handle {
             FileHandle::Own => self.send_replies(buffer),
             FileHandle::Inner(handle) => self.regular_write(handle, buffer),
         }
     }
 
-    pub fn read(&self, handle: FileHandle, buffer: &mut [u8]) -> Result<usize, Errno> {
+    pub fn read(&self, handle: FileHandle, buffer: UserBuffer) -> Result<usize, Errno> {
         match handle {
             FileHandle::Own => self.receive_commands_blocking(buffer),
             FileHandle::Inner(handle) => self.regular_read(handle, buffer),
@@ -157,14 +363,17 @@ impl Scheme {
     }
 
     pub fn send_reply(&self, reply: Reply) {
-        let success = with_thread(reply.to, |receiver| {
+        // The receiver may be gone already if it was killed while it waited for the reply.
+        let success = try_with_thread(reply.to, |receiver| {
             if receiver.ipc_blocked_on() != self.id {
                 false
             } else {
                 receiver.reply.store(reply.payload);
+                receiver.wakeup();
                 true
             }
-        });
+        })
+        .unwrap_or(false);
         /*let success = if let Some(receiver) = self.a.lock().remove(&reply.to) {
             receiver.reply.store(reply.payload);
             true
@@ -172,36 +381,454 @@ impl Scheme {
             false
         };*/
 
-        // This needs to be outside the lock.
         if success {
-            scheduler::wakeup_and_yield(reply.to);
+            scheduler::thread_yield();
+        }
+    }
+
+    /// Receives commands into a buffer, blocks if there are none.
+    /// Returns the amount of bytes filled in.
+    pub fn receive_commands_blocking(&self, buffer: UserBuffer) -> Result<usize, Errno> {
+        let dst = buffer.as_mut_ptr()? as *mut Command;
+        let commands = self
+            .command_queue
+            .pop_front_many(buffer.len() / size_of::<Command>());
+        let count = commands.len();
+
+        for (i, command) in commands.into_iter().enumerate() {
+            // Safety: the buffer has room for every popped command, which don't have to be aligned.
+            unsafe { ptr::write_unaligned(dst.add(i), command) };
+        }
+
+        Ok(count * size_of::<Command>())
+    }
+
+    /// Sends a command and waits for the reply, returns the value of a successful reply.
+    fn request(&self, command: CommandData) -> Result<u64, Errno> {
+        let reply = self.send_command_blocking(command);
+        match reply.status {
+            Errno::Success => Ok(reply.value),
+            e => Err(e),
+        }
+    }
+
+    pub fn regular_read(
+        &self,
+        handle: InnerFileHandle,
+        buffer: UserBuffer,
+    ) -> Result<usize, Errno> {
+        self.transfer(handle, 0, buffer, CommandData::Read)
+    }
+
+    pub fn regular_write(
+        &self,
+        handle: InnerFileHandle,
+        buffer: UserBuffer,
+    ) -> Result<usize, Errno> {
+        self.transfer(handle, 0, buffer, CommandData::Write)
+    }
+
+    /// Reads from a file at an offset, without moving the offset of the file.
+    pub fn read_at(
+        &self,
+        handle: FileHandle,
+        buffer: UserBuffer,
+        offset: u64,
+    ) -> Result<usize, Errno> {
+        let handle = inner_handle(handle)?;
+        self.transfer(handle, offset, buffer, CommandData::ReadAt)
+    }
+
+    /// Writes to a file at an offset, without moving the offset of the file.
+    pub fn write_at(
+        &self,
+        handle: FileHandle,
+        buffer: UserBuffer,
+        offset: u64,
+    ) -> Result<usize, Errno> {
+        let handle = inner_handle(handle)?;
+        self.transfer(handle, offset, buffer, CommandData::WriteAt)
+    }
+
+    /// Reads directory entries, starting at the entry after the cookie.
+    /// Returns the amount of bytes filled in.
+    pub fn read_dir(
+        &self,
+        handle: FileHandle,
+        buffer: UserBuffer,
+        cookie: u64,
+    ) -> Result<usize, Errno> {
+        let handle = inner_handle(handle)?;
+        self.transfer(handle, cookie, buffer, CommandData::ReadDir)
+    }
+
+    /// Gets the attributes of a file, the buffer is the size of a `FileStat`.
+    /// Returns the amount of bytes filled in.
+    pub fn file_stat(&self, handle: FileHandle, buffer: UserBuffer) -> Result<usize, Errno> {
+        let handle = inner_handle(handle)?;
+        self.transfer(handle, 0, buffer, CommandData::FileStat)
+    }
+
+    /// Sends a command that transfers data, the buffer of the client is granted while the scheme
+    /// handles the command.
+    fn transfer<F>(
+        &self,
+        handle: InnerFileHandle,
+        offset: u64,
+        buffer: UserBuffer,
+        command: F,
+    ) -> Result<usize, Errno>
+    where
+        F: FnOnce(TransferCommand) -> CommandData,
+    {
+        self.with_buffer_grant(buffer, |grant, len| {
+            command(TransferCommand {
+                handle,
+                grant,
+                len,
+                offset,
+            })
+        })
+    }
+
+    /// Sends a command that uses a grant of a buffer of the client, the grant exists while the
+    /// scheme handles the command. Only a writable buffer can be written to by the scheme.
+    /// The command gets the grant and the length of the buffer.
+    /// The scheme replies with the amount of bytes transferred, which is at most the length.
+    fn with_buffer_grant<F>(&self, buffer: UserBuffer, command: F) -> Result<usize, Errno>
+    where
+        F: FnOnce(GrantId, u64) -> CommandData,
+    {
+        let len = buffer.len();
+        // Safety: the buffer outlives the grant, because the grant is removed before returning.
+        let grant = unsafe { Grant::new(buffer) };
+
+        let id = self.next_grant_id.fetch_add(1, Ordering::Relaxed);
+        self.grants.lock().insert(id, grant);
+
+        let result = self.request(command(id, len as u64));
+
+        // The scheme may still be copying if it replied early, removing waits until it's done.
+        self.grants.lock().remove(&id);
+
+        // Never trust the scheme to report a sensible amount.
+        result.map(|transferred| min(transferred, len as u64) as usize)
+    }
+
+    /// Closes a file.
+    pub fn close(&self, handle: FileHandle) -> Result<(), Errno> {
+        match handle {
+            // The scheme itself is not affected by closing a handle to it.
+            FileHandle::Own => Ok(()),
+            FileHandle::Inner(handle) => self.request(CommandData::Close(handle)).map(|_| ()),
+        }
+    }
+
+    /// Moves the offset of a file, returns the new offset.
+    pub fn seek(&self, handle: FileHandle, offset: i64, whence: Whence) -> Result<u64, Errno> {
+        match handle {
+            FileHandle::Own => Err(Errno::Spipe),
+            FileHandle::Inner(handle) => self.request(CommandData::Seek(SeekCommand {
+                handle,
+                offset,
+                whence,
+            })),
         }
     }
 
-    pub fn receive_commands_blocking(&self, buffer: &mut [u8]) -> Result<usize, Errno> {
-        // TODO: document
-        let buffer = unsafe {
-            slice::from_raw_parts_mut(
-                buffer as *mut _ as *mut Command,
-                buffer.len() / size_of::<Command>(),
-            )
+    /// Changes the fd flags of a file.
+    pub fn set_flags(&self, handle: FileHandle, fd_flags: FdFlags) -> Result<(), Errno> {
+        let handle = inner_handle(handle)?;
+        self.request(CommandData::SetFlags(SetFlagsCommand { handle, fd_flags }))
+            .map(|_| ())
+    }
+
+    /// Syncs a file to its storage, only the data if `data_only` is set.
+    pub fn sync(&self, handle: FileHandle, data_only: bool) -> Result<(), Errno> {
+        let handle = inner_handle(handle)?;
+        let command = if data_only {
+            CommandData::DataSync(handle)
+        } else {
+            CommandData::Sync(handle)
         };
+        self.request(command).map(|_| ())
+    }
+
+    /// Advises the scheme about how a range of a file will be used.
+    pub fn advise(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> Result<(), Errno> {
+        let handle = inner_handle(handle)?;
+        self.request(CommandData::Advise(AdviseCommand {
+            handle,
+            offset,
+            len,
+            advice,
+        }))
+        .map(|_| ())
+    }
+
+    /// Creates a directory.
+    pub fn create_directory(&self, path: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::CreateDirectory(SchemePath::new(path)?))
+            .map(|_| ())
+    }
+
+    /// Removes an empty directory.
+    pub fn remove_directory(&self, path: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::RemoveDirectory(SchemePath::new(path)?))
+            .map(|_| ())
+    }
+
+    /// Removes a file.
+    pub fn unlink_file(&self, path: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::UnlinkFile(SchemePath::new(path)?))
+            .map(|_| ())
+    }
+
+    /// Renames a file or directory.
+    pub fn rename(&self, old: &[u8], new: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::Rename(PathPairCommand {
+            old: SchemePath::new(old)?,
+            new: SchemePath::new(new)?,
+        }))
+        .map(|_| ())
+    }
+
+    /// Creates a hard link at the new path to the old path.
+    pub fn link(&self, lookup_flags: LookupFlags, old: &[u8], new: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::Link(LinkCommand {
+            lookup_flags,
+            old: SchemePath::new(old)?,
+            new: SchemePath::new(new)?,
+        }))
+        .map(|_| ())
+    }
+
+    /// Creates a symbolic link at a path with the given contents.
+    pub fn symlink(&self, contents: &[u8], path: &[u8]) -> Result<(), Errno> {
+        self.request(CommandData::Symlink(PathPairCommand {
+            old: SchemePath::new(contents)?,
+            new: SchemePath::new(path)?,
+        }))
+        .map(|_| ())
+    }
+
+    /// Reads the contents of a symbolic link, returns the amount of bytes filled in.
+    pub fn read_link(&self, path: &[u8], buffer: UserBuffer) -> Result<usize, Errno> {
+        let path = SchemePath::new(path)?;
+        self.with_buffer_grant(buffer, |grant, len| {
+            CommandData::ReadLink(ReadLinkCommand { grant, len, path })
+        })
+    }
 
-        let x = self.command_queue.pop_front_many(buffer);
-        Ok(x * size_of::<Command>())
-        // TODO: map memory if required?
+    /// Gets the attributes of a file by path, the buffer is the size of a `FileStat`.
+    /// Returns the amount of bytes filled in.
+    pub fn path_file_stat(
+        &self,
+        lookup_flags: LookupFlags,
+        path: &[u8],
+        buffer: UserBuffer,
+    ) -> Result<usize, Errno> {
+        let path = SchemePath::new(path)?;
+        self.with_buffer_grant(buffer, |grant, len| {
+            CommandData::PathFileStat(PathTransferCommand {
+                lookup_flags,
+                grant,
+                len,
+                path,
+            })
+        })
     }
 
-    pub fn regular_read(&self, handle: InnerFileHandle, buffer: &mut [u8]) -> Result<usize, Errno> {
-        // TODO: share memory or smth
-        let reply = self.send_command_blocking(CommandData::Read(handle));
-        // TODO: ??
+    /// Sets the timestamps of a file by path.
+    pub fn set_times(
+        &self,
+        lookup_flags: LookupFlags,
+        path: &[u8],
+        atim: u64,
+        mtim: u64,
+        fst_flags: FstFlags,
+    ) -> Result<(), Errno> {
+        self.request(CommandData::SetTimes(SetTimesCommand {
+            lookup_flags,
+            atim,
+            mtim,
+            fst_flags,
+            path: SchemePath::new(path)?,
+        }))
+        .map(|_| ())
+    }
 
-        Ok(reply.value as usize)
+    /// Copies from the buffer of a grant, starting at an offset in the buffer.
+    /// Returns the amount of bytes copied.
+    pub fn grant_copy_from(
+        &self,
+        grant: GrantId,
+        offset: usize,
+        dst: UserBuffer,
+    ) -> Result<usize, Errno> {
+        let grants = self.grants.lock();
+        grants
+            .get(&grant)
+            .ok_or(Errno::Inval)?
+            .copy_from(offset, dst)
     }
 
-    pub fn regular_write(&self, handle: InnerFileHandle, buffer: &[u8]) -> Result<usize, Errno> {
-        // TODO
-        Ok(0)
+    /// Copies to the buffer of a grant, starting at an offset in the buffer.
+    /// Returns the amount of bytes copied.
+    pub fn grant_copy_to(
+        &self,
+        grant: GrantId,
+        offset: usize,
+        src: UserBuffer,
+    ) -> Result<usize, Errno> {
+        let grants = self.grants.lock();
+        grants.get(&grant).ok_or(Errno::Inval)?.copy_to(offset, src)
+    }
+}
+
+/// Gets the handle of a file inside a scheme.
+/// The scheme itself only supports reading commands and writing replies.
+fn inner_handle(handle: FileHandle) -> Result<InnerFileHandle, Errno> {
+    match handle {
+        FileHandle::Inner(handle) => Ok(handle),
+        FileHandle::Own => Err(Errno::NotSup),
     }
 }
+
+/// Serves a file in memory through the self scheme, until the file is closed.
+#[cfg(feature = "test-scheme")]
+extern "C" fn test_server(_arg: u64) {
+    use crate::tasking::scheduler::thread_exit;
+    use crate::tasking::scheme_container::schemes;
+    use crate::wasm::wasi::{FileStat, FileType};
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::slice;
+
+    let self_scheme = schemes().read().open_self(Box::new([])).unwrap();
+    let (scheme, _) = self_scheme.scheme_and_handle().unwrap();
+    let mut contents = Vec::new();
+
+    loop {
+        let mut buffer = [0u8; size_of::<Command>()];
+        let received = scheme
+            .receive_commands_blocking(UserBuffer::from(&mut buffer[..]))
+            .unwrap();
+        assert_eq!(received, buffer.len());
+        // Safety: the buffer holds one command, written by the scheme.
+        let command = unsafe { ptr::read_unaligned(buffer.as_ptr() as *const Command) };
+
+        let mut closed = false;
+        let value = match command.payload {
+            CommandData::Open(_) => Ok(1),
+            CommandData::Write(transfer) => {
+                contents.resize(transfer.len as usize, 0);
+                scheme.grant_copy_from(transfer.grant, 0, UserBuffer::from(&mut contents[..]))
+            }
+            CommandData::ReadAt(transfer) => {
+                let start = min(transfer.offset as usize, contents.len());
+                scheme.grant_copy_to(transfer.grant, 0, UserBuffer::from(&contents[start..]))
+            }
+            CommandData::FileStat(transfer) => {
+                let stat = FileStat {
+                    dev: 0,
+                    ino: 1,
+                    filetype: FileType::RegularFile,
+                    nlink: 1,
+                    size: contents.len() as u64,
+                    atim: 0,
+                    mtim: 0,
+                    ctim: 0,
+                };
+                // Safety: the slice covers the stat, which lives until the end of the copy.
+                let stat = unsafe {
+                    slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<FileStat>())
+                };
+                scheme.grant_copy_to(transfer.grant, 0, UserBuffer::from(stat))
+            }
+            CommandData::Close(_) => {
+                closed = true;
+                Ok(0)
+            }
+            _ => Err(Errno::NotSup),
+        };
+
+        let reply = Reply {
+            to: command.thread_id,
+            payload: match value {
+                Ok(value) => ReplyPayload {
+                    status: Errno::Success,
+                    value: value as u64,
+                },
+                Err(status) => ReplyPayload { status, value: 0 },
+            },
+        };
+        // Safety: the slice covers the reply, which lives until the end of the send.
+        let reply =
+            unsafe { slice::from_raw_parts(&reply as *const _ as *const u8, size_of::<Reply>()) };
+        let sent = scheme
+            .send_replies(UserBuffer::from(reply))
+            .expect("send reply");
+        assert_eq!(sent, reply.len());
+
+        if closed {
+            thread_exit(0);
+        }
+    }
+}
+
+/// Scheme test: a round trip through a file served by a kernel thread.
+#[cfg(feature = "test-scheme")]
+pub fn test_main() {
+    use crate::arch::address::VirtAddr;
+    use crate::tasking::scheme_container::schemes;
+    use crate::tasking::thread::Thread;
+    use crate::wasm::wasi::FileStat;
+    use alloc::boxed::Box;
+    use core::mem::MaybeUninit;
+
+    let domain = with_current_thread(|thread| thread.domain().clone());
+    // Safety: the entry point is a function that takes one argument and never returns.
+    let server = unsafe { Thread::create(domain, VirtAddr::new(test_server as usize), 0) }
+        .expect("server thread");
+    scheduler::add_and_schedule_thread(server);
+
+    let self_scheme = schemes().read().open_self(Box::new([])).unwrap();
+    let (scheme, _) = self_scheme.scheme_and_handle().unwrap();
+    let mode = OpenMode {
+        rights_base: Rights::all(),
+        rights_inheriting: Rights::all(),
+        o_flags: OFlags::CREAT | OFlags::TRUNC,
+        fd_flags: FdFlags::empty(),
+    };
+    let handle = scheme.open(b"hello", mode).expect("open");
+
+    let written = scheme.write(handle, UserBuffer::from(&b"hello world"[..]));
+    assert_eq!(written.expect("write"), 11);
+
+    let mut buffer = [0u8; 16];
+    let read = scheme.read_at(handle, UserBuffer::from(&mut buffer[..]), 6);
+    assert_eq!(read.expect("read"), 5);
+    assert_eq!(&buffer[..5], b"world");
+
+    // The buffer is read-only, so the scheme can't write to it.
+    let read = scheme.read_at(handle, UserBuffer::from(&buffer[..]), 0);
+    assert!(read.is_err());
+
+    let mut stat = MaybeUninit::<FileStat>::uninit();
+    // Safety: the buffer covers the stat.
+    let stat_buffer =
+        unsafe { UserBuffer::mutable(stat.as_mut_ptr() as *mut u8, size_of::<FileStat>()) };
+    let filled = scheme.file_stat(handle, stat_buffer).expect("file stat");
+    assert_eq!(filled, size_of::<FileStat>());
+    // Safety: the scheme filled in the whole stat.
+    assert_eq!(unsafe { stat.assume_init() }.size, 11);
+
+    scheme.close(handle).expect("close");
+}
@@ -1,13 +1,20 @@
 use crate::sync::spinlock::RwLock;
 use crate::tasking::file::{FileDescriptor, FileHandle};
-use crate::tasking::scheme::{Scheme, SchemePtr};
-use crate::wasm::wasi::Errno;
+use crate::tasking::scheme::{OpenMode, Scheme, SchemePtr};
+use crate::wasm::wasi::{Errno, OFlags};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::Entry;
 use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use spin::Once;
 
+/// Separates the scheme name from the path inside the scheme: `name:path`.
+const SCHEME_SEPARATOR: u8 = b':';
+
+/// Separates the components of a path.
+const PATH_SEPARATOR: u8 = b'/';
+
 /// Scheme identifier.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(transparent)]
@@ -67,15 +74,136 @@ impl SchemeContainer {
 
     pub fn open_self(&self, name: Box<[u8]>) -> Result<FileDescriptor, Errno> {
         let (_, w) = self.name_scheme_map.get(&name).ok_or(Errno::NoDev)?;
-        Ok(FileDescriptor::from(w.clone(), FileHandle::Own))
+        Ok(FileDescriptor::from(
+            w.clone(),
+            FileHandle::Own,
+            Box::new([]),
+            OpenMode::directory(),
+        ))
+    }
+
+    /// Checks if a scheme is the self scheme.
+    fn is_self(&self, scheme: &SchemePtr) -> bool {
+        self.name_scheme_map
+            .get(&b""[..])
+            .map_or(false, |(_, w)| Weak::ptr_eq(w, scheme))
     }
 
-    pub fn open(&self, name: Box<[u8]>, i: i32) -> Result<FileDescriptor, Errno> {
-        let (a, w) = self.name_scheme_map.get(&name).ok_or(Errno::NoDev)?;
-        // TODO: filename arg
-        a.open(i)
-            .map(|handle| FileDescriptor::from(w.clone(), handle))
+    /// Resolves a path to the scheme it is in and the path inside that scheme.
+    /// Without a directory, the path must be of the form `name:path`: it selects the scheme with
+    /// that name, and is relative to the root of that scheme. Only the kernel resolves those, for
+    /// the pre-opens of the manifest. Other paths are relative to the directory `dir`, given by its
+    /// scheme and its path inside the scheme, and can't escape that directory. A `:` has no special
+    /// meaning in them.
+    fn resolve(
+        &self,
+        dir: Option<(&SchemePtr, &[u8])>,
+        path: &[u8],
+    ) -> Result<(SchemePtr, Box<[u8]>), Errno> {
+        match dir {
+            None => {
+                let (name, path) = split_scheme_name(path).ok_or(Errno::NoEnt)?;
+                let (_, w) = self.name_scheme_map.get(name).ok_or(Errno::NoEnt)?;
+                Ok((w.clone(), resolve_relative(&[], path)?))
+            }
+            Some((scheme, dir_path)) => {
+                // Absolute paths are not relative to the directory.
+                if path.first() == Some(&PATH_SEPARATOR) {
+                    return Err(Errno::NotCapable);
+                }
+                Ok((scheme.clone(), resolve_relative(dir_path, path)?))
+            }
+        }
+    }
+}
+
+/// Splits a path in the scheme name and the path inside the scheme,
+/// if the path starts with a scheme name.
+pub fn split_scheme_name(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = path
+        .iter()
+        .position(|&b| b == SCHEME_SEPARATOR || b == PATH_SEPARATOR)?;
+    if path[end] == SCHEME_SEPARATOR {
+        Some((&path[..end], &path[end + 1..]))
+    } else {
+        None
+    }
+}
+
+/// Resolves a path relative to a base path inside the same scheme.
+/// The result has no `.`, `..` or empty components, and is never above the base path.
+fn resolve_relative(base: &[u8], path: &[u8]) -> Result<Box<[u8]>, Errno> {
+    let mut components = base
+        .split(|&b| b == PATH_SEPARATOR)
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>();
+    let floor = components.len();
+
+    for component in path.split(|&b| b == PATH_SEPARATOR) {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                if components.len() == floor {
+                    return Err(Errno::NotCapable);
+                }
+                components.pop();
+            }
+            component => components.push(component),
+        }
     }
+
+    Ok(components.join(&PATH_SEPARATOR).into_boxed_slice())
+}
+
+/// Resolves a path to the scheme it is in and the path inside that scheme,
+/// see `SchemeContainer::resolve`.
+/// The root of the self scheme is the scheme itself and not a file of the scheme, see `open`,
+/// so it can't be resolved.
+pub fn resolve(
+    dir: Option<(&SchemePtr, &[u8])>,
+    path: &[u8],
+) -> Result<(Arc<Scheme>, Box<[u8]>), Errno> {
+    let (scheme, path) = {
+        let container = schemes().read();
+        let (scheme, path) = container.resolve(dir, path)?;
+        if container.is_self(&scheme) && path.is_empty() {
+            return Err(Errno::NotSup);
+        }
+        (scheme, path)
+    };
+    Ok((scheme.upgrade().ok_or(Errno::NoDev)?, path))
+}
+
+/// Opens a path, see `SchemeContainer::resolve` for how paths are resolved.
+/// The root of the self scheme is the scheme itself. Other files are opened by their scheme,
+/// which handles the open flags and checks the requested rights.
+pub fn open(
+    dir: Option<(&SchemePtr, &[u8])>,
+    path: &[u8],
+    mode: OpenMode,
+) -> Result<FileDescriptor, Errno> {
+    let (scheme, path, is_self) = {
+        let container = schemes().read();
+        let (scheme, path) = container.resolve(dir, path)?;
+        let is_self = container.is_self(&scheme);
+        (scheme, path, is_self)
+    };
+
+    let handle = if is_self && path.is_empty() {
+        // The scheme itself behaves like a directory that always exists.
+        if mode.o_flags.contains(OFlags::CREAT | OFlags::EXCL) {
+            return Err(Errno::Exist);
+        }
+        if mode.o_flags.contains(OFlags::TRUNC) {
+            return Err(Errno::Isdir);
+        }
+        FileHandle::Own
+    } else {
+        // The container lock is not held here, opening blocks until the scheme replies.
+        scheme.upgrade().ok_or(Errno::NoDev)?.open(&path, mode)?
+    };
+
+    Ok(FileDescriptor::from(scheme, handle, path, mode))
 }
 
 static SCHEMES: Once<RwLock<SchemeContainer>> = Once::new();
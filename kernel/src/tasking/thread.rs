@@ -5,20 +5,20 @@ use crate::arch::paging::{EntryFlags, PAGE_SIZE};
 use crate::arch::simd::SimdState;
 use crate::arch::{preempt_disable, preempt_enable};
 use crate::mm::mapper::MemoryError;
-use crate::mm::vma_allocator::{LazilyMappedVma, MappableVma, MappedVma};
-use crate::sync::spinlock::{PreemptCounterInfluence, RwLock, Spinlock};
-use crate::tasking::file::FileDescriptorTable;
+use crate::mm::vma_allocator::{MappableVma, MappedVma};
+use crate::sync::spinlock::RwLock;
+use crate::tasking::process::Process;
 use crate::tasking::protection_domain::ProtectionDomain;
 use crate::tasking::scheduler::with_core_scheduler;
 use crate::tasking::scheme::ReplyPayloadTcb;
-use crate::tasking::scheme_container::{schemes, SchemeId};
-use crate::wasm::vmctx::{VmContextContainer, WASM_PAGE_SIZE};
-use alloc::boxed::Box;
+use crate::tasking::scheme_container::SchemeId;
+use crate::wasm::instance::Instance;
+use crate::wasm::vmctx::VmContextContainer;
 use alloc::sync::Arc;
 use atomic::Atomic;
 use core::borrow::Borrow;
 use core::cmp::Ordering;
-use spin::MutexGuard;
+use core::ptr;
 
 /// Stack size in bytes.
 const STACK_SIZE: usize = 1024 * 256;
@@ -72,6 +72,13 @@ impl ThreadId {
     pub fn as_u32(&self) -> u32 {
         self.id
     }
+
+    /// Thread id from a raw number, as returned by `as_u32`.
+    /// Ids are not recycled yet, so they all have the same generation.
+    #[inline]
+    pub fn from_u32(id: u32) -> Self {
+        Self { generation: 0, id }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -84,24 +91,54 @@ pub enum ThreadStatus {
 
 const_assert!(Atomic::<ThreadStatus>::is_lock_free());
 
-struct StaticWasmThreadData {
-    code: MappedVma,
-    _vmctx_container: VmContextContainer,
+/// Wasm data of a thread, this data is set up once before the thread runs wasm code.
+/// Every thread holds a reference to its process, so the process and its instance are torn down
+/// when the last thread exits and nobody else refers to the process.
+pub struct StaticWasmThreadData {
+    /// Own copy of the context of the instance, only for threads spawned by wasm code.
+    /// Declared before the process, so it is dropped first.
+    pub vmctx_container: Option<VmContextContainer>,
+    /// The process this thread belongs to, the thread must be added to it.
+    pub process: Arc<Process>,
+}
+
+impl StaticWasmThreadData {
+    /// Gets the instance of the process.
+    #[inline]
+    pub fn instance(&self) -> &Arc<Instance> {
+        self.process.instance()
+    }
+
+    /// Gets the context this thread uses to run code of an instance.
+    /// That is the own copy for the instance of the thread, if there is one.
+    pub fn vmctx_container_of<'a>(&'a self, instance: &'a Instance) -> &'a VmContextContainer {
+        match self.vmctx_container {
+            Some(ref vmctx_container) if ptr::eq(instance, &**self.instance()) => vmctx_container,
+            _ => &instance.vmctx_container,
+        }
+    }
+
+    /// Gets the context this thread uses to run code of its own instance.
+    pub fn vmctx_container(&self) -> &VmContextContainer {
+        self.vmctx_container_of(self.instance())
+    }
 }
 
 pub struct Thread {
     pub stack: Stack,
     pub id: ThreadId,
-    heap: RwLock<LazilyMappedVma>,
-    static_wasm_data: Spinlock<Option<StaticWasmThreadData>>,
+    static_wasm_data: RwLock<Option<StaticWasmThreadData>>,
     simd_state: SimdState,
     domain: ProtectionDomain,
     status: Atomic<ThreadStatus>,
-    file_descriptor_table: Spinlock<FileDescriptorTable>, // TODO: avoid locks if we're the only owner
     pub reply: ReplyPayloadTcb,
     /// On which IPC scheme are we blocked on? Only applicable for sync IPC.
     /// If this is equal to the sentinel value, we aren't blocked on a scheme.
     ipc_blocked_on: Atomic<SchemeId>,
+    /// Another thread wants this thread to be killed at its next safe point in wasm code.
+    kill_requested: Atomic<bool>,
+    /// Whether the thread is blocked in a wait that a kill request can interrupt.
+    interruptible_block: Atomic<bool>,
 }
 
 impl Thread {
@@ -128,79 +165,65 @@ impl Thread {
 
     /// Creates a new thread from given parameters.
     pub fn new(stack: Stack, domain: ProtectionDomain) -> Self {
-        // TODO
-        let mut fdt = FileDescriptorTable::new();
-        fdt.insert_lowest({
-            let mut tmp = schemes()
-                .read()
-                .open_self(Box::new([]))
-                .expect("self scheme");
-            tmp.set_pre_open_path(Box::new(*b"."));
-            tmp
-        });
-
         Self {
             stack,
-            heap: RwLock::new(LazilyMappedVma::dummy()),
             id: ThreadId::new(),
-            static_wasm_data: Spinlock::new(None),
+            static_wasm_data: RwLock::new(None),
             domain,
             simd_state: SimdState::new(),
             status: Atomic::new(ThreadStatus::Runnable),
-            file_descriptor_table: Spinlock::new(fdt),
             reply: ReplyPayloadTcb::new(),
             ipc_blocked_on: Atomic::new(SchemeId::sentinel()),
+            kill_requested: Atomic::new(false),
+            interruptible_block: Atomic::new(false),
         }
     }
 
     /// Sets the thread wasm data.
     /// Unsafe when incorrect data is passed, or when used data is overwritten.
-    pub unsafe fn set_wasm_data(
-        &self,
-        code_vma: MappedVma,
-        heap_vma: LazilyMappedVma,
-        vmctx_container: VmContextContainer,
-    ) {
-        *self.heap.write() = heap_vma;
-        *self.static_wasm_data.lock() = Some(StaticWasmThreadData {
-            code: code_vma,
-            _vmctx_container: vmctx_container,
-        });
+    pub unsafe fn set_wasm_data(&self, data: StaticWasmThreadData) {
+        *self.static_wasm_data.write() = Some(data);
     }
 
-    /// Gets the file descriptor table.
-    #[inline]
-    pub fn file_descriptor_table(
-        &self,
-    ) -> MutexGuard<FileDescriptorTable, PreemptCounterInfluence> {
-        self.file_descriptor_table.lock()
+    /// Execute with the wasm data, if this thread runs wasm code.
+    /// This only tries to lock the data, because this is used in fault handlers.
+    /// Returns `None` if there is no wasm data or if it couldn't be locked.
+    pub fn try_with_wasm_data<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&StaticWasmThreadData) -> T,
+    {
+        let data = self.static_wasm_data.try_read()?;
+        data.as_ref().map(f)
     }
 
-    /// Gets the current allocated heap size in WebAssembly pages.
-    pub fn heap_size(&self) -> usize {
-        self.heap.read().size()
+    /// Gets the process this thread belongs to, if this thread runs wasm code.
+    pub fn process(&self) -> Option<Arc<Process>> {
+        self.static_wasm_data
+            .read()
+            .as_ref()
+            .map(|data| data.process.clone())
     }
 
-    /// Grows the heap by `wasm_pages` WebAssembly pages.
-    pub fn heap_grow(&self, wasm_pages: u32) -> u32 {
-        self.heap
-            .write()
-            .expand((wasm_pages as usize) * WASM_PAGE_SIZE)
-            .map_or(core::u32::MAX, |x| (x / WASM_PAGE_SIZE) as u32)
+    /// Removes this thread from its process, if it has one, because it exits.
+    /// This can wake up threads waiting for the process, so it must be done by the thread itself
+    /// and not while it is being cleaned up by the scheduler.
+    pub fn leave_process(&self, exit_code: u32) {
+        if let Some(ref data) = *self.static_wasm_data.read() {
+            data.process.remove_thread(self.id, exit_code);
+        }
     }
 
     /// Unmaps the memory that this thread holds.
     /// Unsafe because you can totally break memory mappings and safety if you call this
     /// while memory of this thread is still used somewhere.
     pub unsafe fn unmap_memory(&self) {
+        // The instance unmaps its own memory when the last user is gone.
+        // This must happen outside of the domain lock, because the instance needs it as well.
+        let data = self.static_wasm_data.write().take();
+        drop(data);
+
         self.domain.with(|vma, mapping| {
-            if let Some(ref mut data) = *self.static_wasm_data.lock() {
-                vma.destroy_vma(mapping, &data.code);
-            }
             vma.destroy_vma(mapping, &self.stack.vma);
-            let mut heap = self.heap.write();
-            vma.destroy_vma(mapping, &*heap);
-            *heap = LazilyMappedVma::dummy();
         });
     }
 
@@ -213,8 +236,11 @@ impl Thread {
     /// Handle a page fault for this thread. Returns true if handled successfully.
     #[inline]
     pub fn page_fault(&self, fault_addr: VirtAddr) -> bool {
-        self.domain
-            .with(|_vma, mapping| self.heap.write().try_handle_page_fault(mapping, fault_addr))
+        self.try_with_wasm_data(|data| {
+            self.domain
+                .with(|_vma, mapping| data.instance().try_handle_page_fault(mapping, fault_addr))
+        })
+        .unwrap_or(false)
     }
 
     /// Save SIMD state.
@@ -237,8 +263,16 @@ impl Thread {
 
     /// Wakes up this thread.
     pub fn wakeup(&self) {
-        if self
-            .status
+        if self.unblock() {
+            // TODO: multicore
+            with_core_scheduler(|s| s.move_wakeup(self.id));
+        }
+    }
+
+    /// Marks this thread as runnable if it is blocked, the caller must queue it in that case.
+    /// Returns true if the thread was blocked.
+    pub(crate) fn unblock(&self) -> bool {
+        self.status
             .compare_exchange(
                 ThreadStatus::Blocked,
                 ThreadStatus::Runnable,
@@ -246,10 +280,6 @@ impl Thread {
                 atomic::Ordering::Relaxed,
             )
             .is_ok()
-        {
-            // TODO: multicore
-            with_core_scheduler(|s| s.move_wakeup(self.id));
-        }
     }
 
     /// Gets the status.
@@ -270,6 +300,37 @@ impl Thread {
     pub fn ipc_blocked_on(&self) -> SchemeId {
         self.ipc_blocked_on.load(atomic::Ordering::Acquire)
     }
+
+    /// Requests this thread to be killed, see `wasm::epoch::kill`.
+    #[inline]
+    pub fn request_kill(&self) {
+        self.kill_requested.store(true, atomic::Ordering::SeqCst);
+    }
+
+    /// Takes the kill request, returns true if this thread must be killed.
+    #[inline]
+    pub fn take_kill_request(&self) -> bool {
+        self.kill_requested.swap(false, atomic::Ordering::AcqRel)
+    }
+
+    /// Returns true if this thread must be killed, without taking the request.
+    #[inline]
+    pub fn kill_requested(&self) -> bool {
+        self.kill_requested.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Sets whether this thread is blocked in a wait that a kill request can interrupt.
+    #[inline]
+    pub fn set_interruptible_block(&self, interruptible: bool) {
+        self.interruptible_block
+            .store(interruptible, atomic::Ordering::SeqCst);
+    }
+
+    /// Gets whether this thread is blocked in a wait that a kill request can interrupt.
+    #[inline]
+    pub fn in_interruptible_block(&self) -> bool {
+        self.interruptible_block.load(atomic::Ordering::SeqCst)
+    }
 }
 
 impl PartialEq for Thread {
@@ -332,6 +393,22 @@ impl Stack {
         }
     }
 
+    /// Checks if the address is inside the guard pages of the stack.
+    pub fn is_in_guard(&self, addr: VirtAddr) -> bool {
+        !self.vma.is_dummy()
+            && addr.as_usize() >= self.vma.address().as_usize()
+            && addr.as_usize() < self.vma.address().as_usize() + AMOUNT_GUARD_PAGES * PAGE_SIZE
+    }
+
+    /// Checks if the range is inside the mapped part of the stack.
+    pub fn is_in_mapped_range(&self, addr: VirtAddr, len: usize) -> bool {
+        let start = self.vma.address().as_usize() + AMOUNT_GUARD_PAGES * PAGE_SIZE;
+        let end = self.vma.address().as_usize() + self.vma.size();
+        !self.vma.is_dummy()
+            && addr.as_usize() >= start
+            && addr.as_usize().checked_add(len).map_or(false, |x| x <= end)
+    }
+
     /// Gets the current location.
     #[inline]
     pub fn get_current_location(&self) -> VirtAddr {
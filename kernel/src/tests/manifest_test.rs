@@ -0,0 +1,5 @@
+/// Manifest parser test.
+#[cfg(feature = "test-manifest")]
+pub fn test_main() {
+    crate::util::manifest::test_main();
+}
@@ -3,6 +3,9 @@ use core::panic::PanicInfo;
 pub use buddy_test::*;
 pub use heap_test::*;
 pub use interval_tree_test::*;
+pub use manifest_test::*;
+pub use scheme_test::*;
+pub use string_list_test::*;
 pub use vmm_test::*;
 
 use crate::arch::qemu;
@@ -10,6 +13,9 @@ use crate::arch::qemu;
 mod buddy_test;
 mod heap_test;
 mod interval_tree_test;
+mod manifest_test;
+mod scheme_test;
+mod string_list_test;
 mod vmm_test;
 
 #[panic_handler]
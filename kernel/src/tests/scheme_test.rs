@@ -0,0 +1,5 @@
+/// Scheme test.
+#[cfg(feature = "test-scheme")]
+pub fn test_main() {
+    crate::tasking::scheme::test_main();
+}
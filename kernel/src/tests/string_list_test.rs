@@ -0,0 +1,5 @@
+/// String list test.
+#[cfg(feature = "test-string-list")]
+pub fn test_main() {
+    crate::util::string_list::test_main();
+}
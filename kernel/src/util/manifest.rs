@@ -0,0 +1,391 @@
+//! Boot manifest support.
+//!
+//! The boot manifest describes which files of the initrd should be started as services.
+//! Files that are not listed in the manifest are treated as data.
+//! The format is a simple line-based format:
+//!
+//! ```text
+//! # Comment
+//! [libc.wasm]
+//! domain = shared
+//! name = libc
+//!
+//! [service.wasm]
+//! domain = shared
+//! args = service.wasm
+//! args = --greeting=hello world
+//! env = RUST_BACKTRACE=1
+//! preopen = .
+//! compile = lazy
+//! fuel = 1000000
+//! fuel_policy = yield
+//! time_slice = 10
+//! memory_limit = 64M
+//!
+//! [worker.wasm]
+//! start = manual
+//! ```
+//!
+//! Every section starts a new service, services are started in order of appearance.
+//! `args` and `env` may be given multiple times, they are appended in order. Every `args` line is
+//! one argument: the value is not split or unquoted, so it can contain spaces.
+//! `preopen` gives a service a directory, it may be given multiple times. A directory of the form
+//! `name:path` is a directory of the scheme with that name, the service sees it under that same
+//! path. Other directories are the self scheme.
+//! Services with the same `domain` name share a `ProtectionDomain`.
+//! A service with a `name` can be imported from by services later in the same domain, using the name
+//! as module name. Named modules without a start function are libraries.
+//! `compile` is either `eager` (the default) or `lazy`, lazily compiled services only compile a
+//! function when it is called for the first time.
+//! `fuel` gives a service a CPU budget: its code is compiled with fuel metering, and consumes fuel
+//! at every function entry and loop iteration. `fuel_policy` determines what happens when the
+//! budget is used up: `yield` (the default) yields and continues with a new budget, `kill` kills
+//! the thread and `event` continues with a new budget and delivers a "budget exhausted" event to
+//! the service.
+//! `interruptible = true` compiles a service with epoch interruption, so its threads can be killed
+//! by other threads at a safe point. `time_slice` makes a service interruptible too: its threads
+//! yield after running for the given amount of timer ticks.
+//! `memory_limit` limits the total size of the memories a service creates, in bytes. The size may
+//! have a `K`, `M` or `G` suffix. Growing a memory beyond the limit fails.
+//! `start` is either `boot` (the default) or `manual`. Manual services are not started at boot,
+//! supervisors spawn them by file name using the kwast host module, once per spawn. Their name is
+//! not registered, so they can't be used as a library.
+//! If the initrd contains a precompiled artifact `<file>.aot` next to a service, it is used instead
+//! of compiling the service at boot, as long as it is up to date.
+
+use crate::wasm::fuel::FuelPolicy;
+use alloc::vec::Vec;
+
+/// File name of the manifest inside the initrd.
+pub const MANIFEST_FILE_NAME: &[u8] = b"boot.manifest";
+
+/// Domain name of the domain the kernel boot thread runs in.
+pub const KERNEL_DOMAIN_NAME: &str = "kernel";
+
+/// A single service entry in the manifest.
+#[derive(Debug, Clone)]
+pub struct ManifestEntry<'a> {
+    /// File name inside the initrd.
+    pub file: &'a str,
+    /// Name of the protection domain.
+    pub domain: &'a str,
+    /// Instance name, other modules can import from this name.
+    pub name: Option<&'a str>,
+    /// Program arguments, one per `args` line.
+    pub args: Vec<&'a str>,
+    /// Environment variables in the form of `KEY=VALUE`.
+    pub env: Vec<&'a str>,
+    /// Pre-opened directories.
+    pub preopens: Vec<&'a str>,
+    /// How the service is compiled.
+    pub compile_mode: CompileMode,
+    /// Amount of fuel the service gets at once, `None` disables fuel metering.
+    pub fuel: Option<u64>,
+    /// What happens when the service runs out of fuel.
+    pub fuel_policy: FuelPolicy,
+    /// Whether the service is compiled with epoch interruption, see `is_interruptible`.
+    pub interruptible: bool,
+    /// Amount of timer ticks a thread of the service runs before it yields, if any.
+    pub time_slice: Option<u64>,
+    /// Maximum total size in bytes of the memories of the service, if any.
+    pub memory_limit: Option<usize>,
+    /// When the service is started.
+    pub start_mode: StartMode,
+}
+
+/// When a service is started.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StartMode {
+    /// The service is started at boot.
+    Boot,
+    /// The service is started when a supervisor spawns it.
+    Manual,
+}
+
+/// How a service is compiled.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CompileMode {
+    /// All functions are compiled before the service starts.
+    Eager,
+    /// Functions are compiled when they are called for the first time.
+    Lazy,
+}
+
+/// Parsed boot manifest.
+#[derive(Debug)]
+pub struct Manifest<'a> {
+    entries: Vec<ManifestEntry<'a>>,
+}
+
+/// Error kind that can occur during manifest parsing.
+#[derive(Debug)]
+pub enum ManifestErrorKind {
+    /// The manifest is not valid UTF-8.
+    InvalidEncoding,
+    /// A key-value pair appears outside of a section.
+    OutsideSection,
+    /// A line could not be parsed.
+    Syntax,
+    /// Unknown key.
+    UnknownKey,
+    /// The value is not valid for the key.
+    InvalidValue,
+}
+
+/// Error that can occur during manifest parsing.
+#[derive(Debug)]
+pub struct ManifestError {
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Kind of error.
+    pub kind: ManifestErrorKind,
+}
+
+impl<'a> ManifestEntry<'a> {
+    /// Creates a new entry with default values.
+    fn new(file: &'a str) -> Self {
+        Self {
+            file,
+            domain: KERNEL_DOMAIN_NAME,
+            name: None,
+            args: Vec::new(),
+            env: Vec::new(),
+            preopens: Vec::new(),
+            compile_mode: CompileMode::Eager,
+            fuel: None,
+            fuel_policy: FuelPolicy::Yield,
+            interruptible: false,
+            time_slice: None,
+            memory_limit: None,
+            start_mode: StartMode::Boot,
+        }
+    }
+
+    /// Checks if the service is compiled with epoch interruption, a time slice requires it.
+    pub fn is_interruptible(&self) -> bool {
+        self.interruptible || self.time_slice.is_some()
+    }
+}
+
+impl<'a> Manifest<'a> {
+    /// Parses a manifest.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ManifestError> {
+        let data = core::str::from_utf8(data).map_err(|_| ManifestError {
+            line: 0,
+            kind: ManifestErrorKind::InvalidEncoding,
+        })?;
+
+        let mut entries: Vec<ManifestEntry<'a>> = Vec::new();
+
+        for (i, line) in data.lines().enumerate() {
+            let error = |kind| ManifestError { line: i + 1, kind };
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') || line.len() < 3 {
+                    return Err(error(ManifestErrorKind::Syntax));
+                }
+
+                entries.push(ManifestEntry::new(line[1..line.len() - 1].trim()));
+                continue;
+            }
+
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(error(ManifestErrorKind::Syntax)),
+            };
+
+            let entry = entries
+                .last_mut()
+                .ok_or_else(|| error(ManifestErrorKind::OutsideSection))?;
+
+            match key {
+                "domain" => entry.domain = value,
+                "name" => entry.name = Some(value),
+                "args" => entry.args.push(value),
+                "env" => entry.env.push(value),
+                "preopen" => entry.preopens.push(value),
+                "compile" => {
+                    entry.compile_mode = match value {
+                        "eager" => CompileMode::Eager,
+                        "lazy" => CompileMode::Lazy,
+                        _ => return Err(error(ManifestErrorKind::InvalidValue)),
+                    }
+                }
+                "fuel" => {
+                    entry.fuel = Some(
+                        value
+                            .parse()
+                            .map_err(|_| error(ManifestErrorKind::InvalidValue))?,
+                    )
+                }
+                "fuel_policy" => {
+                    entry.fuel_policy = match value {
+                        "yield" => FuelPolicy::Yield,
+                        "kill" => FuelPolicy::Kill,
+                        "event" => FuelPolicy::Event,
+                        _ => return Err(error(ManifestErrorKind::InvalidValue)),
+                    }
+                }
+                "interruptible" => {
+                    entry.interruptible = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(error(ManifestErrorKind::InvalidValue)),
+                    }
+                }
+                "time_slice" => {
+                    entry.time_slice = Some(
+                        value
+                            .parse()
+                            .map_err(|_| error(ManifestErrorKind::InvalidValue))?,
+                    )
+                }
+                "memory_limit" => {
+                    entry.memory_limit = Some(
+                        parse_size(value).ok_or_else(|| error(ManifestErrorKind::InvalidValue))?,
+                    )
+                }
+                "start" => {
+                    entry.start_mode = match value {
+                        "boot" => StartMode::Boot,
+                        "manual" => StartMode::Manual,
+                        _ => return Err(error(ManifestErrorKind::InvalidValue)),
+                    }
+                }
+                _ => return Err(error(ManifestErrorKind::UnknownKey)),
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Gets the entries in start order.
+    pub fn entries(&self) -> &[ManifestEntry<'a>] {
+        &self.entries
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'K' => (&value[..value.len() - 1], 10),
+        b'M' => (&value[..value.len() - 1], 20),
+        b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+
+    number.trim().parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// Manifest parser test.
+#[cfg(feature = "test-manifest")]
+pub fn test_main() {
+    let manifest = Manifest::parse(
+        b"# Comment\n\
+          [libc.wasm]\n\
+          domain = shared\n\
+          name = libc\n\
+          \n\
+          [ service.wasm ]\n\
+          domain = shared\n\
+          args = service.wasm\n\
+          args = --greeting=hello  world\n\
+          args = -x\n\
+          env = A=1\n\
+          env = B = 2\n\
+          preopen = .\n\
+          preopen = tmp:data\n\
+          compile = lazy\n\
+          fuel = 1000\n\
+          fuel_policy = kill\n\
+          time_slice = 10\n\
+          memory_limit = 64M\n\
+          start = manual\n",
+    )
+    .expect("valid manifest");
+
+    let entries = manifest.entries();
+    assert_eq!(entries.len(), 2);
+
+    let libc = &entries[0];
+    assert_eq!(libc.file, "libc.wasm");
+    assert_eq!(libc.domain, "shared");
+    assert_eq!(libc.name, Some("libc"));
+    assert!(libc.args.is_empty());
+    assert_eq!(libc.compile_mode, CompileMode::Eager);
+    assert_eq!(libc.fuel, None);
+    assert!(!libc.is_interruptible());
+    assert_eq!(libc.start_mode, StartMode::Boot);
+
+    let service = &entries[1];
+    assert_eq!(service.file, "service.wasm");
+    assert_eq!(service.name, None);
+    assert_eq!(
+        service.args,
+        ["service.wasm", "--greeting=hello  world", "-x"]
+    );
+    assert_eq!(service.env, ["A=1", "B = 2"]);
+    assert_eq!(service.preopens, [".", "tmp:data"]);
+    assert_eq!(service.compile_mode, CompileMode::Lazy);
+    assert_eq!(service.fuel, Some(1000));
+    assert!(matches!(service.fuel_policy, FuelPolicy::Kill));
+    assert_eq!(service.time_slice, Some(10));
+    assert!(service.is_interruptible());
+    assert_eq!(service.memory_limit, Some(64 << 20));
+    assert_eq!(service.start_mode, StartMode::Manual);
+
+    // Entries without keys get the defaults.
+    let manifest = Manifest::parse(b"[a.wasm]\n[b.wasm]\n").expect("valid manifest");
+    assert_eq!(manifest.entries().len(), 2);
+    assert_eq!(manifest.entries()[1].domain, KERNEL_DOMAIN_NAME);
+
+    let error_of = |data: &[u8]| Manifest::parse(data).expect_err("invalid manifest");
+
+    let error = error_of(b"domain = shared\n");
+    assert_eq!(error.line, 1);
+    assert!(matches!(error.kind, ManifestErrorKind::OutsideSection));
+
+    let error = error_of(b"[a.wasm]\n\n[b.wasm\n");
+    assert_eq!(error.line, 3);
+    assert!(matches!(error.kind, ManifestErrorKind::Syntax));
+
+    let error = error_of(b"[]\n");
+    assert!(matches!(error.kind, ManifestErrorKind::Syntax));
+
+    let error = error_of(b"[a.wasm]\ndomain\n");
+    assert_eq!(error.line, 2);
+    assert!(matches!(error.kind, ManifestErrorKind::Syntax));
+
+    let error = error_of(b"[a.wasm]\ncolour = blue\n");
+    assert!(matches!(error.kind, ManifestErrorKind::UnknownKey));
+
+    for line in &[
+        "compile = sometimes",
+        "fuel = lots",
+        "fuel_policy = panic",
+        "interruptible = yes",
+        "time_slice = -1",
+        "memory_limit = 1T",
+        "memory_limit = M",
+        "start = later",
+    ] {
+        let data = format!("[a.wasm]\n{}\n", line);
+        let error = error_of(data.as_bytes());
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, ManifestErrorKind::InvalidValue));
+    }
+
+    let error = error_of(b"[a.wasm]\nname = \xff\n");
+    assert!(matches!(error.kind, ManifestErrorKind::InvalidEncoding));
+
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("4K"), Some(4096));
+    assert_eq!(parse_size("2G"), Some(2 << 30));
+    assert_eq!(parse_size(""), None);
+}
@@ -1,4 +1,6 @@
 pub mod boot_module;
+pub mod manifest;
+pub mod string_list;
 pub mod tar;
 pub mod unchecked;
 #[macro_use]
@@ -0,0 +1,92 @@
+//! List of null-terminated strings, stored consecutively in one buffer.
+//! This is the layout WASI expects for the program arguments and environment.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::iter;
+
+/// A list of null-terminated strings.
+#[derive(Debug)]
+pub struct StringList {
+    /// All strings after each other, each one is null-terminated.
+    buffer: Box<[u8]>,
+    /// Start offset of each string in the buffer.
+    offsets: Box<[u32]>,
+}
+
+impl StringList {
+    /// Creates a list from strings.
+    /// Strings containing a null byte are cut off at that byte.
+    pub fn new<'a, I>(strings: I) -> Self
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut buffer = Vec::new();
+        let mut offsets = Vec::new();
+
+        for string in strings {
+            let string = match string.iter().position(|x| *x == 0) {
+                Some(i) => &string[..i],
+                None => string,
+            };
+
+            offsets.push(buffer.len() as u32);
+            buffer.extend(string.iter().chain(iter::once(&0)));
+        }
+
+        Self {
+            buffer: buffer.into_boxed_slice(),
+            offsets: offsets.into_boxed_slice(),
+        }
+    }
+
+    /// Amount of strings.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Checks if there are no strings.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Gets the buffer containing all strings, including their null terminators.
+    #[inline]
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Gets the start offset of each string in the buffer.
+    #[inline]
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+}
+
+/// String list test.
+#[cfg(feature = "test-string-list")]
+pub fn test_main() {
+    let list = StringList::new(
+        [&b"prog"[..], b"", b"--flag=x", b"cut\0off"]
+            .iter()
+            .copied(),
+    );
+    assert_eq!(list.len(), 4);
+    assert!(!list.is_empty());
+    assert_eq!(list.offsets(), [0, 5, 6, 15]);
+    assert_eq!(list.buffer(), b"prog\0\0--flag=x\0cut\0");
+    assert_eq!(list.buffer().len(), 19);
+
+    // Every offset points to the start of its string, right after the previous terminator.
+    for (i, offset) in list.offsets().iter().enumerate().skip(1) {
+        assert_eq!(list.buffer()[*offset as usize - 1], 0, "string {}", i);
+    }
+
+    let empty = StringList::new(iter::empty());
+    assert_eq!(empty.len(), 0);
+    assert!(empty.is_empty());
+    assert!(empty.buffer().is_empty());
+    assert!(empty.offsets().is_empty());
+}
@@ -27,6 +27,7 @@ struct PosixHeader {
 }
 
 /// Representation of a tar archive.
+#[derive(Copy, Clone)]
 pub struct Tar<'a> {
     contents: &'a [u8],
 }
@@ -34,6 +35,7 @@ pub struct Tar<'a> {
 /// Representation of a file in a tar archive.
 #[derive(Debug)]
 pub struct TarFile<'a> {
+    name: &'a [u8],
     data: &'a [u8],
 }
 
@@ -49,9 +51,23 @@ impl<'a> Tar<'a> {
     pub unsafe fn from_slice(contents: &'a [u8]) -> Option<Self> {
         (contents.len() % 512 == 0).then_some(Self { contents })
     }
+
+    /// Finds a file by name.
+    pub fn find(&self, name: &[u8]) -> Option<TarFile<'a>> {
+        self.into_iter().find(|file| file.name() == name)
+    }
 }
 
 impl<'a> TarFile<'a> {
+    /// Gets the file name, without a leading "./".
+    pub fn name(&self) -> &'a [u8] {
+        if self.name.starts_with(b"./") {
+            &self.name[2..]
+        } else {
+            self.name
+        }
+    }
+
     /// Gets the file contents as a slice.
     pub fn as_slice(&self) -> &'a [u8] {
         self.data
@@ -129,7 +145,13 @@ impl<'a> Iterator for TarIterator<'a> {
             return None;
         }
 
+        let name = match header.name.iter().position(|x| *x == 0) {
+            Some(i) => &header.name[..i],
+            None => &header.name[..],
+        };
+
         Some(TarFile {
+            name,
             data: unsafe { slice::from_raw_parts(data_ptr as *const u8, size) },
         })
     }
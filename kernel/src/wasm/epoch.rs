@@ -0,0 +1,138 @@
+//! Epoch-based interruption, a cheaper alternative to fuel metering.
+//! The timer interrupt bumps the global epoch. Code compiled with epoch interruption compares the
+//! epoch against the deadline in its VmContext at function entries and loop headers, and traps
+//! with `TRAP_EPOCH_DEADLINE` once the deadline is reached. That trap can be resumed, so the kernel
+//! can suspend or kill the thread at that safe point.
+//!
+//! Interruptible instances can have a time slice: their threads yield after using it up.
+//! Other threads of the process can kill a thread of an interruptible instance using the
+//! `thread_kill` call of the kwast host module. The kill request is kept in the thread, and the
+//! deadline trap handler checks it. The thread is only killed at a safe point, so it is never torn
+//! down in the middle of a host call.
+
+use crate::tasking::thread::Thread;
+use crate::wasm::instance::Instance;
+use crate::wasm::trap::{TrapAction, KILLED_EXIT_CODE};
+use crate::wasm::vmctx::VmContextContainer;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// Deadline that is never reached.
+pub const NO_DEADLINE: u64 = u64::MAX;
+
+/// The global epoch, bumped by the timer interrupt.
+#[no_mangle]
+pub static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Epoch interruption state of an instance.
+#[derive(Debug)]
+pub struct EpochInterruption {
+    /// Amount of epochs a thread can run before it yields, if any.
+    time_slice: Option<u64>,
+}
+
+impl EpochInterruption {
+    /// Creates the state for an instance.
+    pub fn new(time_slice: Option<u64>) -> Self {
+        Self { time_slice }
+    }
+}
+
+/// Gets the current epoch.
+pub fn current() -> u64 {
+    EPOCH.load(Ordering::Relaxed)
+}
+
+/// Calculates the deadline of an instance for a thread that starts its time slice now.
+pub fn next_deadline(interruption: &EpochInterruption) -> u64 {
+    interruption.time_slice.map_or(NO_DEADLINE, |time_slice| {
+        current().saturating_add(time_slice)
+    })
+}
+
+/// Handles a thread reaching the epoch deadline of an instance.
+pub fn deadline_reached(thread: &Thread, instance: &Instance) -> TrapAction {
+    let interruption = match instance.epoch {
+        Some(ref interruption) => interruption,
+        None => return TrapAction::Continue,
+    };
+
+    if thread.take_kill_request() {
+        TrapAction::Exit(KILLED_EXIT_CODE)
+    } else if interruption.time_slice.is_some() {
+        TrapAction::Yield
+    } else {
+        TrapAction::Continue
+    }
+}
+
+/// Renews the epoch deadline of an instance before a thread that reached it continues.
+/// The deadline is kept in the context the thread runs the instance with.
+pub fn renew_deadline(thread: &Thread, instance: &Instance, vmctx_container: &VmContextContainer) {
+    let interruption = match instance.epoch {
+        Some(ref interruption) => interruption,
+        None => return,
+    };
+
+    vmctx_container.set_epoch_deadline(next_deadline(interruption));
+
+    // Pairs with the fence in `kill`: either we see the request, or the killer resets the
+    // deadline after we renewed it.
+    fence(Ordering::SeqCst);
+    if thread.kill_requested() {
+        vmctx_container.set_epoch_deadline(0);
+    }
+}
+
+/// Kills a thread at its next safe point in interruptible wasm code.
+/// The deadline is reset in the context of the thread and in the contexts of the instances it
+/// imports from, so a thread running code of such an instance reaches a safe point there.
+/// Other threads running code of those instances trap once and continue.
+/// A thread inside a host call is killed when it returns to wasm code, so it is never torn down
+/// in the middle of a host call. A thread blocked in an interruptible wait is woken up, the host
+/// call then returns `Errno::Intr`. Waiting for scheme commands is not interruptible, a scheme
+/// server is killed once it receives a command.
+/// Returns false if the thread doesn't run interruptible wasm code.
+pub fn kill(thread: &Thread) -> bool {
+    thread
+        .try_with_wasm_data(|data| {
+            let instance = data.instance();
+            instance.epoch.as_ref()?;
+            thread.request_kill();
+
+            // Pairs with the fence in `renew_deadline`.
+            fence(Ordering::SeqCst);
+            data.vmctx_container().set_epoch_deadline(0);
+            for_each_dependency(instance, |dependency| {
+                if dependency.epoch.is_some() {
+                    dependency.vmctx_container.set_epoch_deadline(0);
+                }
+            });
+
+            // Pairs with the fence in `ThreadBlockGuard::activate_interruptible`.
+            fence(Ordering::SeqCst);
+            if thread.in_interruptible_block() {
+                thread.wakeup();
+            }
+
+            Some(())
+        })
+        .flatten()
+        .is_some()
+}
+
+/// Calls a function for every instance an instance imports from, directly or indirectly.
+fn for_each_dependency<F: FnMut(&Instance)>(instance: &Instance, mut f: F) {
+    let mut visited: Vec<&Instance> = Vec::new();
+    let mut stack: Vec<&Instance> = instance.dependencies.iter().map(|d| &**d).collect();
+    while let Some(dependency) = stack.pop() {
+        if visited.iter().any(|v| ptr::eq(*v, dependency)) {
+            continue;
+        }
+
+        f(dependency);
+        visited.push(dependency);
+        stack.extend(dependency.dependencies.iter().map(|d| &**d));
+    }
+}
@@ -0,0 +1,81 @@
+//! CPU budgets of instances, enforced using fuel metering.
+//! Code compiled with fuel metering consumes fuel from the VmContext at function entries
+//! and loop headers.
+//! When the fuel runs out, the code traps with `TRAP_OUT_OF_FUEL`. Unlike other traps, that trap
+//! can be resumed: the budget policy of the instance decides what happens with the thread.
+//! The fuel is refilled to the full budget whenever the thread may continue.
+
+use crate::wasm::instance::Instance;
+use crate::wasm::trap::TrapAction;
+use crate::wasm::vmctx::VmContextContainer;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// What happens when an instance has used up its budget.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FuelPolicy {
+    /// The thread yields, and continues with a new budget when it is scheduled again.
+    Yield,
+    /// The thread is killed.
+    Kill,
+    /// The thread continues with a new budget, and a "budget exhausted" event is delivered to
+    /// the instance. The instance can take the events using the kwast host module.
+    Event,
+}
+
+/// CPU budget of an instance.
+#[derive(Debug)]
+pub struct FuelBudget {
+    /// Amount of fuel the instance gets at once.
+    fuel: u64,
+    policy: FuelPolicy,
+    /// Amount of budget exhausted events that were not taken yet.
+    exhausted_events: AtomicU32,
+}
+
+impl FuelBudget {
+    /// Creates a budget.
+    pub fn new(fuel: u64, policy: FuelPolicy) -> Self {
+        Self {
+            fuel,
+            policy,
+            exhausted_events: AtomicU32::new(0),
+        }
+    }
+
+    /// Amount of fuel the instance gets at once.
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
+    /// Takes the budget exhausted events that were delivered since the last call.
+    /// Returns the amount of events.
+    pub fn take_exhausted_events(&self) -> u32 {
+        self.exhausted_events.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Handles an instance running out of fuel, applies its budget policy.
+/// Instances without a budget are compiled without fuel metering, so they should never get here.
+pub fn out_of_fuel(instance: &Instance) -> TrapAction {
+    let budget = match instance.fuel {
+        Some(ref budget) => budget,
+        None => return TrapAction::Trap,
+    };
+
+    match budget.policy {
+        FuelPolicy::Yield => TrapAction::Yield,
+        FuelPolicy::Kill => TrapAction::Trap,
+        FuelPolicy::Event => {
+            budget.exhausted_events.fetch_add(1, Ordering::Relaxed);
+            TrapAction::Continue
+        }
+    }
+}
+
+/// Refills the fuel of an instance before a thread that ran out of fuel continues.
+/// The fuel is kept in the context the thread runs the instance with.
+pub fn refill(instance: &Instance, vmctx_container: &VmContextContainer) {
+    if let Some(ref budget) = instance.fuel {
+        vmctx_container.set_fuel(budget.fuel);
+    }
+}
@@ -0,0 +1,32 @@
+//! Registry of host modules.
+//! A host module is a named set of kernel functions that wasm modules can import.
+
+use crate::arch::address::VirtAddr;
+use crate::wasm::{kwast, threads, wasi};
+use alloc::collections::BTreeMap;
+use cranelift_codegen::ir::Signature;
+use lazy_static::lazy_static;
+
+/// Functions of a host module: maps the field name to the address and the expected signature.
+pub type HostFunctionMap = BTreeMap<&'static str, (VirtAddr, Signature)>;
+
+lazy_static! {
+    static ref HOST_MODULES: BTreeMap<&'static str, &'static HostFunctionMap> = {
+        let mut map = BTreeMap::new();
+        map.insert("wasi_snapshot_preview1", wasi::host_functions());
+        map.insert("kwast", kwast::host_functions());
+        map.insert("wasi", threads::host_functions());
+        map
+    };
+}
+
+/// Gets the address of an imported function and validates its signature.
+pub fn resolve_function(module: &str, field: &str, sig: &Signature) -> Option<VirtAddr> {
+    let (addr, reference_sig) = HOST_MODULES.get(module)?.get(field)?;
+
+    if reference_sig != sig {
+        None
+    } else {
+        Some(*addr)
+    }
+}
@@ -0,0 +1,231 @@
+//! Instances of WebAssembly modules.
+//! Named instances are kept in a registry, so other instances in the same domain can import
+//! their functions, memories, tables and globals.
+
+use crate::arch::address::VirtAddr;
+use crate::arch::paging::ActiveMapping;
+use crate::mm::vma_allocator::{MappableVma, MappedVma};
+use crate::sync::spinlock::{RwLock, Spinlock};
+use crate::sync::wait_table::WaitTable;
+use crate::tasking::protection_domain::ProtectionDomain;
+use crate::wasm::epoch::EpochInterruption;
+use crate::wasm::fuel::FuelBudget;
+use crate::wasm::lazy::LazyCode;
+use crate::wasm::memory::Memory;
+use crate::wasm::passive_data::{PassiveData, PassiveElements};
+use crate::wasm::symbols::SymbolTable;
+use crate::wasm::trap::TrapTable;
+use crate::wasm::vmctx::VmContextContainer;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use wasm_compiler::module_env::{Export, GlobalDecl};
+
+/// An instantiated module.
+pub struct Instance {
+    pub code: MappedVma,
+    pub domain: ProtectionDomain,
+    pub vmctx_container: VmContextContainer,
+    /// Memories, imported memories come first.
+    pub memories: Box<[Arc<Memory>]>,
+    pub trap_table: RwLock<TrapTable>,
+    pub symbol_table: RwLock<SymbolTable>,
+    /// Lazy compilation state, only for lazily compiled instances.
+    pub lazy_code: Option<LazyCode>,
+    /// CPU budget, only for instances compiled with fuel metering.
+    pub fuel: Option<FuelBudget>,
+    /// Epoch interruption state, only for instances compiled with epoch interruption.
+    pub epoch: Option<EpochInterruption>,
+    pub passive_data: Spinlock<PassiveData>,
+    pub passive_elements: Spinlock<PassiveElements>,
+    pub exports: BTreeMap<Box<str>, Export>,
+    /// Globals, imported globals come first.
+    pub globals: Box<[GlobalDecl]>,
+    /// Instances we import from, they must live at least as long as we do.
+    pub dependencies: Vec<Arc<Instance>>,
+    /// Address of the start function, if any.
+    pub start: Option<VirtAddr>,
+}
+
+/// Entry in the instance registry.
+enum RegistryEntry {
+    /// Declared, but not instantiated yet.
+    Pending {
+        /// The named instance the instantiation waits for, if any.
+        waiting_for: Option<Box<str>>,
+    },
+    /// Successfully instantiated.
+    Ready(Arc<Instance>),
+    /// Instantiation failed.
+    Failed,
+}
+
+lazy_static! {
+    static ref INSTANCES: RwLock<BTreeMap<Box<str>, RegistryEntry>> = RwLock::new(BTreeMap::new());
+    /// Threads waiting for pending instances, all of them wait on address 0.
+    static ref WAITERS: WaitTable = WaitTable::new();
+}
+
+/// Why a named instance can't be looked up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LookupError {
+    /// The name is unknown.
+    Unknown,
+    /// The instantiation failed.
+    Failed,
+    /// The instance waits for the importer, directly or through other pending instances.
+    Cycle,
+}
+
+impl Instance {
+    /// Gets an export by name.
+    pub fn export(&self, name: &str) -> Option<Export> {
+        self.exports.get(name).copied()
+    }
+
+    /// Gets the offset of an address inside the code of this instance.
+    pub fn code_offset(&self, addr: VirtAddr) -> Option<usize> {
+        if self.code.is_contained(addr) {
+            Some(addr.as_usize() - self.code.address().as_usize())
+        } else {
+            None
+        }
+    }
+
+    /// Finds the instance that contains the code at an address, searches the dependencies too.
+    pub fn find_code(&self, addr: VirtAddr) -> Option<&Instance> {
+        if self.code.is_contained(addr) {
+            Some(self)
+        } else {
+            self.dependencies
+                .iter()
+                .find_map(|dependency| dependency.find_code(addr))
+        }
+    }
+
+    /// Try handle a page fault inside a memory used by this instance or its dependencies.
+    pub fn try_handle_page_fault(&self, mapping: &mut ActiveMapping, fault_addr: VirtAddr) -> bool {
+        self.memories
+            .iter()
+            .any(|memory| memory.try_handle_page_fault(mapping, fault_addr))
+            || self
+                .dependencies
+                .iter()
+                .any(|dependency| dependency.try_handle_page_fault(mapping, fault_addr))
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let code = &self.code;
+        self.domain.with(|vma, mapping| {
+            vma.destroy_vma(mapping, code);
+        });
+    }
+}
+
+/// Declares a named instance that will be registered later.
+/// Returns false if the name is already in use.
+pub fn declare(name: &str) -> bool {
+    let mut instances = INSTANCES.write();
+    if instances.contains_key(name) {
+        false
+    } else {
+        instances.insert(
+            Box::from(name),
+            RegistryEntry::Pending { waiting_for: None },
+        );
+        true
+    }
+}
+
+/// Registers a declared instance.
+pub fn register(name: &str, instance: Arc<Instance>) {
+    INSTANCES
+        .write()
+        .insert(Box::from(name), RegistryEntry::Ready(instance));
+    WAITERS.notify(0, u32::MAX);
+}
+
+/// Marks a declared instance as failed, so importers stop waiting for it.
+pub fn fail(name: &str) {
+    INSTANCES
+        .write()
+        .insert(Box::from(name), RegistryEntry::Failed);
+    WAITERS.notify(0, u32::MAX);
+}
+
+/// Looks up a named instance, waits if it is declared but not instantiated yet.
+/// `importer` is the name of the pending instance that looks it up, if it has one. It is used to
+/// detect import cycles, which would otherwise wait forever.
+pub fn lookup(name: &str, importer: Option<&str>) -> Result<Arc<Instance>, LookupError> {
+    let result = loop {
+        {
+            let mut instances = INSTANCES.write();
+            match instances.get(name) {
+                None => break Err(LookupError::Unknown),
+                Some(RegistryEntry::Ready(instance)) => break Ok(instance.clone()),
+                Some(RegistryEntry::Failed) => break Err(LookupError::Failed),
+                Some(RegistryEntry::Pending { .. }) => {}
+            }
+
+            if let Some(importer) = importer {
+                if waits_for(&instances, name, importer) {
+                    break Err(LookupError::Cycle);
+                }
+                set_waiting_for(&mut instances, importer, Some(name));
+            }
+        }
+
+        WAITERS.wait(
+            0,
+            || {
+                matches!(
+                    INSTANCES.read().get(name),
+                    Some(RegistryEntry::Pending { .. })
+                )
+            },
+            None,
+        );
+    };
+
+    if let Some(importer) = importer {
+        set_waiting_for(&mut INSTANCES.write(), importer, None);
+    }
+
+    result
+}
+
+/// Checks if the instance `name` is `target`, or waits for `target` through pending instances.
+fn waits_for(instances: &BTreeMap<Box<str>, RegistryEntry>, name: &str, target: &str) -> bool {
+    let mut current = name;
+    // Every pending instance waits for at most one other, so this is a chain. Cycles are never
+    // entered, but bound the walk anyway.
+    for _ in 0..=instances.len() {
+        if current == target {
+            return true;
+        }
+
+        match instances.get(current) {
+            Some(RegistryEntry::Pending {
+                waiting_for: Some(next),
+            }) => current = next,
+            _ => return false,
+        }
+    }
+
+    false
+}
+
+/// Sets the instance a pending instance waits for.
+fn set_waiting_for(
+    instances: &mut BTreeMap<Box<str>, RegistryEntry>,
+    name: &str,
+    dependency: Option<&str>,
+) {
+    if let Some(RegistryEntry::Pending { waiting_for }) = instances.get_mut(name) {
+        *waiting_for = dependency.map(Box::from);
+    }
+}
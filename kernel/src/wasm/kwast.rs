@@ -0,0 +1,191 @@
+//! Kwast host module, native kernel calls that are not part of WASI.
+
+use crate::arch::address::VirtAddr;
+use crate::mm::tcb_alloc::try_with_thread;
+use crate::tasking::file::FileHandle;
+use crate::tasking::process;
+use crate::tasking::thread::ThreadId;
+use crate::wasm::epoch;
+use crate::wasm::host_modules::HostFunctionMap;
+use crate::wasm::programs;
+use crate::wasm::vmctx::VmContext;
+use crate::wasm::wasi::{Errno, Fd, Size, WasmPtr, WasmStatus};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::convert::TryInto;
+use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, Signature};
+use lazy_static::lazy_static;
+use wasm_compiler::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
+
+abi_functions! {
+    scheme_receive_commands: (fd: Fd, buf: WasmPtr<u8>, buf_len: Size, nread: WasmPtr<Size>) -> Errno,
+    scheme_send_replies: (fd: Fd, buf: WasmPtr<u8>, buf_len: Size, nwritten: WasmPtr<Size>) -> Errno,
+    scheme_grant_read: (fd: Fd, grant: u64, offset: u64, buf: WasmPtr<u8>, buf_len: Size, nread: WasmPtr<Size>) -> Errno,
+    scheme_grant_write: (fd: Fd, grant: u64, offset: u64, buf: WasmPtr<u8>, buf_len: Size, nwritten: WasmPtr<Size>) -> Errno,
+    budget_exhausted_events: (events: WasmPtr<u32>) -> Errno,
+    process_spawn: (file: WasmPtr<u8>, file_len: Size, handle: WasmPtr<u32>) -> Errno,
+    process_wait: (handle: u32, exit_code: WasmPtr<u32>) -> Errno,
+    thread_kill: (tid: u32) -> Errno,
+}
+
+impl AbiFunctions for VmContext {
+    fn scheme_receive_commands(
+        &self,
+        fd: Fd,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        nread: WasmPtr<Size>,
+    ) -> WasmStatus {
+        self.with_fd_handle(fd, |scheme, handle| {
+            // Only the owner of a scheme can receive its commands.
+            if let FileHandle::Inner(_) = handle {
+                return Err(Errno::BadF);
+            }
+
+            let buf = buf.buffer_mut(self, buf_len)?;
+            let read = scheme.receive_commands_blocking(buf)?;
+            nread.cell(self)?.set(read.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn scheme_send_replies(
+        &self,
+        fd: Fd,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        nwritten: WasmPtr<Size>,
+    ) -> WasmStatus {
+        self.with_fd_handle(fd, |scheme, handle| {
+            // Only the owner of a scheme can reply to its commands.
+            if let FileHandle::Inner(_) = handle {
+                return Err(Errno::BadF);
+            }
+
+            let buf = buf.buffer(self, buf_len)?;
+            let written = scheme.send_replies(buf)?;
+            nwritten
+                .cell(self)?
+                .set(written.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn scheme_grant_read(
+        &self,
+        fd: Fd,
+        grant: u64,
+        offset: u64,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        nread: WasmPtr<Size>,
+    ) -> WasmStatus {
+        self.with_fd_handle(fd, |scheme, handle| {
+            // Only the owner of a scheme can access the grants of its clients.
+            if let FileHandle::Inner(_) = handle {
+                return Err(Errno::BadF);
+            }
+
+            let offset = offset.try_into().map_err(|_| Errno::Inval)?;
+            let buf = buf.buffer_mut(self, buf_len)?;
+            let read = scheme.grant_copy_from(grant, offset, buf)?;
+            nread.cell(self)?.set(read.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn scheme_grant_write(
+        &self,
+        fd: Fd,
+        grant: u64,
+        offset: u64,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        nwritten: WasmPtr<Size>,
+    ) -> WasmStatus {
+        self.with_fd_handle(fd, |scheme, handle| {
+            // Only the owner of a scheme can access the grants of its clients.
+            if let FileHandle::Inner(_) = handle {
+                return Err(Errno::BadF);
+            }
+
+            let offset = offset.try_into().map_err(|_| Errno::Inval)?;
+            let buf = buf.buffer(self, buf_len)?;
+            let written = scheme.grant_copy_to(grant, offset, buf)?;
+            nwritten
+                .cell(self)?
+                .set(written.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn budget_exhausted_events(&self, events: WasmPtr<u32>) -> WasmStatus {
+        // Instances without a budget never get events.
+        let count = self
+            .instance()
+            .fuel
+            .as_ref()
+            .map_or(0, |budget| budget.take_exhausted_events());
+        events.cell(self)?.set(count);
+
+        Ok(())
+    }
+
+    fn process_spawn(&self, file: WasmPtr<u8>, file_len: Size, handle: WasmPtr<u32>) -> WasmStatus {
+        let process = process::current().ok_or(Errno::Fault)?;
+        let file = file.str(self, file_len)?;
+        // Check this before spawning, so the child can't be lost.
+        let handle = handle.cell(self)?;
+
+        let exit_status = match programs::spawn(file) {
+            Some(Ok(exit_status)) => exit_status,
+            Some(Err(e)) => {
+                println!("Could not spawn {}: {:?}", file, e);
+                return Err(Errno::NoExec);
+            }
+            None => return Err(Errno::NoEnt),
+        };
+        handle.set(process.add_child(exit_status).ok_or(Errno::MFile)?);
+
+        Ok(())
+    }
+
+    fn process_wait(&self, handle: u32, exit_code: WasmPtr<u32>) -> WasmStatus {
+        let process = process::current().ok_or(Errno::Fault)?;
+        let exit_code = exit_code.cell(self)?;
+        let exit_status = process.child(handle).ok_or(Errno::Child)?;
+        // The child is kept if the wait is interrupted, so another thread can still wait for it.
+        exit_code.set(exit_status.wait().ok_or(Errno::Intr)?);
+        process.take_child(handle);
+
+        Ok(())
+    }
+
+    fn thread_kill(&self, tid: u32) -> WasmStatus {
+        let process = process::current().ok_or(Errno::Fault)?;
+
+        try_with_thread(ThreadId::from_u32(tid), |thread| {
+            // Only threads of the same process can be killed.
+            match thread.process() {
+                Some(ref target) if Arc::ptr_eq(target, &process) => {}
+                _ => return Err(Errno::Srch),
+            }
+
+            if epoch::kill(thread) {
+                Ok(())
+            } else {
+                Err(Errno::NotSup)
+            }
+        })
+        .unwrap_or(Err(Errno::Srch))
+    }
+}
+
+/// Gets the functions of this host module.
+pub fn host_functions() -> &'static HostFunctionMap {
+    &ABI_MAP
+}
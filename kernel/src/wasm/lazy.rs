@@ -0,0 +1,163 @@
+//! Lazy compilation of the defined functions of an instance, see `wasm_compiler::lazy`.
+//! Lazily compiled functions are placed after the stubs in the code area of the instance,
+//! the rest of the code area is reserved for them. Every function gets its own pages,
+//! so code that is already executable never has to be made writable again.
+//! Functions are compiled without holding a lock, because compiling can take long. Threads of
+//! the instance may compile the same function at the same time, only the first one installs it.
+
+use crate::arch::address::{align_up, VirtAddr};
+use crate::arch::paging::EntryFlags;
+use crate::mm::mapper::{MemoryError, MemoryMapper};
+use crate::mm::vma_allocator::MappableVma;
+use crate::sync::spinlock::Spinlock;
+use crate::wasm::instance::Instance;
+use crate::wasm::main::{relocate, Error};
+use crate::wasm::symbols::FunctionSymbol;
+use crate::wasm::vmctx::VmContext;
+use alloc::boxed::Box;
+use core::ptr::copy_nonoverlapping;
+use cranelift_wasm::FuncIndex;
+use wasm_compiler::lazy::LazyModule;
+use wasm_compiler::trap::TrapSite;
+use wasm_compiler::{target_isa, CompiledFunction};
+
+/// Size of the code area reserved for lazily compiled functions.
+pub const LAZY_CODE_RESERVE: usize = 256 * 1024 * 1024;
+
+/// Lazy compilation state of an instance.
+pub struct LazyCode {
+    module: LazyModule<'static>,
+    /// Stubs of the defined functions, in order of their index.
+    stubs: Box<[CompiledFunction]>,
+    defined_function_offset: usize,
+    /// Locked while a compiled function is installed.
+    placement: Spinlock<Placement>,
+}
+
+/// Where the compiled functions are in the code area.
+struct Placement {
+    /// Addresses of the defined functions that are compiled already.
+    compiled: Box<[Option<VirtAddr>]>,
+    /// Offset in the code area where the next function goes.
+    next_offset: usize,
+}
+
+impl LazyCode {
+    /// Creates the lazy compilation state, the stubs are at the start of the code area.
+    pub fn new(
+        module: LazyModule<'static>,
+        stubs: Box<[CompiledFunction]>,
+        defined_function_offset: usize,
+        stubs_size: usize,
+    ) -> Self {
+        Self {
+            module,
+            placement: Spinlock::new(Placement {
+                compiled: vec![None; stubs.len()].into_boxed_slice(),
+                next_offset: align_up(stubs_size),
+            }),
+            stubs,
+            defined_function_offset,
+        }
+    }
+
+    /// Gets the address of the stub of a defined function.
+    fn stub_address(&self, instance: &Instance, func_idx: FuncIndex) -> VirtAddr {
+        let stub = self.stubs[func_idx.as_u32() as usize - self.defined_function_offset];
+        instance.code.address() + stub.offset as usize
+    }
+
+    /// Compiles a defined function of the instance, if it isn't compiled yet.
+    /// The slot is set in the context of the caller too, threads can have their own context.
+    /// Returns the address of the compiled code.
+    pub fn compile(
+        &self,
+        instance: &Instance,
+        vmctx: &VmContext,
+        func_idx: FuncIndex,
+    ) -> Result<VirtAddr, Error> {
+        let defined_idx = func_idx.as_u32() as usize - self.defined_function_offset;
+        if let Some(address) = self.placement.lock().compiled[defined_idx] {
+            vmctx.set_lazy_slot(defined_idx as u32, address);
+            return Ok(address);
+        }
+
+        let isa = target_isa();
+        let compiled_code = self
+            .module
+            .compile_function(&*isa, func_idx)
+            .map_err(Error::CompileError)?;
+
+        // Another thread may have installed the function while we were compiling.
+        let mut placement = self.placement.lock();
+        if let Some(address) = placement.compiled[defined_idx] {
+            vmctx.set_lazy_slot(defined_idx as u32, address);
+            return Ok(address);
+        }
+
+        let offset = placement.next_offset;
+        let len = align_up(compiled_code.code.len());
+        if offset + len > instance.code.size() {
+            return Err(Error::MemoryError(MemoryError::NoMoreVMA));
+        }
+
+        // Map the pages writable first, they are made read-only & executable after linking.
+        let address = instance.code.address() + offset;
+        instance.domain.with(|_vma, mapping| {
+            let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NX;
+            mapping
+                .map_range(address, len, flags)
+                .map_err(Error::MemoryError)
+        })?;
+        placement.next_offset += len;
+
+        // Safety: the pages are mapped and large enough.
+        unsafe {
+            copy_nonoverlapping(
+                compiled_code.code.as_ptr(),
+                address.as_mut::<u8>(),
+                compiled_code.code.len(),
+            );
+        }
+
+        relocate(address, &compiled_code.relocations, |target_idx| {
+            self.stub_address(instance, target_idx)
+        });
+
+        instance.domain.with(|_vma, mapping| {
+            mapping
+                .change_flags_range(address, len, EntryFlags::PRESENT)
+                .map_err(Error::MemoryError)
+        })?;
+
+        // Make the trap sites and the symbol relative to the code area.
+        instance
+            .trap_table
+            .write()
+            .extend(compiled_code.trap_sites.iter().map(|site| TrapSite {
+                code_offset: site.code_offset + offset as u32,
+                trap_code: site.trap_code,
+            }));
+        {
+            let mut symbol_table = instance.symbol_table.write();
+            let stub_offset = self.stubs[defined_idx].offset as usize;
+            let name = symbol_table
+                .lookup(stub_offset)
+                .and_then(|symbol| symbol.name.clone());
+            symbol_table.insert(FunctionSymbol {
+                start: offset,
+                end: offset + compiled_code.code.len(),
+                index: func_idx,
+                name,
+            });
+        }
+
+        placement.compiled[defined_idx] = Some(address);
+        instance
+            .vmctx_container
+            .set_lazy_slot(defined_idx as u32, address);
+        vmctx.set_lazy_slot(defined_idx as u32, address);
+
+        Ok(address)
+    }
+}
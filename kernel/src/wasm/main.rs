@@ -1,369 +1,674 @@
 //! Based on https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src
 
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ptr::{copy_nonoverlapping, write_unaligned};
-use cranelift_codegen::binemit::{NullStackMapSink, NullTrapSink, Reloc};
-use cranelift_codegen::ir::{types, LibCall, Signature, Type};
-use cranelift_codegen::isa::{CallConv, TargetIsa};
-use cranelift_codegen::settings::{self, Configurable};
-use cranelift_codegen::{CodegenError, Context};
-use cranelift_wasm::{translate_module, Global, Memory, SignatureIndex};
-use cranelift_wasm::{FuncIndex, FuncTranslator, WasmError};
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::ir::LibCall;
+use cranelift_wasm::{FuncIndex, GlobalIndex, Memory};
 
 use crate::arch::address::{align_up, VirtAddr};
 use crate::arch::paging::EntryFlags;
 use crate::mm::mapper::MemoryError;
 use crate::mm::mapper::MemoryMapper;
-use crate::mm::vma_allocator::{LazilyMappedVma, MappableVma, MappedVma};
+use crate::mm::vma_allocator::{MappableVma, MappedVma};
+use crate::sync::spinlock::{RwLock, Spinlock};
+use crate::tasking::file::FileDescriptorTable;
+use crate::tasking::process::{ExitStatus, Process};
 use crate::tasking::protection_domain::ProtectionDomain;
 use crate::tasking::scheduler::{add_and_schedule_thread, thread_exit, with_current_thread};
-use crate::tasking::thread::Thread;
-use crate::wasm::func_env::FuncEnv;
-use crate::wasm::module_env::{
-    DataInitializer, Export, FunctionBody, FunctionImport, ModuleEnv, TableElements,
-};
-use crate::wasm::reloc_sink::{RelocSink, RelocationTarget};
+use crate::tasking::scheme::OpenMode;
+use crate::tasking::scheme_container::{self, schemes};
+use crate::tasking::thread::{StaticWasmThreadData, Thread};
+use crate::util::manifest::{CompileMode, ManifestEntry};
+use crate::util::string_list::StringList;
+use crate::wasm::epoch::{self, EpochInterruption};
+use crate::wasm::fuel::FuelBudget;
+use crate::wasm::host_modules;
+use crate::wasm::instance::{self, Instance, LookupError};
+use crate::wasm::lazy::{LazyCode, LAZY_CODE_RESERVE};
+use crate::wasm::memory::{Memory as LinearMemory, MemoryAccount};
+use crate::wasm::parallel;
+use crate::wasm::passive_data::{PassiveData, PassiveElements};
 use crate::wasm::runtime::{
-    runtime_memory_grow, runtime_memory_size, RUNTIME_MEMORY_GROW_IDX, RUNTIME_MEMORY_SIZE_IDX,
+    runtime_data_drop, runtime_elem_drop, runtime_memory_atomic_notify,
+    runtime_memory_atomic_wait32, runtime_memory_atomic_wait64, runtime_memory_copy,
+    runtime_memory_fill, runtime_memory_grow, runtime_memory_init, runtime_memory_size,
+    runtime_ref_func, runtime_table_copy, runtime_table_fill, runtime_table_get,
+    runtime_table_grow, runtime_table_init, runtime_table_set,
 };
-use crate::wasm::table::Table;
+use crate::wasm::signatures;
+use crate::wasm::symbols::{FunctionSymbol, SymbolTable};
+use crate::wasm::table::{FunctionReferences, SharedTable, Table};
+use crate::wasm::trap::{TrapTable, INSTANTIATION_ERROR_EXIT_CODE};
 use crate::wasm::vmctx::{
-    VmContext, VmContextContainer, VmFunctionImportEntry, VmTableElement, HEAP_GUARD_SIZE,
-    HEAP_SIZE, WASM_PAGE_SIZE,
+    memory_style, VmContext, VmContextContainer, VmFunctionImportEntry, VmTableElement,
+    HEAP_GUARD_SIZE, WASM_PAGE_SIZE,
 };
-use crate::wasm::wasi::get_address_for_wasi_and_validate_sig;
+use alloc::collections::BTreeMap;
+use core::cmp::min;
 use core::mem;
-
-pub const WASM_VMCTX_TYPE: Type = types::I64;
-pub const WASM_CALL_CONV: CallConv = CallConv::SystemV;
+use wasm_compiler::artifact;
+use wasm_compiler::lazy::{compile_lazily, LazyModule, LAZY_STUB_COMPILE_OFFSET};
+use wasm_compiler::module_env::{Export, Import};
+use wasm_compiler::reloc_sink::{Relocation, RelocationTarget};
+use wasm_compiler::runtime::{
+    RUNTIME_DATA_DROP_IDX, RUNTIME_ELEM_DROP_IDX, RUNTIME_LAZY_COMPILE_IDX,
+    RUNTIME_MEMORY_ATOMIC_NOTIFY_IDX, RUNTIME_MEMORY_ATOMIC_WAIT32_IDX,
+    RUNTIME_MEMORY_ATOMIC_WAIT64_IDX, RUNTIME_MEMORY_COPY_IDX, RUNTIME_MEMORY_FILL_IDX,
+    RUNTIME_MEMORY_GROW_IDX, RUNTIME_MEMORY_INIT_IDX, RUNTIME_MEMORY_SIZE_IDX,
+    RUNTIME_REF_FUNC_IDX, RUNTIME_TABLE_COPY_IDX, RUNTIME_TABLE_FILL_IDX, RUNTIME_TABLE_GET_IDX,
+    RUNTIME_TABLE_GROW_IDX, RUNTIME_TABLE_INIT_IDX, RUNTIME_TABLE_SET_IDX,
+};
+use wasm_compiler::{target_isa, CompileOptions, CompiledModule};
 
 extern "C" {
     pub fn __rust_probestack();
+    fn lazy_compile_trampoline();
 }
 
 static PROBESTACK: unsafe extern "C" fn() = __rust_probestack;
 
 #[derive(Debug)]
 pub enum Error {
-    /// WebAssembly translation error.
-    WasmError(WasmError),
-    /// Code generation error.
-    CodegenError(CodegenError),
+    /// Compilation error.
+    CompileError(wasm_compiler::Error),
     /// Memory error.
     MemoryError(MemoryError),
     /// No start specified.
     NoStart,
-    /// Missing import
-    MissingImport,
+    /// Missing import, or an import with the wrong type.
+    MissingImport { module: String, field: String },
+    /// A data or element segment does not fit in its memory or table.
+    SegmentOutOfBounds,
+    /// The instance name is already in use.
+    NameInUse,
+    /// The instance imports from a named instance that waits for it.
+    ImportCycle(String),
+    /// The memory limit of the process would be exceeded.
+    MemoryLimitExceeded,
+    /// A global has an initializer that is not supported.
+    UnsupportedGlobal,
 }
 
-struct CompileResult<'data> {
-    isa: Box<dyn TargetIsa>,
-    contexts: Box<[Context]>,
-    start_func: Option<FuncIndex>,
-    func_sigs: Box<[SignatureIndex]>,
-    signatures: Box<[Signature]>,
-    memories: Box<[Memory]>,
-    data_initializers: Box<[DataInitializer<'data>]>,
-    function_imports: Box<[FunctionImport]>,
-    tables: Box<[cranelift_wasm::Table]>,
-    table_elements: Box<[TableElements]>,
-    globals: Box<[Global]>,
-    total_size: usize,
+/// Data passed to the thread that starts the wasm application.
+struct StartData {
+    compiled_module: CompiledModule<'static>,
+    lazy_module: Option<LazyModule<'static>>,
+    fuel: Option<FuelBudget>,
+    epoch: Option<EpochInterruption>,
+    memory_account: Arc<MemoryAccount>,
+    name: Option<Box<str>>,
+    preopens: Box<[Box<[u8]>]>,
+    args: StringList,
+    env: StringList,
+    exit_status: Arc<ExitStatus>,
 }
 
 struct Instantiation<'r, 'data> {
-    compile_result: &'r CompileResult<'data>,
-    func_offsets: Vec<usize>,
+    compiled_module: &'r CompiledModule<'data>,
+    /// Global signature indices of the signatures of the module, see the signature registry.
+    sig_ids: Vec<u32>,
+    /// Compiles the defined functions on demand if the module is lazily compiled.
+    lazy_module: Option<LazyModule<'static>>,
+    /// CPU budget if the module is compiled with fuel metering.
+    fuel: Option<FuelBudget>,
+    /// Epoch interruption state if the module is compiled with epoch interruption.
+    epoch: Option<EpochInterruption>,
+    /// The defined memories are charged to this account.
+    memory_account: Arc<MemoryAccount>,
+    /// Name of the instance in the registry, if it has one.
+    name: Option<&'r str>,
 }
 
-struct WasmInstance {
-    code_vma: MappedVma,
-    heap_vma: LazilyMappedVma,
-    vmctx_container: VmContextContainer,
-    start_address: VirtAddr,
+/// Resolved imports, in the order of their index spaces.
+struct Imports<'r> {
+    /// Name of the importing instance, if it has one.
+    importer: Option<&'r str>,
+    /// Address and context of the imported functions, host functions don't have a context.
+    functions: Vec<(VirtAddr, Option<VirtAddr>)>,
+    memories: Vec<Arc<LinearMemory>>,
+    tables: Vec<SharedTable>,
+    /// Pointers to the storage of the imported globals.
+    globals: Vec<VirtAddr>,
+    /// Instances we import from.
+    dependencies: Vec<Arc<Instance>>,
 }
 
-impl<'data> CompileResult<'data> {
-    /// Compile result to instantiation.
-    pub fn instantiate(&self) -> Instantiation {
-        Instantiation::new(self)
+impl Imports<'_> {
+    /// Finds the export of a named instance in the same domain.
+    /// The instance becomes a dependency.
+    fn find_export(
+        &mut self,
+        import: &Import,
+        domain: &ProtectionDomain,
+    ) -> Result<(Arc<Instance>, Export), Error> {
+        let instance = match instance::lookup(&import.module, self.importer) {
+            Ok(instance) if instance.domain.is_same(domain) => instance,
+            Err(LookupError::Cycle) => return Err(Error::ImportCycle(import.module.clone())),
+            _ => return Err(missing_import(import)),
+        };
+        let export = instance
+            .export(&import.field)
+            .ok_or_else(|| missing_import(import))?;
+
+        if !self
+            .dependencies
+            .iter()
+            .any(|dependency| Arc::ptr_eq(dependency, &instance))
+        {
+            self.dependencies.push(instance.clone());
+        }
+
+        Ok((instance, export))
     }
+}
 
-    /// Gets the signature of a function.
-    pub fn get_sig(&self, func_idx: FuncIndex) -> &Signature {
-        let sig_idx = self.func_sigs[func_idx.as_u32() as usize];
-        &self.signatures[sig_idx.as_u32() as usize]
+/// Creates the error for an import that can't be resolved.
+fn missing_import(import: &Import) -> Error {
+    Error::MissingImport {
+        module: import.module.clone(),
+        field: import.field.clone(),
     }
 }
 
 impl<'r, 'data> Instantiation<'r, 'data> {
     /// Creates a new instantiation.
-    fn new(compile_result: &'r CompileResult<'data>) -> Self {
-        let capacity = compile_result.contexts.len();
-
+    fn new(
+        compiled_module: &'r CompiledModule<'data>,
+        lazy_module: Option<LazyModule<'static>>,
+        fuel: Option<FuelBudget>,
+        epoch: Option<EpochInterruption>,
+        memory_account: Arc<MemoryAccount>,
+        name: Option<&'r str>,
+    ) -> Self {
         Self {
-            compile_result,
-            func_offsets: Vec::with_capacity(capacity),
+            compiled_module,
+            sig_ids: compiled_module
+                .signatures
+                .iter()
+                .map(signatures::register)
+                .collect(),
+            lazy_module,
+            fuel,
+            epoch,
+            memory_account,
+            name,
         }
     }
 
     /// Gets the offset of the defined functions in the function array.
     fn defined_function_offset(&self) -> usize {
-        self.compile_result.function_imports.len()
+        self.compiled_module.defined_function_offset()
+    }
+
+    /// Gets the global signature index of a function.
+    fn get_sig_id(&self, func_idx: FuncIndex) -> u32 {
+        let sig_idx = self.compiled_module.func_sigs[func_idx.as_u32() as usize];
+        self.sig_ids[sig_idx.as_u32() as usize]
     }
 
     // Helper to get  the function address from a function index.
     fn get_func_address(&self, code_vma: &MappedVma, index: FuncIndex) -> VirtAddr {
-        let offset = self.func_offsets[index.as_u32() as usize - self.defined_function_offset()];
+        let offset = self.compiled_module.get_function(index).offset as usize;
         VirtAddr::new(code_vma.address().as_usize() + offset)
     }
 
     /// Emit code.
-    fn emit(&mut self) -> Result<(MappedVma, LazilyMappedVma, Vec<RelocSink>), Error> {
-        let (code_vma, heap_vma) = with_current_thread(|thread| {
+    /// For lazily compiled modules, the code area also reserves space for the compiled functions.
+    fn emit(&self) -> Result<MappedVma, Error> {
+        // Create code area, will be made executable read-only later.
+        let code = &self.compiled_module.code;
+        let code_vma = with_current_thread(|thread| {
             thread.domain().with(|vma, mapping| {
-                // Create code area, will be made executable read-only later.
-                let code_vma = {
-                    let len = align_up(self.compile_result.total_size);
-                    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NX;
-
-                    vma.create_vma(len)
-                        .and_then(|v| v.map(mapping, 0, len, flags))
-                        .map_err(Error::MemoryError)?
+                let len = align_up(code.len());
+                let reserve = if self.lazy_module.is_some() {
+                    LAZY_CODE_RESERVE
+                } else {
+                    0
                 };
+                let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NX;
 
-                let heap_vma = {
-                    let mem = self.compile_result.memories.get(0).unwrap_or(&Memory {
-                        minimum: 0,
-                        maximum: None,
-                        shared: false,
-                    });
-                    let minimum = mem.minimum as usize * WASM_PAGE_SIZE;
-
-                    // Note: func_env assumes 4GiB is available, also makes it so that we can't construct
-                    //       a pointer outside (See issue #10 also)
-                    let maximum = HEAP_SIZE;
+                vma.create_vma(len + reserve)
+                    .and_then(|v| v.map(mapping, 0, len, flags))
+                    .map_err(Error::MemoryError)
+            })
+        })?;
 
-                    if minimum as u64 > HEAP_SIZE || maximum > HEAP_SIZE {
-                        return Err(Error::MemoryError(MemoryError::InvalidRange));
-                    }
+        // Safety: the code area is mapped and large enough.
+        unsafe {
+            copy_nonoverlapping(code.as_ptr(), code_vma.address().as_mut::<u8>(), code.len());
+        }
 
-                    let len = maximum + HEAP_GUARD_SIZE;
-                    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NX;
-                    vma.create_vma(len as usize)
-                        .and_then(|v| v.map_lazily(mapping, minimum, flags))
-                        .map_err(Error::MemoryError)?
-                };
+        Ok(code_vma)
+    }
 
-                Ok((code_vma, heap_vma))
+    /// Creates the symbol table for the defined functions.
+    fn symbol_table(&self) -> SymbolTable {
+        let symbols = self
+            .compiled_module
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(idx, function)| {
+                let index = FuncIndex::from_u32((idx + self.defined_function_offset()) as u32);
+                FunctionSymbol {
+                    start: function.offset as usize,
+                    end: (function.offset + function.size) as usize,
+                    index,
+                    name: self
+                        .compiled_module
+                        .func_names
+                        .get(&index)
+                        .map(|name| Box::from(*name)),
+                }
             })
-        })?;
+            .collect();
 
-        // Emit code
-        let capacity = self.compile_result.contexts.len();
-        let mut reloc_sinks: Vec<RelocSink> = Vec::with_capacity(capacity);
-        let mut offset: usize = 0;
-
-        for context in self.compile_result.contexts.iter() {
-            let mut reloc_sink = RelocSink::new();
-            let mut trap_sink = NullTrapSink {};
-            let mut null_stackmap_sink = NullStackMapSink {};
-
-            let info = unsafe {
-                let ptr = (code_vma.address() + offset).as_mut();
-
-                context.emit_to_memory(
-                    &*self.compile_result.isa,
-                    ptr,
-                    &mut reloc_sink,
-                    &mut trap_sink,
-                    &mut null_stackmap_sink,
-                )
+        SymbolTable::new(symbols)
+    }
+
+    /// Creates a defined memory of the module, charged to the memory account.
+    fn create_memory(
+        &self,
+        memory: &Memory,
+        domain: &ProtectionDomain,
+    ) -> Result<Arc<LinearMemory>, Error> {
+        let minimum = memory.minimum as usize * WASM_PAGE_SIZE;
+
+        // Note: with the static style, func_env assumes 4GiB is available, also makes it so
+        //       that we can't construct a pointer outside (See issue #10 also)
+        let reservation = memory_style(memory).reservation_size(memory);
+        let maximum = memory.maximum.map_or(reservation, |maximum| {
+            min(maximum as u64 * WASM_PAGE_SIZE as u64, reservation)
+        }) as usize;
+
+        if minimum > maximum {
+            return Err(Error::MemoryError(MemoryError::InvalidRange));
+        }
+
+        if !self.memory_account.charge(minimum) {
+            return Err(Error::MemoryLimitExceeded);
+        }
+
+        let heap_vma = domain.with(|vma, mapping| {
+            let len = reservation + HEAP_GUARD_SIZE;
+            let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NX;
+            vma.create_vma(len as usize)
+                .and_then(|v| v.map_lazily(mapping, minimum, flags))
+                .map_err(Error::MemoryError)
+        });
+
+        match heap_vma {
+            Ok(heap_vma) => Ok(Arc::new(LinearMemory::new(
+                domain.clone(),
+                heap_vma,
+                maximum,
+                memory.shared,
+                self.memory_account.clone(),
+            ))),
+            Err(e) => {
+                self.memory_account.release(minimum);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolves the imports, from host modules or from named instances.
+    /// This waits for named instances that are not instantiated yet.
+    fn resolve_imports(&self, domain: &ProtectionDomain) -> Result<Imports<'r>, Error> {
+        let compiled_module = self.compiled_module;
+        let mut imports = Imports {
+            importer: self.name,
+            functions: Vec::with_capacity(compiled_module.function_imports.len()),
+            memories: Vec::with_capacity(compiled_module.memory_imports.len()),
+            tables: Vec::with_capacity(compiled_module.table_imports.len()),
+            globals: Vec::with_capacity(compiled_module.global_imports.len()),
+            dependencies: Vec::new(),
+        };
+
+        for (i, import) in compiled_module.function_imports.iter().enumerate() {
+            let func_idx = FuncIndex::from_u32(i as u32);
+            let sig = compiled_module.get_sig(func_idx);
+
+            if let Some(address) =
+                host_modules::resolve_function(&import.module, &import.field, sig)
+            {
+                imports.functions.push((address, None));
+                continue;
+            }
+
+            let element = match imports.find_export(import, domain)? {
+                (instance, Export::Function(idx)) => {
+                    instance.vmctx_container.function_references().get(idx)
+                }
+                _ => return Err(missing_import(import)),
             };
 
-            self.func_offsets.push(offset);
-            reloc_sinks.push(reloc_sink);
+            if element.sig_idx != self.get_sig_id(func_idx) as u64 {
+                return Err(missing_import(import));
+            }
 
-            offset += info.total_size as usize;
+            imports
+                .functions
+                .push((element.address, Some(element.vmctx)));
         }
 
-        //println!("{:x}", offset);
+        for (i, import) in compiled_module.memory_imports.iter().enumerate() {
+            let memory = match imports.find_export(import, domain)? {
+                (instance, Export::Memory(idx)) => instance.memories[idx.as_u32() as usize].clone(),
+                _ => return Err(missing_import(import)),
+            };
 
-        Ok((code_vma, heap_vma, reloc_sinks))
-    }
+            let declared = &compiled_module.memories[i];
+            if memory.pages() < declared.minimum
+                || declared
+                    .maximum
+                    .map_or(false, |maximum| memory.maximum_pages() > maximum)
+                || memory.is_shared() != declared.shared
+            {
+                return Err(missing_import(import));
+            }
 
-    /// Emit and link.
-    pub fn emit_and_link(mut self) -> Result<WasmInstance, Error> {
-        let defined_function_offset = self.defined_function_offset();
-        let (code_vma, heap_vma, reloc_sinks) = self.emit()?;
-
-        // Relocations
-        for (idx, reloc_sink) in reloc_sinks.iter().enumerate() {
-            for relocation in &reloc_sink.relocations {
-                let reloc_addr = code_vma.address().as_usize()
-                    + self.func_offsets[idx]
-                    + relocation.code_offset as usize;
-
-                // Determine target address.
-                let target_off = match relocation.target {
-                    RelocationTarget::UserFunction(target_idx) => {
-                        self.func_offsets[target_idx.as_u32() as usize - defined_function_offset]
-                    }
-                    RelocationTarget::RuntimeFunction(idx) => match idx {
-                        RUNTIME_MEMORY_GROW_IDX => runtime_memory_grow as usize,
-                        RUNTIME_MEMORY_SIZE_IDX => runtime_memory_size as usize,
-                        _ => unreachable!(),
-                    },
-                    RelocationTarget::LibCall(libcall) => match libcall {
-                        LibCall::Probestack => PROBESTACK as usize,
-                        _ => unimplemented!("{:?}", libcall),
-                    },
-                    // Not necessary unless we split rodata and code
-                    //RelocationTarget::JumpTable(jt) => {
-                    //    let ctx = &self.compile_result.contexts[idx];
-                    //    let offset = ctx
-                    //        .func
-                    //        .jt_offsets
-                    //        .get(jt)
-                    //        .expect("jump table should exist");
-                    //    self.func_offsets[idx] + *offset as usize
-                    //}
-                };
+            imports.memories.push(memory);
+        }
 
-                // Relocate!
-                match relocation.reloc {
-                    Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => {
-                        let delta = target_off
-                            .wrapping_sub(self.func_offsets[idx] + relocation.code_offset as usize)
-                            .wrapping_add(relocation.addend as usize);
+        for (i, import) in compiled_module.table_imports.iter().enumerate() {
+            let table = match imports.find_export(import, domain)? {
+                (instance, Export::Table(idx)) => instance.vmctx_container.get_table(idx).clone(),
+                _ => return Err(missing_import(import)),
+            };
 
-                        unsafe {
-                            write_unaligned(reloc_addr as *mut u32, delta as u32);
-                        }
-                    }
-                    Reloc::Abs8 => {
-                        let delta = target_off.wrapping_add(relocation.addend as usize);
+            {
+                let declared = &compiled_module.tables[i];
+                let table = table.lock();
+                if table.ty() != declared.ty
+                    || table.size() < declared.minimum
+                    || declared
+                        .maximum
+                        .map_or(false, |maximum| table.maximum() > maximum)
+                {
+                    return Err(missing_import(import));
+                }
+            }
 
-                        unsafe {
-                            write_unaligned(reloc_addr as *mut u64, delta as u64);
-                        }
+            imports.tables.push(table);
+        }
+
+        for (i, import) in compiled_module.global_imports.iter().enumerate() {
+            let global_ptr = match imports.find_export(import, domain)? {
+                (instance, Export::Global(idx)) => {
+                    let declared = &compiled_module.globals[i];
+                    let global = &instance.globals[idx.as_u32() as usize];
+                    if global.ty != declared.ty || global.mutability != declared.mutability {
+                        return Err(missing_import(import));
                     }
-                    Reloc::X86PCRelRodata4 => { /* ignore */ }
-                    _ => unimplemented!("{:?}", relocation),
+
+                    // Safety: valid index, it comes from the exports of the instance.
+                    VirtAddr::new(
+                        unsafe { instance.vmctx_container.global_ptr(idx.as_u32()) } as usize
+                    )
                 }
-            }
+                _ => return Err(missing_import(import)),
+            };
+
+            imports.globals.push(global_ptr);
         }
 
+        Ok(imports)
+    }
+
+    /// Applies the relocations to the emitted code.
+    fn link(&self, code_vma: &MappedVma) {
+        relocate(
+            code_vma.address(),
+            &self.compiled_module.relocations,
+            |target_idx| self.get_func_address(code_vma, target_idx),
+        );
+    }
+
+    /// Emit and link.
+    pub fn emit_and_link(mut self) -> Result<Arc<Instance>, Error> {
+        let domain = with_current_thread(|thread| thread.domain().clone());
+
+        // Resolve the imports first, because we might have to wait for other instances.
+        let imports = self.resolve_imports(&domain)?;
+
+        let code_vma = self.emit()?;
+        self.link(&code_vma);
+
         // Now the code is written, change it to read-only & executable.
-        with_current_thread(|thread| {
-            thread.domain().with(|_vma, mapping| {
-                let flags = EntryFlags::PRESENT;
-                mapping
-                    .change_flags_range(code_vma.address(), code_vma.size(), flags)
-                    .map_err(Error::MemoryError)
-            })
+        domain.with(|_vma, mapping| {
+            let flags = EntryFlags::PRESENT;
+            let len = align_up(self.compiled_module.code.len());
+            mapping
+                .change_flags_range(code_vma.address(), len, flags)
+                .map_err(Error::MemoryError)
         })?;
 
-        let start_func = self.compile_result.start_func.ok_or(Error::NoStart)?;
-        let start_address = self.get_func_address(&code_vma, start_func);
+        let start = self
+            .compiled_module
+            .start_func
+            .map(|start_func| self.get_func_address(&code_vma, start_func));
+
+        // Imported memories come first, then the defined memories.
+        let memories = imports
+            .memories
+            .iter()
+            .cloned()
+            .map(Ok)
+            .chain(
+                self.compiled_module.memories[imports.memories.len()..]
+                    .iter()
+                    .map(|memory| self.create_memory(memory, &domain)),
+            )
+            .collect::<Result<Box<[_]>, _>>()?;
+
+        let vmctx_container = self.create_vmctx_container(&code_vma, &memories, &imports)?;
 
-        let vmctx_container = self.create_vmctx_container(&code_vma, &heap_vma)?;
+        let lazy_code = self.lazy_module.take().map(|lazy_module| {
+            LazyCode::new(
+                lazy_module,
+                self.compiled_module.functions.clone(),
+                self.defined_function_offset(),
+                self.compiled_module.code.len(),
+            )
+        });
 
-        Ok(WasmInstance {
-            code_vma,
-            heap_vma,
+        let instance = Arc::new(Instance {
+            code: code_vma,
+            domain,
             vmctx_container,
-            start_address,
-        })
+            memories,
+            trap_table: RwLock::new(TrapTable::new(self.compiled_module.trap_sites.to_vec())),
+            symbol_table: RwLock::new(self.symbol_table()),
+            lazy_code,
+            fuel: self.fuel.take(),
+            epoch: self.epoch.take(),
+            passive_data: Spinlock::new(PassiveData::new(self.compiled_module.passive_data.iter())),
+            passive_elements: Spinlock::new(PassiveElements::new(
+                self.compiled_module.passive_elements.clone(),
+            )),
+            exports: self
+                .compiled_module
+                .exports
+                .iter()
+                .map(|(name, export)| (Box::from(*name), *export))
+                .collect::<BTreeMap<_, _>>(),
+            globals: self.compiled_module.globals.clone(),
+            dependencies: imports.dependencies,
+            start,
+        });
+
+        // Safety: the instance owns the context.
+        unsafe {
+            instance.vmctx_container.set_instance(&*instance);
+        }
+
+        if let Some(ref budget) = instance.fuel {
+            instance.vmctx_container.set_fuel(budget.fuel());
+        }
+        if let Some(ref interruption) = instance.epoch {
+            instance
+                .vmctx_container
+                .set_epoch_deadline(epoch::next_deadline(interruption));
+        }
+
+        Ok(instance)
     }
 
     /// Creates the VmContext container.
     fn create_vmctx_container(
         &self,
         code_vma: &MappedVma,
-        heap_vma: &LazilyMappedVma,
+        memories: &[Arc<LinearMemory>],
+        imports: &Imports,
     ) -> Result<VmContextContainer, Error> {
         // Create the vm context.
         let mut vmctx_container = {
-            // Initialize table vectors.
-            let tables: Vec<Table> = self
-                .compile_result
+            // Imported tables come first, then the defined tables.
+            let tables: Vec<SharedTable> = imports
                 .tables
                 .iter()
-                .map(|x| Table::new(x))
+                .cloned()
+                .chain(
+                    self.compiled_module.tables[imports.tables.len()..]
+                        .iter()
+                        .map(Table::new_shared),
+                )
                 .collect();
 
+            // The stubs of lazily compiled functions initially jump to the lazy compilation.
+            let lazy_slots: Vec<VirtAddr> = if self.lazy_module.is_some() {
+                self.compiled_module
+                    .functions
+                    .iter()
+                    .map(|stub| {
+                        code_vma.address() + (stub.offset + LAZY_STUB_COMPILE_OFFSET) as usize
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
             unsafe {
                 VmContextContainer::new(
-                    heap_vma.address(),
-                    self.compile_result.globals.len() as u32,
-                    self.compile_result.function_imports.len() as u32,
+                    memories,
+                    self.compiled_module.globals.len() as u32,
+                    self.compiled_module.global_imports.len() as u32,
+                    self.compiled_module.function_imports.len() as u32,
                     tables,
+                    &self.sig_ids,
+                    &lazy_slots,
                 )
             }
         };
+        let vmctx = VirtAddr::new(vmctx_container.ptr() as usize);
 
-        // Resolve import addresses.
+        // Table elements for all functions, imported functions come first.
+        let mut functions: Vec<VmTableElement> =
+            Vec::with_capacity(self.compiled_module.func_sigs.len());
+
+        // Fill in the imports.
         {
             // Safety: we are the only ones who have access to this slice right now.
             let function_imports = unsafe { vmctx_container.function_imports_as_mut_slice() };
 
-            for (entry, (i, import)) in function_imports
+            for (i, (entry, (address, import_vmctx))) in function_imports
                 .iter_mut()
-                .zip(self.compile_result.function_imports.iter().enumerate())
+                .zip(imports.functions.iter())
+                .enumerate()
             {
-                println!("{} {:?}", i, import);
-
-                let sig = self.compile_result.get_sig(FuncIndex::from_u32(i as u32));
+                // Host functions are called with the context of the caller.
+                let import_vmctx = import_vmctx.unwrap_or(vmctx);
 
-                *entry = match import.module.as_str() {
-                    "wasi_snapshot_preview1" => VmFunctionImportEntry {
-                        address: get_address_for_wasi_and_validate_sig(&import.field, sig)
-                            .ok_or(Error::MissingImport)?,
-                    },
-                    _ => unimplemented!(), // TODO
+                *entry = VmFunctionImportEntry {
+                    address: *address,
+                    vmctx: import_vmctx,
                 };
+
+                functions.push(VmTableElement::new(
+                    *address,
+                    self.get_sig_id(FuncIndex::from_u32(i as u32)),
+                    import_vmctx,
+                ));
             }
-        }
 
-        // Create tables.
-        {
-            // Fill in the tables.
-            for elements in self.compile_result.table_elements.iter() {
-                // TODO: support this and verify bounds?
-                assert!(elements.base.is_none(), "not implemented yet");
-
-                let offset = elements.offset;
-                let table = vmctx_container.get_table(elements.index);
-
-                for (i, func_idx) in elements.elements.iter().enumerate() {
-                    table.set(
-                        i + offset,
-                        VmTableElement::new(
-                            self.get_func_address(code_vma, *func_idx),
-                            self.compile_result.func_sigs[func_idx.as_u32() as usize],
-                        ),
-                    );
+            for (i, global_ptr) in imports.globals.iter().enumerate() {
+                // Safety: valid index, and the dependencies keep the global alive.
+                unsafe {
+                    vmctx_container.set_global_import(i as u32, global_ptr.as_mut());
                 }
             }
         }
 
-        // Run data initializers
+        // Defined functions.
+        for i in self.defined_function_offset()..self.compiled_module.func_sigs.len() {
+            let func_idx = FuncIndex::from_u32(i as u32);
+            functions.push(VmTableElement::new(
+                self.get_func_address(code_vma, func_idx),
+                self.get_sig_id(func_idx),
+                vmctx,
+            ));
+        }
+
+        vmctx_container.set_function_references(FunctionReferences::new(functions));
+
+        // Create globals
         {
-            for initializer in self.compile_result.data_initializers.iter() {
-                assert_eq!(initializer.memory_index.as_u32(), 0);
-                // TODO: support this
-                assert!(initializer.base.is_none());
+            let num_imported_globals = self.compiled_module.global_imports.len();
+            for (i, global) in self
+                .compiled_module
+                .globals
+                .iter()
+                .enumerate()
+                .skip(num_imported_globals)
+            {
+                // Safety: valid index, and the globals before this one are initialized.
+                unsafe { vmctx_container.set_global(i as u32, &global) }
+                    .ok_or(Error::UnsupportedGlobal)?;
+            }
+        }
 
-                let offset = initializer.offset;
+        // Fill in the tables.
+        {
+            for elements in self.compiled_module.table_elements.iter() {
+                let offset =
+                    self.segment_offset(&vmctx_container, elements.base, elements.offset)?;
+                let values: Vec<VmTableElement> = elements
+                    .elements
+                    .iter()
+                    .map(|func_idx| vmctx_container.function_references().get(*func_idx))
+                    .collect();
+
+                vmctx_container
+                    .get_table(elements.index)
+                    .lock()
+                    .set_range(offset, &values)
+                    .ok_or(Error::SegmentOutOfBounds)?;
+            }
+        }
 
-                if offset > heap_vma.size() - initializer.data.len() {
-                    return Err(Error::MemoryError(MemoryError::InvalidRange));
+        // Run data initializers
+        {
+            for initializer in self.compiled_module.data_initializers.iter() {
+                let memory = &memories[initializer.memory_index.as_u32() as usize];
+                let offset =
+                    self.segment_offset(&vmctx_container, initializer.base, initializer.offset)?;
+
+                match offset.checked_add(initializer.data.len()) {
+                    Some(end) if end <= memory.size() => {}
+                    _ => return Err(Error::SegmentOutOfBounds),
                 }
 
-                let offset = heap_vma.address() + offset;
+                // An imported memory might have grown, so not all pages have to be mapped.
+                memory
+                    .map_range(offset, initializer.data.len())
+                    .map_err(Error::MemoryError)?;
+
+                let offset = memory.address() + offset;
 
                 //println!(
                 //    "Copy {:?} to {:?} length {}",
@@ -379,163 +684,307 @@ impl<'r, 'data> Instantiation<'r, 'data> {
                     );
                 }
             }
+        }
+
+        Ok(vmctx_container)
+    }
+
+    /// Calculates the offset of a data or element segment.
+    /// The offset is the value of the base global, if any, plus the constant offset.
+    /// Globals must already be initialized.
+    fn segment_offset(
+        &self,
+        vmctx_container: &VmContextContainer,
+        base: Option<GlobalIndex>,
+        offset: usize,
+    ) -> Result<usize, Error> {
+        match base {
+            Some(base) => {
+                if base.as_u32() as usize >= self.compiled_module.globals.len() {
+                    return Err(Error::SegmentOutOfBounds);
+                }
 
-            vmctx_container.write_tables_to_vmctx();
+                // Safety: index checked above.
+                // Offsets are unsigned, even though the global type is i32.
+                let value = unsafe { vmctx_container.get_global_i32(base.as_u32()) } as u32;
+                offset
+                    .checked_add(value as usize)
+                    .ok_or(Error::SegmentOutOfBounds)
+            }
+            None => Ok(offset),
         }
+    }
+}
+
+/// Applies relocations to code at `code`, with offsets relative to the start of the code.
+/// `func_address` gives the address of a defined function.
+pub fn relocate<F>(code: VirtAddr, relocations: &[Relocation], func_address: F)
+where
+    F: Fn(FuncIndex) -> VirtAddr,
+{
+    for relocation in relocations {
+        let reloc_addr = code.as_usize() + relocation.code_offset as usize;
+
+        // Determine target address.
+        let target_addr = match relocation.target {
+            RelocationTarget::UserFunction(target_idx) => func_address(target_idx).as_usize(),
+            RelocationTarget::RuntimeFunction(idx) => match idx {
+                RUNTIME_MEMORY_GROW_IDX => runtime_memory_grow as usize,
+                RUNTIME_MEMORY_SIZE_IDX => runtime_memory_size as usize,
+                RUNTIME_MEMORY_COPY_IDX => runtime_memory_copy as usize,
+                RUNTIME_MEMORY_FILL_IDX => runtime_memory_fill as usize,
+                RUNTIME_MEMORY_INIT_IDX => runtime_memory_init as usize,
+                RUNTIME_MEMORY_ATOMIC_WAIT32_IDX => runtime_memory_atomic_wait32 as usize,
+                RUNTIME_MEMORY_ATOMIC_WAIT64_IDX => runtime_memory_atomic_wait64 as usize,
+                RUNTIME_MEMORY_ATOMIC_NOTIFY_IDX => runtime_memory_atomic_notify as usize,
+                RUNTIME_DATA_DROP_IDX => runtime_data_drop as usize,
+                RUNTIME_TABLE_GROW_IDX => runtime_table_grow as usize,
+                RUNTIME_TABLE_GET_IDX => runtime_table_get as usize,
+                RUNTIME_TABLE_SET_IDX => runtime_table_set as usize,
+                RUNTIME_TABLE_FILL_IDX => runtime_table_fill as usize,
+                RUNTIME_TABLE_COPY_IDX => runtime_table_copy as usize,
+                RUNTIME_TABLE_INIT_IDX => runtime_table_init as usize,
+                RUNTIME_ELEM_DROP_IDX => runtime_elem_drop as usize,
+                RUNTIME_REF_FUNC_IDX => runtime_ref_func as usize,
+                RUNTIME_LAZY_COMPILE_IDX => lazy_compile_trampoline as usize,
+                _ => unreachable!(),
+            },
+            RelocationTarget::LibCall(libcall) => match libcall {
+                LibCall::Probestack => PROBESTACK as usize,
+                _ => unimplemented!("{:?}", libcall),
+            },
+        };
+
+        // Relocate!
+        match relocation.reloc {
+            Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => {
+                let delta = target_addr
+                    .wrapping_sub(reloc_addr)
+                    .wrapping_add(relocation.addend as usize);
+
+                unsafe {
+                    write_unaligned(reloc_addr as *mut u32, delta as u32);
+                }
+            }
+            Reloc::Abs8 => {
+                let delta = target_addr.wrapping_add(relocation.addend as usize);
 
-        // Create globals
-        {
-            for (i, global) in self.compile_result.globals.iter().enumerate() {
-                // Safety: valid index
                 unsafe {
-                    vmctx_container.set_global(i as u32, &global);
+                    write_unaligned(reloc_addr as *mut u64, delta as u64);
                 }
             }
+            Reloc::X86PCRelRodata4 => { /* ignore */ }
+            _ => unimplemented!("{:?}", relocation),
         }
+    }
+}
 
-        Ok(vmctx_container)
+/// Loads the precompiled artifact of a module if it is up to date, compiles the module otherwise.
+/// Lazily compiled modules come with the state to compile their functions on demand.
+fn load_or_compile(
+    buffer: &'static [u8],
+    artifact: Option<&'static [u8]>,
+    entry: &ManifestEntry,
+) -> Result<(CompiledModule<'static>, Option<LazyModule<'static>>), Error> {
+    let isa = target_isa();
+    let options = CompileOptions {
+        fuel_metering: entry.fuel.is_some(),
+        epoch_interruption: entry.is_interruptible(),
+    };
+
+    if let Some(artifact) = artifact {
+        match artifact::deserialize(artifact, buffer, &*isa, options) {
+            Ok(compiled_module) => return Ok((compiled_module, None)),
+            Err(e) => println!("Not using the artifact of {}: {:?}", entry.file, e),
+        }
+    }
+
+    match entry.compile_mode {
+        CompileMode::Eager => {
+            println!("Compiling {}", entry.file);
+            let compiled_module = parallel::compile(&*isa, buffer, options)?;
+            Ok((compiled_module, None))
+        }
+        CompileMode::Lazy => {
+            println!("Compiling {} lazily", entry.file);
+            let (compiled_module, lazy_module) =
+                compile_lazily(&*isa, buffer, options).map_err(Error::CompileError)?;
+            Ok((compiled_module, Some(lazy_module)))
+        }
     }
 }
 
-/// Runs WebAssembly from a buffer.
-pub fn run(buffer: &[u8], domain: ProtectionDomain) -> Result<(), Error> {
-    let compile_result = Box::new(compile(buffer)?);
-    let compile_result = Box::into_raw(compile_result);
+/// Runs WebAssembly from a buffer, as described by a manifest entry.
+/// Uses the precompiled artifact of the module if there is one that is up to date.
+/// Named modules are registered as instances, so modules later in the manifest can import from them.
+/// The buffer must live forever, because lazily compiled functions are translated from it.
+/// Returns the exit status of the new process, libraries exit as soon as they are instantiated.
+pub fn run(
+    buffer: &'static [u8],
+    artifact: Option<&'static [u8]>,
+    domain: ProtectionDomain,
+    entry: &ManifestEntry,
+) -> Result<Arc<ExitStatus>, Error> {
+    let (compiled_module, lazy_module) = load_or_compile(buffer, artifact, entry)?;
+
+    // Only named modules can be used as a library.
+    if compiled_module.start_func.is_none() && entry.name.is_none() {
+        return Err(Error::NoStart);
+    }
+
+    let start_data = Box::new(StartData {
+        compiled_module,
+        lazy_module,
+        fuel: entry
+            .fuel
+            .map(|fuel| FuelBudget::new(fuel, entry.fuel_policy)),
+        epoch: if entry.is_interruptible() {
+            Some(EpochInterruption::new(entry.time_slice))
+        } else {
+            None
+        },
+        memory_account: MemoryAccount::new(entry.memory_limit),
+        name: entry.name.map(Box::from),
+        preopens: entry
+            .preopens
+            .iter()
+            .map(|path| Box::from(path.as_bytes()))
+            .collect(),
+        args: StringList::new(entry.args.iter().map(|arg| arg.as_bytes())),
+        env: StringList::new(entry.env.iter().map(|var| var.as_bytes())),
+        exit_status: ExitStatus::new(),
+    });
+    let exit_status = start_data.exit_status.clone();
+
+    // Declare the name now, so importers wait until the instantiation is done.
+    if let Some(name) = entry.name {
+        if !instance::declare(name) {
+            return Err(Error::NameInUse);
+        }
+    }
+
+    let start_data = Box::into_raw(start_data);
     // Safety: valid and correct entry point.
     let thread = unsafe {
         Thread::create(
             domain,
-            VirtAddr::new(start_from_compile_result as usize),
-            compile_result as usize,
+            VirtAddr::new(start_from_start_data as usize),
+            start_data as usize,
         )
-        .map_err(Error::MemoryError)?
+        .map_err(|e| {
+            if let Some(name) = entry.name {
+                instance::fail(name);
+            }
+            Error::MemoryError(e)
+        })?
     };
     add_and_schedule_thread(thread);
 
-    Ok(())
+    Ok(exit_status)
 }
 
-/// Start the wasm application from compile result.
-extern "C" fn start_from_compile_result(compile_result: *mut CompileResult) {
-    let compile_result = unsafe { Box::from_raw(compile_result) };
-    let instantiation = compile_result.instantiate();
+/// Creates the file descriptor table of a process with the pre-opened directories.
+/// A pre-open of the form `name:path` mounts that directory of the named scheme, other pre-opens
+/// are the self scheme.
+fn setup_preopens(preopens: Box<[Box<[u8]>]>) -> FileDescriptorTable {
+    let mut tbl = FileDescriptorTable::new();
+
+    for path in preopens.into_vec() {
+        let fd = if scheme_container::split_scheme_name(&path).is_some() {
+            scheme_container::open(None, &path, OpenMode::directory())
+        } else {
+            schemes().read().open_self(Box::new([]))
+        };
+        let mut fd = match fd {
+            Ok(fd) => fd,
+            Err(e) => {
+                println!("Failed to pre-open {:?}: {:?}", path, e);
+                continue;
+            }
+        };
+        fd.set_pre_open_path(path);
 
-    match instantiation.emit_and_link() {
-        Ok(wasm_instance) => {
-            let vmctx = wasm_instance.vmctx_container.ptr();
+        if tbl.insert_lowest(fd).is_none() {
+            println!("Too many pre-opened directories");
+            break;
+        }
+    }
 
-            let func: extern "C" fn(*const VmContext) =
-                unsafe { mem::transmute(wasm_instance.start_address.as_usize()) };
+    tbl
+}
 
-            with_current_thread(|thread| {
-                // Safety: this is a new thread without existing wasm data.
-                unsafe {
-                    thread.set_wasm_data(
-                        wasm_instance.code_vma,
-                        wasm_instance.heap_vma,
-                        wasm_instance.vmctx_container,
-                    )
-                }
-            });
+/// Start the wasm application from the start data.
+extern "C" fn start_from_start_data(start_data: *mut StartData) {
+    let start_data = unsafe { Box::from_raw(start_data) };
+    let StartData {
+        compiled_module,
+        lazy_module,
+        fuel,
+        epoch,
+        memory_account,
+        name,
+        preopens,
+        args,
+        env,
+        exit_status,
+    } = *start_data;
+
+    let file_descriptor_table = setup_preopens(preopens);
+
+    let instantiation = Instantiation::new(
+        &compiled_module,
+        lazy_module,
+        fuel,
+        epoch,
+        memory_account,
+        name.as_deref(),
+    );
 
-            drop(compile_result);
+    match instantiation.emit_and_link() {
+        Ok(instance) => {
+            drop(compiled_module);
 
-            func(vmctx);
-        }
+            if let Some(ref name) = name {
+                instance::register(name, instance.clone());
+            }
 
-        Err(e) => {
-            drop(compile_result);
-            println!("Error while starting: {:?}", e);
-        }
-    }
+            // Modules without a start function are libraries, the registry keeps them alive.
+            if let Some(start) = instance.start {
+                let vmctx = instance.vmctx_container.ptr();
 
-    thread_exit(0);
-}
+                let func: extern "C" fn(*const VmContext) =
+                    unsafe { mem::transmute(start.as_usize()) };
 
-/// Compiles a WebAssembly buffer.
-fn compile(buffer: &[u8]) -> Result<CompileResult, Error> {
-    let isa_builder =
-        cranelift_native::builder().expect("native flag builder should be constructable");
-    let mut flag_builder = settings::builder();
-
-    // Flags
-    flag_builder
-        .set("opt_level", "speed_and_size")
-        .expect("valid flag");
-    flag_builder
-        .set("enable_probestack", "true")
-        .expect("valid flag");
-    flag_builder.set("enable_simd", "true").expect("valid flag");
-    // TODO: avoid div traps?
-
-    let flags = settings::Flags::new(flag_builder);
-    // println!("{}", flags.to_string());
-    let isa = isa_builder.finish(flags);
-
-    // Module
-    let mut env = ModuleEnv::new(isa.frontend_config());
-    let translation = translate_module(&buffer, &mut env).map_err(Error::WasmError)?;
-    let defined_function_offset = env.function_imports.len();
-
-    // Compile the functions and store their contexts.
-    let mut contexts: Vec<Context> = Vec::with_capacity(env.func_bodies.len());
-    let mut total_size: usize = 0;
-    for idx in 0..env.func_bodies.len() {
-        let mut ctx = Context::new();
-        ctx.func.signature =
-            env.get_sig_from_func(FuncIndex::from_u32((idx + defined_function_offset) as u32));
-
-        print!("\r compiling {:?}", idx);
-
-        let FunctionBody { body, offset } = env.func_bodies[idx];
-
-        let mut func_trans = FuncTranslator::new();
-        func_trans
-            .translate(
-                &translation,
-                body,
-                offset,
-                &mut ctx.func,
-                &mut FuncEnv::new(&env),
-            )
-            .map_err(Error::WasmError)?;
+                let process = Process::new(instance, file_descriptor_table, args, env, exit_status);
+                process.add_thread(with_current_thread(|thread| thread.id));
 
-        let info = ctx.compile(&*isa).map_err(Error::CodegenError)?;
-        total_size += info.total_size as usize;
-        contexts.push(ctx);
-    }
+                with_current_thread(|thread| {
+                    // Safety: this is a new thread without existing wasm data.
+                    unsafe {
+                        thread.set_wasm_data(StaticWasmThreadData {
+                            vmctx_container: None,
+                            process,
+                        })
+                    }
+                });
 
-    // Determine start function. If it's not given, search for "_start" as specified by WASI.
-    let start_func = env.start_func.or_else(|| match env.exports.get("_start") {
-        Some(Export::Function(idx)) => Some(*idx),
-        _ => None,
-    });
+                func(vmctx);
+            } else {
+                exit_status.set(0);
+            }
+        }
 
-    let compile_result = CompileResult {
-        isa,
-        contexts: contexts.into_boxed_slice(),
-        memories: env.memories.into_boxed_slice(),
-        func_sigs: env.func_sigs.into_boxed_slice(),
-        data_initializers: env.data_initializers.into_boxed_slice(),
-        start_func,
-        function_imports: env.function_imports.into_boxed_slice(),
-        tables: env.tables.into_boxed_slice(),
-        table_elements: env.table_elements.into_boxed_slice(),
-        globals: env.globals.into_boxed_slice(),
-        total_size,
-        signatures: env.signatures.into_boxed_slice(),
-    };
+        Err(e) => {
+            drop(compiled_module);
 
-    // Check the signature of the start function.
-    // Must not take any arguments (which means arg length == 1 because vmctx)
-    // and not have return values.
-    if let Some(start_func) = start_func {
-        let sig = compile_result.get_sig(start_func);
+            if let Some(ref name) = name {
+                instance::fail(name);
+            }
 
-        if !sig.returns.is_empty() || sig.params.len() != 1 {
-            return Err(Error::NoStart);
+            println!("Error while starting: {:?}", e);
+            exit_status.set(INSTANTIATION_ERROR_EXIT_CODE);
         }
     }
 
-    Ok(compile_result)
+    thread_exit(0);
 }
@@ -0,0 +1,243 @@
+//! Linear memories.
+//! The size of a memory is limited by its declared maximum, and by the memory limit of the process
+//! that created it. All memories of a process are charged to the memory account of that process,
+//! so one process can't use up the memory of the others.
+
+use crate::arch::address::VirtAddr;
+use crate::arch::paging::{ActiveMapping, PAGE_SIZE};
+use crate::mm::mapper::{MemoryError, MemoryMapper};
+use crate::mm::vma_allocator::{LazilyMappedVma, MappableVma};
+use crate::sync::spinlock::RwLock;
+use crate::sync::wait_table::{WaitResult, WaitTable};
+use crate::tasking::protection_domain::ProtectionDomain;
+use crate::wasm::vmctx::{VmMemory, WASM_PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Memory usage of a process, shared by all memories the process creates.
+#[derive(Debug)]
+pub struct MemoryAccount {
+    /// Amount of bytes charged to this account.
+    used: AtomicUsize,
+    /// Maximum amount of bytes that can be charged, if any.
+    limit: Option<usize>,
+}
+
+impl MemoryAccount {
+    /// Creates an account with a limit in bytes.
+    pub fn new(limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            used: AtomicUsize::new(0),
+            limit,
+        })
+    }
+
+    /// Charges bytes to this account.
+    /// Returns false, without charging anything, if that would exceed the limit.
+    pub fn charge(&self, bytes: usize) -> bool {
+        let limit = self.limit.unwrap_or(core::usize::MAX);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&used| used <= limit)
+            })
+            .is_ok()
+    }
+
+    /// Releases bytes that were charged to this account.
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A linear memory.
+/// Memories can be shared between instances inside the same protection domain.
+/// Shared memories can also be used by multiple threads at the same time, those threads can wait
+/// on an address of the memory until another thread notifies them.
+pub struct Memory {
+    vma: RwLock<LazilyMappedVma>,
+    domain: ProtectionDomain,
+    /// The VmContext representation, the contexts of all instances using this memory point here.
+    vm_memory: Box<VmMemory>,
+    /// Maximum size in bytes.
+    maximum: usize,
+    /// The size of the memory is charged to this account.
+    account: Arc<MemoryAccount>,
+    /// Whether the memory is a shared memory of the threads proposal.
+    shared: bool,
+    /// Threads waiting on an offset in the memory, only used by shared memories.
+    waiters: WaitTable,
+}
+
+impl Memory {
+    /// Creates a memory from a lazily mapped Vma in a domain.
+    /// The memory can grow up to `maximum` bytes, which must fit in the Vma.
+    /// The current size of the Vma must already be charged to the account.
+    pub fn new(
+        domain: ProtectionDomain,
+        vma: LazilyMappedVma,
+        maximum: usize,
+        shared: bool,
+        account: Arc<MemoryAccount>,
+    ) -> Self {
+        let vm_memory = Box::new(VmMemory {
+            base_address: vma.address(),
+            current_length: AtomicU64::new(vma.size() as u64),
+        });
+
+        Self {
+            vma: RwLock::new(vma),
+            domain,
+            vm_memory,
+            maximum,
+            account,
+            shared,
+            waiters: WaitTable::new(),
+        }
+    }
+
+    /// Gets the base address.
+    pub fn address(&self) -> VirtAddr {
+        self.vma.read().address()
+    }
+
+    /// Gets the current size in bytes.
+    pub fn size(&self) -> usize {
+        self.vma.read().size()
+    }
+
+    /// Gets the current size in WebAssembly pages.
+    pub fn pages(&self) -> u32 {
+        (self.size() / WASM_PAGE_SIZE) as u32
+    }
+
+    /// Gets the size in WebAssembly pages the memory can grow up to.
+    pub fn maximum_pages(&self) -> u32 {
+        (self.maximum / WASM_PAGE_SIZE) as u32
+    }
+
+    /// Grows the memory by `wasm_pages` WebAssembly pages.
+    /// Returns the old size in pages, or `u32::MAX` on failure. Growing fails if the memory would
+    /// exceed its maximum, or if the process would exceed its memory limit.
+    pub fn grow(&self, wasm_pages: u32) -> u32 {
+        let mut vma = self.vma.write();
+        let old_size = vma.size();
+        let amount = wasm_pages as usize * WASM_PAGE_SIZE;
+
+        match old_size.checked_add(amount) {
+            Some(new_size) if new_size <= self.maximum => {}
+            _ => return core::u32::MAX,
+        }
+
+        if !self.account.charge(amount) {
+            return core::u32::MAX;
+        }
+
+        if vma.expand(amount).is_err() {
+            self.account.release(amount);
+            return core::u32::MAX;
+        }
+
+        self.vm_memory
+            .current_length
+            .store(vma.size() as u64, Ordering::Release);
+        (old_size / WASM_PAGE_SIZE) as u32
+    }
+
+    /// Maps the pages of a range that are not mapped yet.
+    /// Used to access the memory outside of wasm code, where page faults are not handled.
+    pub fn map_range(&self, offset: usize, len: usize) -> Result<(), MemoryError> {
+        // Same lock order as the page fault handler: first the domain, then the memory.
+        self.domain.with(|_vma, mapping| {
+            let mut vma = self.vma.write();
+            let end = match offset.checked_add(len) {
+                Some(end) if end <= vma.size() => vma.address() + end,
+                _ => return Err(MemoryError::InvalidRange),
+            };
+
+            let mut addr = (vma.address() + offset).align_down();
+            while addr.as_usize() < end.as_usize() {
+                if mapping.translate(addr).is_none() && !vma.try_handle_page_fault(mapping, addr) {
+                    return Err(MemoryError::OOM);
+                }
+
+                addr += PAGE_SIZE;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Checks if this is a shared memory.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Gets the address of an atomic access of `size` bytes.
+    /// Returns `None` if the access is unaligned or outside of the memory.
+    fn atomic_address(&self, offset: u32, size: u32) -> Option<VirtAddr> {
+        if offset % size != 0 || offset as usize + size as usize > self.size() {
+            None
+        } else {
+            Some(self.address() + offset as usize)
+        }
+    }
+
+    /// memory.atomic.wait32 and memory.atomic.wait64, for an access of `size` bytes.
+    /// Blocks the current thread until it is notified, if `matches` holds for the address.
+    /// The deadline is an Hpet counter value.
+    /// Returns `None` if the access is invalid, or if the memory is not shared.
+    pub fn atomic_wait<F>(
+        &self,
+        offset: u32,
+        size: u32,
+        matches: F,
+        deadline: Option<u64>,
+    ) -> Option<WaitResult>
+    where
+        F: FnOnce(VirtAddr) -> bool,
+    {
+        if !self.shared {
+            return None;
+        }
+
+        let address = self.atomic_address(offset, size)?;
+
+        // The value is read while notifiers are held off, it can't cause a page fault there.
+        self.map_range(offset as usize, size as usize).ok()?;
+
+        Some(
+            self.waiters
+                .wait_interruptible(offset as usize, || matches(address), deadline),
+        )
+    }
+
+    /// memory.atomic.notify, wakes up at most `count` threads waiting on the offset.
+    /// Returns the amount of woken up threads, or `None` if the access is invalid.
+    pub fn atomic_notify(&self, offset: u32, count: u32) -> Option<u32> {
+        self.atomic_address(offset, 4)?;
+        Some(self.waiters.notify(offset as usize, count))
+    }
+
+    /// Gets the pointer to the VmContext representation.
+    /// The pointer stays valid as long as the memory lives.
+    pub fn vm_memory_ptr(&self) -> *const VmMemory {
+        &*self.vm_memory
+    }
+
+    /// Try handle a page fault inside this memory.
+    pub fn try_handle_page_fault(&self, mapping: &mut ActiveMapping, fault_addr: VirtAddr) -> bool {
+        self.vma.write().try_handle_page_fault(mapping, fault_addr)
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        let size = self.domain.with(|vma_allocator, mapping| {
+            let vma = self.vma.read();
+            vma_allocator.destroy_vma(mapping, &*vma);
+            vma.size()
+        });
+        self.account.release(size);
+    }
+}
@@ -1,11 +1,22 @@
 //! WebAssembly runtime
 //! Used https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src as a reference.
 
-mod func_env;
+pub mod epoch;
+pub mod fuel;
+pub mod host_modules;
+pub mod instance;
+pub mod kwast;
+mod lazy;
 pub mod main;
-mod module_env;
-mod reloc_sink;
+mod memory;
+mod parallel;
+pub mod passive_data;
+pub mod programs;
 mod runtime;
+mod signatures;
+pub mod symbols;
 mod table;
+pub mod threads;
+pub mod trap;
 pub mod vmctx;
 pub mod wasi;
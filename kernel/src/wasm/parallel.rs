@@ -0,0 +1,148 @@
+//! Compiles the defined functions of a module on a pool of kernel worker threads.
+//! Workers take the next function from a shared counter. The results are merged back in index
+//! order, so the code layout doesn't depend on the scheduling of the workers.
+
+use crate::arch::address::VirtAddr;
+use crate::sync::wait_queue::WaitQueue;
+use crate::tasking::scheduler::{add_and_schedule_thread, thread_exit, with_current_thread};
+use crate::tasking::thread::Thread;
+use crate::wasm::main::Error;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_wasm::FuncIndex;
+use wasm_compiler::{target_isa, CompileOptions, CompiledCode, CompiledModule, TranslatedModule};
+
+/// Maximum amount of worker threads per module.
+// TODO: base this on the amount of cores once we go multicore.
+const MAX_WORKERS: usize = 4;
+
+/// Message to the compiling thread.
+enum Message {
+    /// A function was compiled, or failed to compile.
+    Compiled(usize, Result<CompiledCode, wasm_compiler::Error>),
+    /// A worker has no more work left.
+    Done,
+}
+
+/// Shared between the compiling thread and the workers.
+struct CompileJob {
+    /// Only valid until all workers are done, the compiling thread waits for that.
+    module: *const TranslatedModule<'static>,
+    /// Index of the next defined function to compile.
+    next: AtomicUsize,
+    messages: WaitQueue<Message>,
+}
+
+// Safety: the module is only read, and outlives its use by the workers.
+unsafe impl Send for CompileJob {}
+unsafe impl Sync for CompileJob {}
+
+/// Compiles functions of the job until there are none left.
+fn work(job: &CompileJob, isa: &dyn TargetIsa) {
+    // Safety: the compiling thread keeps the module alive until we're done.
+    let module = unsafe { &*job.module };
+    let num_defined_functions = module.num_defined_functions();
+
+    loop {
+        let idx = job.next.fetch_add(1, Ordering::Relaxed);
+        if idx >= num_defined_functions {
+            break;
+        }
+
+        let func_idx = FuncIndex::from_u32((idx + module.defined_function_offset()) as u32);
+        let result = module.compile_function(isa, func_idx);
+
+        // The compilation fails anyway, don't start on the remaining functions.
+        if result.is_err() {
+            job.next.store(num_defined_functions, Ordering::Relaxed);
+        }
+
+        job.messages.push_back(Message::Compiled(idx, result));
+    }
+
+    job.messages.push_back(Message::Done);
+}
+
+/// Entry point of a worker thread.
+extern "C" fn worker(job: *const CompileJob) {
+    // Safety: the compiling thread gave us a reference.
+    let job = unsafe { Arc::from_raw(job) };
+    let isa = target_isa();
+    work(&job, &*isa);
+    drop(isa);
+    drop(job);
+    thread_exit(0);
+}
+
+/// Compiles a WebAssembly buffer, the compiling thread helps the workers.
+/// If a function fails to compile, the error of the function with the lowest index is returned.
+pub fn compile(
+    isa: &dyn TargetIsa,
+    buffer: &'static [u8],
+    options: CompileOptions,
+) -> Result<CompiledModule<'static>, Error> {
+    let module = TranslatedModule::new(isa, buffer, options).map_err(Error::CompileError)?;
+    let num_defined_functions = module.num_defined_functions();
+
+    let job = Arc::new(CompileJob {
+        module: &module,
+        next: AtomicUsize::new(0),
+        messages: WaitQueue::new(),
+    });
+
+    // Start the workers, the compiling thread is a worker too.
+    let domain = with_current_thread(|thread| thread.domain().clone());
+    let mut workers = 1;
+    for _ in 1..min(MAX_WORKERS, num_defined_functions) {
+        let arg = Arc::into_raw(job.clone());
+        // Safety: valid and correct entry point.
+        match unsafe {
+            Thread::create(domain.clone(), VirtAddr::new(worker as usize), arg as usize)
+        } {
+            Ok(thread) => {
+                add_and_schedule_thread(thread);
+                workers += 1;
+            }
+            Err(e) => {
+                // Safety: the reference was not handed over.
+                drop(unsafe { Arc::from_raw(arg) });
+                println!("Could not create a compile worker: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    work(&job, isa);
+
+    // Collect the results until all workers are done.
+    let mut functions: Vec<Option<CompiledCode>> =
+        (0..num_defined_functions).map(|_| None).collect();
+    let mut error: Option<(usize, wasm_compiler::Error)> = None;
+    while workers > 0 {
+        match job.messages.pop_front() {
+            Message::Compiled(idx, Ok(code)) => functions[idx] = Some(code),
+            Message::Compiled(idx, Err(e)) => {
+                if error
+                    .as_ref()
+                    .map_or(true, |(error_idx, _)| idx < *error_idx)
+                {
+                    error = Some((idx, e));
+                }
+            }
+            Message::Done => workers -= 1,
+        }
+    }
+
+    if let Some((_, e)) = error {
+        return Err(Error::CompileError(e));
+    }
+
+    let functions = functions
+        .into_iter()
+        .map(|code| code.expect("all functions are compiled"))
+        .collect();
+    module.finish(functions).map_err(Error::CompileError)
+}
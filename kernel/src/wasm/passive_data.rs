@@ -0,0 +1,65 @@
+//! Passive data and element segments, used by the bulk memory and table operations.
+
+use alloc::boxed::Box;
+use cranelift_wasm::{DataIndex, ElemIndex, FuncIndex};
+use hashbrown::HashMap;
+
+/// Passive data segments of an instance.
+pub struct PassiveData {
+    segments: HashMap<DataIndex, Box<[u8]>>,
+}
+
+/// Passive element segments of an instance.
+pub struct PassiveElements {
+    segments: HashMap<ElemIndex, Box<[FuncIndex]>>,
+}
+
+impl PassiveData {
+    /// Creates the passive data store from the segments of a module.
+    pub fn new<'a, I>(segments: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a DataIndex, &'a &'a [u8])>,
+    {
+        Self {
+            segments: segments
+                .into_iter()
+                .map(|(index, data)| (*index, Box::from(*data)))
+                .collect(),
+        }
+    }
+
+    /// Gets the contents of a segment.
+    /// Dropped segments and active segments are empty.
+    pub fn get(&self, index: DataIndex) -> &[u8] {
+        match self.segments.get(&index) {
+            Some(data) => data,
+            None => &[],
+        }
+    }
+
+    /// Drops a segment.
+    pub fn drop_segment(&mut self, index: DataIndex) {
+        self.segments.remove(&index);
+    }
+}
+
+impl PassiveElements {
+    /// Creates the passive element store from the segments of a module.
+    pub fn new(segments: HashMap<ElemIndex, Box<[FuncIndex]>>) -> Self {
+        Self { segments }
+    }
+
+    /// Gets the functions of a segment.
+    /// Dropped segments and active segments are empty.
+    pub fn get(&self, index: ElemIndex) -> &[FuncIndex] {
+        match self.segments.get(&index) {
+            Some(elements) => elements,
+            None => &[],
+        }
+    }
+
+    /// Drops a segment.
+    pub fn drop_segment(&mut self, index: ElemIndex) {
+        self.segments.remove(&index);
+    }
+}
@@ -0,0 +1,73 @@
+//! Programs that are started on demand instead of at boot.
+//! Supervisors spawn them by file name using the kwast host module. Every spawn starts a new
+//! process, in the domain the manifest gives the program. Spawned programs are not registered by
+//! name, so other instances can't import from them.
+
+use crate::sync::spinlock::RwLock;
+use crate::tasking::process::ExitStatus;
+use crate::tasking::protection_domain::ProtectionDomain;
+use crate::util::manifest::ManifestEntry;
+use crate::wasm::main::{run, Error};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use lazy_static::lazy_static;
+
+/// A program that can be spawned.
+struct Program {
+    buffer: &'static [u8],
+    artifact: Option<&'static [u8]>,
+    domain: ProtectionDomain,
+    entry: ManifestEntry<'static>,
+}
+
+lazy_static! {
+    static ref PROGRAMS: RwLock<BTreeMap<&'static str, Program>> = RwLock::new(BTreeMap::new());
+}
+
+/// Registers a program, so it can be spawned by its file name.
+/// The arguments are the same as for `run`.
+/// Returns false if a program with the same file name is already registered.
+pub fn register(
+    buffer: &'static [u8],
+    artifact: Option<&'static [u8]>,
+    domain: ProtectionDomain,
+    entry: ManifestEntry<'static>,
+) -> bool {
+    let mut programs = PROGRAMS.write();
+    if programs.contains_key(entry.file) {
+        false
+    } else {
+        programs.insert(
+            entry.file,
+            Program {
+                buffer,
+                artifact,
+                domain,
+                entry,
+            },
+        );
+        true
+    }
+}
+
+/// Spawns a program by its file name.
+/// Returns `None` if there is no such program, otherwise the result of `run`.
+pub fn spawn(file: &str) -> Option<Result<Arc<ExitStatus>, Error>> {
+    // Don't hold the lock while the program is compiled.
+    let (buffer, artifact, domain, mut entry) = {
+        let programs = PROGRAMS.read();
+        let program = programs.get(file)?;
+        (
+            program.buffer,
+            program.artifact,
+            program.domain.clone(),
+            program.entry.clone(),
+        )
+    };
+
+    // Every spawn is a new instance, so the name can't refer to one of them. Registering it would
+    // also make the next spawn fail and keep the instance alive after the process exited.
+    entry.name = None;
+
+    Some(run(buffer, artifact, domain, &entry))
+}
@@ -1,56 +1,334 @@
-use crate::tasking::scheduler::with_current_thread;
-use crate::wasm::main::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
-use crate::wasm::vmctx::{VmContext, WASM_PAGE_SIZE};
-use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, Signature};
-use lazy_static::lazy_static;
-
-/// Runtime namespace for `ExternalName`.
-pub const RUNTIME_NAMESPACE: u32 = 1;
-pub const RUNTIME_MEMORY_GROW_IDX: u32 = 0;
-pub const RUNTIME_MEMORY_SIZE_IDX: u32 = 1;
-
-/// Runtime function data.
-pub struct RuntimeFunctionData {
-    pub index: u32,
-    pub signature: Signature,
-}
-
-lazy_static! {
-    pub static ref RUNTIME_MEMORY_GROW_DATA: RuntimeFunctionData = RuntimeFunctionData {
-        index: RUNTIME_MEMORY_GROW_IDX,
-        signature: Signature {
-            params: vec![
-                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
-                AbiParam::new(types::I32), // Memory index
-                AbiParam::new(types::I32), // Pages
-            ],
-            returns: vec![AbiParam::new(types::I32)],
-            call_conv: WASM_CALL_CONV,
-        },
-    };
-
-    pub static ref RUNTIME_MEMORY_SIZE_DATA: RuntimeFunctionData = RuntimeFunctionData {
-        index: RUNTIME_MEMORY_SIZE_IDX,
-        signature: Signature {
-            params: vec![
-                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
-                AbiParam::new(types::I32), // Memory index
-            ],
-            returns: vec![AbiParam::new(types::I32)],
-            call_conv: WASM_CALL_CONV,
-        },
-    };
+use crate::arch::hpet;
+use crate::sync::wait_table::WaitResult;
+use crate::tasking::process;
+use crate::tasking::scheduler::thread_exit;
+use crate::wasm::trap::COMPILE_ERROR_EXIT_CODE;
+use crate::wasm::vmctx::VmContext;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use cranelift_wasm::{DataIndex, ElemIndex, FuncIndex, TableIndex};
+use wasm_compiler::runtime::{RUNTIME_ATOMIC_TRAP, RUNTIME_OK, RUNTIME_TRAP};
+
+/// Checks if the range `[offset, offset + len)` fits in `size` bytes.
+fn in_bounds(offset: u32, len: u32, size: usize) -> bool {
+    offset as u64 + len as u64 <= size as u64
 }
 
 /// memory.size
-pub extern "C" fn runtime_memory_size(_vmctx: &VmContext, idx: u32) -> u32 {
-    assert_eq!(idx, 0);
-    let heap_size = with_current_thread(|thread| thread.heap_size());
-    (heap_size / WASM_PAGE_SIZE) as u32
+pub extern "C" fn runtime_memory_size(vmctx: &VmContext, idx: u32) -> u32 {
+    vmctx.memory(idx).pages()
 }
 
 /// memory.grow
-pub extern "C" fn runtime_memory_grow(_vmctx: &VmContext, idx: u32, wasm_pages: u32) -> u32 {
-    assert_eq!(idx, 0);
-    with_current_thread(|thread| thread.heap_grow(wasm_pages))
+pub extern "C" fn runtime_memory_grow(vmctx: &VmContext, idx: u32, wasm_pages: u32) -> u32 {
+    vmctx.memory(idx).grow(wasm_pages)
+}
+
+/// memory.copy
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_memory_copy(
+    vmctx: &VmContext,
+    idx: u32,
+    dst: u32,
+    src: u32,
+    len: u32,
+) -> u32 {
+    let memory = vmctx.memory(idx);
+    let heap_size = memory.size();
+    if !in_bounds(dst, len, heap_size) || !in_bounds(src, len, heap_size) {
+        return RUNTIME_TRAP;
+    }
+
+    // Safety: both ranges are inside the heap, which is mapped lazily on access.
+    unsafe {
+        ptr::copy(
+            (memory.address() + src as usize).as_const::<u8>(),
+            (memory.address() + dst as usize).as_mut::<u8>(),
+            len as usize,
+        );
+    }
+
+    RUNTIME_OK
+}
+
+/// memory.fill
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_memory_fill(
+    vmctx: &VmContext,
+    idx: u32,
+    dst: u32,
+    val: u32,
+    len: u32,
+) -> u32 {
+    let memory = vmctx.memory(idx);
+    let heap_size = memory.size();
+    if !in_bounds(dst, len, heap_size) {
+        return RUNTIME_TRAP;
+    }
+
+    // Safety: the range is inside the heap, which is mapped lazily on access.
+    unsafe {
+        ptr::write_bytes(
+            (memory.address() + dst as usize).as_mut::<u8>(),
+            val as u8,
+            len as usize,
+        );
+    }
+
+    RUNTIME_OK
+}
+
+/// memory.init
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_memory_init(
+    vmctx: &VmContext,
+    idx: u32,
+    seg_idx: u32,
+    dst: u32,
+    src: u32,
+    len: u32,
+) -> u32 {
+    let instance = vmctx.instance();
+    let memory = vmctx.memory(idx);
+    let heap_size = memory.size();
+    let passive_data = instance.passive_data.lock();
+    let segment = passive_data.get(DataIndex::from_u32(seg_idx));
+    if !in_bounds(dst, len, heap_size) || !in_bounds(src, len, segment.len()) {
+        return RUNTIME_TRAP;
+    }
+
+    // Safety: the range is inside the heap, which is mapped lazily on access.
+    unsafe {
+        ptr::copy_nonoverlapping(
+            segment[src as usize..].as_ptr(),
+            (memory.address() + dst as usize).as_mut::<u8>(),
+            len as usize,
+        );
+    }
+
+    RUNTIME_OK
+}
+
+/// Converts a wait timeout in nanoseconds to a deadline, a negative timeout means no timeout.
+fn wait_deadline(timeout: i64) -> Option<u64> {
+    if timeout < 0 {
+        return None;
+    }
+
+    // Without a Hpet, we can't time out.
+    hpet().map(|hpet| {
+        hpet.counter()
+            .saturating_add(hpet.ns_to_counter(timeout as u64))
+    })
+}
+
+/// Converts the result of a wait to a runtime function return value.
+fn wait_result(result: Option<WaitResult>) -> u32 {
+    match result {
+        Some(WaitResult::Woken) => 0,
+        Some(WaitResult::NotEqual) => 1,
+        // The thread is killed once it is back in wasm code.
+        Some(WaitResult::TimedOut) | Some(WaitResult::Interrupted) => 2,
+        None => RUNTIME_ATOMIC_TRAP,
+    }
+}
+
+/// memory.atomic.wait32
+/// Returns `RUNTIME_ATOMIC_TRAP` if the caller must trap.
+pub extern "C" fn runtime_memory_atomic_wait32(
+    vmctx: &VmContext,
+    idx: u32,
+    addr: u32,
+    expected: u32,
+    timeout: i64,
+) -> u32 {
+    wait_result(vmctx.memory(idx).atomic_wait(
+        addr,
+        4,
+        // Safety: the address is aligned, inside the memory, and mapped.
+        |address| unsafe { (*address.as_const::<AtomicU32>()).load(Ordering::SeqCst) == expected },
+        wait_deadline(timeout),
+    ))
+}
+
+/// memory.atomic.wait64
+/// Returns `RUNTIME_ATOMIC_TRAP` if the caller must trap.
+pub extern "C" fn runtime_memory_atomic_wait64(
+    vmctx: &VmContext,
+    idx: u32,
+    addr: u32,
+    expected: u64,
+    timeout: i64,
+) -> u32 {
+    wait_result(vmctx.memory(idx).atomic_wait(
+        addr,
+        8,
+        // Safety: the address is aligned, inside the memory, and mapped.
+        |address| unsafe { (*address.as_const::<AtomicU64>()).load(Ordering::SeqCst) == expected },
+        wait_deadline(timeout),
+    ))
+}
+
+/// memory.atomic.notify
+/// Returns the amount of woken up waiters, or `RUNTIME_ATOMIC_TRAP` if the caller must trap.
+pub extern "C" fn runtime_memory_atomic_notify(
+    vmctx: &VmContext,
+    idx: u32,
+    addr: u32,
+    count: u32,
+) -> u32 {
+    vmctx
+        .memory(idx)
+        .atomic_notify(addr, count)
+        .unwrap_or(RUNTIME_ATOMIC_TRAP)
+}
+
+/// data.drop
+pub extern "C" fn runtime_data_drop(vmctx: &VmContext, seg_idx: u32) {
+    vmctx
+        .instance()
+        .passive_data
+        .lock()
+        .drop_segment(DataIndex::from_u32(seg_idx));
+}
+
+/// Converts the result of a table operation to a runtime function return value.
+fn trap_if_none<T>(result: Option<T>) -> u32 {
+    match result {
+        Some(_) => RUNTIME_OK,
+        None => RUNTIME_TRAP,
+    }
+}
+
+/// table.grow
+/// Returns the old size, or -1 if the table can't grow.
+pub extern "C" fn runtime_table_grow(vmctx: &VmContext, idx: u32, delta: u32, init: u64) -> u32 {
+    vmctx
+        .instance()
+        .vmctx_container
+        .table_grow(TableIndex::from_u32(idx), delta, init)
+        .unwrap_or(core::u32::MAX)
+}
+
+/// table.get
+/// The index must already be checked by the caller.
+pub extern "C" fn runtime_table_get(vmctx: &VmContext, idx: u32, index: u32) -> u64 {
+    vmctx
+        .instance()
+        .vmctx_container
+        .table_get(TableIndex::from_u32(idx), index)
+        .unwrap_or(0)
+}
+
+/// table.set
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_table_set(vmctx: &VmContext, idx: u32, index: u32, value: u64) -> u32 {
+    trap_if_none(vmctx.instance().vmctx_container.table_set(
+        TableIndex::from_u32(idx),
+        index,
+        value,
+    ))
+}
+
+/// table.fill
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_table_fill(
+    vmctx: &VmContext,
+    idx: u32,
+    dst: u32,
+    value: u64,
+    len: u32,
+) -> u32 {
+    trap_if_none(vmctx.instance().vmctx_container.table_fill(
+        TableIndex::from_u32(idx),
+        dst,
+        value,
+        len,
+    ))
+}
+
+/// table.copy
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_table_copy(
+    vmctx: &VmContext,
+    dst_idx: u32,
+    src_idx: u32,
+    dst: u32,
+    src: u32,
+    len: u32,
+) -> u32 {
+    trap_if_none(vmctx.instance().vmctx_container.table_copy(
+        TableIndex::from_u32(dst_idx),
+        TableIndex::from_u32(src_idx),
+        dst,
+        src,
+        len,
+    ))
+}
+
+/// table.init
+/// Returns non-zero if the caller must trap.
+pub extern "C" fn runtime_table_init(
+    vmctx: &VmContext,
+    idx: u32,
+    seg_idx: u32,
+    dst: u32,
+    src: u32,
+    len: u32,
+) -> u32 {
+    let instance = vmctx.instance();
+    let passive_elements = instance.passive_elements.lock();
+    let segment = passive_elements.get(ElemIndex::from_u32(seg_idx));
+    trap_if_none(instance.vmctx_container.table_init(
+        TableIndex::from_u32(idx),
+        segment,
+        dst,
+        src,
+        len,
+    ))
+}
+
+/// elem.drop
+pub extern "C" fn runtime_elem_drop(vmctx: &VmContext, seg_idx: u32) {
+    vmctx
+        .instance()
+        .passive_elements
+        .lock()
+        .drop_segment(ElemIndex::from_u32(seg_idx));
+}
+
+/// ref.func
+pub extern "C" fn runtime_ref_func(vmctx: &VmContext, func_idx: u32) -> u64 {
+    vmctx
+        .instance()
+        .vmctx_container
+        .function_references()
+        .get(FuncIndex::from_u32(func_idx))
+        .reference
+}
+
+/// Compiles a function of a lazily compiled instance, called by the lazy compilation trampoline.
+/// Returns the address of the compiled code, the trampoline continues there.
+#[no_mangle]
+pub extern "C" fn runtime_lazy_compile(vmctx: &VmContext, func_idx: u32) -> usize {
+    let instance = vmctx.instance();
+    let lazy_code = instance
+        .lazy_code
+        .as_ref()
+        .expect("stubs only exist in lazily compiled instances");
+
+    let result = lazy_code.compile(instance, vmctx, FuncIndex::from_u32(func_idx));
+
+    // The bodies were validated when the module was loaded, so this only fails if code generation
+    // fails. The other threads can't continue without the function either, so the whole process
+    // exits.
+    match result {
+        Ok(address) => address.as_usize(),
+        Err(_) => {
+            if let Some(process) = process::current() {
+                process.exit(COMPILE_ERROR_EXIT_CODE);
+            }
+            thread_exit(COMPILE_ERROR_EXIT_CODE);
+        }
+    }
 }
@@ -0,0 +1,23 @@
+//! Signature registry.
+//! Every distinct signature gets a global index, so indirect calls can check signatures of
+//! functions from other instances.
+
+use crate::sync::spinlock::RwLock;
+use cranelift_codegen::ir::Signature;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref SIGNATURES: RwLock<HashMap<Signature, u32>> = RwLock::new(HashMap::new());
+}
+
+/// Gets the global index of a signature, registers the signature if it's new.
+pub fn register(signature: &Signature) -> u32 {
+    if let Some(index) = SIGNATURES.read().get(signature) {
+        return *index;
+    }
+
+    let mut signatures = SIGNATURES.write();
+    let next_index = signatures.len() as u32;
+    *signatures.entry(signature.clone()).or_insert(next_index)
+}
@@ -0,0 +1,70 @@
+//! Maps code addresses back to wasm functions, used for backtraces.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+use core::mem;
+use cranelift_wasm::FuncIndex;
+
+/// A defined function in the code area.
+pub struct FunctionSymbol {
+    /// Start offset in the code area.
+    pub start: usize,
+    /// End offset in the code area (exclusive).
+    pub end: usize,
+    /// Function index in the module.
+    pub index: FuncIndex,
+    /// Name from the name section, if any.
+    pub name: Option<Box<str>>,
+}
+
+/// Symbols of an instance, sorted by their offset in the code area.
+pub struct SymbolTable {
+    functions: Box<[FunctionSymbol]>,
+}
+
+impl SymbolTable {
+    /// Creates a symbol table from function symbols.
+    pub fn new(mut functions: Vec<FunctionSymbol>) -> Self {
+        functions.sort_unstable_by_key(|f| f.start);
+        Self {
+            functions: functions.into_boxed_slice(),
+        }
+    }
+
+    /// Adds a function symbol.
+    pub fn insert(&mut self, function: FunctionSymbol) {
+        let mut functions = mem::take(&mut self.functions).into_vec();
+        let index = functions
+            .binary_search_by_key(&function.start, |f| f.start)
+            .unwrap_or_else(|index| index);
+        functions.insert(index, function);
+        self.functions = functions.into_boxed_slice();
+    }
+
+    /// Looks up the function at an offset in the code area.
+    pub fn lookup(&self, code_offset: usize) -> Option<&FunctionSymbol> {
+        self.functions
+            .binary_search_by(|f| {
+                if code_offset < f.start {
+                    Ordering::Greater
+                } else if code_offset >= f.end {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()
+            .map(|i| &self.functions[i])
+    }
+}
+
+impl fmt::Display for FunctionSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "func[{}] <{}>", self.index.as_u32(), name),
+            None => write!(f, "func[{}]", self.index.as_u32()),
+        }
+    }
+}
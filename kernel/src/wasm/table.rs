@@ -1,34 +1,163 @@
 use crate::arch::address::VirtAddr;
+use crate::sync::spinlock::Spinlock;
 use crate::wasm::vmctx::{VmTable, VmTableElement};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use cranelift_wasm::TableElementType;
+use cranelift_wasm::{FuncIndex, TableElementType};
+use wasm_compiler::module_env::TableDecl;
+
+/// Implementation limit on the amount of elements in a table, avoids exhausting kernel memory.
+pub const MAX_TABLE_SIZE: u32 = 1 << 20;
+
+/// A table that can be shared between instances.
+pub type SharedTable = Arc<Spinlock<Table>>;
 
 /// A table, manages table data for the runtime.
 pub struct Table {
     vec: Vec<VmTableElement>,
+    ty: TableElementType,
+    maximum: u32,
+    /// The VmContext representation, the contexts of all instances using this table point here.
+    vm_table: VmTable,
+}
+
+/// Function references of an instance, indexed by function index.
+/// These are the table elements for `ref.func` and for element segments.
+pub struct FunctionReferences {
+    elements: Box<[VmTableElement]>,
 }
 
 impl Table {
-    /// Creates a new table.
-    pub fn new(table: &cranelift_wasm::Table) -> Self {
-        let vec = match table.ty {
-            TableElementType::Func => vec![VmTableElement::null(); table.minimum as usize],
-            TableElementType::Val(_) => unimplemented!("other type than anyfunc"),
+    /// Creates a new shared table.
+    pub fn new_shared(table: &TableDecl) -> SharedTable {
+        let vec = vec![VmTableElement::null(); table.minimum as usize];
+        let vm_table = VmTable {
+            base_address: VirtAddr::new(vec.as_ptr() as usize),
+            amount_items: vec.len() as u32,
         };
 
-        Self { vec }
+        Arc::new(Spinlock::new(Self {
+            vec,
+            ty: table.ty,
+            maximum: table
+                .maximum
+                .map_or(MAX_TABLE_SIZE, |x| x.min(MAX_TABLE_SIZE)),
+            vm_table,
+        }))
+    }
+
+    /// Current amount of elements.
+    pub fn size(&self) -> u32 {
+        self.vec.len() as u32
+    }
+
+    /// Amount of elements the table can grow up to.
+    pub fn maximum(&self) -> u32 {
+        self.maximum
+    }
+
+    /// Gets the element type.
+    pub fn ty(&self) -> TableElementType {
+        self.ty
+    }
+
+    /// Gets a table element.
+    /// Returns `None` if the offset is out of bounds.
+    pub fn get(&self, offset: usize) -> Option<VmTableElement> {
+        self.vec.get(offset).copied()
     }
 
     /// Sets a table element.
-    pub fn set(&mut self, offset: usize, value: VmTableElement) {
-        self.vec[offset] = value;
+    /// Returns `None` if the offset is out of bounds.
+    pub fn set(&mut self, offset: usize, value: VmTableElement) -> Option<()> {
+        let element = self.vec.get_mut(offset)?;
+        *element = value;
+        Some(())
+    }
+
+    /// Gets a range of table elements.
+    /// Returns `None` if the range is out of bounds.
+    pub fn get_range(&self, offset: usize, len: usize) -> Option<&[VmTableElement]> {
+        self.vec.get(offset..offset.checked_add(len)?)
+    }
+
+    /// Sets a range of table elements.
+    /// Returns `None` if the range is out of bounds.
+    pub fn set_range(&mut self, offset: usize, values: &[VmTableElement]) -> Option<()> {
+        let range = self
+            .vec
+            .get_mut(offset..offset.checked_add(values.len())?)?;
+        range.copy_from_slice(values);
+        Some(())
+    }
+
+    /// Fills a range of table elements with the same value.
+    /// Returns `None` if the range is out of bounds.
+    pub fn fill(&mut self, offset: usize, value: VmTableElement, len: usize) -> Option<()> {
+        let range = self.vec.get_mut(offset..offset.checked_add(len)?)?;
+        for element in range {
+            *element = value;
+        }
+        Some(())
     }
 
-    /// Gets the VmContext representation
-    pub fn as_vm_table(&self) -> VmTable {
-        VmTable {
+    /// Grows the table by `delta` elements, initialized to `value`.
+    /// Returns the old size, or `None` if the table can't grow that much.
+    pub fn grow(&mut self, delta: u32, value: VmTableElement) -> Option<u32> {
+        let old_size = self.size();
+        let new_size = old_size.checked_add(delta)?;
+        if new_size > self.maximum {
+            return None;
+        }
+
+        self.vec.resize(new_size as usize, value);
+
+        // The elements might have moved.
+        self.vm_table = VmTable {
             base_address: VirtAddr::new(self.vec.as_ptr() as usize),
             amount_items: self.vec.len() as u32,
+        };
+
+        Some(old_size)
+    }
+
+    /// Converts a reference value to a table element for this table.
+    pub fn element_from_reference(&self, reference: u64) -> VmTableElement {
+        match self.ty {
+            // Safety: wasm code can only get function references from `FunctionReferences`.
+            TableElementType::Func => unsafe { VmTableElement::from_func_ref(reference) },
+            TableElementType::Val(_) => VmTableElement::extern_ref(reference),
+        }
+    }
+
+    /// Gets the pointer to the VmContext representation.
+    /// The pointer stays valid as long as the table lives.
+    pub fn vm_table_ptr(&self) -> *const VmTable {
+        &self.vm_table
+    }
+}
+
+impl FunctionReferences {
+    /// Creates the function references from the table elements of all functions.
+    pub fn new(elements: Vec<VmTableElement>) -> Self {
+        let mut elements = elements.into_boxed_slice();
+
+        // The reference value of a function is the address of its element, which never moves.
+        // That way a reference can be used by every instance that shares a table.
+        for element in elements.iter_mut() {
+            element.reference = element as *const VmTableElement as u64;
         }
+
+        Self { elements }
+    }
+
+    /// Gets the table element of a function.
+    /// The reserved function index is used for null references in element segments.
+    pub fn get(&self, index: FuncIndex) -> VmTableElement {
+        self.elements
+            .get(index.as_u32() as usize)
+            .copied()
+            .unwrap_or_else(VmTableElement::null)
     }
 }
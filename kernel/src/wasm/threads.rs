@@ -0,0 +1,176 @@
+//! Threads spawned by wasm code, following the wasi-threads proposal.
+//! A spawned thread runs in the same domain as the thread that spawned it, and shares the code,
+//! tables and memories of the instance. It has its own stack and its own copy of the VmContext,
+//! so it has its own globals, like the stack pointer of the program.
+//! The thread starts at the exported `wasi_thread_start(tid: i32, start_arg: i32)` function.
+//! Spawned threads belong to the process of the thread that spawned them.
+
+use crate::arch::address::VirtAddr;
+use crate::tasking::process;
+use crate::tasking::scheduler::{add_and_schedule_thread, thread_exit, with_current_thread};
+use crate::tasking::thread::{StaticWasmThreadData, Thread, ThreadId};
+use crate::wasm::epoch;
+use crate::wasm::host_modules::HostFunctionMap;
+use crate::wasm::signatures;
+use crate::wasm::trap::KILLED_EXIT_CODE;
+use crate::wasm::vmctx::VmContext;
+use crate::wasm::wasi::Errno;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::mem;
+use core::ptr;
+use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, Signature};
+use lazy_static::lazy_static;
+use wasm_compiler::module_env::Export;
+use wasm_compiler::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
+
+/// Name of the exported function spawned threads start at.
+const THREAD_START: &str = "wasi_thread_start";
+
+/// Data handed over to a spawned thread.
+struct SpawnData {
+    /// Address of the start function.
+    start: VirtAddr,
+    start_arg: u32,
+    wasm_data: StaticWasmThreadData,
+}
+
+lazy_static! {
+    static ref HOST_FUNCTIONS: HostFunctionMap = {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "thread-spawn",
+            (
+                VirtAddr::new(thread_spawn as usize),
+                Signature {
+                    params: vec![
+                        AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                        AbiParam::new(types::I32),
+                    ],
+                    returns: vec![AbiParam::new(types::I32)],
+                    call_conv: WASM_CALL_CONV,
+                },
+            ),
+        );
+        map
+    };
+}
+
+/// Signature of the start function: `(tid: i32, start_arg: i32)`.
+fn thread_start_signature() -> Signature {
+    Signature {
+        params: vec![
+            AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+            AbiParam::new(types::I32),
+            AbiParam::new(types::I32),
+        ],
+        returns: vec![],
+        call_conv: WASM_CALL_CONV,
+    }
+}
+
+/// thread-spawn, returns the id of the new thread, or a negative errno on failure.
+extern "C" fn thread_spawn(vmctx: &VmContext, start_arg: u32) -> i32 {
+    match spawn(vmctx, start_arg) {
+        Ok(tid) => tid.as_u32() as i32,
+        Err(e) => -(e as i32),
+    }
+}
+
+/// Spawns a thread running the instance of the calling context.
+fn spawn(vmctx: &VmContext, start_arg: u32) -> Result<ThreadId, Errno> {
+    let process = process::current().ok_or(Errno::Again)?;
+    let instance = process.instance();
+
+    // Only the instance of the process can spawn threads, not the instances it imports from.
+    if !ptr::eq(vmctx.instance(), &**instance) {
+        return Err(Errno::NotSup);
+    }
+
+    let start = match instance.export(THREAD_START) {
+        Some(Export::Function(idx)) => instance.vmctx_container.function_references().get(idx),
+        _ => return Err(Errno::NoEnt),
+    };
+    if start.sig_idx != signatures::register(&thread_start_signature()) as u64 {
+        return Err(Errno::Inval);
+    }
+
+    // Safety: these are the globals of the instance, and the thread keeps the instance alive.
+    let vmctx_container = unsafe { instance.vmctx_container.duplicate(&instance.globals) };
+    if let Some(ref budget) = instance.fuel {
+        vmctx_container.set_fuel(budget.fuel());
+    }
+    if let Some(ref interruption) = instance.epoch {
+        vmctx_container.set_epoch_deadline(epoch::next_deadline(interruption));
+    }
+
+    let domain = instance.domain.clone();
+    let spawn_data = Box::into_raw(Box::new(SpawnData {
+        start: start.address,
+        start_arg,
+        wasm_data: StaticWasmThreadData {
+            vmctx_container: Some(vmctx_container),
+            process: process.clone(),
+        },
+    }));
+
+    // Safety: valid and correct entry point.
+    match unsafe {
+        Thread::create(
+            domain,
+            VirtAddr::new(start_spawned_thread as usize),
+            spawn_data as usize,
+        )
+    } {
+        Ok(thread) => {
+            // Add the thread before it can run, so the process can't exit in between.
+            let tid = thread.id;
+            process.add_thread(tid);
+            add_and_schedule_thread(thread);
+            Ok(tid)
+        }
+        Err(_) => {
+            // Safety: the data was not handed over.
+            drop(unsafe { Box::from_raw(spawn_data) });
+            Err(Errno::Again)
+        }
+    }
+}
+
+/// Entry point of a spawned thread.
+extern "C" fn start_spawned_thread(spawn_data: *mut SpawnData) {
+    // Safety: the spawning thread handed the data over to us.
+    let spawn_data = unsafe { Box::from_raw(spawn_data) };
+    let SpawnData {
+        start,
+        start_arg,
+        wasm_data,
+    } = *spawn_data;
+
+    // The context is allocated separately, it does not move with the wasm data.
+    let vmctx = wasm_data.vmctx_container().ptr();
+    let func: extern "C" fn(*const VmContext, i32, i32) =
+        unsafe { mem::transmute(start.as_usize()) };
+
+    let tid = with_current_thread(|thread| {
+        // Safety: this is a new thread without existing wasm data.
+        unsafe {
+            thread.set_wasm_data(wasm_data);
+        }
+        thread.id
+    });
+
+    // The process may have exited before we could be killed, see `Process::exit`.
+    if process::current().map_or(false, |process| process.is_exiting()) {
+        thread_exit(KILLED_EXIT_CODE);
+    }
+
+    func(vmctx, tid.as_u32() as i32, start_arg as i32);
+
+    thread_exit(0);
+}
+
+/// Gets the functions of this host module.
+pub fn host_functions() -> &'static HostFunctionMap {
+    &HOST_FUNCTIONS
+}
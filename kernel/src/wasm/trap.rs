@@ -0,0 +1,253 @@
+//! WebAssembly traps.
+//! Faults in wasm code are mapped back to a `TrapCode` using the trap sites recorded by the compiler.
+//! Out-of-fuel traps and epoch deadline traps can be resumed, see `fuel` and `epoch`.
+
+use crate::arch::address::VirtAddr;
+use crate::mm::vma_allocator::MappableVma;
+use crate::tasking::scheduler::{thread_exit, thread_yield, with_current_thread};
+use crate::tasking::thread::Thread;
+use crate::wasm::epoch;
+use crate::wasm::fuel;
+use crate::wasm::instance::Instance;
+use crate::wasm::vmctx::VmContextContainer;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::{self, size_of};
+use cranelift_codegen::ir::TrapCode;
+use wasm_compiler::trap::{TrapSite, TRAP_EPOCH_DEADLINE, TRAP_OUT_OF_FUEL};
+
+/// Maximum amount of frames in a backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+/// Size of the instruction Cranelift emits for traps, `ud2`.
+const TRAP_INSTRUCTION_SIZE: usize = 2;
+
+/// Exit codes starting from this value are reserved for traps.
+pub const TRAP_EXIT_CODE_BASE: u32 = 0xFFFF_FF00;
+
+/// Exit code of a process that could not be instantiated.
+pub const INSTANTIATION_ERROR_EXIT_CODE: u32 = TRAP_EXIT_CODE_BASE + 0xFC;
+
+/// Exit code of a thread that was killed by another thread.
+pub const KILLED_EXIT_CODE: u32 = TRAP_EXIT_CODE_BASE + 0xFD;
+
+/// Exit code of a thread that called a lazily compiled function which could not be compiled.
+pub const COMPILE_ERROR_EXIT_CODE: u32 = TRAP_EXIT_CODE_BASE + 0xFE;
+
+/// What a thread does after a resumable trap.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrapAction {
+    /// Continue right away.
+    Continue,
+    /// Yield, continue afterwards.
+    Yield,
+    /// Terminate because of the trap.
+    Trap,
+    /// Exit with an exit code, without reporting a trap.
+    Exit(u32),
+}
+
+/// Trap sites of an instance, sorted by their offset in the code area.
+pub struct TrapTable {
+    sites: Box<[TrapSite]>,
+}
+
+impl TrapTable {
+    /// Creates a trap table from trap sites with offsets relative to the code area.
+    pub fn new(mut sites: Vec<TrapSite>) -> Self {
+        sites.sort_unstable_by_key(|site| site.code_offset);
+        Self {
+            sites: sites.into_boxed_slice(),
+        }
+    }
+
+    /// Adds trap sites with offsets relative to the code area.
+    pub fn extend<I: Iterator<Item = TrapSite>>(&mut self, sites: I) {
+        let mut all = mem::take(&mut self.sites).into_vec();
+        all.extend(sites);
+        *self = Self::new(all);
+    }
+
+    /// Looks up the trap code at an offset in the code area.
+    pub fn lookup(&self, code_offset: usize) -> Option<TrapCode> {
+        self.sites
+            .binary_search_by_key(&code_offset, |site| site.code_offset as usize)
+            .ok()
+            .map(|i| self.sites[i].trap_code)
+    }
+}
+
+/// Gets the exit code for a trap.
+pub fn exit_code_for_trap(trap_code: TrapCode) -> u32 {
+    TRAP_EXIT_CODE_BASE
+        + match trap_code {
+            TrapCode::StackOverflow => 0,
+            TrapCode::HeapOutOfBounds => 1,
+            TrapCode::TableOutOfBounds => 2,
+            TrapCode::IndirectCallToNull => 3,
+            TrapCode::BadSignature => 4,
+            TrapCode::IntegerOverflow => 5,
+            TrapCode::IntegerDivisionByZero => 6,
+            TrapCode::BadConversionToInteger => 7,
+            TrapCode::UnreachableCodeReached => 8,
+            TrapCode::Interrupt => 9,
+            TRAP_OUT_OF_FUEL => 10,
+            _ => 0xFF,
+        }
+}
+
+/// Terminates the current thread because of a trap.
+/// `ip` and `fp` are the instruction pointer and frame pointer at the moment of the trap,
+/// they are used to print a backtrace.
+pub fn terminate(trap_code: TrapCode, ip: VirtAddr, fp: VirtAddr) -> ! {
+    println!("wasm trap: {:?}, thread killed", trap_code);
+    print_backtrace(ip, fp);
+    thread_exit(exit_code_for_trap(trap_code));
+}
+
+/// Handles a fault at instruction pointer `ip` with frame pointer `fp`.
+/// If the fault was caused by wasm code of the current thread, the thread is terminated.
+/// `fallback` is used if the instruction is not a known trap site.
+/// Returns if the fault was not caused by wasm code, the caller should treat it as a kernel fault.
+pub fn handle_fault(ip: VirtAddr, fp: VirtAddr, fallback: TrapCode) {
+    let trap_code = with_current_thread(|thread| {
+        thread.try_with_wasm_data(|data| {
+            // The code can belong to an instance we import from.
+            let instance = data.instance().find_code(ip)?;
+            let offset = instance.code_offset(ip)?;
+            Some(
+                instance
+                    .trap_table
+                    .read()
+                    .lookup(offset)
+                    .unwrap_or(fallback),
+            )
+        })
+    });
+
+    if let Some(Some(trap_code)) = trap_code {
+        terminate(trap_code, ip, fp);
+    }
+}
+
+/// Executes something with the instance, the context the thread uses for that instance and the
+/// trap code of the trap site at `ip`, if it is a trap site in wasm code of the current thread.
+fn with_trap_site<F, T>(ip: VirtAddr, f: F) -> Option<T>
+where
+    F: FnOnce(&Thread, &Instance, &VmContextContainer, TrapCode) -> Option<T>,
+{
+    with_current_thread(|thread| {
+        thread.try_with_wasm_data(|data| {
+            let instance = data.instance().find_code(ip)?;
+            let offset = instance.code_offset(ip)?;
+            let trap_code = instance.trap_table.read().lookup(offset)?;
+            f(
+                thread,
+                instance,
+                data.vmctx_container_of(instance),
+                trap_code,
+            )
+        })
+    })
+    .flatten()
+}
+
+/// Handles a trap instruction at instruction pointer `ip` with frame pointer `fp`.
+/// If the trap is resumable and the thread may continue, returns the address to continue at.
+/// Otherwise this is the same as `handle_fault`.
+pub fn handle_trap_instruction(ip: VirtAddr, fp: VirtAddr) -> Option<VirtAddr> {
+    let action = with_trap_site(ip, |thread, instance, _vmctx_container, trap_code| {
+        let action = match trap_code {
+            TRAP_OUT_OF_FUEL => fuel::out_of_fuel(instance),
+            TRAP_EPOCH_DEADLINE => epoch::deadline_reached(thread, instance),
+            _ => return None,
+        };
+        Some((trap_code, action))
+    });
+
+    // Yield and exit outside of the closures, the scheduler can't be used in there.
+    match action {
+        Some((trap_code, TrapAction::Trap)) => terminate(trap_code, ip, fp),
+        Some((_, TrapAction::Exit(exit_code))) => thread_exit(exit_code),
+        Some((_, action)) => {
+            if action == TrapAction::Yield {
+                thread_yield();
+            }
+
+            // Renew after yielding, so the time the thread waited doesn't count.
+            with_trap_site(ip, |thread, instance, vmctx_container, trap_code| {
+                match trap_code {
+                    TRAP_OUT_OF_FUEL => fuel::refill(instance, vmctx_container),
+                    TRAP_EPOCH_DEADLINE => epoch::renew_deadline(thread, instance, vmctx_container),
+                    _ => {}
+                }
+                Some(())
+            });
+
+            Some(ip + TRAP_INSTRUCTION_SIZE)
+        }
+        None => {
+            handle_fault(ip, fp, TrapCode::UnreachableCodeReached);
+            None
+        }
+    }
+}
+
+/// Prints a backtrace of the wasm code of the current thread by walking the frame pointer chain.
+/// Stops at the first frame that doesn't belong to wasm code.
+fn print_backtrace(ip: VirtAddr, fp: VirtAddr) {
+    with_current_thread(|thread| {
+        thread.try_with_wasm_data(|data| {
+            let print_frame = |nr: usize, addr: VirtAddr| -> bool {
+                let instance = match data.instance().find_code(addr) {
+                    Some(instance) => instance,
+                    None => return false,
+                };
+
+                let offset = addr.as_usize() - instance.code.address().as_usize();
+                match instance.symbol_table.read().lookup(offset) {
+                    Some(symbol) => println!(
+                        "  #{} {:?} {}+{:#x}",
+                        nr,
+                        addr,
+                        symbol,
+                        offset - symbol.start
+                    ),
+                    None => println!("  #{} {:?} <unknown>", nr, addr),
+                }
+
+                true
+            };
+
+            println!("Backtrace:");
+            let mut nr = 0;
+            if print_frame(nr, ip) {
+                nr += 1;
+            }
+
+            // Each frame starts with the saved frame pointer of the caller, followed by the return address.
+            let mut fp = fp;
+            while nr < MAX_BACKTRACE_FRAMES
+                && thread.stack.is_in_mapped_range(fp, 2 * size_of::<usize>())
+            {
+                // Safety: the frame is inside the mapped stack of this thread.
+                let (next_fp, return_address) = unsafe {
+                    let ptr = fp.as_const::<usize>();
+                    (ptr.read(), ptr.add(1).read())
+                };
+
+                if !print_frame(nr, VirtAddr::new(return_address)) {
+                    break;
+                }
+
+                // The stack grows down, so callers must have a higher frame pointer.
+                if next_fp <= fp.as_usize() {
+                    break;
+                }
+
+                fp = VirtAddr::new(next_fp);
+                nr += 1;
+            }
+        })
+    });
+}
@@ -1,18 +1,34 @@
 use crate::arch::address::VirtAddr;
-use crate::arch::paging::PAGE_SIZE;
-use crate::wasm::table::Table;
+use crate::wasm::epoch::{EPOCH, NO_DEADLINE};
+use crate::wasm::instance::Instance;
+use crate::wasm::memory::Memory;
+use crate::wasm::table::{FunctionReferences, SharedTable};
 use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::alloc::Layout;
-use core::mem::{align_of, size_of};
+use core::cmp::min;
+use core::mem::align_of;
+use core::ptr::copy_nonoverlapping;
 use core::slice;
-use cranelift_wasm::{Global, GlobalInit, SignatureIndex, TableIndex};
+use core::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use cranelift_wasm::{FuncIndex, GlobalInit, TableIndex};
+use wasm_compiler::module_env::GlobalDecl;
+use wasm_compiler::vmctx::{
+    VmContextLayout, FUNCTION_IMPORT_ENTRY_SIZE, GLOBAL_SIZE, TABLE_ELEMENT_SIZE,
+};
 
-pub const WASM_PAGE_SIZE: usize = 64 * 1024;
+pub use wasm_compiler::vmctx::{memory_style, HEAP_GUARD_SIZE, HEAP_SIZE, WASM_PAGE_SIZE};
 
-pub const HEAP_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
-
-pub const HEAP_GUARD_SIZE: u64 = PAGE_SIZE as u64;
+/// Memory representation as it is for the VmContext.
+#[repr(C)]
+#[derive(Debug)]
+pub struct VmMemory {
+    /// Base address, doesn't change when the memory grows.
+    pub base_address: VirtAddr,
+    /// Current size in bytes, code checks accesses to dynamic memories against this.
+    pub current_length: AtomicU64,
+}
 
 /// Table representation as it is for the VmContext.
 #[repr(C)]
@@ -29,70 +45,45 @@ pub struct VmTable {
 #[derive(Debug, Copy, Clone)]
 pub struct VmTableElement {
     pub address: VirtAddr,
+    /// Global signature index, see the signature registry.
     pub sig_idx: u64,
+    /// Context to call the function with, functions can belong to other instances.
+    pub vmctx: VirtAddr,
+    /// Reference value as seen by wasm code, zero for null.
+    /// For functions this is the address of the element in the function references of
+    /// the instance, for externref this is the host value.
+    pub reference: u64,
 }
 
 #[repr(C)]
 pub struct VmFunctionImportEntry {
     pub address: VirtAddr,
+    /// Context to call the function with.
+    pub vmctx: VirtAddr,
 }
 
 /// Context for a Wasm execution.
-/// Layout of the VmContext:
-///
-/// -----------------------------
-/// |       Heap pointer        |
-/// -----------------------------
-/// |        all globals        |
-/// -----------------------------
-/// | all VmFunctionImportEntry |
-/// -----------------------------
-/// |       all VmTable         |
-/// -----------------------------
-///
+/// This is a variable size struct, see `VmContextLayout` for the full layout.
+/// Memories and tables are owned by `Memory` and `Table`, which can be shared between instances.
 #[repr(C, align(16))]
 pub struct VmContext {
-    // Note: Variable size struct, heap pointer provided for convenience.
-    pub heap_ptr: VirtAddr,
+    // Note: Variable size struct, instance pointer provided for convenience.
+    instance_ptr: VirtAddr,
 }
 
-// All globals have the same size right now.
-// TODO: make sure not all globals take the same amount of bytes
-type VmGlobal = [u8; 8];
+type VmGlobal = [u8; GLOBAL_SIZE];
+
+// The generated code accesses these structures using the offsets in `wasm_compiler::vmctx`,
+// the field order must match those.
+assert_eq_size!(VmTableElement, [u8; TABLE_ELEMENT_SIZE]);
+assert_eq_size!(VmFunctionImportEntry, [u8; FUNCTION_IMPORT_ENTRY_SIZE]);
 
 pub struct VmContextContainer {
     ptr: VirtAddr,
-    num_imported_funcs: u32,
-    num_globals: u32,
-    tables: Vec<Table>,
-}
-
-impl VmTableElement {
-    /// Offset of the field `address`.
-    #[inline]
-    pub fn address_offset() -> i32 {
-        offset_of!(Self, address) as i32
-    }
-
-    /// Offset of the field `sig_idx`.
-    #[inline]
-    pub fn sig_idx_offset() -> i32 {
-        offset_of!(Self, sig_idx) as i32
-    }
-}
-
-impl VmTable {
-    /// Offset of the field `base_address`.
-    #[inline]
-    pub fn base_address_offset() -> i32 {
-        offset_of!(Self, base_address) as i32
-    }
-
-    /// Offset of the field `amount_items`.
-    #[inline]
-    pub fn amount_items_offset() -> i32 {
-        offset_of!(Self, amount_items) as i32
-    }
+    layout: VmContextLayout,
+    num_imported_globals: u32,
+    tables: Vec<SharedTable>,
+    functions: FunctionReferences,
 }
 
 impl VmTableElement {
@@ -101,95 +92,201 @@ impl VmTableElement {
         Self {
             address: VirtAddr::null(),
             sig_idx: core::u64::MAX, // Important: check func_env
+            vmctx: VirtAddr::null(),
+            reference: 0,
         }
     }
 
-    /// Creates a new table element.
-    pub fn new(address: VirtAddr, sig_idx: SignatureIndex) -> Self {
+    /// Creates a new table element for a function.
+    /// The reference value is set when the element gets its final place.
+    pub fn new(address: VirtAddr, sig_idx: u32, vmctx: VirtAddr) -> Self {
         Self {
             address,
-            sig_idx: sig_idx.as_u32() as u64,
+            sig_idx: sig_idx as u64,
+            vmctx,
+            reference: 0,
         }
     }
-}
-
-impl VmContext {
-    /// Heap offset in the context.
-    pub fn heap_offset() -> i32 {
-        offset_of!(VmContext, heap_ptr) as i32
-    }
 
-    /// Heap offset field size.
-    pub const fn heap_offset_field_size() -> i32 {
-        size_of::<VirtAddr>() as i32
-    }
-
-    /// Offset of the globals.
-    pub fn globals_offset() -> i32 {
-        Self::heap_offset() + Self::heap_offset_field_size()
-    }
-
-    /// Offset of a global entry.
-    pub fn global_entry_offset(index: u32) -> isize {
-        Self::globals_offset() as isize + (size_of::<VmGlobal>() * index as usize) as isize
+    /// Creates a new table element for an externref.
+    /// Can't be called, the signature is the same as for null.
+    pub fn extern_ref(reference: u64) -> Self {
+        Self {
+            reference,
+            ..Self::null()
+        }
     }
 
-    /// Offset of imported functions.
-    pub fn imported_funcs_offset(num_globals: u32) -> isize {
-        Self::global_entry_offset(num_globals)
+    /// Gets the table element of a function reference value.
+    /// Unsafe because the reference must be null or come from `FunctionReferences`.
+    pub unsafe fn from_func_ref(reference: u64) -> Self {
+        match reference {
+            0 => Self::null(),
+            reference => (reference as *const Self).read(),
+        }
     }
+}
 
-    /// Offset of an imported function entry.
-    pub fn imported_func_entry_offset(num_globals: u32, index: u32) -> isize {
-        Self::imported_funcs_offset(num_globals) as isize
-            + (size_of::<VmFunctionImportEntry>() * index as usize) as isize
+impl VmContext {
+    /// Gets the instance this context belongs to.
+    pub fn instance(&self) -> &Instance {
+        // Safety: the instance is set before any code runs, and the instance owns this context.
+        unsafe { &*self.instance_ptr.as_const::<Instance>() }
     }
 
-    /// Offset of the tables.
-    pub fn tables_offset(num_globals: u32, num_imported_funcs: u32) -> isize {
-        Self::imported_func_entry_offset(num_globals, num_imported_funcs)
+    /// Gets a memory of the instance this context belongs to.
+    pub fn memory(&self, idx: u32) -> &Memory {
+        &self.instance().memories[idx as usize]
     }
 
-    /// Offset of a table.
-    pub fn table_entry_offset(num_globals: u32, num_imported_funcs: u32, index: u32) -> isize {
-        Self::tables_offset(num_globals, num_imported_funcs)
-            + (index as usize * size_of::<VmTable>()) as isize
+    /// Sets the lazy function slot of a defined function, see `VmContextContainer::set_lazy_slot`.
+    /// Threads can have their own copy of the context, this sets the slot in this copy.
+    pub fn set_lazy_slot(&self, index: u32, address: VirtAddr) {
+        let layout = &self.instance().vmctx_container.layout;
+        // Safety: all contexts of an instance have the same layout.
+        unsafe {
+            store_lazy_slot(
+                VirtAddr::new(self as *const Self as usize),
+                layout,
+                index,
+                address,
+            );
+        }
     }
+}
 
-    /// Calculates the size of the context.
-    pub fn size(num_globals: u32, num_imported_funcs: u32, num_tables: u32) -> usize {
-        Self::table_entry_offset(num_globals, num_imported_funcs, num_tables) as usize
-    }
+/// Stores the address in the lazy function slot of a context.
+/// Unsafe because the context must have the given layout.
+#[allow(clippy::cast_ptr_alignment)]
+unsafe fn store_lazy_slot(ptr: VirtAddr, layout: &VmContextLayout, index: u32, address: VirtAddr) {
+    assert!(index < layout.num_lazy_funcs());
+
+    // Other threads may be jumping through the slot, so it must be written atomically.
+    let slot_ptr = ptr
+        .as_mut::<u8>()
+        .offset(layout.lazy_func_slot_offset(index)) as *const AtomicUsize;
+    (*slot_ptr).store(address.as_usize(), Ordering::Release);
 }
 
 #[allow(clippy::cast_ptr_alignment)]
 impl VmContextContainer {
     /// Creates a new container for a VmContext.
+    /// The memories and tables contain the imported ones first.
+    /// The signature ids are the global signature indices of the signatures of the module.
+    /// The lazy slots are the initial values of the lazy function slots, empty if the module is
+    /// not lazily compiled.
     pub unsafe fn new(
-        heap: VirtAddr,
+        memories: &[Arc<Memory>],
         num_globals: u32,
+        num_imported_globals: u32,
         num_imported_funcs: u32,
-        tables: Vec<Table>,
+        tables: Vec<SharedTable>,
+        sig_ids: &[u32],
+        lazy_slots: &[VirtAddr],
     ) -> Self {
+        let layout = VmContextLayout::new(
+            num_globals,
+            num_imported_funcs,
+            memories.len() as u32,
+            tables.len() as u32,
+            sig_ids.len() as u32,
+        )
+        .with_lazy_funcs(lazy_slots.len() as u32);
+
         // Allocate the memory for the VmContext.
-        let layout = Self::layout(num_globals, num_imported_funcs, tables.len() as u32);
-        let ptr = alloc(layout);
+        let alloc_layout = Self::alloc_layout(&layout);
+        let ptr = alloc(alloc_layout);
         if ptr.is_null() {
-            handle_alloc_error(layout);
+            handle_alloc_error(alloc_layout);
+        }
+
+        // Table elements refer to this context, copies for other threads keep pointing here.
+        let primary_ptr =
+            ptr.offset(VmContextLayout::primary_vmctx_offset() as isize) as *mut *const u8;
+        *primary_ptr = ptr;
+
+        // Only code compiled with fuel metering uses this, it is set when the budget is known.
+        let fuel_ptr = ptr.offset(VmContextLayout::fuel_offset() as isize) as *mut i64;
+        *fuel_ptr = 0;
+
+        // Only code compiled with epoch interruption uses these.
+        let epoch_ptr =
+            ptr.offset(VmContextLayout::epoch_ptr_offset() as isize) as *mut *const AtomicU64;
+        *epoch_ptr = &EPOCH;
+        let epoch_deadline_ptr =
+            ptr.offset(VmContextLayout::epoch_deadline_offset() as isize) as *mut u64;
+        *epoch_deadline_ptr = NO_DEADLINE;
+
+        // The memories keep their VmContext representation boxed, so we can point to it.
+        for (i, memory) in memories.iter().enumerate() {
+            let memory_ptr =
+                ptr.offset(layout.memory_entry_offset(i as u32)) as *mut *const VmMemory;
+            *memory_ptr = memory.vm_memory_ptr();
+        }
+
+        // The tables don't move inside their shared container, so we can point to them.
+        for (i, table) in tables.iter().enumerate() {
+            let table_ptr = ptr.offset(layout.table_entry_offset(i as u32)) as *mut *const VmTable;
+            *table_ptr = table.lock().vm_table_ptr();
         }
 
-        // Set the heap pointer here already.
-        let heap_ptr = ptr.offset(VmContext::heap_offset() as isize) as *mut VirtAddr;
-        *heap_ptr = heap;
+        // Indirect calls compare against these.
+        for (i, sig_id) in sig_ids.iter().enumerate() {
+            let sig_id_ptr = ptr.offset(layout.signature_id_offset(i as u32)) as *mut u64;
+            *sig_id_ptr = *sig_id as u64;
+        }
+
+        // The stubs of lazily compiled functions jump to these.
+        for (i, address) in lazy_slots.iter().enumerate() {
+            let slot_ptr = ptr.offset(layout.lazy_func_slot_offset(i as u32)) as *mut VirtAddr;
+            *slot_ptr = *address;
+        }
 
         Self {
             ptr: VirtAddr::from(ptr),
-            num_imported_funcs,
-            num_globals,
+            layout,
+            num_imported_globals,
             tables,
+            functions: FunctionReferences::new(Vec::new()),
         }
     }
 
+    /// Creates a copy of this context for another thread running the same instance.
+    /// The copy shares everything except for the fuel, epoch deadline and defined globals,
+    /// the defined globals are reset to their initial values.
+    /// Unsafe because the globals must be the globals of the instance, and the instance must
+    /// outlive the copy.
+    pub unsafe fn duplicate(&self, globals: &[GlobalDecl]) -> Self {
+        let alloc_layout = Self::alloc_layout(&self.layout);
+        let ptr = alloc(alloc_layout);
+        if ptr.is_null() {
+            handle_alloc_error(alloc_layout);
+        }
+
+        // The primary context pointer is copied as well, so we still refer to the original.
+        copy_nonoverlapping(self.ptr.as_const::<u8>(), ptr, self.layout.size());
+
+        let mut container = Self {
+            ptr: VirtAddr::from(ptr),
+            layout: self.layout,
+            num_imported_globals: self.num_imported_globals,
+            tables: self.tables.clone(),
+            functions: FunctionReferences::new(Vec::new()),
+        };
+
+        for (i, global) in globals
+            .iter()
+            .enumerate()
+            .skip(self.num_imported_globals as usize)
+        {
+            // Function references refer to the original, instantiation checked the initializers.
+            let initialized = container.write_global(i as u32, global, &self.functions);
+            debug_assert!(initialized.is_some());
+        }
+
+        container
+    }
+
     /// Gets the pointer to the context.
     pub fn ptr(&self) -> *const VmContext {
         self.ptr.as_const::<VmContext>()
@@ -200,71 +297,227 @@ impl VmContextContainer {
         self.ptr.as_mut::<u8>()
     }
 
+    /// Sets the instance pointer.
+    /// Unsafe because the instance must own this container.
+    pub unsafe fn set_instance(&self, instance: *const Instance) {
+        let ptr = self
+            .ptr
+            .as_mut::<u8>()
+            .offset(VmContextLayout::instance_offset() as isize)
+            as *mut *const Instance;
+        *ptr = instance;
+    }
+
+    /// Sets the fuel, saturates at the maximum the counter can hold.
+    pub fn set_fuel(&self, fuel: u64) {
+        // Safety: the counter is inside the context. Other threads of the instance may be
+        //         consuming fuel, so it must be written atomically.
+        unsafe {
+            let fuel_ptr = self
+                .ptr
+                .as_mut::<u8>()
+                .offset(VmContextLayout::fuel_offset() as isize)
+                as *const AtomicI64;
+            (*fuel_ptr).store(min(fuel, i64::MAX as u64) as i64, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the epoch deadline.
+    pub fn set_epoch_deadline(&self, deadline: u64) {
+        // Safety: the deadline is inside the context. Other threads of the instance may be
+        //         checking it, so it must be written atomically.
+        unsafe {
+            let deadline_ptr = self
+                .ptr
+                .as_mut::<u8>()
+                .offset(VmContextLayout::epoch_deadline_offset() as isize)
+                as *const AtomicU64;
+            (*deadline_ptr).store(deadline, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the lazy function slot of a defined function to the address of its compiled code.
+    pub fn set_lazy_slot(&self, index: u32, address: VirtAddr) {
+        // Safety: this is our own layout.
+        unsafe {
+            store_lazy_slot(self.ptr, &self.layout, index, address);
+        }
+    }
+
     /// Gets the function imports as a slice.
     /// Unsafe because you might be able to get multiple mutable references.
     pub unsafe fn function_imports_as_mut_slice(&mut self) -> &mut [VmFunctionImportEntry] {
         // Safety: we allocated the memory correctly and the bounds are correct at this point.
         let ptr = self
             .ptr_mut_u8()
-            .offset(VmContext::imported_funcs_offset(self.num_globals) as isize)
+            .offset(self.layout.imported_funcs_offset())
             as *mut VmFunctionImportEntry;
-        slice::from_raw_parts_mut(ptr, self.num_imported_funcs as usize)
+        slice::from_raw_parts_mut(ptr, self.layout.num_imported_funcs() as usize)
     }
 
-    /// Gets a mut slice to the tables.
-    pub fn get_table(&mut self, idx: TableIndex) -> &mut Table {
-        &mut self.tables[idx.as_u32() as usize]
+    /// Gets a table.
+    pub fn get_table(&self, idx: TableIndex) -> &SharedTable {
+        &self.tables[idx.as_u32() as usize]
     }
 
-    /// Write the table data to the VmContext.
-    pub fn write_tables_to_vmctx(&mut self) {
-        // Safety: we allocated the memory correctly and the bounds are correct at this point.
-        let vm_tables = unsafe {
-            let ptr = self.ptr_mut_u8().offset(VmContext::tables_offset(
-                self.num_globals,
-                self.num_imported_funcs,
-            )) as *mut VmTable;
-            slice::from_raw_parts_mut(ptr, self.tables.len())
-        };
+    /// Sets the function references, must be done before tables are filled.
+    pub fn set_function_references(&mut self, functions: FunctionReferences) {
+        self.functions = functions;
+    }
 
-        for (table, vm_table) in self.tables.iter().zip(vm_tables.iter_mut()) {
-            *vm_table = table.as_vm_table();
-        }
+    /// Gets the function references.
+    pub fn function_references(&self) -> &FunctionReferences {
+        &self.functions
     }
 
-    /// Sets a global.
-    /// Unsafe because index might be outside bounds.
-    pub unsafe fn set_global(&mut self, idx: u32, global: &Global) {
-        debug_assert!(idx < self.num_globals);
+    /// table.get, returns the reference value.
+    pub fn table_get(&self, idx: TableIndex, offset: u32) -> Option<u64> {
+        self.get_table(idx)
+            .lock()
+            .get(offset as usize)
+            .map(|element| element.reference)
+    }
+
+    /// table.set from a reference value.
+    pub fn table_set(&self, idx: TableIndex, offset: u32, reference: u64) -> Option<()> {
+        let mut table = self.get_table(idx).lock();
+        let element = table.element_from_reference(reference);
+        table.set(offset as usize, element)
+    }
+
+    /// table.grow, returns the old size.
+    pub fn table_grow(&self, idx: TableIndex, delta: u32, reference: u64) -> Option<u32> {
+        let mut table = self.get_table(idx).lock();
+        let element = table.element_from_reference(reference);
+        table.grow(delta, element)
+    }
+
+    /// table.fill with a reference value.
+    pub fn table_fill(&self, idx: TableIndex, offset: u32, reference: u64, len: u32) -> Option<()> {
+        let mut table = self.get_table(idx).lock();
+        let element = table.element_from_reference(reference);
+        table.fill(offset as usize, element, len as usize)
+    }
+
+    /// table.copy, the tables may be the same.
+    pub fn table_copy(
+        &self,
+        dst_idx: TableIndex,
+        src_idx: TableIndex,
+        dst: u32,
+        src: u32,
+        len: u32,
+    ) -> Option<()> {
+        let elements: Vec<VmTableElement> = self
+            .get_table(src_idx)
+            .lock()
+            .get_range(src as usize, len as usize)?
+            .to_vec();
+        self.get_table(dst_idx)
+            .lock()
+            .set_range(dst as usize, &elements)
+    }
+
+    /// table.init from the functions of an element segment.
+    pub fn table_init(
+        &self,
+        idx: TableIndex,
+        segment: &[FuncIndex],
+        dst: u32,
+        src: u32,
+        len: u32,
+    ) -> Option<()> {
+        let src = src as usize;
+        let elements: Vec<VmTableElement> = segment
+            .get(src..src.checked_add(len as usize)?)?
+            .iter()
+            .map(|func_idx| self.functions.get(*func_idx))
+            .collect();
+        self.get_table(idx)
+            .lock()
+            .set_range(dst as usize, &elements)
+    }
+
+    /// Sets the pointer of an imported global to the global of the exporting instance.
+    /// Unsafe because index might be outside bounds, and the pointer must stay valid.
+    pub unsafe fn set_global_import(&mut self, idx: u32, global: *mut VmGlobal) {
+        debug_assert!(idx < self.num_imported_globals);
         let ptr = self
             .ptr_mut_u8()
-            .offset(VmContext::global_entry_offset(idx));
+            .offset(VmContextLayout::global_entry_offset(idx));
+        (ptr as *mut *mut VmGlobal).write(global);
+    }
+
+    /// Gets the pointer to the storage of a global, follows imported globals.
+    /// Unsafe because index might be outside bounds.
+    pub unsafe fn global_ptr(&self, idx: u32) -> *mut VmGlobal {
+        debug_assert!(idx < self.layout.num_globals());
+        let ptr = self
+            .ptr
+            .as_mut::<u8>()
+            .offset(VmContextLayout::global_entry_offset(idx)) as *mut VmGlobal;
+
+        if idx < self.num_imported_globals {
+            (ptr as *const *mut VmGlobal).read()
+        } else {
+            ptr
+        }
+    }
+
+    /// Sets a global, returns None if the initializer is not supported.
+    /// Unsafe because index might be outside bounds, and globals used by the initializer
+    /// must be initialized.
+    /// The function references must be set before.
+    pub unsafe fn set_global(&mut self, idx: u32, global: &GlobalDecl) -> Option<()> {
+        self.write_global(idx, global, &self.functions)
+    }
+
+    /// Writes the initial value of a global, ref.func initializers use the given references.
+    unsafe fn write_global(
+        &self,
+        idx: u32,
+        global: &GlobalDecl,
+        functions: &FunctionReferences,
+    ) -> Option<()> {
+        debug_assert!(idx >= self.num_imported_globals && idx < self.layout.num_globals());
+        let ptr = self.global_ptr(idx);
 
         match global.initializer {
             GlobalInit::I32Const(v) => (ptr as *mut i32).write(v),
-            _ => unimplemented!(),
+            GlobalInit::I64Const(v) => (ptr as *mut i64).write(v),
+            GlobalInit::F32Const(v) => (ptr as *mut u32).write(v),
+            GlobalInit::F64Const(v) => (ptr as *mut u64).write(v),
+            GlobalInit::GetGlobal(other) => {
+                debug_assert!(other.as_u32() < self.layout.num_globals());
+                ptr.write(self.global_ptr(other.as_u32()).read());
+            }
+            GlobalInit::RefNullConst => (ptr as *mut u64).write(0),
+            GlobalInit::RefFunc(func_idx) => {
+                (ptr as *mut u64).write(functions.get(func_idx).reference)
+            }
+            _ => return None,
         }
+
+        Some(())
+    }
+
+    /// Gets the value of an i32 global.
+    /// Unsafe because index might be outside bounds.
+    pub unsafe fn get_global_i32(&self, idx: u32) -> i32 {
+        (self.global_ptr(idx) as *const i32).read()
     }
 
     /// Calculates the allocation layout of the VmContext.
-    fn layout(num_globals: u32, num_imported_funcs: u32, num_tables: u32) -> Layout {
-        let size = VmContext::size(num_globals, num_imported_funcs, num_tables);
+    fn alloc_layout(layout: &VmContextLayout) -> Layout {
         let align = align_of::<VmContext>();
-        Layout::from_size_align(size, align).unwrap()
+        Layout::from_size_align(layout.size(), align).unwrap()
     }
 }
 
 impl Drop for VmContextContainer {
     fn drop(&mut self) {
         unsafe {
-            dealloc(
-                self.ptr_mut_u8(),
-                Self::layout(
-                    self.num_globals,
-                    self.num_imported_funcs,
-                    self.tables.len() as u32,
-                ),
-            );
+            dealloc(self.ptr_mut_u8(), Self::alloc_layout(&self.layout));
         }
     }
 }
@@ -1,7 +1,8 @@
-use crate::tasking::scheduler::with_current_thread;
+use crate::tasking::grant::UserBuffer;
 use crate::wasm::vmctx::VmContext;
 use bitflags::bitflags;
 use core::cell::Cell;
+use core::convert::TryFrom;
 use core::marker::PhantomData;
 use core::mem::{align_of, size_of};
 use core::{iter, slice};
@@ -191,14 +192,14 @@ impl<T> WasmPtr<T> {
 
     /// Internal helper function to get a real pointer or an error from a WasmPtr.
     fn get_ptr_and_verify(&self, ctx: &VmContext, size: usize) -> WasmResult<*const u8> {
+        // WASI always works on the first memory.
+        let memory = ctx.instance().memories.first().ok_or(Errno::Fault)?;
         let alignment = align_of::<T>() as u32;
-        if self.offset % alignment != 0
-            || self.offset as usize + size > with_current_thread(|thread| thread.heap_size())
-        {
+        if self.offset % alignment != 0 || self.offset as usize + size > memory.size() {
             Err(Errno::Fault)
         } else {
             // Safety: pointer is correctly aligned and points to real data.
-            unsafe { Ok(ctx.heap_ptr.as_const::<u8>().add(self.offset as usize)) }
+            unsafe { Ok(memory.address().as_const::<u8>().add(self.offset as usize)) }
         }
     }
 
@@ -223,6 +224,34 @@ impl<T> WasmPtr<T> {
         .map(|p| unsafe { slice::from_raw_parts(p as *const Cell<T>, len) })
     }
 
+    /// Gets the buffer of `len` values behind a Wasm pointer, does checks for alignment and bounds.
+    /// This is how buffers of wasm code are handed to schemes, which only copy from them.
+    pub fn buffer<'s>(&self, ctx: &'s VmContext, len: Size) -> WasmResult<UserBuffer<'s>> {
+        let (ptr, len) = self.get_buffer_ptr(ctx, len)?;
+        // Safety: see `get_buffer_ptr`.
+        Ok(unsafe { UserBuffer::shared(ptr, len) })
+    }
+
+    /// Gets the buffer of `len` values behind a Wasm pointer as mutable,
+    /// does checks for alignment and bounds.
+    /// This is how buffers of wasm code are handed to schemes, which only copy into them.
+    pub fn buffer_mut<'s>(&self, ctx: &'s VmContext, len: Size) -> WasmResult<UserBuffer<'s>> {
+        let (ptr, len) = self.get_buffer_ptr(ctx, len)?;
+        // Safety: see `get_buffer_ptr`.
+        Ok(unsafe { UserBuffer::mutable(ptr as *mut u8, len) })
+    }
+
+    /// Internal helper function to get the pointer and length in bytes of `len` values.
+    /// The range is inside the memory, which lives as long as the instance of the context.
+    /// No Rust reference to the range is created, because wasm code of other threads can access
+    /// a shared memory at the same time.
+    fn get_buffer_ptr(&self, ctx: &VmContext, len: Size) -> WasmResult<(*const u8, usize)> {
+        let len = size_of::<T>()
+            .checked_mul(len as usize)
+            .ok_or(Errno::Fault)?;
+        self.get_ptr_and_verify(ctx, len).map(|p| (p, len))
+    }
+
     /// Gets a string from a Wasm pointer, does checks for valid UTF-8 string.
     /// Returns Ok(str) on success and Err(Errno) on fail.
     pub fn str<'s>(&self, ctx: &VmContext, len: Size) -> WasmResult<&'s str> {
@@ -289,6 +318,16 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[repr(C)]
+    pub struct FstFlags: u16 {
+        const ATIM = 1 << 0;
+        const ATIM_NOW = 1 << 1;
+        const MTIM = 1 << 2;
+        const MTIM_NOW = 1 << 3;
+    }
+}
+
 bitflags! {
     #[repr(C)]
     pub struct Rights: u64 {
@@ -347,3 +386,89 @@ pub struct PreStat {
     pub tag: u8,
     pub inner: PreStatInner,
 }
+
+#[repr(u8)]
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileType {
+    Unknown,
+    BlockDevice,
+    CharacterDevice,
+    Directory,
+    RegularFile,
+    SocketDgram,
+    SocketStream,
+    SymbolicLink,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct FdStat {
+    pub fs_filetype: FileType,
+    pub fs_flags: FdFlags,
+    pub fs_rights_base: Rights,
+    pub fs_rights_inheriting: Rights,
+}
+
+/// File attributes, these are filled in by the scheme of the file.
+#[repr(C)]
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct FileStat {
+    pub dev: u64,
+    pub ino: u64,
+    pub filetype: FileType,
+    pub nlink: u64,
+    pub size: u64,
+    pub atim: u64,
+    pub mtim: u64,
+    pub ctim: u64,
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Whence {
+    Set,
+    Cur,
+    End,
+}
+
+impl TryFrom<u32> for Whence {
+    type Error = Errno;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Whence::Set),
+            1 => Ok(Whence::Cur),
+            2 => Ok(Whence::End),
+            _ => Err(Errno::Inval),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+    DontNeed,
+    NoReuse,
+}
+
+impl TryFrom<u32> for Advice {
+    type Error = Errno;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Advice::Normal),
+            1 => Ok(Advice::Sequential),
+            2 => Ok(Advice::Random),
+            3 => Ok(Advice::WillNeed),
+            4 => Ok(Advice::DontNeed),
+            5 => Ok(Advice::NoReuse),
+            _ => Err(Errno::Inval),
+        }
+    }
+}
@@ -10,20 +10,24 @@ pub use definitions::*;
 
 use crate::arch::address::VirtAddr;
 use crate::tasking::file::{FileDescriptor, FileHandle, FileIdx};
-use crate::tasking::scheduler::{self, with_current_thread};
-use crate::tasking::scheme::Scheme;
-use crate::tasking::scheme_container::schemes;
-use crate::wasm::main::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
+use crate::tasking::process::{self, Process};
+use crate::tasking::scheduler;
+use crate::tasking::scheme::{OpenMode, Scheme};
+use crate::tasking::scheme_container;
+use crate::util::string_list::StringList;
+use crate::wasm::host_modules::HostFunctionMap;
 use crate::wasm::vmctx::VmContext;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use core::convert::{TryFrom, TryInto};
-use core::slice;
 use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, Signature};
 use lazy_static::lazy_static;
+use wasm_compiler::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
 
 abi_functions! {
+    args_get: (argv: WasmPtr<WasmPtr<u8>>, argv_buf: WasmPtr<u8>) -> Errno,
+    args_sizes_get: (argc: WasmPtr<Size>, argv_buf_size: WasmPtr<Size>) -> Errno,
     environ_sizes_get: (environc: WasmPtr<Size>, environ_buf_size: WasmPtr<Size>) -> Errno,
     environ_get: (environ: WasmPtr<WasmPtr<u8>>, environ_buf: WasmPtr<u8>) -> Errno,
     fd_close: (fd: Fd) -> Errno,
@@ -31,50 +35,63 @@ abi_functions! {
     fd_write: (fd: Fd, iovs: WasmPtr<CioVec>, iovs_len: Size, nwritten: WasmPtr<u32>) -> Errno,
     fd_prestat_get: (fd: Fd, prestat: WasmPtr<PreStat>) -> Errno,
     fd_prestat_dir_name: (fd: Fd, path: WasmPtr<u8>, path_len: Size) -> Errno,
+    fd_advise: (fd: Fd, offset: u64, len: u64, advice: u32) -> Errno,
+    fd_datasync: (fd: Fd) -> Errno,
+    fd_fdstat_get: (fd: Fd, stat: WasmPtr<FdStat>) -> Errno,
+    fd_fdstat_set_flags: (fd: Fd, flags: FdFlags) -> Errno,
+    fd_filestat_get: (fd: Fd, stat: WasmPtr<FileStat>) -> Errno,
+    fd_pread: (fd: Fd, iovs: WasmPtr<CioVec>, iovs_len: Size, offset: u64, nread: WasmPtr<u32>) -> Errno,
+    fd_pwrite: (fd: Fd, iovs: WasmPtr<CioVec>, iovs_len: Size, offset: u64, nwritten: WasmPtr<u32>) -> Errno,
+    fd_readdir: (fd: Fd, buf: WasmPtr<u8>, buf_len: Size, cookie: u64, buf_used: WasmPtr<Size>) -> Errno,
+    fd_renumber: (fd: Fd, to: Fd) -> Errno,
+    fd_seek: (fd: Fd, offset: i64, whence: u32, new_offset: WasmPtr<u64>) -> Errno,
+    fd_sync: (fd: Fd) -> Errno,
+    fd_tell: (fd: Fd, offset: WasmPtr<u64>) -> Errno,
+    path_create_directory: (fd: Fd, path: WasmPtr<u8>, path_len: Size) -> Errno,
+    path_filestat_get: (fd: Fd, flags: LookupFlags, path: WasmPtr<u8>, path_len: Size, stat: WasmPtr<FileStat>) -> Errno,
+    path_filestat_set_times: (fd: Fd, flags: LookupFlags, path: WasmPtr<u8>, path_len: Size, atim: u64, mtim: u64, fst_flags: u32) -> Errno,
+    path_link: (old_fd: Fd, old_flags: LookupFlags, old_path: WasmPtr<u8>, old_path_len: Size, new_fd: Fd, new_path: WasmPtr<u8>, new_path_len: Size) -> Errno,
     path_open: (dir_fd: Fd, dir_flags: LookupFlags, path: WasmPtr<u8>, path_len: Size, o_flags: OFlags, fs_rights_base: Rights, fs_rights_inheriting: Rights, fd_flags: FdFlags, fd: WasmPtr<Fd>) -> Errno,
+    path_readlink: (fd: Fd, path: WasmPtr<u8>, path_len: Size, buf: WasmPtr<u8>, buf_len: Size, buf_used: WasmPtr<Size>) -> Errno,
+    path_remove_directory: (fd: Fd, path: WasmPtr<u8>, path_len: Size) -> Errno,
+    path_rename: (old_fd: Fd, old_path: WasmPtr<u8>, old_path_len: Size, new_fd: Fd, new_path: WasmPtr<u8>, new_path_len: Size) -> Errno,
+    path_symlink: (old_path: WasmPtr<u8>, old_path_len: Size, fd: Fd, new_path: WasmPtr<u8>, new_path_len: Size) -> Errno,
+    path_unlink_file: (fd: Fd, path: WasmPtr<u8>, path_len: Size) -> Errno,
     proc_exit: (exit_code: ExitCode) -> (),
 }
 
 // TODO: capabilities
 impl AbiFunctions for VmContext {
+    fn args_get(&self, argv: WasmPtr<WasmPtr<u8>>, argv_buf: WasmPtr<u8>) -> WasmStatus {
+        let process = current_process()?;
+        self.string_list_get(process.args(), argv, argv_buf)
+    }
+
+    fn args_sizes_get(&self, argc: WasmPtr<Size>, argv_buf_size: WasmPtr<Size>) -> WasmStatus {
+        let process = current_process()?;
+        self.string_list_sizes_get(process.args(), argc, argv_buf_size)
+    }
+
     fn environ_sizes_get(
         &self,
         environc: WasmPtr<Size>,
         environ_buf_size: WasmPtr<Size>,
     ) -> WasmStatus {
-        environc.cell(self)?.set(1);
-        // This is the sum of the string lengths in bytes (including \0 terminators)
-        let abcdefg = "RUST_BACKTRACE=1";
-        environ_buf_size
-            .cell(self)?
-            .set(1 + abcdefg.bytes().len() as u32 /* TODO: make safe */);
-        //environc.cell(self)?.set(0);
-        //environ_buf_size.cell(self)?.set(0);
-        Ok(())
+        let process = current_process()?;
+        self.string_list_sizes_get(process.env(), environc, environ_buf_size)
     }
 
     fn environ_get(&self, environ: WasmPtr<WasmPtr<u8>>, environ_buf: WasmPtr<u8>) -> WasmStatus {
-        // The bytes should be all after each other consecutively in `environ_buf`.
-        let abcdefg = "RUST_BACKTRACE=1";
-        let slice = environ_buf.slice(
-            &self,
-            (1 + abcdefg.bytes().len()) as u32, /* TODO: make safe */
-        )?;
-        for (byte, cell) in abcdefg.bytes().zip(slice.iter()) {
-            cell.set(byte);
-        }
-        slice[slice.len() - 1].set(0);
-
-        // Write pointers to the environment variables in the buffer.
-        let slice = environ.slice(&self, 1)?;
-        slice[0].set(WasmPtr::from(environ_buf.offset()));
-
-        Ok(())
+        let process = current_process()?;
+        self.string_list_get(process.env(), environ, environ_buf)
     }
 
     fn fd_close(&self, fd: Fd) -> WasmStatus {
-        println!("fd_close: {}", fd);
-        Ok(())
+        let file = current_process()?
+            .file_descriptor_table()
+            .remove(fd as FileIdx)
+            .ok_or(Errno::BadF)?;
+        close_file(file)
     }
 
     fn fd_read(
@@ -84,16 +101,12 @@ impl AbiFunctions for VmContext {
         iovs_len: u32,
         nread: WasmPtr<u32>,
     ) -> WasmStatus {
-        self.with_fd_handle(fd, |scheme, handle| {
+        self.with_fd_handle_checked(fd, Rights::FD_READ, |scheme, handle| {
             let mut read = 0usize;
             let iovs = iovs.slice(self, iovs_len)?;
             for iov in iovs {
                 let iov = iov.get();
-                let buf = iov.buf.slice(self, iov.buf_len)?;
-
-                // TODO: safety
-                let buf =
-                    unsafe { slice::from_raw_parts_mut(buf as *const _ as *mut u8, buf.len()) };
+                let buf = iov.buf.buffer_mut(self, iov.buf_len)?;
                 let read_now = scheme.read(handle, buf)?;
                 read = read.saturating_add(read_now);
             }
@@ -137,15 +150,12 @@ impl AbiFunctions for VmContext {
             return Ok(());
         }
 
-        self.with_fd_handle(fd, |scheme, handle| {
+        self.with_fd_handle_checked(fd, Rights::FD_WRITE, |scheme, handle| {
             let mut written = 0usize;
             let iovs = iovs.slice(self, iovs_len)?;
             for iov in iovs {
                 let iov = iov.get();
-                let buf = iov.buf.slice(self, iov.buf_len)?;
-
-                // TODO: safety
-                let buf = unsafe { slice::from_raw_parts(buf as *const _ as *const u8, buf.len()) };
+                let buf = iov.buf.buffer(self, iov.buf_len)?;
                 let written_now = scheme.write(handle, buf)?;
                 written = written.saturating_add(written_now);
             }
@@ -190,6 +200,255 @@ impl AbiFunctions for VmContext {
         })
     }
 
+    fn fd_advise(&self, fd: Fd, offset: u64, len: u64, advice: u32) -> WasmStatus {
+        let advice = Advice::try_from(advice)?;
+        self.with_fd_handle_checked(fd, Rights::FD_ADVISE, |scheme, handle| {
+            scheme.advise(handle, offset, len, advice)
+        })
+    }
+
+    fn fd_datasync(&self, fd: Fd) -> WasmStatus {
+        self.with_fd_handle_checked(fd, Rights::FD_DATASYNC, |scheme, handle| {
+            scheme.sync(handle, true)
+        })
+    }
+
+    fn fd_fdstat_get(&self, fd: Fd, stat: WasmPtr<FdStat>) -> WasmStatus {
+        let fd_stat = self.with_fd(fd, |file| Ok(file.fd_stat()))?;
+        stat.cell(self)?.set(fd_stat);
+        Ok(())
+    }
+
+    fn fd_fdstat_set_flags(&self, fd: Fd, flags: FdFlags) -> WasmStatus {
+        self.with_fd_handle_checked(fd, Rights::FD_FDSTAT_SET_FLAGS, |scheme, handle| {
+            scheme.set_flags(handle, flags)
+        })?;
+
+        // The descriptor could have been closed or replaced while the scheme was busy.
+        let process = current_process()?;
+        let mut tbl = process.file_descriptor_table();
+        tbl.get_mut(fd as FileIdx)
+            .ok_or(Errno::BadF)?
+            .set_fd_flags(flags);
+        Ok(())
+    }
+
+    fn fd_filestat_get(&self, fd: Fd, stat: WasmPtr<FileStat>) -> WasmStatus {
+        self.with_fd_handle_checked(fd, Rights::FD_FILESTAT_GET, |scheme, handle| {
+            // The scheme itself is a directory, the kernel describes it.
+            if let FileHandle::Own = handle {
+                stat.cell(self)?.set(FileStat {
+                    dev: 0,
+                    ino: 0,
+                    filetype: FileType::Directory,
+                    nlink: 1,
+                    size: 0,
+                    atim: 0,
+                    mtim: 0,
+                    ctim: 0,
+                });
+                return Ok(());
+            }
+
+            let buf = stat.buffer_mut(self, 1)?;
+            if scheme.file_stat(handle, buf)? < buf.len() {
+                Err(Errno::Io)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn fd_pread(
+        &self,
+        fd: Fd,
+        iovs: WasmPtr<CioVec>,
+        iovs_len: Size,
+        offset: u64,
+        nread: WasmPtr<u32>,
+    ) -> WasmStatus {
+        let rights = Rights::FD_READ | Rights::FD_SEEK;
+        self.with_fd_handle_checked(fd, rights, |scheme, handle| {
+            let mut read = 0usize;
+            let iovs = iovs.slice(self, iovs_len)?;
+            for iov in iovs {
+                let iov = iov.get();
+                let buf = iov.buf.buffer_mut(self, iov.buf_len)?;
+                let read_now = scheme.read_at(handle, buf, offset.saturating_add(read as u64))?;
+                read = read.saturating_add(read_now);
+
+                // A short read means the end of the file is reached.
+                if read_now < buf.len() {
+                    break;
+                }
+            }
+
+            nread.cell(self)?.set(read.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn fd_pwrite(
+        &self,
+        fd: Fd,
+        iovs: WasmPtr<CioVec>,
+        iovs_len: Size,
+        offset: u64,
+        nwritten: WasmPtr<u32>,
+    ) -> WasmStatus {
+        let rights = Rights::FD_WRITE | Rights::FD_SEEK;
+        self.with_fd_handle_checked(fd, rights, |scheme, handle| {
+            let mut written = 0usize;
+            let iovs = iovs.slice(self, iovs_len)?;
+            for iov in iovs {
+                let iov = iov.get();
+                let buf = iov.buf.buffer(self, iov.buf_len)?;
+                let written_now =
+                    scheme.write_at(handle, buf, offset.saturating_add(written as u64))?;
+                written = written.saturating_add(written_now);
+
+                // A short write means the scheme can't take more data right now.
+                if written_now < buf.len() {
+                    break;
+                }
+            }
+
+            nwritten
+                .cell(self)?
+                .set(written.try_into().unwrap_or(u32::MAX));
+
+            Ok(())
+        })
+    }
+
+    fn fd_readdir(
+        &self,
+        fd: Fd,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        cookie: u64,
+        buf_used: WasmPtr<Size>,
+    ) -> WasmStatus {
+        self.with_fd_handle_checked(fd, Rights::FD_READDIR, |scheme, handle| {
+            let buf = buf.buffer_mut(self, buf_len)?;
+            let used = scheme.read_dir(handle, buf, cookie)?;
+            // The scheme can't fill in more than the buffer, which has a `Size` length.
+            buf_used.cell(self)?.set(used as Size);
+
+            Ok(())
+        })
+    }
+
+    fn fd_renumber(&self, fd: Fd, to: Fd) -> WasmStatus {
+        let replaced = current_process()?
+            .file_descriptor_table()
+            .renumber(fd as FileIdx, to as FileIdx)?;
+        replaced.map_or(Ok(()), close_file)
+    }
+
+    fn fd_seek(&self, fd: Fd, offset: i64, whence: u32, new_offset: WasmPtr<u64>) -> WasmStatus {
+        let whence = Whence::try_from(whence)?;
+        // Only getting the current offset is allowed with just the right to tell.
+        let rights = if offset == 0 && whence == Whence::Cur {
+            Rights::FD_TELL
+        } else {
+            Rights::FD_SEEK
+        };
+        let offset = self.with_fd_handle_checked(fd, rights, |scheme, handle| {
+            scheme.seek(handle, offset, whence)
+        })?;
+        new_offset.cell(self)?.set(offset);
+
+        Ok(())
+    }
+
+    fn fd_sync(&self, fd: Fd) -> WasmStatus {
+        self.with_fd_handle_checked(fd, Rights::FD_SYNC, |scheme, handle| {
+            scheme.sync(handle, false)
+        })
+    }
+
+    fn fd_tell(&self, fd: Fd, offset: WasmPtr<u64>) -> WasmStatus {
+        let current = self.with_fd_handle_checked(fd, Rights::FD_TELL, |scheme, handle| {
+            scheme.seek(handle, 0, Whence::Cur)
+        })?;
+        offset.cell(self)?.set(current);
+
+        Ok(())
+    }
+
+    fn path_create_directory(&self, fd: Fd, path: WasmPtr<u8>, path_len: Size) -> WasmStatus {
+        let (scheme, path) =
+            self.resolve_path(fd, Rights::PATH_CREATE_DIRECTORY, path, path_len)?;
+        scheme.create_directory(&path)
+    }
+
+    fn path_filestat_get(
+        &self,
+        fd: Fd,
+        flags: LookupFlags,
+        path: WasmPtr<u8>,
+        path_len: Size,
+        stat: WasmPtr<FileStat>,
+    ) -> WasmStatus {
+        let (scheme, path) = self.resolve_path(fd, Rights::PATH_FILESTAT_GET, path, path_len)?;
+        let buf = stat.buffer_mut(self, 1)?;
+        if scheme.path_file_stat(flags, &path, buf)? < buf.len() {
+            Err(Errno::Io)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        fd: Fd,
+        flags: LookupFlags,
+        path: WasmPtr<u8>,
+        path_len: Size,
+        atim: u64,
+        mtim: u64,
+        fst_flags: u32,
+    ) -> WasmStatus {
+        let fst_flags = u16::try_from(fst_flags)
+            .ok()
+            .and_then(FstFlags::from_bits)
+            .ok_or(Errno::Inval)?;
+        // A timestamp can't be both given and the current time.
+        if fst_flags.contains(FstFlags::ATIM | FstFlags::ATIM_NOW)
+            || fst_flags.contains(FstFlags::MTIM | FstFlags::MTIM_NOW)
+        {
+            return Err(Errno::Inval);
+        }
+
+        let (scheme, path) =
+            self.resolve_path(fd, Rights::PATH_FILESTAT_SET_TIMES, path, path_len)?;
+        scheme.set_times(flags, &path, atim, mtim, fst_flags)
+    }
+
+    fn path_link(
+        &self,
+        old_fd: Fd,
+        old_flags: LookupFlags,
+        old_path: WasmPtr<u8>,
+        old_path_len: Size,
+        new_fd: Fd,
+        new_path: WasmPtr<u8>,
+        new_path_len: Size,
+    ) -> WasmStatus {
+        let (old_scheme, old_path) =
+            self.resolve_path(old_fd, Rights::PATH_LINK_SOURCE, old_path, old_path_len)?;
+        let (new_scheme, new_path) =
+            self.resolve_path(new_fd, Rights::PATH_LINK_TARGET, new_path, new_path_len)?;
+        // Links can't cross schemes.
+        if !Arc::ptr_eq(&old_scheme, &new_scheme) {
+            return Err(Errno::Xdev);
+        }
+
+        old_scheme.link(old_flags, &old_path, &new_path)
+    }
+
     fn path_open(
         &self,
         dir_fd: Fd,
@@ -202,47 +461,192 @@ impl AbiFunctions for VmContext {
         fd_flags: FdFlags,
         fd: WasmPtr<Fd>,
     ) -> WasmStatus {
-        // TODO: handle the flags and rights
-        println!("path_open: {} {}", dir_fd, path.str(self, path_len)?);
+        let path = path.str(self, path_len)?.as_bytes();
 
-        /*self.with_fd(dir_fd, |dir_fd| {
+        let mut required = Rights::PATH_OPEN;
+        if o_flags.contains(OFlags::CREAT) {
+            required |= Rights::PATH_CREATE_FILE;
+        }
+        if o_flags.contains(OFlags::TRUNC) {
+            required |= Rights::PATH_FILESTAT_SET_SIZE;
+        }
 
-            // TODO
-            fd.cell(&self)?.set(3); // TODO: hack
-            Ok(())
-        })*/
-        let idx = with_current_thread(|t| {
-            t.file_descriptor_table().insert_lowest({
-                schemes()
-                    .read()
-                    .open_self(Box::new([]))
-                    .expect("self scheme")
-            })
-        })
-        .unwrap(); // TODO
+        // Don't hold the table lock while the scheme opens the file.
+        let (dir_scheme, dir_path, dir_rights_inheriting) = self.with_fd(dir_fd, |dir| {
+            dir.check_rights(required)?;
+            Ok((
+                dir.scheme().clone(),
+                Box::<[u8]>::from(dir.path()),
+                dir.rights_inheriting(),
+            ))
+        })?;
+
+        // The new descriptor can't have more rights than the directory hands out.
+        let mode = OpenMode {
+            rights_base: fs_rights_base & dir_rights_inheriting,
+            rights_inheriting: fs_rights_inheriting & dir_rights_inheriting,
+            o_flags,
+            fd_flags,
+        };
+        let file = scheme_container::open(Some((&dir_scheme, &dir_path)), path, mode)?;
+
+        let idx = current_process()?
+            .file_descriptor_table()
+            .insert_lowest(file)
+            .ok_or(Errno::MFile)?;
 
         fd.cell(self)?.set(idx as u32);
 
         Ok(())
     }
 
+    fn path_readlink(
+        &self,
+        fd: Fd,
+        path: WasmPtr<u8>,
+        path_len: Size,
+        buf: WasmPtr<u8>,
+        buf_len: Size,
+        buf_used: WasmPtr<Size>,
+    ) -> WasmStatus {
+        let (scheme, path) = self.resolve_path(fd, Rights::PATH_READLINK, path, path_len)?;
+        let buf = buf.buffer_mut(self, buf_len)?;
+        let used = scheme.read_link(&path, buf)?;
+        buf_used.cell(self)?.set(used as Size);
+
+        Ok(())
+    }
+
+    fn path_remove_directory(&self, fd: Fd, path: WasmPtr<u8>, path_len: Size) -> WasmStatus {
+        let (scheme, path) =
+            self.resolve_path(fd, Rights::PATH_REMOVE_DIRECTORY, path, path_len)?;
+        scheme.remove_directory(&path)
+    }
+
+    fn path_rename(
+        &self,
+        old_fd: Fd,
+        old_path: WasmPtr<u8>,
+        old_path_len: Size,
+        new_fd: Fd,
+        new_path: WasmPtr<u8>,
+        new_path_len: Size,
+    ) -> WasmStatus {
+        let (old_scheme, old_path) =
+            self.resolve_path(old_fd, Rights::PATH_RENAME_SOURCE, old_path, old_path_len)?;
+        let (new_scheme, new_path) =
+            self.resolve_path(new_fd, Rights::PATH_RENAME_TARGET, new_path, new_path_len)?;
+        // Files can't be moved to another scheme.
+        if !Arc::ptr_eq(&old_scheme, &new_scheme) {
+            return Err(Errno::Xdev);
+        }
+
+        old_scheme.rename(&old_path, &new_path)
+    }
+
+    fn path_symlink(
+        &self,
+        old_path: WasmPtr<u8>,
+        old_path_len: Size,
+        fd: Fd,
+        new_path: WasmPtr<u8>,
+        new_path_len: Size,
+    ) -> WasmStatus {
+        // The old path is the contents of the link, it is only resolved when the link is used.
+        let contents = old_path.str(self, old_path_len)?.as_bytes();
+        let (scheme, path) = self.resolve_path(fd, Rights::PATH_SYMLINK, new_path, new_path_len)?;
+        scheme.symlink(contents, &path)
+    }
+
+    fn path_unlink_file(&self, fd: Fd, path: WasmPtr<u8>, path_len: Size) -> WasmStatus {
+        let (scheme, path) = self.resolve_path(fd, Rights::PATH_UNLINK_FILE, path, path_len)?;
+        scheme.unlink_file(&path)
+    }
+
     fn proc_exit(&self, exit_code: ExitCode) {
+        if let Some(process) = process::current() {
+            process.exit(exit_code);
+        }
         scheduler::thread_exit(exit_code);
     }
 }
 
 impl VmContext {
+    /// Writes the amount of strings and the total buffer size of a string list.
+    fn string_list_sizes_get(
+        &self,
+        list: &StringList,
+        count: WasmPtr<Size>,
+        buf_size: WasmPtr<Size>,
+    ) -> WasmStatus {
+        let len = Size::try_from(list.len()).map_err(|_| Errno::Overflow)?;
+        let size = Size::try_from(list.buffer().len()).map_err(|_| Errno::Overflow)?;
+        count.cell(self)?.set(len);
+        buf_size.cell(self)?.set(size);
+        Ok(())
+    }
+
+    /// Writes a string list: the strings are written consecutively in `buf`,
+    /// and the pointers to each string are written in `ptrs`.
+    fn string_list_get(
+        &self,
+        list: &StringList,
+        ptrs: WasmPtr<WasmPtr<u8>>,
+        buf: WasmPtr<u8>,
+    ) -> WasmStatus {
+        let len = Size::try_from(list.len()).map_err(|_| Errno::Overflow)?;
+        let size = Size::try_from(list.buffer().len()).map_err(|_| Errno::Overflow)?;
+
+        // Both slices are bounds checked, which also means the pointers below can't overflow.
+        let buf_slice = buf.slice(self, size)?;
+        let ptrs_slice = ptrs.slice(self, len)?;
+
+        for (cell, byte) in buf_slice.iter().zip(list.buffer()) {
+            cell.set(*byte);
+        }
+
+        for (cell, offset) in ptrs_slice.iter().zip(list.offsets()) {
+            cell.set(WasmPtr::from(buf.offset() + offset));
+        }
+
+        Ok(())
+    }
+
     /// Execute with fd handle context.
-    fn with_fd_handle<F, T>(&self, fd: Fd, f: F) -> WasmResult<T>
+    pub(crate) fn with_fd_handle<F, T>(&self, fd: Fd, f: F) -> WasmResult<T>
     where
         F: FnOnce(Arc<Scheme>, FileHandle) -> WasmResult<T>,
     {
-        with_current_thread(|thread| {
-            let tbl = thread.file_descriptor_table();
-            let fd = tbl.get(fd as FileIdx).ok_or(Errno::BadF)?;
-            let (scheme, handle) = fd.scheme_and_handle()?;
-            drop(tbl);
-            f(scheme, handle)
+        self.with_fd_handle_checked(fd, Rights::empty(), f)
+    }
+
+    /// Execute with fd handle context, if the descriptor has the required rights.
+    fn with_fd_handle_checked<F, T>(&self, fd: Fd, required: Rights, f: F) -> WasmResult<T>
+    where
+        F: FnOnce(Arc<Scheme>, FileHandle) -> WasmResult<T>,
+    {
+        let process = current_process()?;
+        let tbl = process.file_descriptor_table();
+        let fd = tbl.get(fd as FileIdx).ok_or(Errno::BadF)?;
+        fd.check_rights(required)?;
+        let (scheme, handle) = fd.scheme_and_handle()?;
+        drop(tbl);
+        f(scheme, handle)
+    }
+
+    /// Resolves a path relative to a directory, the directory must have the required rights.
+    /// Returns the scheme the path is in and the path inside that scheme.
+    fn resolve_path(
+        &self,
+        dir_fd: Fd,
+        required: Rights,
+        path: WasmPtr<u8>,
+        path_len: Size,
+    ) -> WasmResult<(Arc<Scheme>, Box<[u8]>)> {
+        let path = path.str(self, path_len)?.as_bytes();
+        self.with_fd(dir_fd, |dir| {
+            dir.check_rights(required)?;
+            scheme_container::resolve(Some((dir.scheme(), dir.path())), path)
         })
     }
 
@@ -251,20 +655,28 @@ impl VmContext {
     where
         F: FnOnce(&FileDescriptor) -> WasmResult<T>,
     {
-        with_current_thread(|thread| {
-            let tbl = thread.file_descriptor_table();
-            f(tbl.get(fd as FileIdx).ok_or(Errno::BadF)?)
-        })
+        let process = current_process()?;
+        let tbl = process.file_descriptor_table();
+        f(tbl.get(fd as FileIdx).ok_or(Errno::BadF)?)
     }
 }
 
-/// Gets the address for a wasi syscall and validate signature.
-pub fn get_address_for_wasi_and_validate_sig(name: &str, sig: &Signature) -> Option<VirtAddr> {
-    let (addr, reference_sig) = ABI_MAP.get(name)?;
+/// Gets the process of the current thread.
+/// Host functions are only called from wasm code, so the thread always belongs to a process.
+fn current_process() -> WasmResult<Arc<Process>> {
+    process::current().ok_or(Errno::Fault)
+}
 
-    if reference_sig != sig {
-        None
-    } else {
-        Some(*addr)
+/// Closes a file that was removed from the descriptor table.
+fn close_file(file: FileDescriptor) -> WasmStatus {
+    match file.scheme_and_handle() {
+        Ok((scheme, handle)) => scheme.close(handle),
+        // The scheme is gone, so are its files.
+        Err(_) => Ok(()),
     }
 }
+
+/// Gets the functions of this host module.
+pub fn host_functions() -> &'static HostFunctionMap {
+    &ABI_MAP
+}
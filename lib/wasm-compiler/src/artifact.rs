@@ -0,0 +1,879 @@
+//! Artifacts are compiled modules stored in a file, so modules don't have to be compiled at boot.
+//!
+//! An artifact is only used for the exact module, compiler settings and compile options it was
+//! created for, the header contains a hash of them. The hash only detects stale artifacts: the code inside an
+//! artifact is trusted, just like the rest of the initrd.
+//!
+//! All integers are little-endian. After the header, the artifact contains the parts of
+//! `CompiledModule` in order of declaration. Lists are prefixed by their length as a `u32`.
+//! Tables, globals and signatures use a compact encoding of the Cranelift types,
+//! things that can't be encoded make serialization fail with `ArtifactError::Unsupported`.
+
+use crate::compile::{CompileOptions, CompiledFunction, CompiledModule};
+use crate::module_env::{DataInitializer, Export, GlobalDecl, Import, TableDecl, TableElements};
+use crate::reloc_sink::{Relocation, RelocationTarget};
+use crate::trap::TrapSite;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::str;
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, LibCall, Signature, TrapCode, Type};
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_wasm::{
+    DataIndex, ElemIndex, FuncIndex, GlobalIndex, GlobalInit, Memory, MemoryIndex, SignatureIndex,
+    TableElementType, TableIndex,
+};
+use hashbrown::HashMap;
+
+/// Identifies an artifact.
+const MAGIC: &[u8; 8] = b"KWASMAOT";
+
+/// Format version.
+/// Must be bumped when the format, the generated code or the VmContext layout changes.
+pub const VERSION: u32 = 6;
+
+/// File name suffix of an artifact, appended to the file name of the module.
+pub const ARTIFACT_SUFFIX: &str = ".aot";
+
+/// Value types that can be encoded, the encoding is the index in this list.
+const VALUE_TYPES: [Type; 7] = [
+    types::I32,
+    types::I64,
+    types::F32,
+    types::F64,
+    types::I8X16,
+    types::R32,
+    types::R64,
+];
+
+#[derive(Debug)]
+pub enum ArtifactError {
+    /// Not an artifact, or an artifact of another format version.
+    InvalidHeader,
+    /// The artifact was created for another module or other compiler settings.
+    Stale,
+    /// The artifact is truncated or contains invalid data.
+    Malformed,
+    /// The compiled module contains something the format can't represent.
+    Unsupported,
+}
+
+/// Hashes data using 64-bit FNV-1a.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Hashes the compiler settings, including the CPU features the code may use, and the options.
+pub fn settings_hash(isa: &dyn TargetIsa, options: CompileOptions) -> u64 {
+    hash(format!("{} {}\n{}\n{:?}", isa.name(), isa.triple(), isa, options).as_bytes())
+}
+
+/// Serializes the compiled module of `source` to an artifact.
+pub fn serialize(
+    module: &CompiledModule,
+    source: &[u8],
+    isa: &dyn TargetIsa,
+    options: CompileOptions,
+) -> Result<Vec<u8>, ArtifactError> {
+    let mut w = Writer { buffer: Vec::new() };
+
+    // Header
+    w.buffer.extend_from_slice(MAGIC);
+    w.u32(VERSION);
+    w.u64(hash(source));
+    w.u64(settings_hash(isa, options));
+
+    // Code
+    w.bytes(&module.code);
+    w.len(module.functions.len());
+    for function in module.functions.iter() {
+        w.u32(function.offset);
+        w.u32(function.size);
+    }
+    w.len(module.relocations.len());
+    for relocation in module.relocations.iter() {
+        w.relocation(relocation)?;
+    }
+    w.len(module.trap_sites.len());
+    for site in module.trap_sites.iter() {
+        w.u32(site.code_offset);
+        w.trap_code(site.trap_code)?;
+    }
+
+    // Module
+    w.option_u32(module.start_func.map(|idx| idx.as_u32()));
+    w.len(module.func_sigs.len());
+    for sig_idx in module.func_sigs.iter() {
+        w.u32(sig_idx.as_u32());
+    }
+    w.len(module.signatures.len());
+    for signature in module.signatures.iter() {
+        w.signature(signature, isa)?;
+    }
+    w.len(module.memories.len());
+    for memory in module.memories.iter() {
+        w.u32(memory.minimum);
+        w.option_u32(memory.maximum);
+        w.u8(memory.shared as u8);
+    }
+    w.len(module.data_initializers.len());
+    for initializer in module.data_initializers.iter() {
+        w.u32(initializer.memory_index.as_u32());
+        w.option_u32(initializer.base.map(|idx| idx.as_u32()));
+        w.u64(initializer.offset as u64);
+        w.bytes(initializer.data);
+    }
+    w.len(module.passive_data.len());
+    for (idx, data) in module.passive_data.iter() {
+        w.u32(idx.as_u32());
+        w.bytes(data);
+    }
+    w.len(module.passive_elements.len());
+    for (idx, elements) in module.passive_elements.iter() {
+        w.u32(idx.as_u32());
+        w.func_indices(elements);
+    }
+    for imports in &[
+        &module.function_imports,
+        &module.memory_imports,
+        &module.table_imports,
+        &module.global_imports,
+    ] {
+        w.len(imports.len());
+        for import in imports.iter() {
+            w.str(&import.module);
+            w.str(&import.field);
+        }
+    }
+    w.len(module.exports.len());
+    for (name, export) in module.exports.iter() {
+        w.str(name);
+        let (kind, idx) = match *export {
+            Export::Function(idx) => (0, idx.as_u32()),
+            Export::Table(idx) => (1, idx.as_u32()),
+            Export::Memory(idx) => (2, idx.as_u32()),
+            Export::Global(idx) => (3, idx.as_u32()),
+        };
+        w.u8(kind);
+        w.u32(idx);
+    }
+    w.len(module.tables.len());
+    for table in module.tables.iter() {
+        match table.ty {
+            TableElementType::Func => w.u8(0),
+            TableElementType::Val(ty) => w.u8(1 + value_type_to(ty)?),
+        }
+        w.u32(table.minimum);
+        w.option_u32(table.maximum);
+    }
+    w.len(module.table_elements.len());
+    for elements in module.table_elements.iter() {
+        w.u32(elements.index.as_u32());
+        w.option_u32(elements.base.map(|idx| idx.as_u32()));
+        w.u64(elements.offset as u64);
+        w.func_indices(&elements.elements);
+    }
+    w.len(module.globals.len());
+    for global in module.globals.iter() {
+        w.u8(value_type_to(global.ty)?);
+        w.u8(global.mutability as u8);
+        w.global_init(global.initializer)?;
+    }
+    w.len(module.func_names.len());
+    for (idx, name) in module.func_names.iter() {
+        w.u32(idx.as_u32());
+        w.str(name);
+    }
+
+    Ok(w.buffer)
+}
+
+/// Deserializes an artifact.
+/// Fails with `ArtifactError::Stale` if the artifact was not created for `source`, `isa` and
+/// `options`.
+pub fn deserialize<'data>(
+    artifact: &'data [u8],
+    source: &[u8],
+    isa: &dyn TargetIsa,
+    options: CompileOptions,
+) -> Result<CompiledModule<'data>, ArtifactError> {
+    let mut r = Reader { data: artifact };
+
+    // Header
+    if r.take(MAGIC.len())? != MAGIC || r.u32()? != VERSION {
+        return Err(ArtifactError::InvalidHeader);
+    }
+    if r.u64()? != hash(source) || r.u64()? != settings_hash(isa, options) {
+        return Err(ArtifactError::Stale);
+    }
+
+    // Code
+    let code = r.bytes()?.to_vec();
+    let functions = r.list(|r| {
+        let function = CompiledFunction {
+            offset: r.u32()?,
+            size: r.u32()?,
+        };
+        match function.offset.checked_add(function.size) {
+            Some(end) if end as usize <= code.len() => Ok(function),
+            _ => Err(ArtifactError::Malformed),
+        }
+    })?;
+    let relocations = r.list(|r| {
+        let relocation = r.relocation()?;
+        // Relocations write at most 8 bytes.
+        if relocation.code_offset as usize + 8 > code.len() {
+            return Err(ArtifactError::Malformed);
+        }
+        Ok(relocation)
+    })?;
+    let trap_sites = r.list(|r| {
+        Ok(TrapSite {
+            code_offset: r.u32()?,
+            trap_code: r.trap_code()?,
+        })
+    })?;
+
+    // Module
+    let start_func = r.option_u32()?.map(FuncIndex::from_u32);
+    let func_sigs = r.list(|r| Ok(SignatureIndex::from_u32(r.u32()?)))?;
+    let signatures = r.list(|r| r.signature(isa))?;
+    let memories = r.list(|r| {
+        Ok(Memory {
+            minimum: r.u32()?,
+            maximum: r.option_u32()?,
+            shared: r.u8()? != 0,
+        })
+    })?;
+    let data_initializers = r.list(|r| {
+        Ok(DataInitializer {
+            memory_index: MemoryIndex::from_u32(r.u32()?),
+            base: r.option_u32()?.map(GlobalIndex::from_u32),
+            offset: r.u64()? as usize,
+            data: r.bytes()?,
+        })
+    })?;
+    let passive_data = r.map(|r| Ok((DataIndex::from_u32(r.u32()?), r.bytes()?)))?;
+    let passive_elements = r.map(|r| Ok((ElemIndex::from_u32(r.u32()?), r.func_indices()?)))?;
+    let mut imports = Vec::with_capacity(4);
+    for _ in 0..4 {
+        imports.push(r.list(|r| {
+            Ok(Import {
+                module: String::from(r.str()?),
+                field: String::from(r.str()?),
+            })
+        })?);
+    }
+    let exports = r.map(|r| {
+        let name = r.str()?;
+        let kind = r.u8()?;
+        let idx = r.u32()?;
+        let export = match kind {
+            0 => Export::Function(FuncIndex::from_u32(idx)),
+            1 => Export::Table(TableIndex::from_u32(idx)),
+            2 => Export::Memory(MemoryIndex::from_u32(idx)),
+            3 => Export::Global(GlobalIndex::from_u32(idx)),
+            _ => return Err(ArtifactError::Malformed),
+        };
+        Ok((name, export))
+    })?;
+    let tables = r.list(|r| {
+        let ty = match r.u8()? {
+            0 => TableElementType::Func,
+            ty => TableElementType::Val(value_type_from(ty - 1)?),
+        };
+        Ok(TableDecl {
+            ty,
+            minimum: r.u32()?,
+            maximum: r.option_u32()?,
+        })
+    })?;
+    let table_elements = r.list(|r| {
+        Ok(TableElements {
+            index: TableIndex::from_u32(r.u32()?),
+            base: r.option_u32()?.map(GlobalIndex::from_u32),
+            offset: r.u64()? as usize,
+            elements: r.func_indices()?,
+        })
+    })?;
+    let globals = r.list(|r| {
+        Ok(GlobalDecl {
+            ty: value_type_from(r.u8()?)?,
+            mutability: r.u8()? != 0,
+            initializer: r.global_init()?,
+        })
+    })?;
+    let func_names = r.map(|r| Ok((FuncIndex::from_u32(r.u32()?), r.str()?)))?;
+
+    if !r.data.is_empty() {
+        return Err(ArtifactError::Malformed);
+    }
+
+    // Calls to user functions can only target defined functions.
+    let num_imported_funcs = imports[0].len();
+    let defined_functions = num_imported_funcs..num_imported_funcs + functions.len();
+    if relocations
+        .iter()
+        .any(|relocation| match relocation.target {
+            RelocationTarget::UserFunction(idx) => {
+                !defined_functions.contains(&(idx.as_u32() as usize))
+            }
+            _ => false,
+        })
+    {
+        return Err(ArtifactError::Malformed);
+    }
+
+    let mut imports = imports.into_iter();
+    let mut next_imports = || {
+        imports
+            .next()
+            .expect("four import lists")
+            .into_boxed_slice()
+    };
+
+    Ok(CompiledModule {
+        code,
+        functions: functions.into_boxed_slice(),
+        relocations: relocations.into_boxed_slice(),
+        trap_sites: trap_sites.into_boxed_slice(),
+        start_func,
+        func_sigs: func_sigs.into_boxed_slice(),
+        signatures: signatures.into_boxed_slice(),
+        memories: memories.into_boxed_slice(),
+        data_initializers: data_initializers.into_boxed_slice(),
+        passive_data,
+        passive_elements,
+        function_imports: next_imports(),
+        memory_imports: next_imports(),
+        table_imports: next_imports(),
+        global_imports: next_imports(),
+        exports,
+        tables: tables.into_boxed_slice(),
+        table_elements: table_elements.into_boxed_slice(),
+        globals: globals.into_boxed_slice(),
+        func_names,
+    })
+}
+
+/// Gets the encoding of a value type.
+fn value_type_to(ty: Type) -> Result<u8, ArtifactError> {
+    VALUE_TYPES
+        .iter()
+        .position(|t| *t == ty)
+        .map(|encoding| encoding as u8)
+        .ok_or(ArtifactError::Unsupported)
+}
+
+/// Gets a value type from its encoding.
+fn value_type_from(encoding: u8) -> Result<Type, ArtifactError> {
+    VALUE_TYPES
+        .get(encoding as usize)
+        .copied()
+        .ok_or(ArtifactError::Malformed)
+}
+
+/// Writes the parts of an artifact.
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn option_u32(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u32(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, str: &str) {
+        self.bytes(str.as_bytes());
+    }
+
+    fn func_indices(&mut self, indices: &[FuncIndex]) {
+        self.len(indices.len());
+        for idx in indices {
+            self.u32(idx.as_u32());
+        }
+    }
+
+    fn signature(
+        &mut self,
+        signature: &Signature,
+        isa: &dyn TargetIsa,
+    ) -> Result<(), ArtifactError> {
+        if signature.call_conv != isa.frontend_config().default_call_conv {
+            return Err(ArtifactError::Unsupported);
+        }
+
+        for params in &[&signature.params, &signature.returns] {
+            self.len(params.len());
+            for param in params.iter() {
+                let purpose = match param.purpose {
+                    ArgumentPurpose::Normal => 0,
+                    ArgumentPurpose::VMContext => 1,
+                    _ => return Err(ArtifactError::Unsupported),
+                };
+                self.u8(value_type_to(param.value_type)?);
+                self.u8(purpose);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn relocation(&mut self, relocation: &Relocation) -> Result<(), ArtifactError> {
+        self.u32(relocation.code_offset);
+        self.u8(match relocation.reloc {
+            Reloc::X86PCRel4 => 0,
+            Reloc::X86CallPCRel4 => 1,
+            Reloc::Abs8 => 2,
+            Reloc::X86PCRelRodata4 => 3,
+            _ => return Err(ArtifactError::Unsupported),
+        });
+        match relocation.target {
+            RelocationTarget::UserFunction(idx) => {
+                self.u8(0);
+                self.u32(idx.as_u32());
+            }
+            RelocationTarget::RuntimeFunction(idx) => {
+                self.u8(1);
+                self.u32(idx);
+            }
+            RelocationTarget::LibCall(LibCall::Probestack) => {
+                self.u8(2);
+                self.u32(0);
+            }
+            RelocationTarget::LibCall(_) => return Err(ArtifactError::Unsupported),
+        }
+        self.u64(relocation.addend as u64);
+        Ok(())
+    }
+
+    fn trap_code(&mut self, trap_code: TrapCode) -> Result<(), ArtifactError> {
+        let encoding = match trap_code {
+            TrapCode::StackOverflow => 0,
+            TrapCode::HeapOutOfBounds => 1,
+            TrapCode::TableOutOfBounds => 2,
+            TrapCode::IndirectCallToNull => 3,
+            TrapCode::BadSignature => 4,
+            TrapCode::IntegerOverflow => 5,
+            TrapCode::IntegerDivisionByZero => 6,
+            TrapCode::BadConversionToInteger => 7,
+            TrapCode::UnreachableCodeReached => 8,
+            TrapCode::Interrupt => 9,
+            TrapCode::User(code) => {
+                self.u8(10);
+                self.u16(code);
+                return Ok(());
+            }
+            _ => return Err(ArtifactError::Unsupported),
+        };
+        self.u8(encoding);
+        Ok(())
+    }
+
+    fn global_init(&mut self, initializer: GlobalInit) -> Result<(), ArtifactError> {
+        let (kind, value) = match initializer {
+            GlobalInit::I32Const(v) => (0, v as u32 as u64),
+            GlobalInit::I64Const(v) => (1, v as u64),
+            GlobalInit::F32Const(v) => (2, v as u64),
+            GlobalInit::F64Const(v) => (3, v),
+            GlobalInit::GetGlobal(idx) => (4, idx.as_u32() as u64),
+            GlobalInit::Import => (5, 0),
+            GlobalInit::RefNullConst => (6, 0),
+            GlobalInit::RefFunc(idx) => (7, idx.as_u32() as u64),
+            _ => return Err(ArtifactError::Unsupported),
+        };
+        self.u8(kind);
+        self.u64(value);
+        Ok(())
+    }
+}
+
+/// Reads the parts of an artifact.
+struct Reader<'data> {
+    data: &'data [u8],
+}
+
+impl<'data> Reader<'data> {
+    fn take(&mut self, len: usize) -> Result<&'data [u8], ArtifactError> {
+        if len > self.data.len() {
+            return Err(ArtifactError::Malformed);
+        }
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ArtifactError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ArtifactError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ArtifactError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ArtifactError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a list length. Every item takes at least one byte, which bounds the allocations.
+    fn len(&mut self) -> Result<usize, ArtifactError> {
+        let len = self.u32()? as usize;
+        if len > self.data.len() {
+            Err(ArtifactError::Malformed)
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn option_u32(&mut self) -> Result<Option<u32>, ArtifactError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.u32()?)),
+            _ => Err(ArtifactError::Malformed),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'data [u8], ArtifactError> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'data str, ArtifactError> {
+        str::from_utf8(self.bytes()?).map_err(|_| ArtifactError::Malformed)
+    }
+
+    fn list<T, F>(&mut self, mut f: F) -> Result<Vec<T>, ArtifactError>
+    where
+        F: FnMut(&mut Self) -> Result<T, ArtifactError>,
+    {
+        let len = self.len()?;
+        let mut list = Vec::with_capacity(len);
+        for _ in 0..len {
+            list.push(f(self)?);
+        }
+        Ok(list)
+    }
+
+    fn map<K, V, F>(&mut self, mut f: F) -> Result<HashMap<K, V>, ArtifactError>
+    where
+        K: core::hash::Hash + Eq,
+        F: FnMut(&mut Self) -> Result<(K, V), ArtifactError>,
+    {
+        let len = self.len()?;
+        let mut map = HashMap::with_capacity(len);
+        for _ in 0..len {
+            let (key, value) = f(self)?;
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+
+    fn func_indices(&mut self) -> Result<Box<[FuncIndex]>, ArtifactError> {
+        Ok(self
+            .list(|r| Ok(FuncIndex::from_u32(r.u32()?)))?
+            .into_boxed_slice())
+    }
+
+    fn signature(&mut self, isa: &dyn TargetIsa) -> Result<Signature, ArtifactError> {
+        let mut signature = Signature::new(isa.frontend_config().default_call_conv);
+        signature.params = self.list(|r| r.abi_param())?;
+        signature.returns = self.list(|r| r.abi_param())?;
+        Ok(signature)
+    }
+
+    fn abi_param(&mut self) -> Result<AbiParam, ArtifactError> {
+        let ty = value_type_from(self.u8()?)?;
+        match self.u8()? {
+            0 => Ok(AbiParam::new(ty)),
+            1 => Ok(AbiParam::special(ty, ArgumentPurpose::VMContext)),
+            _ => Err(ArtifactError::Malformed),
+        }
+    }
+
+    fn relocation(&mut self) -> Result<Relocation, ArtifactError> {
+        let code_offset = self.u32()?;
+        let reloc = match self.u8()? {
+            0 => Reloc::X86PCRel4,
+            1 => Reloc::X86CallPCRel4,
+            2 => Reloc::Abs8,
+            3 => Reloc::X86PCRelRodata4,
+            _ => return Err(ArtifactError::Malformed),
+        };
+        let kind = self.u8()?;
+        let idx = self.u32()?;
+        let target = match kind {
+            0 => RelocationTarget::UserFunction(FuncIndex::from_u32(idx)),
+            1 => RelocationTarget::RuntimeFunction(idx),
+            2 => RelocationTarget::LibCall(LibCall::Probestack),
+            _ => return Err(ArtifactError::Malformed),
+        };
+        Ok(Relocation {
+            code_offset,
+            reloc,
+            target,
+            addend: self.u64()? as i64,
+        })
+    }
+
+    fn trap_code(&mut self) -> Result<TrapCode, ArtifactError> {
+        Ok(match self.u8()? {
+            0 => TrapCode::StackOverflow,
+            1 => TrapCode::HeapOutOfBounds,
+            2 => TrapCode::TableOutOfBounds,
+            3 => TrapCode::IndirectCallToNull,
+            4 => TrapCode::BadSignature,
+            5 => TrapCode::IntegerOverflow,
+            6 => TrapCode::IntegerDivisionByZero,
+            7 => TrapCode::BadConversionToInteger,
+            8 => TrapCode::UnreachableCodeReached,
+            9 => TrapCode::Interrupt,
+            10 => TrapCode::User(self.u16()?),
+            _ => return Err(ArtifactError::Malformed),
+        })
+    }
+
+    fn global_init(&mut self) -> Result<GlobalInit, ArtifactError> {
+        let kind = self.u8()?;
+        let value = self.u64()?;
+        Ok(match kind {
+            0 => GlobalInit::I32Const(value as u32 as i32),
+            1 => GlobalInit::I64Const(value as i64),
+            2 => GlobalInit::F32Const(value as u32),
+            3 => GlobalInit::F64Const(value),
+            4 => GlobalInit::GetGlobal(GlobalIndex::from_u32(value as u32)),
+            5 => GlobalInit::Import,
+            6 => GlobalInit::RefNullConst,
+            7 => GlobalInit::RefFunc(FuncIndex::from_u32(value as u32)),
+            _ => return Err(ArtifactError::Malformed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+    use crate::{target_isa, TARGET_TRIPLE};
+    use cranelift_codegen::{isa, settings};
+
+    /// Two functions of type `() -> i32`, the exported second one calls the first one:
+    /// `(func (result i32) i32.const 42) (func (export "f") (result i32) call 0)`.
+    const MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // Header
+        0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, // Types
+        0x03, 0x03, 0x02, 0x00, 0x00, // Functions
+        0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x01, // Exports
+        0x0a, 0x0b, 0x02, 0x04, 0x00, 0x41, 0x2a, 0x0b, 0x04, 0x00, 0x10, 0x00, 0x0b, // Code
+    ];
+
+    fn isa() -> Box<dyn TargetIsa> {
+        target_isa()
+    }
+
+    fn artifact(isa: &dyn TargetIsa, module: &CompiledModule) -> Vec<u8> {
+        serialize(module, MODULE, isa, CompileOptions::default()).expect("serializable")
+    }
+
+    /// Compares using the debug representation, most compiled parts don't implement `PartialEq`.
+    fn assert_debug_eq<T: core::fmt::Debug>(a: T, b: T) {
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+    }
+
+    #[test]
+    fn round_trip() {
+        let isa = isa();
+        let module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let artifact = artifact(&*isa, &module);
+        let read =
+            deserialize(&artifact, MODULE, &*isa, CompileOptions::default()).expect("deserializes");
+
+        assert_eq!(read.code, module.code);
+        assert_debug_eq(&read.functions, &module.functions);
+        assert_debug_eq(&read.relocations, &module.relocations);
+        assert_debug_eq(&read.trap_sites, &module.trap_sites);
+        assert_eq!(read.start_func, module.start_func);
+        assert_eq!(read.func_sigs, module.func_sigs);
+        assert_eq!(read.signatures, module.signatures);
+        assert_eq!(read.exports.len(), 1);
+        assert_debug_eq(read.exports.get("f"), module.exports.get("f"));
+
+        // Serializing what was read gives the same artifact.
+        assert_eq!(self::artifact(&*isa, &read), artifact);
+    }
+
+    #[test]
+    fn rejects_other_header() {
+        let isa = isa();
+        let module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let artifact = artifact(&*isa, &module);
+        let options = CompileOptions::default();
+
+        let mut wrong_magic = artifact.clone();
+        wrong_magic[0] ^= 0xff;
+        assert!(matches!(
+            deserialize(&wrong_magic, MODULE, &*isa, options),
+            Err(ArtifactError::InvalidHeader)
+        ));
+
+        let mut wrong_version = artifact.clone();
+        wrong_version[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            deserialize(&wrong_version, MODULE, &*isa, options),
+            Err(ArtifactError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn rejects_stale() {
+        let isa = isa();
+        let module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let artifact = artifact(&*isa, &module);
+
+        let mut other_source = MODULE.to_vec();
+        *other_source.last_mut().unwrap() = 0x01;
+        assert!(matches!(
+            deserialize(&artifact, &other_source, &*isa, CompileOptions::default()),
+            Err(ArtifactError::Stale)
+        ));
+
+        let other_options = CompileOptions {
+            fuel_metering: true,
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            deserialize(&artifact, MODULE, &*isa, other_options),
+            Err(ArtifactError::Stale)
+        ));
+    }
+
+    #[test]
+    fn tool_and_kernel_settings_match() {
+        // The AOT tool runs on the build host and the kernel in the guest, both configure the ISA
+        // through `target_isa`, which must not depend on where it runs.
+        let tool_isa = target_isa();
+        let kernel_isa = target_isa();
+        let options = CompileOptions::default();
+        assert_eq!(tool_isa.triple().to_string(), TARGET_TRIPLE);
+        assert_eq!(
+            settings_hash(&*tool_isa, options),
+            settings_hash(&*kernel_isa, options)
+        );
+
+        let module = compile(&*tool_isa, MODULE, options).expect("compiles");
+        let artifact = artifact(&*tool_isa, &module);
+        assert!(deserialize(&artifact, MODULE, &*kernel_isa, options).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_targets() {
+        let isa = isa();
+        let module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let artifact = artifact(&*isa, &module);
+
+        // Code compiled for the build host can't be used by the kernel.
+        let host_isa = isa::lookup_by_name("x86_64-unknown-linux-gnu")
+            .expect("x86_64 isa")
+            .finish(settings::Flags::new(settings::builder()));
+        assert!(matches!(
+            deserialize(&artifact, MODULE, &*host_isa, CompileOptions::default()),
+            Err(ArtifactError::Stale)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let isa = isa();
+        let module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let artifact = artifact(&*isa, &module);
+        let options = CompileOptions::default();
+
+        for len in 0..artifact.len() {
+            assert!(deserialize(&artifact[..len], MODULE, &*isa, options).is_err());
+        }
+
+        let mut trailing = artifact;
+        trailing.push(0);
+        assert!(matches!(
+            deserialize(&trailing, MODULE, &*isa, options),
+            Err(ArtifactError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_relocation_targets() {
+        let isa = isa();
+        let mut module = compile(&*isa, MODULE, CompileOptions::default()).expect("compiles");
+        let position = module
+            .relocations
+            .iter()
+            .position(|relocation| matches!(relocation.target, RelocationTarget::UserFunction(_)))
+            .expect("the call is relocated");
+
+        // Only the two defined functions can be called.
+        module.relocations[position].target =
+            RelocationTarget::UserFunction(FuncIndex::from_u32(2));
+        assert!(matches!(
+            deserialize(
+                &artifact(&*isa, &module),
+                MODULE,
+                &*isa,
+                CompileOptions::default()
+            ),
+            Err(ArtifactError::Malformed)
+        ));
+
+        // The relocation must be inside the code.
+        module.relocations[position].target =
+            RelocationTarget::UserFunction(FuncIndex::from_u32(0));
+        module.relocations[position].code_offset = module.code.len() as u32 - 4;
+        assert!(matches!(
+            deserialize(
+                &artifact(&*isa, &module),
+                MODULE,
+                &*isa,
+                CompileOptions::default()
+            ),
+            Err(ArtifactError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,361 @@
+//! Based on https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src
+
+use crate::func_env::FuncEnv;
+use crate::module_env::{
+    DataInitializer, Export, FunctionBody, GlobalDecl, Import, ModuleEnv, TableDecl, TableElements,
+};
+use crate::reloc_sink::{RelocSink, Relocation};
+use crate::trap::{TrapSink, TrapSite};
+use crate::vmctx::VmContextLayout;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use cranelift_codegen::binemit::NullStackMapSink;
+use cranelift_codegen::ir::Signature;
+use cranelift_codegen::isa::{self, TargetIsa};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::{CodegenError, Context};
+use cranelift_wasm::{translate_module, DataIndex, ElemIndex, Memory, SignatureIndex};
+use cranelift_wasm::{FuncIndex, FuncTranslator, ModuleTranslationState, WasmError};
+use hashbrown::HashMap;
+
+#[derive(Debug)]
+pub enum Error {
+    /// WebAssembly translation error.
+    WasmError(WasmError),
+    /// Code generation error.
+    CodegenError(CodegenError),
+    /// The start function takes arguments or returns values.
+    InvalidStart,
+}
+
+/// A defined function inside the code of a compiled module.
+#[derive(Debug, Copy, Clone)]
+pub struct CompiledFunction {
+    /// Offset in the code.
+    pub offset: u32,
+    /// Size in bytes.
+    pub size: u32,
+}
+
+/// Options that change the generated code.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CompileOptions {
+    /// Consume fuel at function entries and loop headers, and trap with `TRAP_OUT_OF_FUEL` when it
+    /// runs out.
+    pub fuel_metering: bool,
+    /// Check the epoch deadline at function entries and loop headers, and trap with
+    /// `TRAP_EPOCH_DEADLINE` when it is reached.
+    pub epoch_interruption: bool,
+}
+
+/// A compiled module.
+/// The code is position-independent until the relocations are applied.
+pub struct CompiledModule<'data> {
+    /// Code of all defined functions.
+    pub code: Vec<u8>,
+    /// Defined functions, in order of their index.
+    pub functions: Box<[CompiledFunction]>,
+    /// Relocations, with offsets relative to the start of the code.
+    pub relocations: Box<[Relocation]>,
+    /// Trap sites, with offsets relative to the start of the code.
+    pub trap_sites: Box<[TrapSite]>,
+    pub start_func: Option<FuncIndex>,
+    pub func_sigs: Box<[SignatureIndex]>,
+    pub signatures: Box<[Signature]>,
+    pub memories: Box<[Memory]>,
+    pub data_initializers: Box<[DataInitializer<'data>]>,
+    pub passive_data: HashMap<DataIndex, &'data [u8]>,
+    pub passive_elements: HashMap<ElemIndex, Box<[FuncIndex]>>,
+    pub function_imports: Box<[Import]>,
+    pub memory_imports: Box<[Import]>,
+    pub table_imports: Box<[Import]>,
+    pub global_imports: Box<[Import]>,
+    pub exports: HashMap<&'data str, Export>,
+    pub tables: Box<[TableDecl]>,
+    pub table_elements: Box<[TableElements]>,
+    pub globals: Box<[GlobalDecl]>,
+    pub func_names: HashMap<FuncIndex, &'data str>,
+}
+
+impl<'data> CompiledModule<'data> {
+    /// Gets the signature of a function.
+    pub fn get_sig(&self, func_idx: FuncIndex) -> &Signature {
+        let sig_idx = self.func_sigs[func_idx.as_u32() as usize];
+        &self.signatures[sig_idx.as_u32() as usize]
+    }
+
+    /// Gets the offset of the defined functions in the function index space.
+    pub fn defined_function_offset(&self) -> usize {
+        self.function_imports.len()
+    }
+
+    /// Gets a defined function.
+    pub fn get_function(&self, func_idx: FuncIndex) -> CompiledFunction {
+        self.functions[func_idx.as_u32() as usize - self.defined_function_offset()]
+    }
+}
+
+/// Target of WebAssembly code, the AOT tool on the build host compiles for it as well.
+pub const TARGET_TRIPLE: &str = "x86_64-unknown-none";
+
+/// CPU features WebAssembly code may use, the kernel requires them.
+/// These are fixed instead of detected, so the code doesn't depend on the CPU it was compiled on
+/// and artifacts match the settings of the kernel.
+pub const TARGET_FEATURES: [&str; 5] = [
+    "has_sse3",
+    "has_ssse3",
+    "has_sse41",
+    "has_sse42",
+    "has_popcnt",
+];
+
+/// Creates the target ISA with the settings used for WebAssembly code.
+pub fn target_isa() -> Box<dyn TargetIsa> {
+    let mut isa_builder = isa::lookup_by_name(TARGET_TRIPLE).expect("supported target");
+    for feature in TARGET_FEATURES.iter() {
+        isa_builder.enable(feature).expect("valid feature");
+    }
+
+    let mut flag_builder = settings::builder();
+
+    // Flags
+    flag_builder
+        .set("opt_level", "speed_and_size")
+        .expect("valid flag");
+    flag_builder
+        .set("enable_probestack", "true")
+        .expect("valid flag");
+    flag_builder.set("enable_simd", "true").expect("valid flag");
+    // TODO: avoid div traps?
+
+    let flags = settings::Flags::new(flag_builder);
+    // println!("{}", flags.to_string());
+    isa_builder.finish(flags)
+}
+
+/// The code of a single function.
+/// The offsets of the relocations and trap sites are relative to the start of the code.
+pub struct CompiledCode {
+    pub code: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    pub trap_sites: Vec<TrapSite>,
+}
+
+/// A translated module of which the defined functions are not compiled yet.
+/// The functions can be compiled independently of each other, for example by multiple threads.
+pub struct TranslatedModule<'data> {
+    env: ModuleEnv<'data>,
+    translation: ModuleTranslationState,
+    options: CompileOptions,
+}
+
+impl<'data> TranslatedModule<'data> {
+    /// Translates a module, function bodies are translated when they are compiled.
+    pub fn new(
+        isa: &dyn TargetIsa,
+        buffer: &'data [u8],
+        options: CompileOptions,
+    ) -> Result<Self, Error> {
+        let mut env = ModuleEnv::new(isa.frontend_config());
+        let translation = translate_module(buffer, &mut env).map_err(Error::WasmError)?;
+        Ok(Self {
+            env,
+            translation,
+            options,
+        })
+    }
+
+    /// Gets the offset of the defined functions in the function index space.
+    pub fn defined_function_offset(&self) -> usize {
+        self.env.function_imports.len()
+    }
+
+    /// Gets the amount of defined functions.
+    pub fn num_defined_functions(&self) -> usize {
+        self.env.func_bodies.len()
+    }
+
+    /// Translates a defined function without compiling it, which validates its body.
+    pub fn validate_function(&self, func_idx: FuncIndex) -> Result<(), Error> {
+        self.translate_function(func_idx).map(|_| ())
+    }
+
+    /// Translates a defined function to Cranelift IR.
+    fn translate_function(&self, func_idx: FuncIndex) -> Result<Context, Error> {
+        let mut ctx = Context::new();
+        ctx.func.signature = self.env.get_sig_from_func(func_idx);
+
+        let FunctionBody { body, offset } =
+            self.env.func_bodies[func_idx.as_u32() as usize - self.defined_function_offset()];
+
+        let mut func_trans = FuncTranslator::new();
+        func_trans
+            .translate(
+                &self.translation,
+                body,
+                offset,
+                &mut ctx.func,
+                &mut FuncEnv::new(&self.env, self.options),
+            )
+            .map_err(Error::WasmError)?;
+
+        Ok(ctx)
+    }
+
+    /// Compiles a defined function.
+    pub fn compile_function(
+        &self,
+        isa: &dyn TargetIsa,
+        func_idx: FuncIndex,
+    ) -> Result<CompiledCode, Error> {
+        let mut ctx = self.translate_function(func_idx)?;
+
+        let mut code: Vec<u8> = Vec::new();
+        let mut reloc_sink = RelocSink::new();
+        let mut trap_sink = TrapSink::new();
+        let mut null_stackmap_sink = NullStackMapSink {};
+        ctx.compile_and_emit(
+            isa,
+            &mut code,
+            &mut reloc_sink,
+            &mut trap_sink,
+            &mut null_stackmap_sink,
+        )
+        .map_err(Error::CodegenError)?;
+
+        Ok(CompiledCode {
+            code,
+            relocations: reloc_sink.relocations,
+            trap_sites: trap_sink.traps,
+        })
+    }
+
+    /// Creates the compiled module from the code of the defined functions, in order of their index.
+    /// The code is laid out in that order too.
+    pub fn finish(mut self, functions: Vec<CompiledCode>) -> Result<CompiledModule<'data>, Error> {
+        assert_eq!(functions.len(), self.num_defined_functions());
+
+        let mut code: Vec<u8> = Vec::with_capacity(functions.iter().map(|f| f.code.len()).sum());
+        let mut offsets: Vec<CompiledFunction> = Vec::with_capacity(functions.len());
+        let mut relocations: Vec<Relocation> = Vec::new();
+        let mut trap_sites: Vec<TrapSite> = Vec::new();
+        for function in functions {
+            let code_offset = code.len() as u32;
+            code.extend_from_slice(&function.code);
+
+            offsets.push(CompiledFunction {
+                offset: code_offset,
+                size: function.code.len() as u32,
+            });
+
+            // Make the offsets relative to the code instead of the function.
+            relocations.extend(
+                function
+                    .relocations
+                    .into_iter()
+                    .map(|relocation| Relocation {
+                        code_offset: relocation.code_offset + code_offset,
+                        ..relocation
+                    }),
+            );
+            trap_sites.extend(function.trap_sites.iter().map(|site| TrapSite {
+                code_offset: site.code_offset + code_offset,
+                trap_code: site.trap_code,
+            }));
+        }
+
+        compiled_module(&mut self.env, code, offsets, relocations, trap_sites)
+    }
+
+    /// Creates the compiled module with the given code, and keeps what is needed to compile the
+    /// defined functions later.
+    pub(crate) fn finish_lazily(
+        mut self,
+        code: Vec<u8>,
+        functions: Vec<CompiledFunction>,
+        relocations: Vec<Relocation>,
+    ) -> Result<(CompiledModule<'data>, Self), Error> {
+        let compiled_module =
+            compiled_module(&mut self.env, code, functions, relocations, Vec::new())?;
+        Ok((compiled_module, self))
+    }
+
+    /// Gets the layout of the VmContext.
+    pub(crate) fn vmctx_layout(&self) -> VmContextLayout {
+        self.env.vmctx_layout()
+    }
+}
+
+/// Creates the compiled module from the module environment and the code of the defined functions.
+/// What the translation of function bodies needs is kept in the environment.
+fn compiled_module<'data>(
+    env: &mut ModuleEnv<'data>,
+    code: Vec<u8>,
+    functions: Vec<CompiledFunction>,
+    relocations: Vec<Relocation>,
+    trap_sites: Vec<TrapSite>,
+) -> Result<CompiledModule<'data>, Error> {
+    // Determine start function. If it's not given, search for "_start" as specified by WASI.
+    let start_func = env.start_func.or_else(|| match env.exports.get("_start") {
+        Some(Export::Function(idx)) => Some(*idx),
+        _ => None,
+    });
+
+    let compiled_module = CompiledModule {
+        code,
+        functions: functions.into_boxed_slice(),
+        relocations: relocations.into_boxed_slice(),
+        trap_sites: trap_sites.into_boxed_slice(),
+        memories: env.memories.clone().into_boxed_slice(),
+        func_sigs: env.func_sigs.clone().into_boxed_slice(),
+        data_initializers: mem::take(&mut env.data_initializers).into_boxed_slice(),
+        passive_data: mem::take(&mut env.passive_data),
+        passive_elements: mem::take(&mut env.passive_elements),
+        start_func,
+        function_imports: env.function_imports.clone().into_boxed_slice(),
+        memory_imports: mem::take(&mut env.memory_imports).into_boxed_slice(),
+        table_imports: mem::take(&mut env.table_imports).into_boxed_slice(),
+        global_imports: env.global_imports.clone().into_boxed_slice(),
+        tables: env.tables.clone().into_boxed_slice(),
+        table_elements: mem::take(&mut env.table_elements).into_boxed_slice(),
+        globals: env.globals.clone().into_boxed_slice(),
+        exports: mem::take(&mut env.exports),
+        func_names: mem::take(&mut env.func_names),
+        signatures: env.signatures.clone().into_boxed_slice(),
+    };
+
+    // Check the signature of the start function.
+    // Must not take any arguments (which means arg length == 1 because vmctx)
+    // and not have return values.
+    if let Some(start_func) = start_func {
+        let sig = compiled_module.get_sig(start_func);
+
+        if !sig.returns.is_empty() || sig.params.len() != 1 {
+            return Err(Error::InvalidStart);
+        }
+    }
+
+    Ok(compiled_module)
+}
+
+/// Compiles a WebAssembly buffer.
+pub fn compile<'data>(
+    isa: &dyn TargetIsa,
+    buffer: &'data [u8],
+    options: CompileOptions,
+) -> Result<CompiledModule<'data>, Error> {
+    let translated_module = TranslatedModule::new(isa, buffer, options)?;
+    let defined_function_offset = translated_module.defined_function_offset();
+
+    let functions = (0..translated_module.num_defined_functions())
+        .map(|idx| {
+            translated_module.compile_function(
+                isa,
+                FuncIndex::from_u32((idx + defined_function_offset) as u32),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    translated_module.finish(functions)
+}
@@ -0,0 +1,791 @@
+//! Based on https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src
+
+use crate::compile::CompileOptions;
+use crate::module_env::ModuleEnv;
+use crate::runtime::{RuntimeFunctionData, RUNTIME_ATOMIC_TRAP, RUNTIME_NAMESPACE};
+use crate::runtime::{
+    RUNTIME_DATA_DROP_DATA, RUNTIME_ELEM_DROP_DATA, RUNTIME_MEMORY_ATOMIC_NOTIFY_DATA,
+    RUNTIME_MEMORY_ATOMIC_WAIT32_DATA, RUNTIME_MEMORY_ATOMIC_WAIT64_DATA, RUNTIME_MEMORY_COPY_DATA,
+    RUNTIME_MEMORY_FILL_DATA, RUNTIME_MEMORY_GROW_DATA, RUNTIME_MEMORY_INIT_DATA,
+    RUNTIME_MEMORY_SIZE_DATA, RUNTIME_REF_FUNC_DATA, RUNTIME_TABLE_COPY_DATA,
+    RUNTIME_TABLE_FILL_DATA, RUNTIME_TABLE_GET_DATA, RUNTIME_TABLE_GROW_DATA,
+    RUNTIME_TABLE_INIT_DATA, RUNTIME_TABLE_SET_DATA,
+};
+use crate::trap::{TRAP_EPOCH_DEADLINE, TRAP_OUT_OF_FUEL};
+use crate::vmctx::{
+    memory_style, MemoryStyle, VmContextLayout, FUNCTION_IMPORT_ADDRESS_OFFSET,
+    FUNCTION_IMPORT_VMCTX_OFFSET, HEAP_GUARD_SIZE, HEAP_SIZE, MEMORY_BASE_ADDRESS_OFFSET,
+    MEMORY_CURRENT_LENGTH_OFFSET, TABLE_AMOUNT_ITEMS_OFFSET, TABLE_BASE_ADDRESS_OFFSET,
+    TABLE_ELEMENT_ADDRESS_OFFSET, TABLE_ELEMENT_SIG_IDX_OFFSET, TABLE_ELEMENT_SIZE,
+    TABLE_ELEMENT_VMCTX_OFFSET, WASM_PAGE_SIZE,
+};
+use alloc::vec::Vec;
+use cranelift_codegen::cursor::FuncCursor;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::immediates::{Imm64, Offset32, Uimm64};
+use cranelift_codegen::ir::{
+    types, ArgumentPurpose, ExtFuncData, ExternalName, FuncRef, Function, GlobalValue,
+    GlobalValueData, Heap, HeapData, HeapStyle, Inst, InstBuilder, MemFlags, SigRef, Table,
+    TableData, TrapCode, Value,
+};
+use cranelift_codegen::isa::TargetFrontendConfig;
+use cranelift_wasm::{
+    FuncEnvironment, FuncIndex, FuncTranslationState, FunctionBuilder, GlobalIndex, GlobalVariable,
+    MemoryIndex, SignatureIndex, TableIndex, TargetEnvironment, WasmError, WasmResult,
+};
+
+/// Fuel consumed by a function entry or a loop iteration.
+const FUEL_PER_CHECK: i64 = 1;
+
+/// Used to handle transformations on functions.
+pub struct FuncEnv<'m, 'data> {
+    module_env: &'m ModuleEnv<'data>,
+    vmctx_layout: VmContextLayout,
+    options: CompileOptions,
+    vmctx: Option<GlobalValue>,
+    epoch_ptr: Option<GlobalValue>,
+}
+
+impl<'m, 'data> FuncEnv<'m, 'data> {
+    /// Creates a new function environment inside a module environment.
+    pub fn new(module_environment: &'m ModuleEnv<'data>, options: CompileOptions) -> Self {
+        Self {
+            module_env: module_environment,
+            vmctx_layout: module_environment.vmctx_layout(),
+            options,
+            vmctx: None,
+            epoch_ptr: None,
+        }
+    }
+
+    /// VMContext getter.
+    fn vmctx(&mut self, func: &mut Function) -> GlobalValue {
+        self.vmctx.unwrap_or_else(|| {
+            let vmctx = func.create_global_value(GlobalValueData::VMContext);
+            self.vmctx = Some(vmctx);
+            vmctx
+        })
+    }
+
+    /// Translate the signature of a function.
+    fn translate_signature(vmctx: Value, call_args: &[Value]) -> Vec<Value> {
+        let mut call_args_with_vmctx = Vec::with_capacity(call_args.len() + 1);
+        call_args_with_vmctx.push(vmctx);
+        call_args_with_vmctx.extend_from_slice(call_args);
+        call_args_with_vmctx
+    }
+
+    /// Call a runtime function and return the call instruction.
+    fn call_runtime_function_inst(
+        pos: &mut FuncCursor,
+        runtime_func: &RuntimeFunctionData,
+        args: &[Value],
+    ) -> Inst {
+        // TODO: cache?
+        let signature = pos.func.import_signature(runtime_func.signature.clone());
+        let runtime_func_ref = pos.func.import_function(ExtFuncData {
+            name: ExternalName::user(RUNTIME_NAMESPACE, runtime_func.index),
+            signature,
+            colocated: false,
+        });
+        pos.ins().call(runtime_func_ref, args)
+    }
+
+    /// Call a runtime function and return the result.
+    fn call_runtime_function(
+        pos: &mut FuncCursor,
+        runtime_func: &RuntimeFunctionData,
+        args: &[Value],
+    ) -> WasmResult<Value> {
+        let inst = Self::call_runtime_function_inst(pos, runtime_func, args);
+        Ok(*pos.func.dfg.inst_results(inst).first().unwrap())
+    }
+
+    /// Call a runtime function that returns a non-zero value if it wants the caller to trap.
+    fn call_trapping_runtime_function(
+        pos: &mut FuncCursor,
+        runtime_func: &RuntimeFunctionData,
+        args: &[Value],
+        trap_code: TrapCode,
+    ) -> WasmResult<()> {
+        let result = Self::call_runtime_function(pos, runtime_func, args)?;
+        pos.ins().trapnz(result, trap_code);
+        Ok(())
+    }
+
+    /// Call an atomic runtime function, which returns `RUNTIME_ATOMIC_TRAP` if it wants the caller
+    /// to trap, and the result of the instruction otherwise.
+    fn call_atomic_runtime_function(
+        pos: &mut FuncCursor,
+        runtime_func: &RuntimeFunctionData,
+        args: &[Value],
+    ) -> WasmResult<Value> {
+        let result = Self::call_runtime_function(pos, runtime_func, args)?;
+        let trap = pos
+            .ins()
+            .icmp_imm(IntCC::Equal, result, RUNTIME_ATOMIC_TRAP as i32 as i64);
+        pos.ins().trapnz(trap, TrapCode::HeapOutOfBounds);
+        Ok(result)
+    }
+
+    /// Creates an i32 constant for an index.
+    fn index_const(pos: &mut FuncCursor, index: u32) -> Value {
+        pos.ins().iconst(types::I32, Imm64::new(index as i64))
+    }
+
+    /// Emits the checks of a safe point: a function entry or a loop header.
+    /// The traps of these checks are resumable, so they must be a `trapif`: its encoding is a
+    /// conditional jump over the trap instruction, which means the trap instruction is directly
+    /// followed by the next instruction.
+    fn safe_point(&mut self, pos: &mut FuncCursor) {
+        if self.options.fuel_metering {
+            self.consume_fuel(pos);
+        }
+        if self.options.epoch_interruption {
+            self.check_epoch(pos);
+        }
+    }
+
+    /// Consumes fuel, traps if the fuel runs out.
+    /// The counter is not updated atomically, threads of an instance share it,
+    /// so the metering is approximate.
+    fn consume_fuel(&mut self, pos: &mut FuncCursor) {
+        let vmctx = self.vmctx(&mut pos.func);
+        let vmctx = pos.ins().global_value(self.pointer_type(), vmctx);
+        let fuel = pos.ins().load(
+            types::I64,
+            MemFlags::trusted(),
+            vmctx,
+            VmContextLayout::fuel_offset(),
+        );
+        let fuel = pos.ins().iadd_imm(fuel, -FUEL_PER_CHECK);
+        pos.ins().store(
+            MemFlags::trusted(),
+            fuel,
+            vmctx,
+            VmContextLayout::fuel_offset(),
+        );
+        let flags = pos.ins().ifcmp_imm(fuel, 0);
+        pos.ins()
+            .trapif(IntCC::SignedLessThan, flags, TRAP_OUT_OF_FUEL);
+    }
+
+    /// Traps if the epoch reached the deadline.
+    fn check_epoch(&mut self, pos: &mut FuncCursor) {
+        let vmctx = self.vmctx(&mut pos.func);
+        let epoch_ptr = self.epoch_ptr.unwrap_or_else(|| {
+            let epoch_ptr = pos.func.create_global_value(GlobalValueData::Load {
+                base: vmctx,
+                offset: Offset32::new(VmContextLayout::epoch_ptr_offset()),
+                global_type: self.pointer_type(),
+                readonly: true,
+            });
+            self.epoch_ptr = Some(epoch_ptr);
+            epoch_ptr
+        });
+
+        // The epoch is changed by interrupts and the deadline by other threads,
+        // so both must be loaded every time.
+        let epoch_ptr = pos.ins().global_value(self.pointer_type(), epoch_ptr);
+        let epoch = pos
+            .ins()
+            .load(types::I64, MemFlags::trusted(), epoch_ptr, 0);
+        let vmctx = pos.ins().global_value(self.pointer_type(), vmctx);
+        let deadline = pos.ins().load(
+            types::I64,
+            MemFlags::trusted(),
+            vmctx,
+            VmContextLayout::epoch_deadline_offset(),
+        );
+        let flags = pos.ins().ifcmp(epoch, deadline);
+        pos.ins().trapif(
+            IntCC::UnsignedGreaterThanOrEqual,
+            flags,
+            TRAP_EPOCH_DEADLINE,
+        );
+    }
+
+    /// Traps if the index is outside the current bounds of the table.
+    fn table_bounds_check(pos: &mut FuncCursor, table: Table, index: Value) {
+        let bound_gv = pos.func.tables[table].bound_gv;
+        let bound = pos.ins().global_value(types::I32, bound_gv);
+        let out_of_bounds = pos
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, index, bound);
+        pos.ins().trapnz(out_of_bounds, TrapCode::TableOutOfBounds);
+    }
+}
+
+impl<'m, 'data> TargetEnvironment for FuncEnv<'m, 'data> {
+    fn target_config(&self) -> TargetFrontendConfig {
+        self.module_env.target_config()
+    }
+}
+
+impl<'m, 'data> FuncEnvironment for FuncEnv<'m, 'data> {
+    fn make_global(
+        &mut self,
+        func: &mut Function,
+        index: GlobalIndex,
+    ) -> WasmResult<GlobalVariable> {
+        let vmctx = self.vmctx(func);
+        let imported = self.module_env.is_imported_global(index);
+        let index = index.as_u32();
+        let global = self.module_env.globals[index as usize];
+
+        // Imported globals live in the exporting instance, the context holds a pointer to them.
+        let gv = if imported {
+            func.create_global_value(GlobalValueData::Load {
+                base: vmctx,
+                offset: Offset32::new(VmContextLayout::global_entry_offset(index) as i32),
+                global_type: self.pointer_type(),
+                readonly: true,
+            })
+        } else {
+            func.create_global_value(GlobalValueData::IAddImm {
+                base: vmctx,
+                offset: Imm64::new(VmContextLayout::global_entry_offset(index) as i64),
+                global_type: types::I64,
+            })
+        };
+
+        Ok(GlobalVariable::Memory {
+            gv,
+            offset: Offset32::new(0),
+            ty: global.ty,
+        })
+    }
+
+    fn make_heap(&mut self, func: &mut Function, index: MemoryIndex) -> WasmResult<Heap> {
+        let vmctx = self.vmctx(func);
+        let memory = &self.module_env.memories[index.as_u32() as usize];
+
+        // TODO: I64 for memory64, once the parser supports it. No reservation can contain the range
+        //       of a 64-bit index, so those memories need the dynamic style, see `memory_style`.
+        let index_type = types::I32;
+
+        let memory_offset_in_vmctx = self.vmctx_layout.memory_entry_offset(index.as_u32()) as i32;
+
+        // The context holds a pointer to the memory, which can be shared.
+        let memory_gv = func.create_global_value(GlobalValueData::Load {
+            base: vmctx,
+            offset: Offset32::new(memory_offset_in_vmctx),
+            global_type: self.pointer_type(),
+            readonly: true,
+        });
+
+        // Memories never move, only their length changes.
+        let base = func.create_global_value(GlobalValueData::Load {
+            base: memory_gv,
+            offset: Offset32::new(MEMORY_BASE_ADDRESS_OFFSET),
+            global_type: self.pointer_type(),
+            readonly: true,
+        });
+
+        let style = match memory_style(memory) {
+            MemoryStyle::Static => HeapStyle::Static {
+                bound: HEAP_SIZE.into(),
+            },
+            // The current length is loaded with the type of the index, the bounds check compares
+            // them. On little endian, a 32-bit load gets the low half of the length.
+            MemoryStyle::Dynamic => HeapStyle::Dynamic {
+                bound_gv: func.create_global_value(GlobalValueData::Load {
+                    base: memory_gv,
+                    offset: Offset32::new(MEMORY_CURRENT_LENGTH_OFFSET),
+                    global_type: index_type,
+                    readonly: false,
+                }),
+            },
+        };
+
+        Ok(func.create_heap(HeapData {
+            base,
+            min_size: (memory.minimum as u64 * WASM_PAGE_SIZE as u64).into(),
+            offset_guard_size: HEAP_GUARD_SIZE.into(),
+            style,
+            index_type,
+        }))
+    }
+
+    fn make_table(&mut self, func: &mut Function, index: TableIndex) -> Result<Table, WasmError> {
+        let vmctx = self.vmctx(func);
+
+        let table_offset_in_vmctx = self.vmctx_layout.table_entry_offset(index.as_u32()) as i32;
+
+        // The context holds a pointer to the table, which can be shared.
+        let base_gv_offset = func.create_global_value(GlobalValueData::Load {
+            base: vmctx,
+            offset: Offset32::new(table_offset_in_vmctx),
+            global_type: self.pointer_type(),
+            readonly: true,
+        });
+
+        let base_gv = func.create_global_value(GlobalValueData::Load {
+            base: base_gv_offset,
+            offset: Offset32::new(TABLE_BASE_ADDRESS_OFFSET),
+            global_type: self.pointer_type(),
+            readonly: false,
+        });
+
+        let bound_gv = func.create_global_value(GlobalValueData::Load {
+            base: base_gv_offset,
+            offset: Offset32::new(TABLE_AMOUNT_ITEMS_OFFSET),
+            global_type: types::I32,
+            readonly: false,
+        });
+
+        Ok(func.create_table(TableData {
+            base_gv,
+            min_size: Uimm64::new(self.module_env.tables[index.as_u32() as usize].minimum as u64),
+            bound_gv,
+            element_size: Uimm64::new(TABLE_ELEMENT_SIZE as u64),
+            index_type: types::I32,
+        }))
+    }
+
+    fn make_indirect_sig(
+        &mut self,
+        func: &mut Function,
+        index: SignatureIndex,
+    ) -> WasmResult<SigRef> {
+        Ok(func.import_signature(self.module_env.get_sig_from_sigidx(index)))
+    }
+
+    fn make_direct_func(&mut self, func: &mut Function, index: FuncIndex) -> WasmResult<FuncRef> {
+        let signature = func.import_signature(self.module_env.get_sig_from_func(index));
+
+        // User-defined external name. Namespace is defined by us, index is just the function index.
+        let name = ExternalName::user(0, index.as_u32());
+
+        Ok(func.import_function(ExtFuncData {
+            name,
+            signature,
+            colocated: true, // TODO
+        }))
+    }
+
+    fn translate_call_indirect(
+        &mut self,
+        mut pos: FuncCursor,
+        _table_index: TableIndex,
+        table: Table,
+        sig_idx: SignatureIndex,
+        sig_ref: SigRef,
+        callee: Value,
+        call_args: &[Value],
+    ) -> Result<Inst, WasmError> {
+        let table_entry_addr = pos.ins().table_addr(self.pointer_type(), table, callee, 0);
+
+        let func_addr = pos.ins().load(
+            self.pointer_type(),
+            MemFlags::trusted(),
+            table_entry_addr,
+            TABLE_ELEMENT_ADDRESS_OFFSET,
+        );
+
+        let current_sig_idx = pos.ins().load(
+            self.pointer_type(),
+            MemFlags::trusted(),
+            table_entry_addr,
+            TABLE_ELEMENT_SIG_IDX_OFFSET,
+        );
+
+        // The function might belong to another instance.
+        let vmctx = pos.ins().load(
+            self.pointer_type(),
+            MemFlags::trusted(),
+            table_entry_addr,
+            TABLE_ELEMENT_VMCTX_OFFSET,
+        );
+
+        // Table elements refer to the primary context of an instance. If the function belongs to
+        // our instance, keep using our own context, so the thread keeps its own globals.
+        let own_vmctx = self.vmctx(&mut pos.func);
+        let own_vmctx = pos.ins().global_value(self.pointer_type(), own_vmctx);
+        let primary_vmctx = pos.ins().load(
+            self.pointer_type(),
+            MemFlags::trusted(),
+            own_vmctx,
+            VmContextLayout::primary_vmctx_offset(),
+        );
+        let same_instance = pos.ins().icmp(IntCC::Equal, vmctx, primary_vmctx);
+        let vmctx = pos.ins().select(same_instance, own_vmctx, vmctx);
+
+        let call_args_with_vmctx = Self::translate_signature(vmctx, call_args);
+
+        // Check for valid signature, otherwise trap.
+        // Tables can be shared between instances, so the global signature index is used.
+        // It is only known at instantiation, so it's loaded from our own context.
+        // The signature indices are actually 32-bit and we have a reserved value of 64-bit
+        // of all one-bits in the case of an empty entry.
+        // That means in case of an empty entry, this check will always fail, so will always trap.
+        // That means we don't have to check for the null address of the empty entry,
+        // because the signature check will fail anyway.
+        // You can see this as "the empty entry always has an invalid signature".
+        let expected_sig_idx = pos.ins().load(
+            self.pointer_type(),
+            MemFlags::trusted(),
+            own_vmctx,
+            self.vmctx_layout.signature_id_offset(sig_idx.as_u32()) as i32,
+        );
+        let valid = pos
+            .ins()
+            .icmp(IntCC::Equal, current_sig_idx, expected_sig_idx);
+        pos.ins().trapz(valid, TrapCode::BadSignature);
+
+        Ok(pos
+            .ins()
+            .call_indirect(sig_ref, func_addr, &call_args_with_vmctx))
+    }
+
+    fn translate_call(
+        &mut self,
+        mut pos: FuncCursor,
+        callee_index: FuncIndex,
+        callee: FuncRef,
+        call_args: &[Value],
+    ) -> WasmResult<Inst> {
+        if self.module_env.is_imported_func(callee_index) {
+            let sig_ref = pos.func.dfg.ext_funcs[callee].signature;
+
+            // Get callee address and context from vmctx.
+            let vmctx = self.vmctx(&mut pos.func);
+            let gv = pos.func.create_global_value(GlobalValueData::IAddImm {
+                base: vmctx,
+                offset: Imm64::new(
+                    self.vmctx_layout
+                        .imported_func_entry_offset(callee_index.as_u32())
+                        as i64,
+                ),
+                global_type: self.pointer_type(),
+            });
+            let addr = pos.func.create_global_value(GlobalValueData::Load {
+                base: gv,
+                offset: Offset32::new(FUNCTION_IMPORT_ADDRESS_OFFSET),
+                global_type: self.pointer_type(),
+                readonly: true,
+            });
+            let callee_vmctx = pos.func.create_global_value(GlobalValueData::Load {
+                base: gv,
+                offset: Offset32::new(FUNCTION_IMPORT_VMCTX_OFFSET),
+                global_type: self.pointer_type(),
+                readonly: true,
+            });
+            let addr = pos.ins().global_value(self.pointer_type(), addr);
+            let callee_vmctx = pos.ins().global_value(self.pointer_type(), callee_vmctx);
+
+            let call_args_with_vmctx = Self::translate_signature(callee_vmctx, call_args);
+            Ok(pos
+                .ins()
+                .call_indirect(sig_ref, addr, &call_args_with_vmctx))
+        } else {
+            let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+            let call_args_with_vmctx = Self::translate_signature(vmctx, call_args);
+            Ok(pos.ins().call(callee, &call_args_with_vmctx))
+        }
+    }
+
+    fn before_translate_function(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        _state: &FuncTranslationState,
+    ) -> WasmResult<()> {
+        // The prologue is a safe point, so every call is checked in the callee. That includes
+        // calls from other instances, which are checked against the context of the callee.
+        self.safe_point(&mut builder.cursor());
+        Ok(())
+    }
+
+    fn translate_loop_header(&mut self, mut pos: FuncCursor) -> WasmResult<()> {
+        self.safe_point(&mut pos);
+        Ok(())
+    }
+
+    fn translate_memory_grow(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        val: Value,
+    ) -> WasmResult<Value> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function(&mut pos, &RUNTIME_MEMORY_GROW_DATA, &[vmctx, index, val])
+    }
+
+    fn translate_memory_size(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+    ) -> WasmResult<Value> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function(&mut pos, &RUNTIME_MEMORY_SIZE_DATA, &[vmctx, index])
+    }
+
+    fn translate_memory_copy(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        dst: Value,
+        src: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_MEMORY_COPY_DATA,
+            &[vmctx, index, dst, src, len],
+            TrapCode::HeapOutOfBounds,
+        )
+    }
+
+    fn translate_memory_fill(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        dst: Value,
+        val: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_MEMORY_FILL_DATA,
+            &[vmctx, index, dst, val, len],
+            TrapCode::HeapOutOfBounds,
+        )
+    }
+
+    fn translate_memory_init(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        seg_index: u32,
+        dst: Value,
+        src: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let seg_index = Self::index_const(&mut pos, seg_index);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_MEMORY_INIT_DATA,
+            &[vmctx, index, seg_index, dst, src, len],
+            TrapCode::HeapOutOfBounds,
+        )
+    }
+
+    fn translate_data_drop(&mut self, mut pos: FuncCursor, seg_index: u32) -> WasmResult<()> {
+        let seg_index = Self::index_const(&mut pos, seg_index);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function_inst(&mut pos, &RUNTIME_DATA_DROP_DATA, &[vmctx, seg_index]);
+        Ok(())
+    }
+
+    fn translate_table_size(
+        &mut self,
+        mut pos: FuncCursor,
+        _index: TableIndex,
+        table: Table,
+    ) -> WasmResult<Value> {
+        let bound_gv = pos.func.tables[table].bound_gv;
+        Ok(pos.ins().global_value(types::I32, bound_gv))
+    }
+
+    fn translate_table_grow(
+        &mut self,
+        mut pos: FuncCursor,
+        table_index: TableIndex,
+        _table: Table,
+        delta: Value,
+        init_value: Value,
+    ) -> WasmResult<Value> {
+        let table_index = Self::index_const(&mut pos, table_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_GROW_DATA,
+            &[vmctx, table_index, delta, init_value],
+        )
+    }
+
+    fn translate_table_get(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        table_index: TableIndex,
+        table: Table,
+        index: Value,
+    ) -> WasmResult<Value> {
+        let mut pos = builder.cursor();
+        Self::table_bounds_check(&mut pos, table, index);
+        let table_index = Self::index_const(&mut pos, table_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_GET_DATA,
+            &[vmctx, table_index, index],
+        )
+    }
+
+    fn translate_table_set(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        table_index: TableIndex,
+        _table: Table,
+        value: Value,
+        index: Value,
+    ) -> WasmResult<()> {
+        let mut pos = builder.cursor();
+        let table_index = Self::index_const(&mut pos, table_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_SET_DATA,
+            &[vmctx, table_index, index, value],
+            TrapCode::TableOutOfBounds,
+        )
+    }
+
+    fn translate_table_copy(
+        &mut self,
+        mut pos: FuncCursor,
+        dst_table_index: TableIndex,
+        _dst_table: Table,
+        src_table_index: TableIndex,
+        _src_table: Table,
+        dst: Value,
+        src: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let dst_table_index = Self::index_const(&mut pos, dst_table_index.as_u32());
+        let src_table_index = Self::index_const(&mut pos, src_table_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_COPY_DATA,
+            &[vmctx, dst_table_index, src_table_index, dst, src, len],
+            TrapCode::TableOutOfBounds,
+        )
+    }
+
+    fn translate_table_fill(
+        &mut self,
+        mut pos: FuncCursor,
+        table_index: TableIndex,
+        dst: Value,
+        val: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let table_index = Self::index_const(&mut pos, table_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_FILL_DATA,
+            &[vmctx, table_index, dst, val, len],
+            TrapCode::TableOutOfBounds,
+        )
+    }
+
+    fn translate_table_init(
+        &mut self,
+        mut pos: FuncCursor,
+        seg_index: u32,
+        table_index: TableIndex,
+        _table: Table,
+        dst: Value,
+        src: Value,
+        len: Value,
+    ) -> WasmResult<()> {
+        let table_index = Self::index_const(&mut pos, table_index.as_u32());
+        let seg_index = Self::index_const(&mut pos, seg_index);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_trapping_runtime_function(
+            &mut pos,
+            &RUNTIME_TABLE_INIT_DATA,
+            &[vmctx, table_index, seg_index, dst, src, len],
+            TrapCode::TableOutOfBounds,
+        )
+    }
+
+    fn translate_elem_drop(&mut self, mut pos: FuncCursor, seg_index: u32) -> WasmResult<()> {
+        let seg_index = Self::index_const(&mut pos, seg_index);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function_inst(&mut pos, &RUNTIME_ELEM_DROP_DATA, &[vmctx, seg_index]);
+        Ok(())
+    }
+
+    fn translate_ref_func(
+        &mut self,
+        mut pos: FuncCursor,
+        func_index: FuncIndex,
+    ) -> WasmResult<Value> {
+        let func_index = Self::index_const(&mut pos, func_index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_runtime_function(&mut pos, &RUNTIME_REF_FUNC_DATA, &[vmctx, func_index])
+    }
+
+    fn translate_custom_global_get(
+        &mut self,
+        _pos: FuncCursor,
+        _global_index: GlobalIndex,
+    ) -> Result<Value, WasmError> {
+        unimplemented!()
+    }
+
+    fn translate_custom_global_set(
+        &mut self,
+        _pos: FuncCursor,
+        _global_index: GlobalIndex,
+        _val: Value,
+    ) -> Result<(), WasmError> {
+        unimplemented!()
+    }
+
+    fn translate_atomic_wait(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        addr: Value,
+        expected: Value,
+        timeout: Value,
+    ) -> WasmResult<Value> {
+        let runtime_func = if pos.func.dfg.value_type(expected) == types::I64 {
+            &*RUNTIME_MEMORY_ATOMIC_WAIT64_DATA
+        } else {
+            &*RUNTIME_MEMORY_ATOMIC_WAIT32_DATA
+        };
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_atomic_runtime_function(
+            &mut pos,
+            runtime_func,
+            &[vmctx, index, addr, expected, timeout],
+        )
+    }
+
+    fn translate_atomic_notify(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: Heap,
+        addr: Value,
+        count: Value,
+    ) -> WasmResult<Value> {
+        let index = Self::index_const(&mut pos, index.as_u32());
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        Self::call_atomic_runtime_function(
+            &mut pos,
+            &RUNTIME_MEMORY_ATOMIC_NOTIFY_DATA,
+            &[vmctx, index, addr, count],
+        )
+    }
+}
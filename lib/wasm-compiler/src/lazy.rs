@@ -0,0 +1,120 @@
+//! Lazy compilation: defined functions are only compiled when they are called for the first time.
+//!
+//! The code of a lazily compiled module consists of a stub for every defined function.
+//! A stub jumps to the address in its lazy function slot in the VmContext, the VmContext is always
+//! the first argument so the stub finds it in `rdi`. Initially, the slot points to the second part
+//! of the stub, which jumps to the lazy compilation runtime function with the function index in `eax`.
+//! That function compiles the function, stores the address of the compiled code in the slot and
+//! continues in the compiled code. From then on, the stub is a single indirect jump.
+//!
+//! Calls and table elements keep using the stubs, so nothing else has to be patched.
+//!
+//! The function bodies are translated once up front anyway, so an invalid module is rejected
+//! before any of its code runs.
+
+use crate::compile::{
+    CompileOptions, CompiledCode, CompiledFunction, CompiledModule, Error, TranslatedModule,
+};
+use crate::reloc_sink::{Relocation, RelocationTarget};
+use crate::runtime::RUNTIME_LAZY_COMPILE_IDX;
+use alloc::vec::Vec;
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_wasm::FuncIndex;
+
+/// Size of the stub of a function.
+pub const LAZY_STUB_SIZE: u32 = 32;
+
+/// Offset of the part of the stub that jumps to the lazy compilation runtime function.
+/// This is the initial value of a lazy function slot.
+pub const LAZY_STUB_COMPILE_OFFSET: u32 = 6;
+
+/// Offset of the address of the lazy compilation runtime function in a stub.
+const LAZY_STUB_RUNTIME_ADDRESS_OFFSET: u32 = 13;
+
+/// A module that compiles its defined functions on demand.
+pub struct LazyModule<'data> {
+    module: TranslatedModule<'data>,
+}
+
+impl<'data> LazyModule<'data> {
+    /// Compiles a defined function.
+    pub fn compile_function(
+        &self,
+        isa: &dyn TargetIsa,
+        func_idx: FuncIndex,
+    ) -> Result<CompiledCode, Error> {
+        self.module.compile_function(isa, func_idx)
+    }
+}
+
+/// Emits the stub of a defined function.
+fn emit_stub(
+    code: &mut Vec<u8>,
+    relocations: &mut Vec<Relocation>,
+    slot_offset: i32,
+    func_idx: FuncIndex,
+) -> CompiledFunction {
+    let offset = code.len() as u32;
+
+    // jmp qword ptr [rdi + slot_offset]
+    code.extend_from_slice(&[0xff, 0xa7]);
+    code.extend_from_slice(&slot_offset.to_le_bytes());
+    // mov eax, func_idx
+    code.push(0xb8);
+    code.extend_from_slice(&func_idx.as_u32().to_le_bytes());
+    // movabs r11, <runtime function>
+    code.extend_from_slice(&[0x49, 0xbb]);
+    code.extend_from_slice(&0u64.to_le_bytes());
+    // jmp r11
+    code.extend_from_slice(&[0x41, 0xff, 0xe3]);
+    // Pad with int3
+    code.resize((offset + LAZY_STUB_SIZE) as usize, 0xcc);
+
+    relocations.push(Relocation {
+        code_offset: offset + LAZY_STUB_RUNTIME_ADDRESS_OFFSET,
+        reloc: Reloc::Abs8,
+        target: RelocationTarget::RuntimeFunction(RUNTIME_LAZY_COMPILE_IDX),
+        addend: 0,
+    });
+
+    CompiledFunction {
+        offset,
+        size: LAZY_STUB_SIZE,
+    }
+}
+
+/// Translates a WebAssembly buffer without compiling the function bodies.
+/// The code of the compiled module consists of the stubs of the defined functions,
+/// the VmContext needs a lazy function slot for every defined function.
+pub fn compile_lazily<'data>(
+    isa: &dyn TargetIsa,
+    buffer: &'data [u8],
+    options: CompileOptions,
+) -> Result<(CompiledModule<'data>, LazyModule<'data>), Error> {
+    let module = TranslatedModule::new(isa, buffer, options)?;
+    let defined_function_offset = module.defined_function_offset();
+    let num_defined_functions = module.num_defined_functions();
+    for idx in 0..num_defined_functions {
+        module.validate_function(FuncIndex::from_u32((idx + defined_function_offset) as u32))?;
+    }
+    let vmctx_layout = module
+        .vmctx_layout()
+        .with_lazy_funcs(num_defined_functions as u32);
+
+    let mut code: Vec<u8> = Vec::with_capacity(num_defined_functions * LAZY_STUB_SIZE as usize);
+    let mut functions: Vec<CompiledFunction> = Vec::with_capacity(num_defined_functions);
+    let mut relocations: Vec<Relocation> = Vec::with_capacity(num_defined_functions);
+    for idx in 0..num_defined_functions {
+        functions.push(emit_stub(
+            &mut code,
+            &mut relocations,
+            vmctx_layout.lazy_func_slot_offset(idx as u32) as i32,
+            FuncIndex::from_u32((idx + defined_function_offset) as u32),
+        ));
+    }
+
+    let (compiled_module, module) = module.finish_lazily(code, functions, relocations)?;
+
+    Ok((compiled_module, LazyModule { module }))
+}
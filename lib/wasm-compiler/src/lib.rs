@@ -0,0 +1,33 @@
+//! WebAssembly compiler, shared by the kernel and the host-side AOT tool.
+//! Used https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src as a reference.
+//!
+//! The compiler emits position-independent code together with everything needed to instantiate it.
+//! Relocations against runtime functions are resolved by the kernel when it links the code,
+//! which is what makes it possible to store compiled modules as artifacts.
+
+#![no_std]
+#![allow(clippy::new_without_default)]
+
+#[macro_use]
+extern crate alloc;
+
+pub mod artifact;
+mod compile;
+mod func_env;
+pub mod lazy;
+pub mod module_env;
+pub mod reloc_sink;
+pub mod runtime;
+pub mod trap;
+pub mod vmctx;
+
+pub use compile::{
+    compile, target_isa, CompileOptions, CompiledCode, CompiledFunction, CompiledModule, Error,
+    TranslatedModule, TARGET_FEATURES, TARGET_TRIPLE,
+};
+
+use cranelift_codegen::ir::{types, Type};
+use cranelift_codegen::isa::CallConv;
+
+pub const WASM_VMCTX_TYPE: Type = types::I64;
+pub const WASM_CALL_CONV: CallConv = CallConv::SystemV;
@@ -1,15 +1,16 @@
 //! Based on https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src
 
+use crate::vmctx::VmContextLayout;
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
-use cranelift_codegen::ir::{AbiParam, ArgumentPurpose, Signature};
+use cranelift_codegen::ir::{AbiParam, ArgumentPurpose, Signature, Type};
 use cranelift_codegen::isa;
 use cranelift_codegen::isa::TargetFrontendConfig;
 use cranelift_wasm::{
-    DataIndex, ElemIndex, FuncIndex, Global, GlobalIndex, Memory, MemoryIndex, ModuleEnvironment,
-    ModuleTranslationState, SignatureIndex, Table, TableIndex, TargetEnvironment, WasmError,
-    WasmFuncType, WasmResult,
+    DataIndex, ElemIndex, FuncIndex, Global, GlobalIndex, GlobalInit, Memory, MemoryIndex,
+    ModuleEnvironment, ModuleTranslationState, SignatureIndex, Table, TableElementType, TableIndex,
+    TargetEnvironment, WasmError, WasmFuncType, WasmResult,
 };
 use hashbrown::HashMap;
 
@@ -19,19 +20,43 @@ pub struct FunctionBody<'data> {
 }
 
 /// Export.
+#[derive(Debug, Copy, Clone)]
 pub enum Export {
     /// Function export.
     Function(FuncIndex),
+    /// Table export.
+    Table(TableIndex),
+    /// Memory export.
+    Memory(MemoryIndex),
+    /// Global export.
+    Global(GlobalIndex),
 }
 
-/// Function import.
-#[derive(Debug)]
-pub struct FunctionImport {
+/// Import of a function, table, memory or global.
+#[derive(Debug, Clone)]
+pub struct Import {
     pub module: String,
     pub field: String,
 }
 
+/// Table declaration.
+#[derive(Debug, Copy, Clone)]
+pub struct TableDecl {
+    pub ty: TableElementType,
+    pub minimum: u32,
+    pub maximum: Option<u32>,
+}
+
+/// Global declaration.
+#[derive(Debug, Copy, Clone)]
+pub struct GlobalDecl {
+    pub ty: Type,
+    pub mutability: bool,
+    pub initializer: GlobalInit,
+}
+
 /// Table elements.
+#[derive(Debug)]
 pub struct TableElements {
     /// Index of the table where these elements belong to.
     pub index: TableIndex,
@@ -64,20 +89,32 @@ pub struct ModuleEnv<'data> {
     pub func_sigs: Vec<SignatureIndex>,
     /// Function Wasm body contents.
     pub func_bodies: Vec<FunctionBody<'data>>,
-    /// Memories.
+    /// Memories, imported memories come first.
     pub memories: Vec<Memory>,
     /// Keep track of the imported functions.
-    pub function_imports: Vec<FunctionImport>,
-    /// Tables.
-    pub tables: Vec<Table>,
+    pub function_imports: Vec<Import>,
+    /// Keep track of the imported memories.
+    pub memory_imports: Vec<Import>,
+    /// Keep track of the imported tables.
+    pub table_imports: Vec<Import>,
+    /// Keep track of the imported globals.
+    pub global_imports: Vec<Import>,
+    /// Tables, imported tables come first.
+    pub tables: Vec<TableDecl>,
     /// Table elements.
     pub table_elements: Vec<TableElements>,
-    /// Globals.
-    pub globals: Vec<Global>,
+    /// Passive element segments.
+    pub passive_elements: HashMap<ElemIndex, Box<[FuncIndex]>>,
+    /// Globals, imported globals come first.
+    pub globals: Vec<GlobalDecl>,
     /// Data initializers.
     pub data_initializers: Vec<DataInitializer<'data>>,
+    /// Passive data segments.
+    pub passive_data: HashMap<DataIndex, &'data [u8]>,
     /// All exports.
     pub exports: HashMap<&'data str, Export>,
+    /// Function names from the name section.
+    pub func_names: HashMap<FuncIndex, &'data str>,
 }
 
 impl<'data> ModuleEnv<'data> {
@@ -91,11 +128,17 @@ impl<'data> ModuleEnv<'data> {
             func_bodies: Vec::new(),
             memories: Vec::new(),
             function_imports: Vec::new(),
+            memory_imports: Vec::new(),
+            table_imports: Vec::new(),
+            global_imports: Vec::new(),
             tables: Vec::new(),
             table_elements: Vec::new(),
+            passive_elements: HashMap::new(),
             globals: Vec::new(),
             data_initializers: Vec::new(),
+            passive_data: HashMap::new(),
             exports: HashMap::new(),
+            func_names: HashMap::new(),
         }
     }
 
@@ -115,6 +158,43 @@ impl<'data> ModuleEnv<'data> {
         // Imported functions are defined first.
         (index.as_u32() as usize) < self.function_imports.len()
     }
+
+    /// Returns whether the global index corresponds to an imported global.
+    pub fn is_imported_global(&self, index: GlobalIndex) -> bool {
+        // Imported globals are defined first.
+        (index.as_u32() as usize) < self.global_imports.len()
+    }
+
+    /// Gets the layout of the VmContext.
+    pub fn vmctx_layout(&self) -> VmContextLayout {
+        VmContextLayout::new(
+            self.globals.len() as u32,
+            self.function_imports.len() as u32,
+            self.memories.len() as u32,
+            self.tables.len() as u32,
+            self.signatures.len() as u32,
+        )
+    }
+}
+
+impl From<Table> for TableDecl {
+    fn from(table: Table) -> Self {
+        Self {
+            ty: table.ty,
+            minimum: table.minimum,
+            maximum: table.maximum,
+        }
+    }
+}
+
+impl From<Global> for GlobalDecl {
+    fn from(global: Global) -> Self {
+        Self {
+            ty: global.ty,
+            mutability: global.mutability,
+            initializer: global.initializer,
+        }
+    }
 }
 
 impl<'data> TargetEnvironment for ModuleEnv<'data> {
@@ -149,7 +229,7 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
         field: &'data str,
     ) -> WasmResult<()> {
         self.func_sigs.push(sig_index);
-        self.function_imports.push(FunctionImport {
+        self.function_imports.push(Import {
             module: String::from(module),
             field: String::from(field),
         });
@@ -158,29 +238,44 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
 
     fn declare_table_import(
         &mut self,
-        _table: Table,
-        _module: &'data str,
-        _field: &'data str,
+        table: Table,
+        module: &'data str,
+        field: &'data str,
     ) -> WasmResult<()> {
-        unimplemented!()
+        self.tables.push(table.into());
+        self.table_imports.push(Import {
+            module: String::from(module),
+            field: String::from(field),
+        });
+        Ok(())
     }
 
     fn declare_memory_import(
         &mut self,
-        _memory: Memory,
-        _module: &'data str,
-        _field: &'data str,
+        memory: Memory,
+        module: &'data str,
+        field: &'data str,
     ) -> WasmResult<()> {
-        unimplemented!()
+        self.memories.push(memory);
+        self.memory_imports.push(Import {
+            module: String::from(module),
+            field: String::from(field),
+        });
+        Ok(())
     }
 
     fn declare_global_import(
         &mut self,
-        _global: Global,
-        _module: &'data str,
-        _field: &'data str,
+        global: Global,
+        module: &'data str,
+        field: &'data str,
     ) -> WasmResult<()> {
-        unimplemented!()
+        self.globals.push(global.into());
+        self.global_imports.push(Import {
+            module: String::from(module),
+            field: String::from(field),
+        });
+        Ok(())
     }
 
     fn reserve_func_types(&mut self, num: u32) -> WasmResult<()> {
@@ -199,7 +294,7 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
     }
 
     fn declare_table(&mut self, table: Table) -> WasmResult<()> {
-        self.tables.push(table);
+        self.tables.push(table.into());
         Ok(())
     }
 
@@ -209,9 +304,6 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
     }
 
     fn declare_memory(&mut self, memory: Memory) -> WasmResult<()> {
-        // TODO: Shared memories and more than one memory not supported right now.
-        assert_eq!(memory.shared, false);
-        assert_eq!(self.memories.len(), 0);
         self.memories.push(memory);
         Ok(())
     }
@@ -222,7 +314,7 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
     }
 
     fn declare_global(&mut self, global: Global) -> WasmResult<()> {
-        self.globals.push(global);
+        self.globals.push(global.into());
         Ok(())
     }
 
@@ -233,29 +325,28 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
 
     fn declare_table_export(
         &mut self,
-        _table_index: TableIndex,
-        _name: &'data str,
+        table_index: TableIndex,
+        name: &'data str,
     ) -> WasmResult<()> {
-        unimplemented!()
+        self.exports.insert(name, Export::Table(table_index));
+        Ok(())
     }
 
     fn declare_memory_export(
         &mut self,
-        _memory_index: MemoryIndex,
-        _name: &'data str,
+        memory_index: MemoryIndex,
+        name: &'data str,
     ) -> WasmResult<()> {
-        //unimplemented!()
-        // TODO
+        self.exports.insert(name, Export::Memory(memory_index));
         Ok(())
     }
 
     fn declare_global_export(
         &mut self,
-        _global_index: GlobalIndex,
-        _name: &'data str,
+        global_index: GlobalIndex,
+        name: &'data str,
     ) -> WasmResult<()> {
-        //unimplemented!()
-        // TODO
+        self.exports.insert(name, Export::Global(global_index));
         Ok(())
     }
 
@@ -287,18 +378,16 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
 
     fn declare_passive_element(
         &mut self,
-        _index: ElemIndex,
-        _elements: Box<[FuncIndex]>,
+        index: ElemIndex,
+        elements: Box<[FuncIndex]>,
     ) -> WasmResult<()> {
-        unimplemented!()
+        self.passive_elements.insert(index, elements);
+        Ok(())
     }
 
-    fn declare_passive_data(
-        &mut self,
-        _data_index: DataIndex,
-        _data: &'data [u8],
-    ) -> WasmResult<()> {
-        unimplemented!()
+    fn declare_passive_data(&mut self, data_index: DataIndex, data: &'data [u8]) -> WasmResult<()> {
+        self.passive_data.insert(data_index, data);
+        Ok(())
     }
 
     fn define_function_body(
@@ -314,6 +403,11 @@ impl<'data> ModuleEnvironment<'data> for ModuleEnv<'data> {
         Ok(())
     }
 
+    fn declare_func_name(&mut self, func_index: FuncIndex, name: &'data str) -> WasmResult<()> {
+        self.func_names.insert(func_index, name);
+        Ok(())
+    }
+
     fn reserve_data_initializers(&mut self, num: u32) -> WasmResult<()> {
         self.data_initializers.reserve_exact(num as usize);
         Ok(())
@@ -1,6 +1,6 @@
 //! Based on https://github.com/bytecodealliance/wasmtime/tree/master/crates/jit/src
 
-use crate::wasm::runtime::RUNTIME_NAMESPACE;
+use crate::runtime::RUNTIME_NAMESPACE;
 use alloc::vec::Vec;
 use cranelift_codegen::binemit::{self, Addend, CodeOffset, Reloc};
 use cranelift_codegen::ir::{ExternalName, JumpTable, LibCall, SourceLoc};
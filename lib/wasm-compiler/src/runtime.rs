@@ -0,0 +1,283 @@
+//! Runtime functions that compiled code can call.
+//! The implementations live in the kernel, the code refers to them by their index.
+
+use crate::{WASM_CALL_CONV, WASM_VMCTX_TYPE};
+use cranelift_codegen::ir::{types, AbiParam, ArgumentPurpose, Signature};
+use lazy_static::lazy_static;
+
+/// Runtime namespace for `ExternalName`.
+pub const RUNTIME_NAMESPACE: u32 = 1;
+pub const RUNTIME_MEMORY_GROW_IDX: u32 = 0;
+pub const RUNTIME_MEMORY_SIZE_IDX: u32 = 1;
+pub const RUNTIME_MEMORY_COPY_IDX: u32 = 2;
+pub const RUNTIME_MEMORY_FILL_IDX: u32 = 3;
+pub const RUNTIME_MEMORY_INIT_IDX: u32 = 4;
+pub const RUNTIME_DATA_DROP_IDX: u32 = 5;
+pub const RUNTIME_TABLE_GROW_IDX: u32 = 6;
+pub const RUNTIME_TABLE_GET_IDX: u32 = 7;
+pub const RUNTIME_TABLE_SET_IDX: u32 = 8;
+pub const RUNTIME_TABLE_FILL_IDX: u32 = 9;
+pub const RUNTIME_TABLE_COPY_IDX: u32 = 10;
+pub const RUNTIME_TABLE_INIT_IDX: u32 = 11;
+pub const RUNTIME_ELEM_DROP_IDX: u32 = 12;
+pub const RUNTIME_REF_FUNC_IDX: u32 = 13;
+/// Not called by compiled code, the stubs of lazily compiled functions jump to it, see `lazy`.
+pub const RUNTIME_LAZY_COMPILE_IDX: u32 = 14;
+pub const RUNTIME_MEMORY_ATOMIC_WAIT32_IDX: u32 = 15;
+pub const RUNTIME_MEMORY_ATOMIC_WAIT64_IDX: u32 = 16;
+pub const RUNTIME_MEMORY_ATOMIC_NOTIFY_IDX: u32 = 17;
+
+/// Return value of runtime functions that can trap, indicates success.
+pub const RUNTIME_OK: u32 = 0;
+/// Return value of runtime functions that can trap, the caller must trap.
+pub const RUNTIME_TRAP: u32 = 1;
+/// Return value of the atomic wait and notify runtime functions, the caller must trap.
+/// Other values are the result of the instruction.
+pub const RUNTIME_ATOMIC_TRAP: u32 = core::u32::MAX;
+
+/// Runtime function data.
+pub struct RuntimeFunctionData {
+    pub index: u32,
+    pub signature: Signature,
+}
+
+lazy_static! {
+    pub static ref RUNTIME_MEMORY_GROW_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_GROW_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Pages
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_SIZE_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_SIZE_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_COPY_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_COPY_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::I32), // Source
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_FILL_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_FILL_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::I32), // Value
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_INIT_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_INIT_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Segment index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::I32), // Source
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_ATOMIC_WAIT32_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_ATOMIC_WAIT32_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Address
+                AbiParam::new(types::I32), // Expected value
+                AbiParam::new(types::I64), // Timeout
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_ATOMIC_WAIT64_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_ATOMIC_WAIT64_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Address
+                AbiParam::new(types::I64), // Expected value
+                AbiParam::new(types::I64), // Timeout
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_MEMORY_ATOMIC_NOTIFY_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_MEMORY_ATOMIC_NOTIFY_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Memory index
+                AbiParam::new(types::I32), // Address
+                AbiParam::new(types::I32), // Count
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_DATA_DROP_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_DATA_DROP_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Segment index
+            ],
+            returns: vec![],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_GROW_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_GROW_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Table index
+                AbiParam::new(types::I32), // Delta
+                AbiParam::new(types::R64), // Initial value
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_GET_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_GET_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Table index
+                AbiParam::new(types::I32), // Index
+            ],
+            returns: vec![AbiParam::new(types::R64)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_SET_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_SET_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Table index
+                AbiParam::new(types::I32), // Index
+                AbiParam::new(types::R64), // Value
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_FILL_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_FILL_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Table index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::R64), // Value
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_COPY_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_COPY_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Destination table index
+                AbiParam::new(types::I32), // Source table index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::I32), // Source
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_TABLE_INIT_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_TABLE_INIT_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Table index
+                AbiParam::new(types::I32), // Segment index
+                AbiParam::new(types::I32), // Destination
+                AbiParam::new(types::I32), // Source
+                AbiParam::new(types::I32), // Length
+            ],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_ELEM_DROP_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_ELEM_DROP_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Segment index
+            ],
+            returns: vec![],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+
+    pub static ref RUNTIME_REF_FUNC_DATA: RuntimeFunctionData = RuntimeFunctionData {
+        index: RUNTIME_REF_FUNC_IDX,
+        signature: Signature {
+            params: vec![
+                AbiParam::special(WASM_VMCTX_TYPE, ArgumentPurpose::VMContext),
+                AbiParam::new(types::I32), // Function index
+            ],
+            returns: vec![AbiParam::new(types::R64)],
+            call_conv: WASM_CALL_CONV,
+        },
+    };
+}
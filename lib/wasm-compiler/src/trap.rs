@@ -0,0 +1,41 @@
+//! The code generator records where traps can happen, so faults can be mapped back to a `TrapCode`.
+
+use alloc::vec::Vec;
+use cranelift_codegen::binemit::{self, CodeOffset};
+use cranelift_codegen::ir::{SourceLoc, TrapCode};
+
+/// Trap code of the fuel checks, emitted when the fuel runs out.
+/// Unlike other traps, the kernel can resume the code after this trap: the trap instruction is
+/// directly followed by the code after the check.
+pub const TRAP_OUT_OF_FUEL: TrapCode = TrapCode::User(0);
+
+/// Trap code of the epoch checks, emitted when the epoch reaches the deadline.
+/// The kernel can resume the code after this trap, just like after `TRAP_OUT_OF_FUEL`.
+pub const TRAP_EPOCH_DEADLINE: TrapCode = TrapCode::Interrupt;
+
+/// A trap site in the emitted code.
+#[derive(Debug, Copy, Clone)]
+pub struct TrapSite {
+    pub code_offset: CodeOffset,
+    pub trap_code: TrapCode,
+}
+
+/// Trap sink, stores trap sites for code.
+pub struct TrapSink {
+    pub traps: Vec<TrapSite>,
+}
+
+impl TrapSink {
+    pub fn new() -> Self {
+        Self { traps: Vec::new() }
+    }
+}
+
+impl binemit::TrapSink for TrapSink {
+    fn trap(&mut self, code_offset: CodeOffset, _source_loc: SourceLoc, trap_code: TrapCode) {
+        self.traps.push(TrapSite {
+            code_offset,
+            trap_code,
+        });
+    }
+}
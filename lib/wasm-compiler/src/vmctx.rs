@@ -0,0 +1,282 @@
+//! Layout of the VmContext, the code generator and the runtime must agree on it.
+//!
+//! -----------------------------
+//! |     Instance pointer      |
+//! -----------------------------
+//! |  Primary context pointer  |
+//! -----------------------------
+//! |           Fuel            |
+//! -----------------------------
+//! |       Epoch pointer       |
+//! -----------------------------
+//! |      Epoch deadline       |
+//! -----------------------------
+//! |        all globals        |
+//! -----------------------------
+//! | all VmFunctionImportEntry |
+//! -----------------------------
+//! |   all VmMemory pointers   |
+//! -----------------------------
+//! |   all VmTable pointers    |
+//! -----------------------------
+//! |   all signature ids       |
+//! -----------------------------
+//! |   all lazy function slots |
+//! -----------------------------
+//!
+//! Imported globals store a pointer to the global of the exporting instance.
+//! The signature ids are the global signature indices of the module signatures, they are filled in
+//! at instantiation so the code doesn't depend on the order in which modules are loaded.
+//! Lazy function slots only exist for lazily compiled modules, see `lazy`.
+//! The fuel is a signed 64-bit counter, it is only used by code compiled with fuel metering.
+//! The epoch pointer points to the global epoch counter of the kernel, code compiled with epoch
+//! interruption traps once the epoch reaches the deadline.
+//! Memories are owned by the runtime and can be shared, like tables. Imported memories come first.
+//! Every thread running an instance has its own copy of the context, so it has its own globals.
+//! The primary context pointer points to the context the instance was created with. Table elements
+//! refer to that one, so indirect calls into the same instance use the context of the caller.
+
+use core::cmp::min;
+use cranelift_wasm::Memory;
+
+pub const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+pub const HEAP_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+
+pub const HEAP_GUARD_SIZE: u64 = 4096; // One page
+
+/// Maximum reservation of a memory with the dynamic style.
+pub const DYNAMIC_HEAP_SIZE: u64 = 16 * 1024 * 1024 * 1024; // 16 GiB
+
+/// Size of a pointer inside the context.
+const POINTER_SIZE: usize = 8;
+
+/// Size of the fuel counter.
+const FUEL_SIZE: usize = 8;
+
+/// Size of the epoch deadline.
+const EPOCH_DEADLINE_SIZE: usize = 8;
+
+// All globals have the same size right now.
+// TODO: make sure not all globals take the same amount of bytes
+pub const GLOBAL_SIZE: usize = 8;
+
+/// Size of a signature id slot, matches the size of the signature index in a table element.
+pub const SIGNATURE_ID_SIZE: usize = 8;
+
+/// Offset of the field `base_address` in a `VmTable`.
+pub const TABLE_BASE_ADDRESS_OFFSET: i32 = 0;
+/// Offset of the field `amount_items` in a `VmTable`.
+pub const TABLE_AMOUNT_ITEMS_OFFSET: i32 = 8;
+
+/// Offset of the field `base_address` in a `VmMemory`.
+pub const MEMORY_BASE_ADDRESS_OFFSET: i32 = 0;
+/// Offset of the field `current_length` in a `VmMemory`.
+pub const MEMORY_CURRENT_LENGTH_OFFSET: i32 = 8;
+
+/// Offset of the field `address` in a `VmTableElement`.
+pub const TABLE_ELEMENT_ADDRESS_OFFSET: i32 = 0;
+/// Offset of the field `sig_idx` in a `VmTableElement`.
+pub const TABLE_ELEMENT_SIG_IDX_OFFSET: i32 = 8;
+/// Offset of the field `vmctx` in a `VmTableElement`.
+pub const TABLE_ELEMENT_VMCTX_OFFSET: i32 = 16;
+/// Size of a `VmTableElement`.
+pub const TABLE_ELEMENT_SIZE: usize = 32;
+
+/// Offset of the field `address` in a `VmFunctionImportEntry`.
+pub const FUNCTION_IMPORT_ADDRESS_OFFSET: i32 = 0;
+/// Offset of the field `vmctx` in a `VmFunctionImportEntry`.
+pub const FUNCTION_IMPORT_VMCTX_OFFSET: i32 = 8;
+/// Size of a `VmFunctionImportEntry`.
+pub const FUNCTION_IMPORT_ENTRY_SIZE: usize = 16;
+
+/// How the accesses to a memory are kept inside the memory.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryStyle {
+    /// `HEAP_SIZE` bytes and a guard region are reserved. A 32-bit index can't point outside of
+    /// the reservation, so the code doesn't need bounds checks.
+    Static,
+    /// The maximum size, up to `DYNAMIC_HEAP_SIZE`, and a guard region are reserved. The code
+    /// checks every access against the current length. Memory64 needs this style: no reservation
+    /// can contain the range of a 64-bit index. A memory with 32-bit indices must stay below
+    /// 4 GiB in this style, because its bounds check only sees the low half of the length.
+    Dynamic,
+}
+
+impl MemoryStyle {
+    /// Gets the size of the reservation of a memory, without the guard region.
+    pub fn reservation_size(self, memory: &Memory) -> u64 {
+        match self {
+            MemoryStyle::Static => HEAP_SIZE,
+            MemoryStyle::Dynamic => memory.maximum.map_or(DYNAMIC_HEAP_SIZE, |maximum| {
+                min(maximum as u64 * WASM_PAGE_SIZE as u64, DYNAMIC_HEAP_SIZE)
+            }),
+        }
+    }
+}
+
+/// Gets the style of a memory, the code generator and the runtime must agree on it.
+// TODO: the parser doesn't support memory64 yet, so all memories have 32-bit indices.
+//       Memory64 memories must get the dynamic style once it does.
+pub fn memory_style(_memory: &Memory) -> MemoryStyle {
+    MemoryStyle::Static
+}
+
+/// Layout of the VmContext of a module.
+#[derive(Debug, Copy, Clone)]
+pub struct VmContextLayout {
+    num_globals: u32,
+    num_imported_funcs: u32,
+    num_memories: u32,
+    num_tables: u32,
+    num_signatures: u32,
+    num_lazy_funcs: u32,
+}
+
+impl VmContextLayout {
+    /// Creates the layout for a module.
+    pub fn new(
+        num_globals: u32,
+        num_imported_funcs: u32,
+        num_memories: u32,
+        num_tables: u32,
+        num_signatures: u32,
+    ) -> Self {
+        Self {
+            num_globals,
+            num_imported_funcs,
+            num_memories,
+            num_tables,
+            num_signatures,
+            num_lazy_funcs: 0,
+        }
+    }
+
+    /// Adds a lazy function slot for every defined function.
+    pub fn with_lazy_funcs(self, num_lazy_funcs: u32) -> Self {
+        Self {
+            num_lazy_funcs,
+            ..self
+        }
+    }
+
+    /// Instance offset in the context.
+    pub fn instance_offset() -> i32 {
+        0
+    }
+
+    /// Primary context pointer offset in the context.
+    pub fn primary_vmctx_offset() -> i32 {
+        Self::instance_offset() + POINTER_SIZE as i32
+    }
+
+    /// Fuel offset in the context.
+    pub fn fuel_offset() -> i32 {
+        Self::primary_vmctx_offset() + POINTER_SIZE as i32
+    }
+
+    /// Epoch pointer offset in the context.
+    pub fn epoch_ptr_offset() -> i32 {
+        Self::fuel_offset() + FUEL_SIZE as i32
+    }
+
+    /// Epoch deadline offset in the context.
+    pub fn epoch_deadline_offset() -> i32 {
+        Self::epoch_ptr_offset() + POINTER_SIZE as i32
+    }
+
+    /// Offset of the globals.
+    pub fn globals_offset() -> i32 {
+        Self::epoch_deadline_offset() + EPOCH_DEADLINE_SIZE as i32
+    }
+
+    /// Offset of a global entry.
+    pub fn global_entry_offset(index: u32) -> isize {
+        Self::globals_offset() as isize + (GLOBAL_SIZE * index as usize) as isize
+    }
+
+    /// Offset of imported functions.
+    pub fn imported_funcs_offset(&self) -> isize {
+        Self::global_entry_offset(self.num_globals)
+    }
+
+    /// Offset of an imported function entry.
+    pub fn imported_func_entry_offset(&self, index: u32) -> isize {
+        self.imported_funcs_offset() + (FUNCTION_IMPORT_ENTRY_SIZE * index as usize) as isize
+    }
+
+    /// Offset of the memories.
+    pub fn memories_offset(&self) -> isize {
+        self.imported_func_entry_offset(self.num_imported_funcs)
+    }
+
+    /// Offset of a memory pointer.
+    pub fn memory_entry_offset(&self, index: u32) -> isize {
+        self.memories_offset() + (POINTER_SIZE * index as usize) as isize
+    }
+
+    /// Offset of the tables.
+    pub fn tables_offset(&self) -> isize {
+        self.memory_entry_offset(self.num_memories)
+    }
+
+    /// Offset of a table pointer.
+    pub fn table_entry_offset(&self, index: u32) -> isize {
+        self.tables_offset() + (POINTER_SIZE * index as usize) as isize
+    }
+
+    /// Offset of the signature ids.
+    pub fn signature_ids_offset(&self) -> isize {
+        self.table_entry_offset(self.num_tables)
+    }
+
+    /// Offset of a signature id.
+    pub fn signature_id_offset(&self, index: u32) -> isize {
+        self.signature_ids_offset() + (SIGNATURE_ID_SIZE * index as usize) as isize
+    }
+
+    /// Offset of the lazy function slots.
+    pub fn lazy_funcs_offset(&self) -> isize {
+        self.signature_id_offset(self.num_signatures)
+    }
+
+    /// Offset of the lazy function slot of a defined function.
+    pub fn lazy_func_slot_offset(&self, index: u32) -> isize {
+        self.lazy_funcs_offset() + (POINTER_SIZE * index as usize) as isize
+    }
+
+    /// Calculates the size of the context.
+    pub fn size(&self) -> usize {
+        self.lazy_func_slot_offset(self.num_lazy_funcs) as usize
+    }
+
+    /// Amount of globals.
+    pub fn num_globals(&self) -> u32 {
+        self.num_globals
+    }
+
+    /// Amount of imported functions.
+    pub fn num_imported_funcs(&self) -> u32 {
+        self.num_imported_funcs
+    }
+
+    /// Amount of memories.
+    pub fn num_memories(&self) -> u32 {
+        self.num_memories
+    }
+
+    /// Amount of tables.
+    pub fn num_tables(&self) -> u32 {
+        self.num_tables
+    }
+
+    /// Amount of signatures.
+    pub fn num_signatures(&self) -> u32 {
+        self.num_signatures
+    }
+
+    /// Amount of lazy function slots.
+    pub fn num_lazy_funcs(&self) -> u32 {
+        self.num_lazy_funcs
+    }
+}
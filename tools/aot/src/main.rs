@@ -0,0 +1,69 @@
+//! Compiles WebAssembly modules ahead of time, for the target and CPU features of the kernel,
+//! see `wasm_compiler::TARGET_TRIPLE`. The kernel only uses an artifact if the module and the
+//! compiler settings match, otherwise it falls back to compiling the module at boot.
+//!
+//! Usage: kwast-aot [--fuel] [--epoch] <module.wasm> [-o <artifact>]
+//! The artifact is written next to the module by default, see `ARTIFACT_SUFFIX`.
+//! `--fuel` enables fuel metering, which the kernel requires for services with a `fuel` budget.
+//! `--epoch` enables epoch interruption, which the kernel requires for interruptible services.
+
+use std::env;
+use std::fs;
+use std::process;
+use wasm_compiler::artifact::{self, ARTIFACT_SUFFIX};
+use wasm_compiler::{target_isa, CompileOptions};
+
+fn usage() -> ! {
+    eprintln!("Usage: kwast-aot [--fuel] [--epoch] <module.wasm> [-o <artifact>]");
+    process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut input: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut options = CompileOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "--fuel" => options.fuel_metering = true,
+            "--epoch" => options.epoch_interruption = true,
+            _ if input.is_none() => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| format!("{}{}", input, ARTIFACT_SUFFIX));
+
+    let source = fs::read(&input).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", input, e);
+        process::exit(1);
+    });
+
+    let isa = target_isa();
+
+    let compiled_module = wasm_compiler::compile(&*isa, &source, options).unwrap_or_else(|e| {
+        eprintln!("Could not compile {}: {:?}", input, e);
+        process::exit(1);
+    });
+
+    let data = artifact::serialize(&compiled_module, &source, &*isa, options).unwrap_or_else(|e| {
+        eprintln!("Could not create an artifact for {}: {:?}", input, e);
+        process::exit(1);
+    });
+
+    fs::write(&output, &data).unwrap_or_else(|e| {
+        eprintln!("Could not write {}: {}", output, e);
+        process::exit(1);
+    });
+
+    println!(
+        "{} -> {} ({} functions, {} bytes of code)",
+        input,
+        output,
+        compiled_module.functions.len(),
+        compiled_module.code.len()
+    );
+}
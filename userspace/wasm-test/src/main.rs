@@ -1,20 +1,139 @@
-use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::io::Read;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::mem::{size_of, size_of_val};
 
 #[derive(Debug, Copy, Clone)]
 #[repr(transparent)]
 pub struct FileHandle(u64);
 
-#[derive(Debug, Copy, Clone)]
+const MAX_PATH_LEN: usize = 256;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SchemePath {
+    len: u16,
+    bytes: [u8; MAX_PATH_LEN],
+}
+
+impl SchemePath {
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..min(self.len as usize, MAX_PATH_LEN)]
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct OpenCommand {
+    rights_base: u64,
+    rights_inheriting: u64,
+    o_flags: u16,
+    fd_flags: u16,
+    path: SchemePath,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TransferCommand {
+    handle: u64,
+    grant: u64,
+    len: u64,
+    offset: u64,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SeekCommand {
+    handle: u64,
+    offset: i64,
+    whence: u8,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SetFlagsCommand {
+    handle: u64,
+    fd_flags: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct AdviseCommand {
+    handle: u64,
+    offset: u64,
+    len: u64,
+    advice: u8,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PathPairCommand {
+    old: SchemePath,
+    new: SchemePath,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct LinkCommand {
+    lookup_flags: u32,
+    old: SchemePath,
+    new: SchemePath,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PathTransferCommand {
+    lookup_flags: u32,
+    grant: u64,
+    len: u64,
+    path: SchemePath,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ReadLinkCommand {
+    grant: u64,
+    len: u64,
+    path: SchemePath,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SetTimesCommand {
+    lookup_flags: u32,
+    atim: u64,
+    mtim: u64,
+    fst_flags: u16,
+    path: SchemePath,
+}
+
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub enum CommandData {
-    Open(i32),
-    Read(u64),
+    Open(OpenCommand),
+    Read(TransferCommand),
+    Write(TransferCommand),
+    Close(u64),
+    Seek(SeekCommand),
+    SetFlags(SetFlagsCommand),
+    FileStat(TransferCommand),
+    Sync(u64),
+    DataSync(u64),
+    ReadAt(TransferCommand),
+    WriteAt(TransferCommand),
+    ReadDir(TransferCommand),
+    Advise(AdviseCommand),
+    CreateDirectory(SchemePath),
+    RemoveDirectory(SchemePath),
+    UnlinkFile(SchemePath),
+    Rename(PathPairCommand),
+    Link(LinkCommand),
+    Symlink(PathPairCommand),
+    ReadLink(ReadLinkCommand),
+    PathFileStat(PathTransferCommand),
+    SetTimes(SetTimesCommand),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Command {
     sender: u64,
@@ -35,45 +154,263 @@ pub struct Reply {
     payload: ReplyPayload,
 }
 
-fn main() {
-    // File::create("myfile").expect("lol");
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct FileStat {
+    dev: u64,
+    ino: u64,
+    filetype: u8,
+    nlink: u64,
+    size: u64,
+    atim: u64,
+    mtim: u64,
+    ctim: u64,
+}
+
+/// The pre-open of the manifest, the root of the self scheme.
+/// The file descriptors before it are reserved for stdin, stdout and stderr.
+const SCHEME_FD: u32 = 3;
+
+const ERRNO_SUCCESS: u16 = 0;
+const ERRNO_BADF: u16 = 8;
+const ERRNO_EXIST: u16 = 20;
+const ERRNO_INVAL: u16 = 28;
+const ERRNO_NOENT: u16 = 44;
+const ERRNO_NOTSUP: u16 = 58;
+
+const OFLAGS_CREAT: u16 = 1;
+const OFLAGS_DIRECTORY: u16 = 2;
+const OFLAGS_EXCL: u16 = 4;
+const OFLAGS_TRUNC: u16 = 8;
+
+const FILETYPE_REGULAR_FILE: u8 = 4;
 
-    /*println!("abc");
+#[link(wasm_import_module = "kwast")]
+extern "C" {
+    fn scheme_receive_commands(fd: u32, buf: *mut u8, buf_len: u32, nread: *mut u32) -> u16;
+    fn scheme_send_replies(fd: u32, buf: *const u8, buf_len: u32, nwritten: *mut u32) -> u16;
+    fn scheme_grant_read(
+        fd: u32,
+        grant: u64,
+        offset: u64,
+        buf: *mut u8,
+        buf_len: u32,
+        nread: *mut u32,
+    ) -> u16;
+    fn scheme_grant_write(
+        fd: u32,
+        grant: u64,
+        offset: u64,
+        buf: *const u8,
+        buf_len: u32,
+        nwritten: *mut u32,
+    ) -> u16;
+}
 
-    for (k, v) in env::vars() {
-        println!("{}: {}", k, v);
+/// Copies from the buffer of the client, returns the amount of bytes copied.
+fn grant_read(grant: u64, buf: &mut [u8]) -> Result<u64, u16> {
+    let mut nread = 0;
+    match unsafe {
+        scheme_grant_read(
+            SCHEME_FD,
+            grant,
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+            &mut nread,
+        )
+    } {
+        ERRNO_SUCCESS => Ok(nread as u64),
+        errno => Err(errno),
     }
+}
 
-    println!("-----");*/
+/// Copies to the buffer of the client, returns the amount of bytes copied.
+fn grant_write(grant: u64, buf: &[u8]) -> Result<u64, u16> {
+    let mut nwritten = 0;
+    match unsafe {
+        scheme_grant_write(
+            SCHEME_FD,
+            grant,
+            0,
+            buf.as_ptr(),
+            buf.len() as u32,
+            &mut nwritten,
+        )
+    } {
+        ERRNO_SUCCESS => Ok(nwritten as u64),
+        errno => Err(errno),
+    }
+}
 
-    println!("Hello");
+struct OpenFile {
+    path: Vec<u8>,
+    offset: u64,
+}
 
-    let mut file = File::open(".").expect("open test");
-    let mut buffer = [0u8; 64];
-    for i in 0..10000 {
-        let res = file.read(&mut buffer[..]).expect("read test");
-        //println!("{}", res);
-        let test = unsafe {
-            std::slice::from_raw_parts(&buffer[..] as *const _ as *const Command, 1) // TODO
-        };
+/// A flat scheme that keeps its files in memory.
+struct MemoryScheme {
+    files: BTreeMap<Vec<u8>, Vec<u8>>,
+    handles: BTreeMap<u64, OpenFile>,
+    next_handle: u64,
+}
+
+impl MemoryScheme {
+    fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            next_handle: 0,
+        }
+    }
 
-        let command = test[0];
-        //println!("read one: {:?}", command);
-        //assert_eq!(command.sender, 1);
+    /// Handles a command, returns the value of the reply.
+    fn handle(&mut self, command: &CommandData) -> Result<u64, u16> {
+        match *command {
+            CommandData::Open(ref open) => self.open(open.path.as_bytes(), open.o_flags),
+            CommandData::Close(handle) => self.handles.remove(&handle).map(|_| 0).ok_or(ERRNO_BADF),
+            CommandData::Read(ref transfer) => {
+                let offset = self.open_file(transfer.handle)?.offset;
+                let read = self.read(transfer, offset)?;
+                self.handles.get_mut(&transfer.handle).unwrap().offset += read;
+                Ok(read)
+            }
+            CommandData::Write(ref transfer) => {
+                let offset = self.open_file(transfer.handle)?.offset;
+                let written = self.write(transfer, offset)?;
+                self.handles.get_mut(&transfer.handle).unwrap().offset += written;
+                Ok(written)
+            }
+            CommandData::ReadAt(ref transfer) => self.read(transfer, transfer.offset),
+            CommandData::WriteAt(ref transfer) => self.write(transfer, transfer.offset),
+            CommandData::FileStat(ref transfer) => {
+                let path = self.open_file(transfer.handle)?.path.clone();
+                self.stat(&path, transfer.grant)
+            }
+            CommandData::PathFileStat(ref transfer) => {
+                self.stat(transfer.path.as_bytes(), transfer.grant)
+            }
+            _ => Err(ERRNO_NOTSUP),
+        }
+    }
 
-        let mut test = unsafe {
-            std::slice::from_raw_parts_mut(&mut buffer[..] as *mut _ as *mut Reply, 1) // TODO
-        };
-        test[0] = Reply {
-            to: command.sender,
-            payload: ReplyPayload {
-                status: 0,
-                value: 12,
+    fn open_file(&self, handle: u64) -> Result<&OpenFile, u16> {
+        self.handles.get(&handle).ok_or(ERRNO_BADF)
+    }
+
+    fn file(&self, path: &[u8]) -> Result<&Vec<u8>, u16> {
+        self.files.get(path).ok_or(ERRNO_NOENT)
+    }
+
+    fn open(&mut self, path: &[u8], o_flags: u16) -> Result<u64, u16> {
+        if o_flags & OFLAGS_DIRECTORY != 0 {
+            return Err(ERRNO_NOTSUP);
+        }
+
+        match self.files.get_mut(path) {
+            Some(_) if o_flags & OFLAGS_EXCL != 0 => return Err(ERRNO_EXIST),
+            Some(data) if o_flags & OFLAGS_TRUNC != 0 => data.clear(),
+            Some(_) => {}
+            None if o_flags & OFLAGS_CREAT != 0 => {
+                self.files.insert(path.to_vec(), Vec::new());
+            }
+            None => return Err(ERRNO_NOENT),
+        }
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(
+            handle,
+            OpenFile {
+                path: path.to_vec(),
+                offset: 0,
             },
+        );
+        Ok(handle)
+    }
+
+    fn read(&self, transfer: &TransferCommand, offset: u64) -> Result<u64, u16> {
+        let data = self.file(&self.open_file(transfer.handle)?.path)?;
+        let start = min(offset, data.len() as u64) as usize;
+        let end = min(offset.saturating_add(transfer.len), data.len() as u64) as usize;
+        grant_write(transfer.grant, &data[start..end])
+    }
+
+    fn write(&mut self, transfer: &TransferCommand, offset: u64) -> Result<u64, u16> {
+        let path = self.open_file(transfer.handle)?.path.clone();
+        offset.checked_add(transfer.len).ok_or(ERRNO_INVAL)?;
+        let mut buffer = vec![0u8; transfer.len as usize];
+        let written = grant_read(transfer.grant, &mut buffer)?;
+
+        let data = self.files.get_mut(&path).ok_or(ERRNO_NOENT)?;
+        let (start, end) = (offset as usize, (offset + written) as usize);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(&buffer[..written as usize]);
+        Ok(written)
+    }
+
+    fn stat(&self, path: &[u8], grant: u64) -> Result<u64, u16> {
+        let stat = FileStat {
+            dev: 0,
+            ino: 0,
+            filetype: FILETYPE_REGULAR_FILE,
+            nlink: 1,
+            size: self.file(path)?.len() as u64,
+            atim: 0,
+            mtim: 0,
+            ctim: 0,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<FileStat>())
+        };
+        grant_write(grant, bytes)
+    }
+}
+
+fn main() {
+    println!("Hello");
+
+    let mut scheme = MemoryScheme::new();
+    // Safety: a zeroed command is a valid open command.
+    let mut commands: [Command; 8] = unsafe { std::mem::zeroed() };
+    let mut replies = Vec::with_capacity(commands.len());
+
+    loop {
+        let mut nread = 0;
+        let errno = unsafe {
+            scheme_receive_commands(
+                SCHEME_FD,
+                commands.as_mut_ptr() as *mut u8,
+                size_of_val(&commands) as u32,
+                &mut nread,
+            )
         };
+        assert_eq!(errno, ERRNO_SUCCESS, "receive commands");
+
+        let count = nread as usize / size_of::<Command>();
+        replies.clear();
+        replies.extend(commands[..count].iter().map(|command| {
+            let (status, value) = match scheme.handle(&command.payload) {
+                Ok(value) => (ERRNO_SUCCESS, value),
+                Err(errno) => (errno, 0),
+            };
+            Reply {
+                to: command.sender,
+                payload: ReplyPayload { status, value },
+            }
+        }));
 
-        let res = file.write(&mut buffer[..24]).expect("write test");
-        //println!("{} {} w", res, i-1);
+        let mut nwritten = 0;
+        let errno = unsafe {
+            scheme_send_replies(
+                SCHEME_FD,
+                replies.as_ptr() as *const u8,
+                (replies.len() * size_of::<Reply>()) as u32,
+                &mut nwritten,
+            )
+        };
+        assert_eq!(errno, ERRNO_SUCCESS, "send replies");
     }
-    println!("end");
 }